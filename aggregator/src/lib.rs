@@ -0,0 +1,74 @@
+#![no_std]
+
+use soroban_sdk::{
+    contract, contractclient, contracterror, contractimpl, symbol_short, Address, Env, Vec,
+};
+
+pub(crate) const MAX_FARMS: u32 = 20;
+
+#[contracterror]
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum AggregatorError {
+    NoFarms = 1,
+    FarmLimit = 2,
+}
+
+/// Subset of the farm interface the aggregator relies on.
+#[contractclient(name = "FarmClient")]
+pub trait FarmInterface {
+    fn claim_all(e: Env, claimer: Address) -> (i128, i128);
+    fn get_reward_token_addresses(e: Env) -> (Address, Option<Address>);
+}
+
+/// Adds `amount` of `token` to the running totals.
+fn add_total(totals: &mut Vec<(Address, i128)>, token: Address, amount: i128) {
+    if amount == 0 {
+        return;
+    }
+    match totals.iter().position(|(total_token, _)| total_token == token) {
+        Some(index) => {
+            let (_, total) = totals.get_unchecked(index as u32);
+            totals.set(index as u32, (token, total + amount));
+        }
+        None => totals.push_back((token, amount)),
+    }
+}
+
+#[contract]
+pub struct Aggregator;
+
+#[contractimpl]
+impl Aggregator {
+    /// Claims `user`'s rewards from every position in each of `farms`, up to MAX_FARMS, and
+    /// returns the totals paid per reward token. The user authorizes the claim on each farm
+    /// along with this call.
+    pub fn claim(e: &Env, user: Address, farms: Vec<Address>) -> Result<Vec<(Address, i128)>, AggregatorError> {
+        user.require_auth();
+
+        if farms.is_empty() {
+            return Err(AggregatorError::NoFarms);
+        }
+        if farms.len() > MAX_FARMS {
+            return Err(AggregatorError::FarmLimit);
+        }
+
+        let mut totals = Vec::new(e);
+        for farm in farms.iter() {
+            let farm_client = FarmClient::new(e, &farm);
+            let (paid1, paid2) = farm_client.claim_all(&user);
+            let (token1, token2) = farm_client.get_reward_token_addresses();
+            add_total(&mut totals, token1, paid1);
+            if let Some(token2) = token2 {
+                add_total(&mut totals, token2, paid2);
+            }
+        }
+
+        e.events()
+            .publish((symbol_short!("Harvest"), user), (farms.len(), totals.clone()));
+
+        Ok(totals)
+    }
+}
+
+mod test;
@@ -0,0 +1,69 @@
+#![cfg(test)]
+extern crate std;
+
+use super::*;
+use soroban_sdk::{contract, contractimpl, symbol_short, testutils::Address as _, Address, Env};
+
+#[contract]
+struct MockFarm;
+
+#[contractimpl]
+impl MockFarm {
+    pub fn setup(e: Env, token1: Address, token2: Option<Address>, paid: (i128, i128)) {
+        e.storage().instance().set(&symbol_short!("token1"), &token1);
+        e.storage().instance().set(&symbol_short!("token2"), &token2);
+        e.storage().instance().set(&symbol_short!("paid"), &paid);
+    }
+
+    pub fn claim_all(e: Env, claimer: Address) -> (i128, i128) {
+        claimer.require_auth();
+        e.storage().instance().get(&symbol_short!("paid")).unwrap()
+    }
+
+    pub fn get_reward_token_addresses(e: Env) -> (Address, Option<Address>) {
+        (
+            e.storage().instance().get(&symbol_short!("token1")).unwrap(),
+            e.storage().instance().get(&symbol_short!("token2")).unwrap(),
+        )
+    }
+}
+
+#[test]
+fn test_claim_sums_per_token() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let user = Address::generate(&e);
+    let token1 = Address::generate(&e);
+    let token2 = Address::generate(&e);
+    let token3 = Address::generate(&e);
+
+    let farm1 = MockFarmClient::new(&e, &e.register_contract(None, MockFarm {}));
+    let farm2 = MockFarmClient::new(&e, &e.register_contract(None, MockFarm {}));
+    let farm3 = MockFarmClient::new(&e, &e.register_contract(None, MockFarm {}));
+    farm1.setup(&token1, &Some(token2.clone()), &(100, 50));
+    farm2.setup(&token2, &None, &(30, 0));
+    farm3.setup(&token3, &Some(token1.clone()), &(0, 7));
+
+    let aggregator = AggregatorClient::new(&e, &e.register_contract(None, Aggregator {}));
+    assert_eq!(
+        aggregator.try_claim(&user, &Vec::new(&e)),
+        Err(Ok(AggregatorError::NoFarms))
+    );
+    let mut too_many = Vec::new(&e);
+    for _ in 0..21 {
+        too_many.push_back(farm1.address.clone());
+    }
+    assert_eq!(
+        aggregator.try_claim(&user, &too_many),
+        Err(Ok(AggregatorError::FarmLimit))
+    );
+
+    // Tokens rewarded by several farms are summed, tokens nothing was paid in are left out
+    let farms = Vec::from_array(&e, [farm1.address.clone(), farm2.address.clone(), farm3.address.clone()]);
+    assert_eq!(
+        aggregator.claim(&user, &farms),
+        Vec::from_array(&e, [(token1, 107), (token2, 80)])
+    );
+    assert_eq!(e.auths()[0].0, user);
+}
@@ -0,0 +1,70 @@
+use soroban_sdk::{Env, String};
+
+pub(crate) const SECONDS_PER_DAY: u64 = 86400;
+pub(crate) const SECONDS_PER_YEAR: u64 = 365 * SECONDS_PER_DAY;
+
+// Formats a fixed point `value` scaled by 10^`decimals` as a decimal string, e.g.
+// 12345678 with 7 decimals becomes "1.2345678"
+pub(crate) fn format_fixed(e: &Env, value: i128, decimals: u32) -> String {
+    let mut buf = [0u8; 48];
+    let mut pos = buf.len();
+    let mut remaining = value.unsigned_abs();
+
+    for _ in 0..decimals {
+        pos -= 1;
+        buf[pos] = b'0' + (remaining % 10) as u8;
+        remaining /= 10;
+    }
+    if decimals > 0 {
+        pos -= 1;
+        buf[pos] = b'.';
+    }
+    loop {
+        pos -= 1;
+        buf[pos] = b'0' + (remaining % 10) as u8;
+        remaining /= 10;
+        if remaining == 0 {
+            break;
+        }
+    }
+    if value < 0 {
+        pos -= 1;
+        buf[pos] = b'-';
+    }
+
+    String::from_bytes(e, &buf[pos..])
+}
+
+// Formats a unix `timestamp` as an ISO 8601 UTC date time, e.g. "2024-09-27T08:00:00Z"
+pub(crate) fn format_iso8601(e: &Env, timestamp: u64) -> String {
+    let days = timestamp / SECONDS_PER_DAY;
+    let seconds_of_day = timestamp % SECONDS_PER_DAY;
+
+    // Civil date from days since the epoch (proleptic Gregorian calendar)
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let mut buf = *b"0000-00-00T00:00:00Z";
+    write_digits(&mut buf[0..4], year);
+    write_digits(&mut buf[5..7], month);
+    write_digits(&mut buf[8..10], day);
+    write_digits(&mut buf[11..13], seconds_of_day / 3600);
+    write_digits(&mut buf[14..16], seconds_of_day % 3600 / 60);
+    write_digits(&mut buf[17..19], seconds_of_day % 60);
+
+    String::from_bytes(e, &buf)
+}
+
+fn write_digits(buf: &mut [u8], mut value: u64) {
+    for digit in buf.iter_mut().rev() {
+        *digit = b'0' + (value % 10) as u8;
+        value /= 10;
+    }
+}
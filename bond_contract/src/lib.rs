@@ -1,19 +1,32 @@
 #![no_std]
 
+mod display;
+mod permit;
 mod token;
 
 use soroban_sdk::{
-    contract, contracterror, contractimpl, symbol_short, xdr::ToXdr, Address, Bytes, BytesN,
-    ConversionError, Env, IntoVal, String, TryFromVal, Val,
+    auth::{ContractContext, InvokerContractAuthEntry, SubContractInvocation},
+    contract, contracterror, contractimpl, contracttype, symbol_short, xdr::ToXdr, Address, Bytes, BytesN,
+    ConversionError, Env, IntoVal, Map, String, Symbol, TryFromVal, Val, Vec,
 };
 
 pub(crate) const DAY_IN_LEDGERS: u32 = 17280;
 pub(crate) const MAX_TTL: u32 = 3110400;
 pub(crate) const DECIMALS: u32 = 7;
+pub(crate) const MAX_PAGE_SIZE: u32 = 50;
+pub(crate) const MAX_PRICE_TIERS: u32 = 20;
+pub(crate) const MIGRATION_DELAY: u64 = 7 * 86400;
+pub(crate) const MAX_QUOTERS: u32 = 10;
+pub(crate) const MAX_METADATA_ENTRIES: u32 = 20;
+pub(crate) const MAX_METADATA_KEY_LEN: u32 = 32;
+pub(crate) const MAX_METADATA_VALUE_LEN: u32 = 256;
+pub(crate) const MAX_AUCTION_BIDS: u32 = 50;
 
 #[derive(Clone, Copy)]
 #[repr(u32)]
 pub enum DataKey {
+    // Token, TokenShare, StartTime, EndTime, CurrentQuote, QuoteExpiration, Treasury, MinDeposit
+    // and AccountingMode are only read to assemble the SeriesConfig of older vaults
     Token = 0,
     TokenShare = 1,
     Admin = 2,
@@ -29,6 +42,89 @@ pub enum DataKey {
     MinDeposit = 12,
     Initialized = 13,
     Stopped = 14,
+    HolderCount = 15,
+    Holder = 16,
+    HolderIndex = 17,
+    // 18 is retired: entitlements are computed from share balances when needed
+    ClaimPeriod = 19,
+    ClaimDeadline = 20,
+    TokenWasmHash = 21,
+    ShareTokenRevision = 22,
+    CostBasis = 23,
+    RetiredShares = 24,
+    MaxDeposit = 25,
+    Guardian = 26,
+    GuardianWindow = 27,
+    LastAdminAction = 28,
+    RatingAgency = 29,
+    RatingCount = 30,
+    Rating = 31,
+    PriceTiers = 32,
+    Beneficiary = 33,
+    Anchor = 34,
+    AnchorFees = 35,
+    TotalAnchorFees = 36,
+    AccountingMode = 37,
+    Donations = 38,
+    SyncTolerance = 39,
+    Discrepancy = 40,
+    MulticallActive = 41,
+    BidQuote = 42,
+    BidQuoteExpiration = 43,
+    BidQuoter = 44,
+    BuybackReserve = 45,
+    TotalRedeemed = 46,
+    LargestDeposit = 47,
+    MigrationRegistry = 48,
+    PendingMigration = 49,
+    MigrationSnapshot = 50,
+    NoteTerm = 51,
+    ReceiptCount = 52,
+    Receipt = 53,
+    PreviousSeries = 54,
+    PermitNonce = 55,
+    CovenantBps = 56,
+    CovenantDeposits = 57,
+    CovenantBalance = 58,
+    Quoters = 59,
+    QuoteQuorum = 60,
+    QuoteDeviation = 61,
+    QuoteSubmission = 62,
+    Metadata = 63,
+    TvlRegistry = 64,
+    ReportedTvl = 65,
+    ChallengeWindow = 66,
+    RedemptionChallenge = 67,
+    TxCounter = 68,
+    PushedRedemption = 69,
+    PushedTotal = 70,
+    SettlementAsset = 71,
+    SettlementRate = 72,
+    QuoteBounds = 73,
+    PendingQuote = 74,
+    MaturityGrace = 75,
+    SettledAt = 76,
+    TreasurySplit = 77,
+    RedemptionFunded = 78,
+    Entered = 79, // Temporary flag set while a deposit or withdrawal executes
+    Certificate = 80,
+    CertificateBasis = 81,
+    Auction = 82,
+    AuctionBids = 83,
+    // 84 and 85 are retired: share balances are not checkpointed, the share token has no
+    // transfer hook to keep them current
+    SoftCap = 86,
+    CancelledAt = 87,
+    Operator = 88,
+    CreatedAt = 89,
+    SeriesConfig = 90,
+    Subscription = 91,
+    SubscriptionEscrow = 92,
+    IdempotencyKey = 93,
+    TransferTolerance = 94,
+    TotalDepositedLifetime = 95,
+    SettlementCarry = 96,
+    RedeemedCostBasis = 97,
 }
 
 impl TryFromVal<Env, DataKey> for Val {
@@ -56,20 +152,498 @@ pub enum VaultError {
     QuoteStillValid = 11,
     QuoteChanged = 12,
     QuoteExpired = 13,
+    ClaimDeadlinePassed = 14,
+    ClaimDeadlineNotReached = 15,
+    SharesAlreadyIssued = 16,
+    GuardianNotSet = 17,
+    AdminStillActive = 18,
+    RatingAgencyNotSet = 19,
+    RatingNotFound = 20,
+    TierCapacityExceeded = 21,
+    BeneficiaryNotSet = 22,
+    AnchorNotSet = 23,
+    UnsupportedInMode = 24,
+    InvalidCall = 25,
+    InsufficientReserve = 26,
+    MigrationRegistryNotSet = 27,
+    MigrationNotScheduled = 28,
+    MigrationTimelocked = 29,
+    SuccessorNotApproved = 30,
+    NoteTermNotSet = 31,
+    ReceiptNotFound = 32,
+    ReceiptRedeemed = 33,
+    SeriesNotLinked = 34,
+    PermitExpired = 35,
+    InvalidNonce = 36,
+    InvalidSigner = 37,
+    QuorumNotReached = 38,
+    NotQuoter = 39,
+    MetadataTooLarge = 40,
+    MetadataNotFound = 41,
+    TvlRegistryNotSet = 42,
+    // 43 is retired: the TVL registry can be replaced
+    ChallengeWindowOpen = 44,
+    ChallengeWindowClosed = 45,
+    // 46 is retired: a second correction fails with AvailableRedemptionAlreadySet
+    NothingPushed = 47,
+    SettlementAssetNotSet = 48,
+    QuoteOutOfBounds = 49,
+    QuoteNotProposed = 50,
+    MathOverflow = 51,
+    ReceivedLessThanExpected = 52,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct DisplayInfo {
+    pub price: String,
+    pub apy: String,
+    pub maturity: String,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct ShareMetadata {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u32,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct BondMetadata {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u32,
+    pub maturity: u64,
+    pub underlying: Address,
+}
+
+// Configuration and totals of the series, for indexers and integrators rendering a vault
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct VaultInfo {
+    pub token: Address,
+    pub share_token: Address,
+    pub symbol: String, // Symbol of the share token, identifying the series
+    pub admin: Address,
+    pub treasury: Address,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub quote_period: u64,
+    pub min_deposit: u128,
+    pub total_fees: i128, // Fees the fiat anchor netted out of deposits
+    pub state: VaultState,
+    pub total_shares: i128,
+    pub total_reserve: i128, // Redemption token balance held by the vault
+}
+
+// Configuration read by every deposit and redemption, stored as a single instance entry that is
+// read and written as a unit, so that it is loaded with one storage access rather than one per
+// value. `series` caches the share token symbol
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct SeriesConfig {
+    pub token: Address,
+    pub share_token: Address,
+    pub series: String,
+    pub treasury: Address,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub mode: AccountingMode,
+    pub min_deposit: u128,
+    pub quote: i128,
+    pub quote_expiration: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct GuardianInfo {
+    pub guardian: Address,
+    pub inactivity_window: u64,
+    pub last_admin_action: u64,
+}
+
+// How shares are priced. FixedQuote issues shares at the admin quote during the primary window
+// and redeems them from the total redemption set at maturity. Nav keeps the deposits in the
+// vault and prices shares against its token balance, so deposits and withdrawals stay open.
+// Notes issues shares at the admin quote as rolling notes: every deposit gets a receipt
+// maturing one note term later, redeemed at par from the funds the admin provides. Auction
+// issues a fixed supply of shares at the uniform quote clearing the bids, redeemed like
+// FixedQuote
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum AccountingMode {
+    FixedQuote = 0,
+    Nav = 1,
+    Notes = 2,
+    Auction = 3,
+}
+
+// Uniform-price issuance of `supply` shares. Bids are taken until `close_time` and no bid is
+// issued more than `reserve_quote` shares per token. `clearing_quote` is set at settlement
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Auction {
+    pub supply: i128,
+    pub reserve_quote: i128,
+    pub close_time: u64,
+    pub clearing_quote: i128,
+}
+
+// Bid escrowing `amount` of the underlying token for shares at a quote of at least `min_quote`
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AuctionBid {
+    pub bidder: Address,
+    pub amount: i128,
+    pub min_quote: i128,
+}
+
+// Commitment to deposit `target` at the `quote` of the time it was made, paid in installments
+// while the series is open. `paid` is escrowed until the target is reached
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Subscription {
+    pub target: i128,
+    pub paid: i128,
+    pub quote: i128,
+}
+
+// Lifecycle of a series: Pending until the start time, Open for deposits until maturity, Closed
+// during the grace period after maturity, in which deposits stay closed and quotes are frozen
+// but the redemption cannot be set yet, Matured until the total redemption is set and Settled
+// from then on. An offering cancelled before maturity stays Cancelled, with deposits refunded
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[contracttype]
+pub enum VaultState {
+    Pending = 0,
+    Open = 1,
+    Closed = 2,
+    Matured = 3,
+    Settled = 4,
+    Cancelled = 5,
+}
+
+// Times the series went through each lifecycle transition, 0 for transitions that have not
+// happened. Series initialized before creation times were recorded report 0 as created_at
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct SeriesTimeline {
+    pub created_at: u64,
+    pub open_at: u64,
+    pub close_at: u64,
+    pub matured_at: u64,
+    pub settled_at: u64,
+    pub cancelled_at: u64,
+}
+
+// Asset a cross-currency series redeems in, and the rate it was settled at in units of the
+// asset per deposit token unit, scaled by 10^DECIMALS. The rate is 0 until settlement
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Settlement {
+    pub asset: Address,
+    pub rate: i128,
+}
+
+// Payload of the TX events published for every deposit and redemption. `tx_id` increases by
+// one with each of them, so indexers can detect missed events. `quote` is the applied price,
+// in shares per underlying like the vault quote
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct TxRecord {
+    pub tx_id: u64,
+    pub series: String, // Share token symbol
+    pub holder: Address,
+    pub amount: i128, // Underlying paid in or out
+    pub shares: i128,
+    pub quote: i128,
+    pub fee: i128,
+}
+
+// Deposit tranche of a rolling note vault, redeemable for `shares` underlying at `maturity`
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct NoteReceipt {
+    pub id: u32,
+    pub amount: i128,
+    pub shares: i128,
+    pub maturity: u64,
+    pub redeemed: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum CreditRating {
+    Aaa = 0,
+    Aa = 1,
+    A = 2,
+    Bbb = 3,
+    Bb = 4,
+    B = 5,
+    Ccc = 6,
+    Cc = 7,
+    C = 8,
+    D = 9,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct RatingAttestation {
+    pub agency: Address,
+    pub rating: CreditRating,
+    pub report_hash: BytesN<32>,
+    pub timestamp: u64,
+}
+
+// Quote applied to deposits while the cumulative total deposit is below `cap`
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PriceTier {
+    pub cap: i128,
+    pub quote: i128,
+}
+
+// Share of a holder's yield, in basis points, donated to `beneficiary` at redemption
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct BeneficiaryShare {
+    pub beneficiary: Address,
+    pub share_bps: u32,
+}
+
+// Share of the deposits, in basis points, routed to a secondary treasury instead of the
+// treasury (e.g. an insurance fund next to the hedging desk)
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct TreasurySplit {
+    pub secondary: Address,
+    pub secondary_bps: u32,
+}
+
+// Contract minting bond certificates for holdings of at least `threshold` shares
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct CertificateConfig {
+    pub certificate: Address,
+    pub threshold: i128,
+}
+
+// Non-fungible certificate for `locked` shares of the series `series`, as stored by the
+// certificate contract
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct BondCertificate {
+    pub owner: Address,
+    pub series: String,
+    pub maturity: u64,
+    pub locked: i128,
+}
+
+// Outcome of reconciling the redemption funds the vault accounts for with its token balance
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct SyncReport {
+    pub balance: i128,
+    pub expected: i128,
+    pub surplus: i128,
+    pub deficit: i128,
+}
+
+// Successor scheduled to receive the vault's funds once the timelock expires
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PendingMigration {
+    pub successor: Address,
+    pub eta: u64,
+}
+
+// Limits on the quotes the admin sets alone: at most `max_deviation_bps` away from the previous
+// quote and within [min_quote, max_quote]. Zero disables a limit. Quotes outside them take
+// effect once co-signed by the guardian or after `timelock`
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct QuoteBounds {
+    pub max_deviation_bps: u32,
+    pub min_quote: i128,
+    pub max_quote: i128,
+    pub timelock: u64,
+}
+
+// Out of bounds quote awaiting the guardian or the end of the timelock
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PendingQuote {
+    pub quote: i128,
+    pub eta: u64,
+}
+
+// Obligations handed over to the successor along with the funds
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct MigrationSnapshot {
+    pub successor: Address,
+    pub balance: i128,
+    pub total_shares: i128,
+    pub total_deposit: i128,
+    pub available_redemption: i128,
+    pub donations: i128,
+    pub buyback_reserve: i128,
+    pub holder_count: u32,
+    pub timestamp: u64,
+    pub redemption_balance: i128, // Settlement asset handed over, 0 without one
+    pub pushed_total: i128,
+    pub subscription_escrow: i128, // Installments of subscriptions not fully paid
+    pub auction_escrow: i128,      // Bids of an auction not settled yet
+}
+
+// Prior series of a chain of consecutive issues, and the redemption it realized per unit
+// deposited (1e7 scale)
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PreviousSeries {
+    pub vault: Address,
+    pub maturity: u64,
+    pub realized_rate: i128,
+}
+
+// Share of the deposits held back in the vault as a redemption buffer, and what the treasury
+// may still draw
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Covenant {
+    pub buffer_bps: u32,
+    pub deposits: i128, // Deposits received since the covenant took effect
+    pub balance: i128,  // Deposits still held in the vault
+    pub drawable: i128,
+}
+
+// Total redemption under challenge. Withdrawals open at `closes_at`, until which the guardian
+// may correct `amount` once
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct RedemptionChallenge {
+    pub amount: i128, // Total redemption funded by the admin
+    pub closes_at: u64,
+    pub corrected: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct IssuanceStats {
+    pub total_deposited_lifetime: i128,
+    pub total_redeemed_lifetime: i128,
+    pub unique_depositors_count: u32,
+    pub largest_single_deposit: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct HolderPosition {
+    pub holder: Address,
+    pub shares: i128,
+    pub cost_basis: i128,
+    pub anchor_fees: i128, // Fees the fiat anchor netted out of deposits made for the holder
+}
+
+// Vault configuration and totals, as exported by `export_state`
+#[derive(Clone)]
+#[contracttype]
+pub struct VaultConfig {
+    pub token: Address,
+    pub share_token: Address,
+    pub admin: Address,
+    pub treasury: Address,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub quote_period: u64,
+    pub min_deposit: u128,
+    pub max_deposit: u128, // 0 when uncapped
+    pub total_shares: i128,
+    pub total_deposit: i128,
+    pub available_redemption: i128,
+    pub retired_shares: i128,
+    pub claim_deadline: u64, // 0 when none is in effect
+    pub holder_count: u32,
+    pub stopped: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum ExportSection {
+    Config = 0,
+    Holders = 1,
+    Ratings = 2,
+}
+
+// Single record of the exported vault state. Holders come with their outstanding redemption
+// entitlement. The config record dwarfs the others, and contract types cannot be boxed
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+#[contracttype]
+pub enum StateEntry {
+    Config(VaultConfig),
+    Holder(HolderPosition, i128),
+    Rating(u32, RatingAttestation),
 }
 
 fn get_token(e: &Env) -> Result<Address, VaultError> {
-    e.storage()
-        .instance()
-        .get(&DataKey::Token)
-        .ok_or(VaultError::NotInitialized)
+    Ok(get_series_config(e)?.token)
+}
+
+fn get_settlement_asset(e: &Env) -> Option<Address> {
+    e.storage().instance().get(&DataKey::SettlementAsset)
+}
+
+fn get_settlement_rate(e: &Env) -> Option<i128> {
+    e.storage().instance().get(&DataKey::SettlementRate)
+}
+
+// Token the redemption is paid in, the settlement asset once the series has been settled in it
+fn get_redemption_token(e: &Env) -> Result<Address, VaultError> {
+    match (get_settlement_asset(e), get_settlement_rate(e)) {
+        (Some(asset), Some(_)) => Ok(asset),
+        _ => get_token(e),
+    }
+}
+
+// Converts an amount of the deposit token to the redemption token
+fn to_redemption_units(e: &Env, amount: i128) -> Result<i128, VaultError> {
+    match get_settlement_rate(e) {
+        Some(rate) => mul_div(amount, rate, 10i128.pow(DECIMALS)),
+        None => Ok(amount),
+    }
+}
+
+// Converts an amount of the redemption token back to the deposit token
+fn to_deposit_units(e: &Env, amount: i128) -> Result<i128, VaultError> {
+    match get_settlement_rate(e) {
+        Some(rate) => mul_div(amount, 10i128.pow(DECIMALS), rate),
+        None => Ok(amount),
+    }
+}
+
+// Computes `a * b / denominator`, failing with MathOverflow where the product does not fit in
+// an i128 or the denominator is zero
+fn mul_div(a: i128, b: i128, denominator: i128) -> Result<i128, VaultError> {
+    a.checked_mul(b)
+        .and_then(|product| product.checked_div(denominator))
+        .ok_or(VaultError::MathOverflow)
+}
+
+// Shares issued for `amount` of the underlying at `quote`, which is scaled by 10^DECIMALS
+fn quote_shares(amount: i128, quote: i128) -> Result<i128, VaultError> {
+    mul_div(amount, quote, 10i128.pow(DECIMALS))
+}
+
+// Part of `total` attributable to `amount` out of `total_shares`
+fn pro_rata(total: i128, amount: i128, total_shares: i128) -> Result<i128, VaultError> {
+    mul_div(total, amount, total_shares)
 }
 
 fn get_token_share(e: &Env) -> Result<Address, VaultError> {
-    e.storage()
-        .instance()
-        .get(&DataKey::TokenShare)
-        .ok_or(VaultError::NotInitialized)
+    Ok(get_series_config(e)?.share_token)
 }
 
 fn get_admin(e: &Env) -> Result<Address, VaultError> {
@@ -79,18 +653,150 @@ fn get_admin(e: &Env) -> Result<Address, VaultError> {
         .ok_or(VaultError::NotInitialized)
 }
 
-fn get_start_time(e: &Env) -> Result<u64, VaultError> {
+// Requires the admin's authorization and records the action for the guardian inactivity check
+fn require_admin(e: &Env) -> Result<Address, VaultError> {
+    let admin = get_admin(e)?;
+    // The admin already authorized the multicall dispatching this setter, and an address can
+    // only be authorized once per invocation
+    if !e.storage().instance().has(&DataKey::MulticallActive) {
+        admin.require_auth();
+    }
+    put_last_admin_action(e, time(e));
+    Ok(admin)
+}
+
+fn get_last_admin_action(e: &Env) -> u64 {
     e.storage()
         .instance()
-        .get(&DataKey::StartTime)
-        .ok_or(VaultError::NotInitialized)
+        .get(&DataKey::LastAdminAction)
+        .unwrap_or(0)
 }
 
-fn get_end_time(e: &Env) -> Result<u64, VaultError> {
+fn put_last_admin_action(e: &Env, timestamp: u64) {
+    e.storage().instance().set(&DataKey::LastAdminAction, &timestamp)
+}
+
+fn get_guardian(e: &Env) -> Option<Address> {
+    e.storage().instance().get(&DataKey::Guardian)
+}
+
+fn get_guardian_window(e: &Env) -> u64 {
     e.storage()
         .instance()
-        .get(&DataKey::EndTime)
-        .ok_or(VaultError::NotInitialized)
+        .get(&DataKey::GuardianWindow)
+        .unwrap_or(0)
+}
+
+// Requires the guardian's authorization, only once the admin has been inactive for the
+// whole inactivity window
+fn require_guardian(e: &Env) -> Result<Address, VaultError> {
+    let guardian = get_guardian(e).ok_or(VaultError::GuardianNotSet)?;
+    guardian.require_auth();
+
+    let inactive_since = get_last_admin_action(e);
+    if time(e) < inactive_since.saturating_add(get_guardian_window(e)) {
+        return Err(VaultError::AdminStillActive);
+    }
+
+    Ok(guardian)
+}
+
+fn get_start_time(e: &Env) -> Result<u64, VaultError> {
+    Ok(get_series_config(e)?.start_time)
+}
+
+fn get_end_time(e: &Env) -> Result<u64, VaultError> {
+    Ok(get_series_config(e)?.end_time)
+}
+
+fn get_maturity_grace(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::MaturityGrace).unwrap_or(0)
+}
+
+// Reads the series configuration. Vaults initialized before it was kept in one entry assemble
+// it from the individual keys once, calling the share token for its symbol, store it and drop
+// the individual keys
+fn get_series_config(e: &Env) -> Result<SeriesConfig, VaultError> {
+    if let Some(config) = e.storage().instance().get(&DataKey::SeriesConfig) {
+        return Ok(config);
+    }
+
+    let storage = e.storage().instance();
+    let share_token: Address = storage.get(&DataKey::TokenShare).ok_or(VaultError::NotInitialized)?;
+    let config = SeriesConfig {
+        token: storage.get(&DataKey::Token).ok_or(VaultError::NotInitialized)?,
+        series: token::Client::new(e, &share_token).symbol(),
+        share_token,
+        treasury: storage.get(&DataKey::Treasury).ok_or(VaultError::NotInitialized)?,
+        start_time: storage.get(&DataKey::StartTime).ok_or(VaultError::NotInitialized)?,
+        end_time: storage.get(&DataKey::EndTime).ok_or(VaultError::NotInitialized)?,
+        mode: storage.get(&DataKey::AccountingMode).unwrap_or(AccountingMode::FixedQuote),
+        min_deposit: storage.get(&DataKey::MinDeposit).ok_or(VaultError::NotInitialized)?,
+        quote: storage.get(&DataKey::CurrentQuote).unwrap_or(0),
+        quote_expiration: storage.get(&DataKey::QuoteExpiration).unwrap_or(0),
+    };
+    put_series_config(e, &config);
+    for key in [
+        DataKey::Token,
+        DataKey::TokenShare,
+        DataKey::Treasury,
+        DataKey::StartTime,
+        DataKey::EndTime,
+        DataKey::AccountingMode,
+        DataKey::MinDeposit,
+        DataKey::CurrentQuote,
+        DataKey::QuoteExpiration,
+    ] {
+        storage.remove(&key);
+    }
+    Ok(config)
+}
+
+fn put_series_config(e: &Env, config: &SeriesConfig) {
+    e.storage().instance().set(&DataKey::SeriesConfig, config)
+}
+
+// Applies `update` to the series configuration and stores it
+fn update_series_config(e: &Env, update: impl FnOnce(&mut SeriesConfig)) -> Result<(), VaultError> {
+    let mut config = get_series_config(e)?;
+    update(&mut config);
+    put_series_config(e, &config);
+    Ok(())
+}
+
+fn get_state(e: &Env) -> Result<VaultState, VaultError> {
+    series_state(e, &get_series_config(e)?)
+}
+
+fn series_state(e: &Env, config: &SeriesConfig) -> Result<VaultState, VaultError> {
+    let now = time(e);
+    let end_time = config.end_time;
+    if e.storage().instance().has(&DataKey::CancelledAt) {
+        Ok(VaultState::Cancelled)
+    } else if now < config.start_time {
+        Ok(VaultState::Pending)
+    } else if now < end_time {
+        Ok(VaultState::Open)
+    } else if e.storage().instance().has(&DataKey::SettledAt) {
+        Ok(VaultState::Settled)
+    } else if now < end_time.saturating_add(get_maturity_grace(e)) {
+        Ok(VaultState::Closed)
+    } else {
+        Ok(VaultState::Matured)
+    }
+}
+
+// Total deposit below which the admin can cancel the offering, 0 when not configured
+fn get_soft_cap(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::SoftCap).unwrap_or(0)
+}
+
+// Fails unless the series is still before maturity
+fn check_before_maturity(e: &Env) -> Result<(), VaultError> {
+    if get_state(e)? >= VaultState::Closed {
+        return Err(VaultError::MaturityReached);
+    }
+    Ok(())
 }
 
 fn get_total_shares(e: &Env) -> Result<i128, VaultError> {
@@ -115,27 +821,31 @@ fn get_available_redemption(e: &Env) -> Result<i128, VaultError> {
 }
 
 fn get_min_deposit(e: &Env) -> Result<u128, VaultError> {
-    e.storage()
-        .instance()
-        .get(&DataKey::MinDeposit)
-        .ok_or(VaultError::NotInitialized)
+    Ok(get_series_config(e)?.min_deposit)
+}
+
+fn get_max_deposit(e: &Env) -> Option<u128> {
+    e.storage().instance().get(&DataKey::MaxDeposit)
+}
+
+fn put_max_deposit(e: &Env, amount: Option<u128>) {
+    if let Some(amount) = amount {
+        e.storage().instance().set(&DataKey::MaxDeposit, &amount)
+    } else {
+        e.storage().instance().remove(&DataKey::MaxDeposit)
+    }
 }
 
 fn get_current_quote(e: &Env) -> Result<i128, VaultError> {
-    let current_quote = e
-        .storage()
-        .instance()
-        .get(&DataKey::CurrentQuote)
-        .ok_or(VaultError::NotInitialized)?;
-    let quote_expiration = e
-        .storage()
-        .instance()
-        .get(&DataKey::QuoteExpiration)
-        .ok_or(VaultError::NotInitialized)?;
+    let config = get_series_config(e)?;
+    check_quote(e, config.quote, config.quote_expiration)
+}
 
+// Returns `current_quote` while it is set and has not expired
+fn check_quote(e: &Env, current_quote: i128, quote_expiration: u64) -> Result<i128, VaultError> {
     // Check they are non-zero
     if current_quote != 0 && quote_expiration != 0 {
-        if time(&e) <= quote_expiration {
+        if time(e) <= quote_expiration {
             Ok(current_quote)
         } else {
             Err(VaultError::QuoteExpired)
@@ -145,454 +855,4672 @@ fn get_current_quote(e: &Env) -> Result<i128, VaultError> {
     }
 }
 
-fn get_quote_period(e: &Env) -> Result<u64, VaultError> {
-    e.storage()
+// Shares the vault buys back per underlying token, scaled like the subscription quote
+fn get_bid_quote(e: &Env) -> Result<i128, VaultError> {
+    let bid_quote: i128 = e.storage().instance().get(&DataKey::BidQuote).unwrap_or(0);
+    let expiration: u64 = e
+        .storage()
         .instance()
-        .get(&DataKey::QuotePeriod)
-        .ok_or(VaultError::NotInitialized)
+        .get(&DataKey::BidQuoteExpiration)
+        .unwrap_or(0);
+
+    if bid_quote == 0 {
+        return Err(VaultError::QuoteRequired);
+    }
+    if time(e) > expiration {
+        return Err(VaultError::QuoteExpired);
+    }
+    Ok(bid_quote)
 }
 
-fn get_treasury(e: &Env) -> Result<Address, VaultError> {
-    e.storage()
-        .instance()
-        .get(&DataKey::Treasury)
-        .ok_or(VaultError::NotInitialized)
+// The buy-back desk setting the bid quote and funding the reserve, the admin by default
+fn get_bid_quoter(e: &Env) -> Result<Address, VaultError> {
+    match e.storage().instance().get(&DataKey::BidQuoter) {
+        Some(quoter) => Ok(quoter),
+        None => get_admin(e),
+    }
 }
 
-fn get_stopped(e: &Env) -> bool {
-    e.storage().instance().get(&DataKey::Stopped).unwrap_or(0) == 1
+// Installments of subscriptions not yet issued or refunded, held by the vault
+fn get_subscription_escrow(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::SubscriptionEscrow).unwrap_or(0)
 }
 
-fn set_stopped(e: &Env, stopped: bool) {
-    e.storage()
-        .instance()
-        .set(&DataKey::Stopped, &if stopped { 1 } else { 0 });
+fn put_subscription_escrow(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::SubscriptionEscrow, &amount)
 }
 
-fn time(e: &Env) -> u64 {
-    e.ledger().timestamp()
+fn get_buyback_reserve(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::BuybackReserve).unwrap_or(0)
 }
 
-fn extend_instance_ttl(e: &Env) {
+fn put_buyback_reserve(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::BuybackReserve, &amount)
+}
+
+fn get_price_tiers(e: &Env) -> Vec<PriceTier> {
     e.storage()
         .instance()
-        .extend_ttl(MAX_TTL - DAY_IN_LEDGERS, MAX_TTL)
+        .get(&DataKey::PriceTiers)
+        .unwrap_or(Vec::new(e))
 }
 
-fn put_token(e: &Env, contract: Address) {
-    e.storage().instance().set(&DataKey::Token, &contract);
+// Returns the quote of the tier the book is currently filling
+fn tier_quote(tiers: &Vec<PriceTier>, total_deposit: i128) -> Result<i128, VaultError> {
+    tiers
+        .iter()
+        .find(|tier| total_deposit < tier.cap)
+        .map(|tier| tier.quote)
+        .ok_or(VaultError::TierCapacityExceeded)
 }
 
-fn put_token_share(e: &Env, contract: Address) {
-    e.storage().instance().set(&DataKey::TokenShare, &contract);
-}
+// Returns the shares issued for depositing `amount` on top of `total_deposit`, pricing each
+// part of the deposit at the quote of the tier it falls in
+fn tiered_quantity(tiers: &Vec<PriceTier>, total_deposit: i128, amount: i128) -> Result<i128, VaultError> {
+    let target = total_deposit + amount;
+    let mut filled = total_deposit;
+    let mut quantity = 0;
 
-fn put_admin(e: &Env, admin: Address) {
-    e.storage().instance().set(&DataKey::Admin, &admin)
-}
+    for tier in tiers.iter() {
+        if filled >= target {
+            break;
+        }
+        if filled >= tier.cap {
+            continue;
+        }
+
+        let end = core::cmp::min(tier.cap, target);
+        quantity = quote_shares(end - filled, tier.quote)?
+            .checked_add(quantity)
+            .ok_or(VaultError::MathOverflow)?;
+        filled = end;
+    }
+
+    if filled < target {
+        return Err(VaultError::TierCapacityExceeded);
+    }
 
-fn put_start_time(e: &Env, time: u64) {
-    e.storage().instance().set(&DataKey::StartTime, &time)
+    Ok(quantity)
 }
 
-fn put_end_time(e: &Env, time: u64) {
-    e.storage().instance().set(&DataKey::EndTime, &time)
+// Returns the quote deposits are currently priced at, from the price tiers when bookbuilding
+// and from the admin quote otherwise, or the NAV quote in NAV mode
+fn get_effective_quote(e: &Env) -> Result<i128, VaultError> {
+    effective_quote(e, &get_series_config(e)?)
 }
 
-fn put_current_quote(e: &Env, amount: i128) {
-    e.storage().instance().set(&DataKey::CurrentQuote, &amount)
+fn effective_quote(e: &Env, config: &SeriesConfig) -> Result<i128, VaultError> {
+    if config.mode == AccountingMode::Nav {
+        return get_nav_quote(e);
+    }
+
+    let tiers = get_price_tiers(e);
+    if tiers.is_empty() {
+        // No quote has been set yet while the expiration is 0
+        if config.quote_expiration == 0 {
+            return Err(VaultError::NotInitialized);
+        }
+        check_quote(e, config.quote, config.quote_expiration)
+    } else {
+        tier_quote(&tiers, get_total_deposit(e)?)
+    }
 }
 
-fn put_quote_expiration(e: &Env) -> Result<(), VaultError> {
-    let expiration_time = time(e) + get_quote_period(e)?;
-    e.storage().instance().set(&DataKey::QuoteExpiration, &expiration_time);
-    Ok(())
+fn get_accounting_mode(e: &Env) -> AccountingMode {
+    get_series_config(e).map_or(AccountingMode::FixedQuote, |config| config.mode)
 }
 
-fn put_quote_period(e: &Env, period: u64) {
-    e.storage().instance().set(&DataKey::QuotePeriod, &period)
+fn is_nav_mode(e: &Env) -> bool {
+    get_accounting_mode(e) == AccountingMode::Nav
 }
 
-fn put_total_shares(e: &Env, amount: i128) {
-    e.storage().instance().set(&DataKey::TotalShares, &amount)
+fn is_notes_mode(e: &Env) -> bool {
+    get_accounting_mode(e) == AccountingMode::Notes
 }
 
-fn put_total_deposit(e: &Env, amount: i128) {
-    e.storage().instance().set(&DataKey::TotalDeposit, &amount)
+// Returns an error for operations that only make sense with fixed quote accounting, of which
+// auctions only change the issuance
+fn require_fixed_quote_mode(e: &Env) -> Result<(), VaultError> {
+    if !matches!(get_accounting_mode(e), AccountingMode::FixedQuote | AccountingMode::Auction) {
+        return Err(VaultError::UnsupportedInMode);
+    }
+    Ok(())
 }
 
-fn put_available_redemption(e: &Env, amount: i128) {
-    e.storage()
-        .instance()
-        .set(&DataKey::AvailableRedemption, &amount)
+// Returns an error for operations on the admin quote, which NAV mode does not use
+fn require_quoted_mode(e: &Env) -> Result<(), VaultError> {
+    if is_nav_mode(e) {
+        return Err(VaultError::UnsupportedInMode);
+    }
+    Ok(())
 }
 
-fn put_treasury(e: &Env, treasury: Address) {
-    e.storage().instance().set(&DataKey::Treasury, &treasury)
+// Underlying tokens backing the shares in NAV mode
+fn get_total_assets(e: &Env) -> Result<i128, VaultError> {
+    Ok(token::Client::new(e, &get_token(e)?).balance(&e.current_contract_address()))
 }
 
-fn put_min_deposit(e: &Env, amount: u128) {
-    e.storage().instance().set(&DataKey::MinDeposit, &amount)
+// Shares issued per underlying token in NAV mode, scaled by 10^DECIMALS. A virtual share and
+// token keep the first deposit from being priced by a donation
+fn get_nav_quote(e: &Env) -> Result<i128, VaultError> {
+    mul_div(get_total_shares(e)? + 1, 10i128.pow(DECIMALS), get_total_assets(e)? + 1)
 }
 
-fn burn_shares(e: &Env, amount: i128) -> Result<(), VaultError> {
-    let total = get_total_shares(e)?;
-    let share_contract_id = get_token_share(e)?;
+// Series attributes live in persistent storage, as up to MAX_METADATA_ENTRIES of them would
+// otherwise be loaded with the instance on every call
+fn get_metadata(e: &Env) -> Map<String, String> {
+    e.storage().persistent().get(&DataKey::Metadata).unwrap_or(Map::new(e))
+}
 
-    token::Client::new(e, &share_contract_id).burn(&e.current_contract_address(), &amount);
-    put_total_shares(e, total - amount);
+fn put_metadata(e: &Env, metadata: &Map<String, String>) {
+    e.storage().persistent().set(&DataKey::Metadata, metadata);
+    e.storage()
+        .persistent()
+        .extend_ttl(&DataKey::Metadata, MAX_TTL - DAY_IN_LEDGERS, MAX_TTL);
+}
 
-    e.events()
-        .publish((symbol_short!("SHARES"), symbol_short!("burned")), amount);
+fn get_quoters(e: &Env) -> Vec<Address> {
+    e.storage().instance().get(&DataKey::Quoters).unwrap_or(Vec::new(e))
+}
 
-    Ok(())
+fn quote_submission_key(quoter: &Address) -> (u32, Address) {
+    (DataKey::QuoteSubmission as u32, quoter.clone())
 }
 
-fn mint_shares(e: &Env, to: Address, amount: i128) -> Result<(), VaultError> {
-    let total = get_total_shares(e)?;
-    let share_contract_id = get_token_share(e)?;
+// Median of `values`, the lower middle one for an even count
+fn median(values: &Vec<i128>) -> i128 {
+    let mut sorted: Vec<i128> = Vec::new(values.env());
+    for value in values.iter() {
+        let mut index = sorted.len();
+        while index > 0 && sorted.get_unchecked(index - 1) > value {
+            index -= 1;
+        }
+        sorted.insert(index, value);
+    }
+    sorted.get_unchecked((sorted.len() - 1) / 2)
+}
 
-    token::Client::new(e, &share_contract_id).mint(&to, &amount);
+// Aggregates the quotes submitted by the quoters within the quote period: the median of those
+// within the deviation band around the median of all of them, provided they reach the quorum
+fn aggregate_quote(e: &Env) -> Result<i128, VaultError> {
+    let quote_period = get_quote_period(e)?;
+    let mut recent = Vec::new(e);
+    for quoter in get_quoters(e).iter() {
+        let submission: Option<(i128, u64)> = e.storage().persistent().get(&quote_submission_key(&quoter));
+        if let Some((quote, submitted_at)) = submission {
+            if time(e) <= submitted_at + quote_period {
+                recent.push_back(quote);
+            }
+        }
+    }
 
-    put_total_shares(e, total + amount);
+    let quorum: u32 = e.storage().instance().get(&DataKey::QuoteQuorum).unwrap_or(0);
+    if recent.is_empty() || recent.len() < quorum {
+        return Err(VaultError::QuorumNotReached);
+    }
 
-    e.events().publish(
-        (symbol_short!("SHARES"), symbol_short!("minted")),
-        (to, amount),
-    );
+    let deviation_bps: u32 = e.storage().instance().get(&DataKey::QuoteDeviation).unwrap_or(0);
+    let reference = median(&recent);
+    let mut agreeing = Vec::new(e);
+    for quote in recent.iter() {
+        if (quote - reference).abs() * 10000 <= reference * deviation_bps as i128 {
+            agreeing.push_back(quote);
+        }
+    }
+    if agreeing.len() < quorum {
+        return Err(VaultError::QuorumNotReached);
+    }
 
-    Ok(())
+    Ok(median(&agreeing))
 }
 
-fn create_contract(e: &Env, token_wasm_hash: BytesN<32>, token: &Address) -> Address {
-    let mut salt = Bytes::new(e);
-    salt.append(&token.to_xdr(e));
-    let salt = e.crypto().sha256(&salt);
-    e.deployer()
-        .with_current_contract(salt)
-        .deploy(token_wasm_hash)
+fn get_quote_bounds(e: &Env) -> Option<QuoteBounds> {
+    e.storage().instance().get(&DataKey::QuoteBounds)
 }
 
-fn check_nonnegative_amount(amount: i128) -> Result<(), VaultError> {
-    if amount < 0 {
-        Err(VaultError::InvalidAmount)
-    } else {
-        Ok(())
+fn within_quote_bounds(e: &Env, quote: i128) -> bool {
+    let bounds = match get_quote_bounds(e) {
+        Some(bounds) => bounds,
+        None => return true,
+    };
+    if quote < bounds.min_quote || (bounds.max_quote > 0 && quote > bounds.max_quote) {
+        return false;
     }
+    // Compared with the last quote set, even if it has expired since
+    let previous = get_series_config(e).map_or(0, |config| config.quote);
+    bounds.max_deviation_bps == 0
+        || previous == 0
+        || (quote - previous).abs() * 10000 <= previous * bounds.max_deviation_bps as i128
 }
 
-fn is_initialized(e: &Env) -> Result<bool, VaultError> {
-    Ok(e.storage()
+fn get_pending_quote(e: &Env) -> Result<PendingQuote, VaultError> {
+    e.storage()
         .instance()
-        .get(&DataKey::Initialized)
+        .get(&DataKey::PendingQuote)
+        .ok_or(VaultError::QuoteNotProposed)
+}
+
+// Replaces the quote set by the admin, provided the current one is no longer valid
+fn replace_quote(e: &Env, amount: i128) -> Result<i128, VaultError> {
+    require_quoted_mode(e)?;
+    check_before_maturity(e)?;
+    if !get_quoters(e).is_empty() {
+        return Err(VaultError::UnsupportedInMode);
+    }
+
+    // Attempt to get the current quote, handle specific errors
+    match get_current_quote(e) {
+        Ok(_) => {
+            // If the current quote is valid, return an error
+            Err(VaultError::QuoteStillValid)
+        },
+        Err(VaultError::NotInitialized) | Err(VaultError::QuoteRequired) | Err(VaultError::QuoteExpired) => {
+            // Proceed with setting the new quote if the current one is not initialized or required
+            check_nonnegative_amount(amount)?;
+            extend_instance_ttl(e);
+            put_current_quote(e, amount)?;
+
+            e.events()
+                .publish((symbol_short!("QUOTE"), symbol_short!("set")), amount);
+
+            Ok(amount)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+fn get_quote_period(e: &Env) -> Result<u64, VaultError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::QuotePeriod)
+        .ok_or(VaultError::NotInitialized)
+}
+
+fn get_treasury(e: &Env) -> Result<Address, VaultError> {
+    Ok(get_series_config(e)?.treasury)
+}
+
+fn get_stopped(e: &Env) -> bool {
+    e.storage().instance().get(&DataKey::Stopped).unwrap_or(0) == 1
+}
+
+fn set_stopped(e: &Env, stopped: bool) {
+    e.storage()
+        .instance()
+        .set(&DataKey::Stopped, &if stopped { 1 } else { 0 });
+}
+
+fn idempotency_key(operation: Symbol, key: BytesN<32>) -> (u32, Symbol, BytesN<32>) {
+    (DataKey::IdempotencyKey as u32, operation, key)
+}
+
+// Returns the result of an admin `operation` already applied under `key`, so that a retried
+// transaction returns it instead of applying the operation again
+fn replayed_result(e: &Env, operation: Symbol, key: &Option<BytesN<32>>) -> Option<i128> {
+    let key = key.clone()?;
+    e.storage().temporary().get(&idempotency_key(operation, key))
+}
+
+// Records the result of an admin `operation` applied under `key`. Keys are kept for a day,
+// long enough to cover the retries of a transaction
+fn record_idempotency_key(e: &Env, operation: Symbol, key: Option<BytesN<32>>, result: i128) {
+    if let Some(key) = key {
+        let storage_key = idempotency_key(operation, key);
+        e.storage().temporary().set(&storage_key, &result);
+        e.storage()
+            .temporary()
+            .extend_ttl(&storage_key, DAY_IN_LEDGERS, DAY_IN_LEDGERS);
+    }
+}
+
+fn time(e: &Env) -> u64 {
+    e.ledger().timestamp()
+}
+
+fn extend_instance_ttl(e: &Env) {
+    e.storage()
+        .instance()
+        .extend_ttl(MAX_TTL - DAY_IN_LEDGERS, MAX_TTL)
+}
+
+fn put_token_share(e: &Env, contract: Address) -> Result<(), VaultError> {
+    update_series_config(e, |config| {
+        config.series = token::Client::new(e, &contract).symbol();
+        config.share_token = contract;
+    })
+}
+
+fn put_admin(e: &Env, admin: Address) {
+    e.storage().instance().set(&DataKey::Admin, &admin)
+}
+
+fn put_current_quote(e: &Env, amount: i128) -> Result<(), VaultError> {
+    let expiration_time = time(e) + get_quote_period(e)?;
+    update_series_config(e, |config| {
+        config.quote = amount;
+        config.quote_expiration = expiration_time;
+    })
+}
+
+fn put_quote_period(e: &Env, period: u64) {
+    e.storage().instance().set(&DataKey::QuotePeriod, &period)
+}
+
+fn put_total_shares(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::TotalShares, &amount)
+}
+
+fn put_total_deposit(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::TotalDeposit, &amount)
+}
+
+fn get_total_deposited_lifetime(e: &Env) -> i128 {
+    e.storage()
+        .instance()
+        .get(&DataKey::TotalDepositedLifetime)
         .unwrap_or(0)
-        == 1)
 }
 
-fn set_initialized(e: &Env) {
-    e.storage().instance().set(&DataKey::Initialized, &1);
+// Adds a deposit to the outstanding total deposit and to the lifetime total, which refunds do
+// not reduce
+fn record_deposit(e: &Env, amount: i128) -> Result<(), VaultError> {
+    put_total_deposit(e, get_total_deposit(e)? + amount);
+    e.storage()
+        .instance()
+        .set(&DataKey::TotalDepositedLifetime, &(get_total_deposited_lifetime(e) + amount));
+    Ok(())
 }
 
-pub trait VaultTrait {
-    fn initialize(
-        e: Env,
-        token_wasm_hash: BytesN<32>,
-        token: Address,
-        admin: Address,
-        start_time: u64,
-        end_time: u64,
-        quote_period: u64,
-        treasury: Address,
-        min_deposit: u128,
-        bond_symbol: String,
-    ) -> Result<String, VaultError>;
+fn get_total_redeemed(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::TotalRedeemed).unwrap_or(0)
+}
 
-    // Returns the token contract address for the vault share token
-    fn bond_id(e: Env) -> Result<Address, VaultError>;
+// Adds underlying paid out for shares to the lifetime redemption total
+fn record_redemption(e: &Env, amount: i128) {
+    e.storage()
+        .instance()
+        .set(&DataKey::TotalRedeemed, &(get_total_redeemed(e) + amount));
+    report_tvl(e);
+}
 
-    // Deposits token. Also mints vault shares for the `from` Identifier. The amount minted
-    // is determined based on the difference between the reserves stored by this contract, and
-    // the actual balance of token for this contract.
-    fn deposit(
-        e: Env,
-        from: Address,
-        amount: i128,
-        expected_quote: i128,
-    ) -> Result<i128, VaultError>;
+// Deposits the shares redeemed so far stood for
+fn get_redeemed_cost_basis(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::RedeemedCostBasis).unwrap_or(0)
+}
 
-    // transfers `amount` of vault share tokens to this contract, burns all pools share tokens in this contracts, and sends the
-    // corresponding amount of token to `to`.
-    // Returns amount of token withdrawn
-    fn withdraw(e: Env, to: Address, amount: i128) -> Result<i128, VaultError>;
+// Releases the deposits standing behind `shares` just burnt by a redemption, pro rata to the
+// shares that were outstanding, so that the value locked drops by their cost and not by the
+// yield paid on them
+fn release_cost_basis(e: &Env, shares: i128) -> Result<(), VaultError> {
+    let redeemed = get_redeemed_cost_basis(e);
+    let outstanding = get_total_shares(e)? + shares;
+    if outstanding <= 0 {
+        return Ok(());
+    }
+    let released = pro_rata(core::cmp::max(get_total_deposit(e)? - redeemed, 0), shares, outstanding)?;
+    e.storage()
+        .instance()
+        .set(&DataKey::RedeemedCostBasis, &(redeemed + released));
+    Ok(())
+}
+
+fn get_tvl_registry(e: &Env) -> Result<Address, VaultError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::TvlRegistry)
+        .ok_or(VaultError::TvlRegistryNotSet)
+}
+
+// Reports the change in the value locked in this series, deposits net of the cost of the
+// shares redeemed, to the TVL registry, if one is set. A failing registry never blocks the vault: the change is kept
+// unreported and carried into the next report
+fn report_tvl(e: &Env) {
+    let Ok(registry) = get_tvl_registry(e) else {
+        return;
+    };
+    let Ok(deposited) = get_total_deposit(e) else {
+        return;
+    };
+    let locked = core::cmp::max(deposited - get_redeemed_cost_basis(e), 0);
+    let reported: i128 = e.storage().instance().get(&DataKey::ReportedTvl).unwrap_or(0);
+    if locked == reported {
+        return;
+    }
+
+    let result = e.try_invoke_contract::<(), soroban_sdk::Error>(
+        &registry,
+        &Symbol::new(e, "report_tvl"),
+        (e.current_contract_address(), locked - reported).into_val(e),
+    );
+    if matches!(result, Ok(Ok(()))) {
+        e.storage().instance().set(&DataKey::ReportedTvl, &locked);
+    }
+}
+
+fn get_largest_deposit(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::LargestDeposit).unwrap_or(0)
+}
+
+fn put_available_redemption(e: &Env, amount: i128) {
+    e.storage()
+        .instance()
+        .set(&DataKey::AvailableRedemption, &amount)
+}
+
+fn put_treasury(e: &Env, treasury: Address) -> Result<(), VaultError> {
+    update_series_config(e, |config| config.treasury = treasury)
+}
+
+fn get_treasury_split(e: &Env) -> Option<TreasurySplit> {
+    e.storage().instance().get(&DataKey::TreasurySplit)
+}
+
+fn get_transfer_tolerance(e: &Env) -> u32 {
+    e.storage().instance().get(&DataKey::TransferTolerance).unwrap_or(0)
+}
+
+fn get_auction(e: &Env) -> Result<Auction, VaultError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::Auction)
+        .ok_or(VaultError::UnsupportedInMode)
+}
+
+fn get_auction_bids(e: &Env) -> Vec<AuctionBid> {
+    e.storage()
+        .persistent()
+        .get(&DataKey::AuctionBids)
+        .unwrap_or(Vec::new(e))
+}
+
+// Whether `emergency_migrate` handed the funds over to a successor
+fn is_migrated(e: &Env) -> bool {
+    e.storage().instance().has(&DataKey::MigrationSnapshot)
+}
+
+// Underlying escrowed by the bids of an auction not settled yet, until it is migrated
+fn get_auction_escrow(e: &Env) -> i128 {
+    if is_migrated(e) {
+        return 0;
+    }
+    match e.storage().instance().get::<_, Auction>(&DataKey::Auction) {
+        Some(auction) if auction.clearing_quote == 0 => {
+            get_auction_bids(e).iter().map(|bid| bid.amount).sum()
+        }
+        _ => 0,
+    }
+}
+
+// Underlying the vault holds on behalf of others rather than for the redemption: the buy-back
+// reserve, the installments of subscriptions and the bids of an unsettled auction
+fn get_escrowed_funds(e: &Env) -> i128 {
+    get_buyback_reserve(e) + get_subscription_escrow(e) + get_auction_escrow(e)
+}
+
+// Bids ordered from the lowest minimum quote, the best for the series, keeping ties in the
+// order they were placed
+fn sorted_auction_bids(e: &Env) -> Vec<AuctionBid> {
+    let mut sorted: Vec<AuctionBid> = Vec::new(e);
+    for bid in get_auction_bids(e).iter() {
+        let index = sorted
+            .iter()
+            .position(|placed| placed.min_quote > bid.min_quote)
+            .unwrap_or(sorted.len() as usize);
+        sorted.insert(index as u32, bid);
+    }
+    sorted
+}
+
+// Lowest quote at which the bids demand the whole supply, taking them from the best for the
+// series. All bids are filled at the reserve quote if they do not
+fn clearing_quote(bids: &Vec<AuctionBid>, auction: &Auction) -> Result<i128, VaultError> {
+    let mut committed = 0;
+    for bid in bids.iter() {
+        committed += bid.amount;
+        if quote_shares(committed, bid.min_quote)? >= auction.supply {
+            return Ok(bid.min_quote);
+        }
+    }
+    Ok(auction.reserve_quote)
+}
+
+fn get_certificate_config(e: &Env) -> Result<CertificateConfig, VaultError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::Certificate)
+        .ok_or(VaultError::InvalidCall)
+}
+
+fn certificate_basis_key(id: u32) -> (u32, u32) {
+    (DataKey::CertificateBasis as u32, id)
+}
+
+fn put_min_deposit(e: &Env, amount: u128) -> Result<(), VaultError> {
+    update_series_config(e, |config| config.min_deposit = amount)
+}
+
+fn get_claim_period(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::ClaimPeriod).unwrap_or(0)
+}
+
+fn put_claim_period(e: &Env, period: u64) {
+    e.storage().instance().set(&DataKey::ClaimPeriod, &period)
+}
+
+fn get_claim_deadline(e: &Env) -> Option<u64> {
+    e.storage().instance().get(&DataKey::ClaimDeadline)
+}
+
+fn put_claim_deadline(e: &Env, deadline: u64) {
+    e.storage().instance().set(&DataKey::ClaimDeadline, &deadline)
+}
+
+fn get_covenant_bps(e: &Env) -> u32 {
+    e.storage().instance().get(&DataKey::CovenantBps).unwrap_or(0)
+}
+
+fn get_covenant_balance(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::CovenantBalance).unwrap_or(0)
+}
+
+fn put_covenant_balance(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::CovenantBalance, &amount)
+}
+
+fn get_covenant(e: &Env) -> Covenant {
+    let buffer_bps = get_covenant_bps(e);
+    let deposits: i128 = e.storage().instance().get(&DataKey::CovenantDeposits).unwrap_or(0);
+    let balance = get_covenant_balance(e);
+    let buffer = deposits * buffer_bps as i128 / 10000;
+    Covenant {
+        buffer_bps,
+        deposits,
+        balance,
+        drawable: core::cmp::max(balance - buffer, 0),
+    }
+}
+
+// Tokens received outside of the redemption flow before the total redemption was set
+fn get_donations(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::Donations).unwrap_or(0)
+}
+
+fn put_donations(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::Donations, &amount)
+}
+
+// Donations and covenant buffer of a series settled in another asset. They stay in the deposit
+// token and are paid out pro rata to the shares redeemed
+fn get_settlement_carry(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::SettlementCarry).unwrap_or(0)
+}
+
+fn put_settlement_carry(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::SettlementCarry, &amount)
+}
+
+fn get_sync_tolerance(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::SyncTolerance).unwrap_or(0)
+}
+
+fn get_discrepancy(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::Discrepancy).unwrap_or(0)
+}
+
+fn get_challenge_window(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::ChallengeWindow).unwrap_or(0)
+}
+
+fn get_redemption_challenge(e: &Env) -> Option<RedemptionChallenge> {
+    e.storage().instance().get(&DataKey::RedemptionChallenge)
+}
+
+fn check_challenge_closed(e: &Env) -> Result<(), VaultError> {
+    if get_redemption_challenge(e).is_some_and(|challenge| time(e) < challenge.closes_at) {
+        return Err(VaultError::ChallengeWindowOpen);
+    }
+    Ok(())
+}
+
+// Checks that the total redemption can be set: the series has matured, the redemption is not
+// set yet and the claim deadline has not passed
+fn check_redemption_open(e: &Env) -> Result<(), VaultError> {
+    match get_state(e)? {
+        VaultState::Cancelled => return Err(VaultError::InvalidCall),
+        state if state < VaultState::Matured => return Err(VaultError::MaturityNotReached),
+        _ => {}
+    }
+    if get_available_redemption(e)? > 0 {
+        return Err(VaultError::AvailableRedemptionAlreadySet);
+    }
+    if claim_deadline_passed(e) {
+        return Err(VaultError::ClaimDeadlinePassed);
+    }
+    Ok(())
+}
+
+// Redemption funds the admin provided through set_total_redemption, settle_redemption and
+// top_up_redemption
+fn get_redemption_funded(e: &Env) -> i128 {
+    e.storage()
+        .instance()
+        .get(&DataKey::RedemptionFunded)
+        .unwrap_or(0)
+}
+
+// Transfers exactly `amount` of `token` from `from` to the vault, rejecting tokens that deliver
+// less than the amount transferred
+fn pull_redemption_funds(e: &Env, token: &Address, from: &Address, amount: i128) -> Result<(), VaultError> {
+    let token_client = token::Client::new(e, token);
+    let before = token_client.balance(&e.current_contract_address());
+    token_client.transfer(from, &e.current_contract_address(), &amount);
+    if token_client.balance(&e.current_contract_address()) - before != amount {
+        return Err(VaultError::InsufficientReserve);
+    }
+    Ok(())
+}
+
+// Rejects funding that leaves the vault unable to pay the redemptions it promised, not counting
+// the funds it holds in escrow for the buy-back desk, subscribers and bidders
+fn check_redemption_covered(e: &Env) -> Result<(), VaultError> {
+    let redemption_token = get_redemption_token(e)?;
+    let mut balance = token::Client::new(e, &redemption_token).balance(&e.current_contract_address());
+    if redemption_token == get_token(e)? {
+        balance -= get_escrowed_funds(e);
+    }
+    if balance < get_available_redemption(e)? + get_pushed_total(e) {
+        return Err(VaultError::InsufficientReserve);
+    }
+    Ok(())
+}
+
+// Opens redemptions of `total_redemption`, of which the admin funded `funded`, starting the
+// challenge window and claim period
+fn open_redemption(e: &Env, funded: i128, total_redemption: i128) -> Result<(), VaultError> {
+    e.storage().instance().set(&DataKey::SettledAt, &time(e));
+    e.storage().instance().set(&DataKey::RedemptionFunded, &funded);
+    put_available_redemption(e, total_redemption);
+    check_redemption_covered(e)?;
+
+    // The claim period runs from when withdrawals open
+    let window = get_challenge_window(e);
+    if window > 0 {
+        e.storage().instance().set(
+            &DataKey::RedemptionChallenge,
+            &RedemptionChallenge {
+                amount: funded,
+                closes_at: time(e) + window,
+                corrected: false,
+            },
+        );
+    }
+
+    let claim_period = get_claim_period(e);
+    if claim_period > 0 {
+        put_claim_deadline(e, time(e) + window + claim_period);
+    }
+
+    Ok(())
+}
+
+fn claim_deadline_passed(e: &Env) -> bool {
+    get_claim_deadline(e).is_some_and(|deadline| time(e) > deadline)
+}
+
+fn holder_key(index: u32) -> (u32, u32) {
+    (DataKey::Holder as u32, index)
+}
+
+fn holder_index_key(holder: Address) -> (u32, Address) {
+    (DataKey::HolderIndex as u32, holder)
+}
+
+fn get_migration_registry(e: &Env) -> Result<Address, VaultError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::MigrationRegistry)
+        .ok_or(VaultError::MigrationRegistryNotSet)
+}
+
+fn get_previous_series(e: &Env) -> Result<PreviousSeries, VaultError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::PreviousSeries)
+        .ok_or(VaultError::SeriesNotLinked)
+}
+
+// Asks the registry whether `successor` is an audited successor contract
+fn require_approved_successor(e: &Env, successor: &Address) -> Result<(), VaultError> {
+    let registry = get_migration_registry(e)?;
+    let approved: bool = e.invoke_contract(
+        &registry,
+        &Symbol::new(e, "is_audited"),
+        (successor.clone(),).into_val(e),
+    );
+    if !approved {
+        return Err(VaultError::SuccessorNotApproved);
+    }
+    Ok(())
+}
+
+fn get_pending_migration(e: &Env) -> Result<PendingMigration, VaultError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::PendingMigration)
+        .ok_or(VaultError::MigrationNotScheduled)
+}
+
+fn get_note_term(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::NoteTerm).unwrap_or(0)
+}
+
+fn receipt_key(holder: Address, id: u32) -> (u32, Address, u32) {
+    (DataKey::Receipt as u32, holder, id)
+}
+
+fn get_receipt_count(e: &Env, holder: Address) -> u32 {
+    e.storage()
+        .persistent()
+        .get(&(DataKey::ReceiptCount as u32, holder))
+        .unwrap_or(0)
+}
+
+fn get_receipt(e: &Env, holder: Address, id: u32) -> Result<NoteReceipt, VaultError> {
+    e.storage()
+        .persistent()
+        .get(&receipt_key(holder, id))
+        .ok_or(VaultError::ReceiptNotFound)
+}
+
+fn put_receipt(e: &Env, holder: Address, receipt: &NoteReceipt) {
+    e.storage()
+        .persistent()
+        .set(&receipt_key(holder, receipt.id), receipt)
+}
+
+// Adds a receipt for `shares` maturing at `maturity` to the receipts of `holder`
+fn append_receipt(e: &Env, holder: &Address, amount: i128, shares: i128, maturity: u64) -> NoteReceipt {
+    let id = get_receipt_count(e, holder.clone());
+    let receipt = NoteReceipt {
+        id,
+        amount,
+        shares,
+        maturity,
+        redeemed: false,
+    };
+    put_receipt(e, holder.clone(), &receipt);
+    e.storage()
+        .persistent()
+        .set(&(DataKey::ReceiptCount as u32, holder.clone()), &(id + 1));
+    receipt
+}
+
+// Records a deposit tranche maturing one note term from now
+fn issue_receipt(e: &Env, holder: &Address, amount: i128, shares: i128) -> NoteReceipt {
+    let receipt = append_receipt(e, holder, amount, shares, time(e) + get_note_term(e));
+
+    e.events().publish(
+        (symbol_short!("NOTE"), symbol_short!("issued")),
+        (holder.clone(), receipt.id, shares, receipt.maturity),
+    );
+
+    receipt
+}
+
+fn get_holder_count(e: &Env) -> u32 {
+    e.storage().instance().get(&DataKey::HolderCount).unwrap_or(0)
+}
+
+fn get_holder(e: &Env, index: u32) -> Option<Address> {
+    e.storage().persistent().get(&holder_key(index))
+}
+
+// Adds `holder` to the holder registry if it is not already part of it. Holders are registered
+// when the vault mints or returns shares to them; the share token has no transfer hook, so an
+// address that only received shares by transfer is not registered
+fn register_holder(e: &Env, holder: &Address) {
+    let index_key = holder_index_key(holder.clone());
+    if e.storage().persistent().has(&index_key) {
+        return;
+    }
+
+    let count = get_holder_count(e);
+    e.storage().persistent().set(&holder_key(count), holder);
+    e.storage().persistent().set(&index_key, &count);
+    e.storage().instance().set(&DataKey::HolderCount, &(count + 1));
+}
+
+fn rating_key(index: u32) -> (u32, u32) {
+    (DataKey::Rating as u32, index)
+}
+
+fn get_rating_agency(e: &Env) -> Option<Address> {
+    e.storage().instance().get(&DataKey::RatingAgency)
+}
+
+fn get_rating_count(e: &Env) -> u32 {
+    e.storage().instance().get(&DataKey::RatingCount).unwrap_or(0)
+}
+
+fn get_rating(e: &Env, index: u32) -> Option<RatingAttestation> {
+    e.storage().persistent().get(&rating_key(index))
+}
+
+// Appends `attestation` to the rating history
+fn push_rating(e: &Env, attestation: &RatingAttestation) {
+    let count = get_rating_count(e);
+    e.storage().persistent().set(&rating_key(count), attestation);
+    e.storage().instance().set(&DataKey::RatingCount, &(count + 1));
+}
+
+fn cost_basis_key(holder: Address) -> (u32, Address) {
+    (DataKey::CostBasis as u32, holder)
+}
+
+fn get_cost_basis(e: &Env, holder: Address) -> i128 {
+    e.storage()
+        .persistent()
+        .get(&cost_basis_key(holder))
+        .unwrap_or(0)
+}
+
+fn put_cost_basis(e: &Env, holder: Address, amount: i128) {
+    e.storage().persistent().set(&cost_basis_key(holder), &amount)
+}
+
+fn beneficiary_key(holder: Address) -> (u32, Address) {
+    (DataKey::Beneficiary as u32, holder)
+}
+
+fn get_beneficiary(e: &Env, holder: Address) -> Option<BeneficiaryShare> {
+    e.storage().persistent().get(&beneficiary_key(holder))
+}
+
+fn anchor_fees_key(holder: Address) -> (u32, Address) {
+    (DataKey::AnchorFees as u32, holder)
+}
+
+fn get_anchor_fees(e: &Env, holder: Address) -> i128 {
+    e.storage()
+        .persistent()
+        .get(&anchor_fees_key(holder))
+        .unwrap_or(0)
+}
+
+fn get_total_anchor_fees(e: &Env) -> i128 {
+    e.storage()
+        .instance()
+        .get(&DataKey::TotalAnchorFees)
+        .unwrap_or(0)
+}
+
+fn get_anchor(e: &Env) -> Result<Address, VaultError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::Anchor)
+        .ok_or(VaultError::AnchorNotSet)
+}
+
+fn holder_position(e: &Env, share_token_client: &token::Client, holder: Address) -> HolderPosition {
+    HolderPosition {
+        shares: share_token_client.balance(&holder),
+        cost_basis: get_cost_basis(e, holder.clone()),
+        anchor_fees: get_anchor_fees(e, holder.clone()),
+        holder,
+    }
+}
+
+// Redemption `holder` is entitled to once the total redemption is set: the pro rata part of
+// the available redemption for the shares they hold. It follows the current share balance, so
+// shares received by transfer carry their entitlement along. Shares whose redemption was
+// pushed are burnt, so they carry none
+fn get_entitlement(e: &Env, holder: Address) -> Result<i128, VaultError> {
+    if !e.storage().instance().has(&DataKey::SettledAt) {
+        return Ok(0);
+    }
+    let shares = token::Client::new(e, &get_token_share(e)?).balance(&holder);
+    let outstanding = get_total_shares(e)?;
+    if shares <= 0 || outstanding <= 0 {
+        return Ok(0);
+    }
+    pro_rata(get_available_redemption(e)?, shares, outstanding)
+}
+
+fn pushed_redemption_key(holder: Address) -> (u32, Address) {
+    (DataKey::PushedRedemption as u32, holder)
+}
+
+fn get_pushed_redemption(e: &Env, holder: Address) -> i128 {
+    e.storage()
+        .persistent()
+        .get(&pushed_redemption_key(holder))
+        .unwrap_or(0)
+}
+
+// Redemptions pushed to holders and not paid out yet
+fn get_pushed_total(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::PushedTotal).unwrap_or(0)
+}
+
+fn get_retired_shares(e: &Env) -> i128 {
+    e.storage()
+        .instance()
+        .get(&DataKey::RetiredShares)
+        .unwrap_or(0)
+}
+
+fn put_retired_shares(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::RetiredShares, &amount)
+}
+
+fn burn_shares(e: &Env, amount: i128) -> Result<(), VaultError> {
+    let total = get_total_shares(e)?;
+    let share_contract_id = get_token_share(e)?;
+
+    put_total_shares(e, total - amount);
+    token::Client::new(e, &share_contract_id).burn(&e.current_contract_address(), &amount);
+
+    e.events()
+        .publish((symbol_short!("SHARES"), symbol_short!("burned")), amount);
+
+    Ok(())
+}
+
+fn mint_shares(e: &Env, to: Address, amount: i128) -> Result<(), VaultError> {
+    let total = get_total_shares(e)?;
+    let share_contract_id = get_token_share(e)?;
+
+    put_total_shares(e, total + amount);
+    register_holder(e, &to);
+
+    token::Client::new(e, &share_contract_id).mint(&to, &amount);
+
+    e.events().publish(
+        (symbol_short!("SHARES"), symbol_short!("minted")),
+        (to, amount),
+    );
+
+    Ok(())
+}
+
+fn get_last_tx_id(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::TxCounter).unwrap_or(0)
+}
+
+// Publishes the TX event of a deposit or redemption under the next transaction id
+fn publish_tx(
+    e: &Env,
+    action: Symbol,
+    holder: &Address,
+    amount: i128,
+    shares: i128,
+    fee: i128,
+) -> Result<(), VaultError> {
+    let tx_id = get_last_tx_id(e) + 1;
+    e.storage().instance().set(&DataKey::TxCounter, &tx_id);
+
+    let quote = if amount > 0 {
+        shares * 10i128.pow(DECIMALS) / amount
+    } else {
+        0
+    };
+    let record = TxRecord {
+        tx_id,
+        series: get_series_config(e)?.series,
+        holder: holder.clone(),
+        amount,
+        shares,
+        quote,
+        fee,
+    };
+    e.events().publish((symbol_short!("TX"), action), record);
+
+    Ok(())
+}
+
+fn get_token_wasm_hash(e: &Env) -> Result<BytesN<32>, VaultError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::TokenWasmHash)
+        .ok_or(VaultError::NotInitialized)
+}
+
+fn put_token_wasm_hash(e: &Env, token_wasm_hash: &BytesN<32>) {
+    e.storage()
+        .instance()
+        .set(&DataKey::TokenWasmHash, token_wasm_hash)
+}
+
+fn get_share_token_revision(e: &Env) -> u32 {
+    e.storage()
+        .instance()
+        .get(&DataKey::ShareTokenRevision)
+        .unwrap_or(0)
+}
+
+fn put_share_token_revision(e: &Env, revision: u32) {
+    e.storage()
+        .instance()
+        .set(&DataKey::ShareTokenRevision, &revision)
+}
+
+fn create_contract(
+    e: &Env,
+    token_wasm_hash: BytesN<32>,
+    token: &Address,
+    revision: u32,
+) -> Address {
+    let mut salt = Bytes::new(e);
+    salt.append(&token.to_xdr(e));
+    // Redeployed share tokens need a fresh salt, the first one keeps the original derivation
+    if revision > 0 {
+        salt.append(&revision.to_xdr(e));
+    }
+    let salt = e.crypto().sha256(&salt);
+    e.deployer()
+        .with_current_contract(salt)
+        .deploy(token_wasm_hash)
+}
+
+// Deploys and initializes a share token carrying the given metadata. Shares are always
+// priced with DECIMALS, so the token must carry as many decimals
+fn deploy_share_token(
+    e: &Env,
+    token_wasm_hash: BytesN<32>,
+    token: &Address,
+    revision: u32,
+    metadata: ShareMetadata,
+) -> Result<Address, VaultError> {
+    if metadata.decimals != DECIMALS {
+        return Err(VaultError::InvalidAmount);
+    }
+    let share_contract_id = create_contract(e, token_wasm_hash, token, revision);
+    token::Client::new(e, &share_contract_id).initialize(
+        &e.current_contract_address(),
+        &metadata.decimals,
+        &metadata.name,
+        &metadata.symbol,
+    );
+    Ok(share_contract_id)
+}
+
+// Where a deposit of `amount` goes, and how much of it each destination receives
+fn deposit_destinations(e: &Env, amount: i128) -> Result<Vec<(Address, i128)>, VaultError> {
+    let config = get_series_config(e)?;
+    // Deposits back the shares directly in NAV mode, and are drawn by the treasury under a covenant
+    if config.mode == AccountingMode::Nav || get_covenant_bps(e) > 0 {
+        return Ok(Vec::from_array(e, [(e.current_contract_address(), amount)]));
+    }
+
+    let treasury = config.treasury;
+    let Some(split) = get_treasury_split(e) else {
+        return Ok(Vec::from_array(e, [(treasury, amount)]));
+    };
+    let secondary_amount = mul_div(amount, split.secondary_bps as i128, 10000)?;
+    let mut destinations = Vec::new(e);
+    if amount > secondary_amount {
+        destinations.push_back((treasury, amount - secondary_amount));
+    }
+    if secondary_amount > 0 {
+        destinations.push_back((split.secondary, secondary_amount));
+    }
+    Ok(destinations)
+}
+
+// The underlying token transfers `deposit` makes on behalf of `from`, one per destination.
+// Contract callers must pre-authorize exactly these invocations
+fn deposit_transfer_contexts(e: &Env, from: &Address, amount: i128) -> Result<Vec<ContractContext>, VaultError> {
+    let token = get_token(e)?;
+    let mut transfers = Vec::new(e);
+    for (destination, leg) in deposit_destinations(e, amount)?.iter() {
+        transfers.push_back(ContractContext {
+            contract: token.clone(),
+            fn_name: Symbol::new(e, "transfer"),
+            args: (from.clone(), destination, leg).into_val(e),
+        });
+    }
+    Ok(transfers)
+}
+
+// Rejects a deposit or withdrawal started while another one is executing, e.g. from a token
+// calling back into the vault. The host rejects re-entering a contract already, the flag keeps
+// the vault safe should a token reach it through another path
+fn enter_guard(e: &Env) -> Result<(), VaultError> {
+    if e.storage().temporary().has(&DataKey::Entered) {
+        return Err(VaultError::InvalidCall);
+    }
+    e.storage().temporary().set(&DataKey::Entered, &true);
+    Ok(())
+}
+
+fn exit_guard(e: &Env) {
+    e.storage().temporary().remove(&DataKey::Entered);
+}
+
+fn permit_nonce_key(from: &Address) -> (u32, Address) {
+    (DataKey::PermitNonce as u32, from.clone())
+}
+
+fn get_permit_nonce(e: &Env, from: &Address) -> u64 {
+    e.storage().persistent().get(&permit_nonce_key(from)).unwrap_or(0)
+}
+
+// Makes the deposit `transfers` of `amount` from `payer` and returns how much their destinations
+// actually received, less than `amount` for tokens charging a fee on transfer. Fails if the
+// shortfall exceeds the transfer tolerance. A leg the payer sends to itself counts in full
+fn receive_deposit(
+    e: &Env,
+    transfers: Vec<ContractContext>,
+    payer: &Address,
+    amount: i128,
+) -> Result<i128, VaultError> {
+    let token = token::Client::new(e, &get_token(e)?);
+    let mut received = 0;
+    let mut recipients: Vec<Address> = Vec::new(e);
+    for (destination, leg) in deposit_destinations(e, amount)?.iter() {
+        if destination == *payer {
+            received += leg;
+        } else if !recipients.contains(&destination) {
+            recipients.push_back(destination);
+        }
+    }
+    let mut balances_before: Vec<i128> = Vec::new(e);
+    for recipient in recipients.iter() {
+        balances_before.push_back(token.balance(&recipient));
+    }
+
+    for transfer in transfers.iter() {
+        e.invoke_contract::<()>(&transfer.contract, &transfer.fn_name, transfer.args);
+    }
+
+    for (recipient, balance_before) in recipients.iter().zip(balances_before.iter()) {
+        received += token.balance(&recipient) - balance_before;
+    }
+
+    let tolerance = mul_div(amount, get_transfer_tolerance(e) as i128, 10000)?;
+    if received <= 0 {
+        return Err(VaultError::InvalidAmount);
+    }
+    if received < amount - tolerance {
+        return Err(VaultError::ReceivedLessThanExpected);
+    }
+    // Anything beyond the requested amount is not credited to the depositor
+    Ok(core::cmp::min(received, amount))
+}
+
+// Takes `amount` from `payer` through the `transfers` invocations and issues the shares for
+// what arrived to `holder`
+fn process_deposit(
+    e: &Env,
+    transfers: Vec<ContractContext>,
+    payer: &Address,
+    holder: &Address,
+    amount: i128,
+    expected_quote: i128,
+    fee: i128,
+) -> Result<i128, VaultError> {
+    if get_stopped(e) {
+        return Err(VaultError::ContractStopped);
+    }
+
+    check_nonnegative_amount(amount)?;
+    extend_instance_ttl(e);
+
+    let config = get_series_config(e)?;
+    match series_state(e, &config)? {
+        VaultState::Open => {}
+        VaultState::Pending => return Err(VaultError::NotOpenYet),
+        VaultState::Cancelled => return Err(VaultError::InvalidCall),
+        _ => return Err(VaultError::MaturityReached),
+    }
+
+    if amount < config.min_deposit as i128 {
+        return Err(VaultError::InvalidAmount);
+    }
+
+    if get_max_deposit(e).is_some_and(|max_deposit| amount as u128 > max_deposit) {
+        return Err(VaultError::InvalidAmount);
+    }
+
+    let nav_mode = config.mode == AccountingMode::Nav;
+    let notes_mode = config.mode == AccountingMode::Notes;
+    if notes_mode && get_note_term(e) == 0 {
+        return Err(VaultError::NoteTermNotSet);
+    }
+
+    // Shares of an auctioned series are only issued by settling the auction
+    if config.mode == AccountingMode::Auction {
+        return Err(VaultError::UnsupportedInMode);
+    }
+
+    let current_quote = effective_quote(e, &config)?;
+
+    // The NAV moves with every yield payment, so the expected quote is a floor there
+    if current_quote != expected_quote && !(nav_mode && current_quote > expected_quote) {
+        return Err(VaultError::QuoteChanged);
+    }
+
+    // The deposit token is called first so the shares are issued for what actually arrived. The
+    // guard keeps the token from calling back into the vault before the books are updated
+    enter_guard(e)?;
+    let total_assets = if nav_mode { get_total_assets(e)? } else { 0 };
+    let amount = receive_deposit(e, transfers, payer, amount)?;
+
+    let tiers = get_price_tiers(e);
+    let quantity = if nav_mode {
+        mul_div(amount, get_total_shares(e)? + 1, total_assets + 1)?
+    } else if tiers.is_empty() {
+        quote_shares(amount, current_quote)?
+    } else {
+        tiered_quantity(&tiers, get_total_deposit(e)?, amount)?
+    };
+
+    if get_covenant_bps(e) > 0 {
+        let covenant = get_covenant(e);
+        e.storage()
+            .instance()
+            .set(&DataKey::CovenantDeposits, &(covenant.deposits + amount));
+        put_covenant_balance(e, covenant.balance + amount);
+    }
+    if notes_mode {
+        issue_receipt(e, holder, amount, quantity);
+    }
+    put_cost_basis(e, holder.clone(), get_cost_basis(e, holder.clone()) + amount);
+    record_deposit(e, amount)?;
+    if amount > get_largest_deposit(e) {
+        e.storage().instance().set(&DataKey::LargestDeposit, &amount);
+    }
+    mint_shares(e, holder.clone(), quantity)?;
+    publish_tx(e, symbol_short!("deposit"), holder, amount, quantity, fee)?;
+    report_tvl(e);
+    exit_guard(e);
+
+    Ok(quantity)
+}
+
+fn operator_key(holder: &Address, operator: &Address) -> (u32, Address, Address) {
+    (DataKey::Operator as u32, holder.clone(), operator.clone())
+}
+
+fn is_operator(e: &Env, holder: &Address, operator: &Address) -> bool {
+    e.storage().persistent().has(&operator_key(holder, operator))
+}
+
+fn subscription_key(holder: &Address) -> (u32, Address) {
+    (DataKey::Subscription as u32, holder.clone())
+}
+
+fn get_subscription(e: &Env, holder: &Address) -> Result<Subscription, VaultError> {
+    e.storage()
+        .persistent()
+        .get(&subscription_key(holder))
+        .ok_or(VaultError::InvalidCall)
+}
+
+// Issues the shares of a fully paid subscription at its quote and sends the escrowed
+// installments where deposits go
+fn issue_subscription(e: &Env, holder: &Address, subscription: &Subscription) -> Result<i128, VaultError> {
+    let amount = subscription.target;
+    let quantity = quote_shares(amount, subscription.quote)?;
+
+    e.storage().persistent().remove(&subscription_key(holder));
+    put_subscription_escrow(e, get_subscription_escrow(e) - amount);
+    if get_covenant_bps(e) > 0 {
+        let covenant = get_covenant(e);
+        e.storage()
+            .instance()
+            .set(&DataKey::CovenantDeposits, &(covenant.deposits + amount));
+        put_covenant_balance(e, covenant.balance + amount);
+    }
+    put_cost_basis(e, holder.clone(), get_cost_basis(e, holder.clone()) + amount);
+    record_deposit(e, amount)?;
+    if amount > get_largest_deposit(e) {
+        e.storage().instance().set(&DataKey::LargestDeposit, &amount);
+    }
+    mint_shares(e, holder.clone(), quantity)?;
+    publish_tx(e, symbol_short!("deposit"), holder, amount, quantity, 0)?;
+
+    let token_client = token::Client::new(e, &get_token(e)?);
+    for (destination, leg) in deposit_destinations(e, amount)?.iter() {
+        if destination != e.current_contract_address() && leg > 0 {
+            token_client.transfer(&e.current_contract_address(), &destination, &leg);
+        }
+    }
+    report_tvl(e);
+
+    Ok(quantity)
+}
+
+// Redeems `amount` of `owner`'s shares and pays the redemption to `receiver`. The shares are
+// moved out of the owner's balance under the allowance they gave the vault when
+// `from_allowance` is set, e.g. for operators, and with the owner's own auth otherwise
+fn redeem_shares(
+    e: &Env,
+    owner: Address,
+    receiver: Address,
+    amount: i128,
+    from_allowance: bool,
+) -> Result<i128, VaultError> {
+    check_nonnegative_amount(amount)?;
+    extend_instance_ttl(e);
+
+    // Rolling notes are redeemed per receipt
+    let config = get_series_config(e)?;
+    if config.mode == AccountingMode::Notes {
+        return Err(VaultError::UnsupportedInMode);
+    }
+
+    let nav_mode = config.mode == AccountingMode::Nav;
+    let available_redemption = get_available_redemption(e)?;
+    if !nav_mode {
+        if series_state(e, &config)? < VaultState::Matured {
+            return Err(VaultError::MaturityNotReached);
+        }
+
+        if claim_deadline_passed(e) {
+            return Err(VaultError::ClaimDeadlinePassed);
+        }
+
+        if available_redemption == 0 {
+            return Err(VaultError::AvailableRedemptionNotSet);
+        }
+        check_challenge_closed(e)?;
+    }
+
+    let share_token_client = token::Client::new(e, &config.share_token);
+
+    // Release the cost basis attributable to the redeemed shares
+    let balance = share_token_client.balance(&owner);
+    if amount > balance {
+        return Err(VaultError::InvalidAmount);
+    }
+    let cost_basis = get_cost_basis(e, owner.clone());
+    let mut released_cost_basis = 0;
+    if cost_basis > 0 && balance > 0 {
+        let remaining_cost_basis = pro_rata(cost_basis, balance - amount, balance)?;
+        put_cost_basis(e, owner.clone(), remaining_cost_basis);
+        released_cost_basis = cost_basis - remaining_cost_basis;
+    }
+
+    // Calculate total amount including yield
+    let asset_amount = if nav_mode {
+        mul_div(amount, get_total_assets(e)? + 1, get_total_shares(e)? + 1)?
+    } else {
+        pro_rata(available_redemption, amount, get_total_shares(e)?)?
+    };
+
+    // The designated share of the yield, if any, is donated. Shares received by transfer carry
+    // no cost basis to tell their yield from, so holders without one donate nothing
+    let designation = get_beneficiary(e, owner.clone());
+    let donation = match designation {
+        Some(ref designation) if released_cost_basis > 0 => {
+            let redeemed_yield =
+                core::cmp::max(asset_amount - to_redemption_units(e, released_cost_basis)?, 0);
+            redeemed_yield * designation.share_bps as i128 / 10000
+        }
+        _ => 0,
+    };
+    let payout = asset_amount - donation;
+
+    // A series settled in another asset also pays out what it holds for the holders in the
+    // deposit token
+    let carry = get_settlement_carry(e);
+    let carried = if carry > 0 { pro_rata(carry, amount, get_total_shares(e)?)? } else { 0 };
+
+    // Storage is fully updated, and the shares burnt by the vault's own share token, before
+    // the redemption token is called
+    enter_guard(e)?;
+    if carried > 0 {
+        put_settlement_carry(e, carry - carried);
+    }
+    if !nav_mode {
+        put_available_redemption(e, available_redemption - asset_amount);
+    }
+    publish_tx(e, symbol_short!("redeem"), &owner, asset_amount, amount, 0)?;
+    if from_allowance {
+        share_token_client.transfer_from(&e.current_contract_address(), &owner, &e.current_contract_address(), &amount);
+    } else {
+        share_token_client.transfer(&owner, &e.current_contract_address(), &amount);
+    }
+    burn_shares(e, amount)?;
+    release_cost_basis(e, amount)?;
+    record_redemption(e, to_deposit_units(e, asset_amount)?);
+
+    let token_client = token::Client::new(e, &get_redemption_token(e)?);
+    if let Some(designation) = designation.filter(|_| donation > 0) {
+        token_client.transfer(&e.current_contract_address(), &designation.beneficiary, &donation);
+        e.events().publish(
+            (symbol_short!("REDEEM"), symbol_short!("donated")),
+            (owner.clone(), designation.beneficiary, donation),
+        );
+    }
+    token_client.transfer(&e.current_contract_address(), &receiver, &payout);
+    if carried > 0 {
+        token::Client::new(e, &config.token).transfer(&e.current_contract_address(), &receiver, &carried);
+        e.events().publish(
+            (symbol_short!("REDEEM"), symbol_short!("carried")),
+            (owner.clone(), receiver.clone(), carried),
+        );
+    }
+    e.events().publish(
+        (symbol_short!("REDEEM"), symbol_short!("holder")),
+        (owner, receiver, payout),
+    );
+    exit_guard(e);
+
+    Ok(payout)
+}
+
+// Converts the argument at `index` of a multicall entry
+fn call_arg<T: TryFromVal<Env, Val>>(e: &Env, args: &Vec<Val>, index: u32) -> Result<T, VaultError> {
+    let arg = args.get(index).ok_or(VaultError::InvalidCall)?;
+    T::try_from_val(e, &arg).map_err(|_| VaultError::InvalidCall)
+}
+
+// Executes one admin function of a multicall and returns its result
+fn dispatch_admin_call(e: &Env, function: &Symbol, args: &Vec<Val>) -> Result<Val, VaultError> {
+    let arity = |count: u32| {
+        if args.len() != count {
+            return Err(VaultError::InvalidCall);
+        }
+        Ok(())
+    };
+
+    let result = if *function == Symbol::new(e, "set_contract_stopped") {
+        arity(1)?;
+        Vault::set_contract_stopped(e.clone(), call_arg(e, args, 0)?, None)?.into_val(e)
+    } else if *function == Symbol::new(e, "set_quote") {
+        arity(1)?;
+        Vault::set_quote(e.clone(), call_arg(e, args, 0)?, None)?.into_val(e)
+    } else if *function == Symbol::new(e, "set_price_tiers") {
+        arity(1)?;
+        Vault::set_price_tiers(e.clone(), call_arg(e, args, 0)?)?.into_val(e)
+    } else if *function == Symbol::new(e, "set_deposit_limits") {
+        arity(2)?;
+        Vault::set_deposit_limits(e.clone(), call_arg(e, args, 0)?, call_arg(e, args, 1)?)?.into_val(e)
+    } else if *function == Symbol::new(e, "set_treasury") {
+        arity(1)?;
+        Vault::set_treasury(e.clone(), call_arg(e, args, 0)?)?.into_val(e)
+    } else if *function == Symbol::new(e, "set_claim_period") {
+        arity(1)?;
+        Vault::set_claim_period(e.clone(), call_arg(e, args, 0)?)?.into_val(e)
+    } else if *function == Symbol::new(e, "set_sync_tolerance") {
+        arity(1)?;
+        Vault::set_sync_tolerance(e.clone(), call_arg(e, args, 0)?)?.into_val(e)
+    } else if *function == Symbol::new(e, "set_anchor") {
+        arity(1)?;
+        Vault::set_anchor(e.clone(), call_arg(e, args, 0)?)?.into_val(e)
+    } else if *function == Symbol::new(e, "set_rating_agency") {
+        arity(1)?;
+        Vault::set_rating_agency(e.clone(), call_arg(e, args, 0)?)?.into_val(e)
+    } else {
+        return Err(VaultError::InvalidCall);
+    };
+
+    Ok(result)
+}
+
+fn check_nonnegative_amount(amount: i128) -> Result<(), VaultError> {
+    if amount < 0 {
+        Err(VaultError::InvalidAmount)
+    } else {
+        Ok(())
+    }
+}
+
+fn is_initialized(e: &Env) -> Result<bool, VaultError> {
+    Ok(e.storage()
+        .instance()
+        .get(&DataKey::Initialized)
+        .unwrap_or(0)
+        == 1)
+}
+
+fn set_initialized(e: &Env) {
+    e.storage().instance().set(&DataKey::Initialized, &1);
+}
+
+pub trait VaultTrait {
+    fn initialize(
+        e: Env,
+        token_wasm_hash: BytesN<32>,
+        token: Address,
+        admin: Address,
+        start_time: u64,
+        end_time: u64,
+        quote_period: u64,
+        treasury: Address,
+        min_deposit: u128,
+        share_metadata: ShareMetadata,
+        mode: AccountingMode,
+    ) -> Result<String, VaultError>;
+
+    // Returns the token contract address for the vault share token
+    fn bond_id(e: Env) -> Result<Address, VaultError>;
+
+    // Deposits token. Also mints vault shares for the `from` Identifier. The amount minted
+    // is determined based on the difference between the reserves stored by this contract, and
+    // the actual balance of token for this contract.
+    fn deposit(
+        e: Env,
+        from: Address,
+        amount: i128,
+        expected_quote: i128,
+    ) -> Result<i128, VaultError>;
+
+    // Returns the auth entries an aggregator contract depositing `amount` on its own behalf must
+    // pass to `authorize_as_current_contract` before calling `deposit`. The vault transfers the
+    // tokens with `from` as the spender, once per destination of the deposit, so the invocation
+    // tree is:
+    //   deposit(from, amount, expected_quote)    <- authorized by `from` as direct invoker
+    //     transfer(from, treasury, leg)          <- must be pre-authorized
+    //     transfer(from, secondary, leg)         <- likewise, under a treasury split
+    // In NAV mode or under a covenant the single transfer goes to the vault itself
+    fn deposit_auth_entries(e: Env, from: Address, amount: i128) -> Result<Vec<InvokerContractAuthEntry>, VaultError>;
+
+    // Deposits `amount` for `from` on the strength of an ed25519 `signature` of `from`'s account
+    // key over `permit_payload`, so that a relayer can submit the transaction and pay its fees.
+    // The tokens are pulled with the same transfers as `deposit`, which the relayer submits with
+    // `from`'s signed authorization of them, see `deposit_auth_entries`, so no prior approve is
+    // needed. Permits expire after the `deadline` timestamp and must use the next nonce of `from`
+    fn deposit_with_authorization(
+        e: Env,
+        from: Address,
+        amount: i128,
+        deadline: u64,
+        nonce: u64,
+        signature: BytesN<64>,
+    ) -> Result<i128, VaultError>;
+
+    // Returns the message `from` signs to permit a deposit at the current quote
+    fn permit_payload(e: Env, from: Address, amount: i128, deadline: u64, nonce: u64) -> Result<Bytes, VaultError>;
+
+    // Returns the nonce the next permit of `from` must use
+    fn permit_nonce(e: Env, from: Address) -> Result<u64, VaultError>;
+
+    // Sets or clears the fiat anchor allowed to deposit on behalf of its customers
+    fn set_anchor(e: Env, anchor: Option<Address>) -> Result<(), VaultError>;
+
+    // Returns the registered fiat anchor
+    fn anchor(e: Env) -> Result<Address, VaultError>;
+
+    // Deposits a fiat-funded `gross_amount` for `holder` through the anchor. The anchor keeps its
+    // `fee` and transfers the net principal, which is what the holder's shares are issued for.
+    // The fee is recorded on the holder's position. Returns the shares issued
+    fn anchor_deposit(
+        e: Env,
+        holder: Address,
+        gross_amount: i128,
+        fee: i128,
+        expected_quote: i128,
+    ) -> Result<i128, VaultError>;
+
+    // Returns the total fees netted out by the anchor
+    fn total_anchor_fees(e: Env) -> Result<i128, VaultError>;
+
+    // Returns the id of the latest deposit or redemption, counted from 1. Every one of them
+    // publishes a TX event carrying its id, so gaps show missed events
+    fn last_tx_id(e: Env) -> Result<u64, VaultError>;
+
+    // Redeems `amount` of the shares of `owner`, burning them, and pays the redemption to
+    // `receiver`, less the share of the yield `owner` donates to its beneficiary.
+    // Returns amount of token withdrawn
+    fn withdraw(e: Env, owner: Address, receiver: Address, amount: i128) -> Result<i128, VaultError>;
+
+    // Approves (or revokes) `operator`, e.g. a custodial platform or smart account contract, to
+    // redeem the shares of `holder` through operator_withdraw under its own auth
+    fn set_operator(e: Env, holder: Address, operator: Address, approved: bool) -> Result<(), VaultError>;
+
+    fn is_operator(e: Env, holder: Address, operator: Address) -> bool;
+
+    // Redeems `amount` of the shares of `owner` on their behalf, like withdraw, and pays the
+    // redemption to `receiver`. The shares are taken under the allowance `owner` gave the vault
+    // on the share token
+    fn operator_withdraw(
+        e: Env,
+        operator: Address,
+        owner: Address,
+        receiver: Address,
+        amount: i128,
+    ) -> Result<i128, VaultError>;
+
+    fn total_deposit(e: Env) -> Result<i128, VaultError>;
+
+    // Sets the term of the rolling notes issued from now on, in seconds. Notes mode only
+    fn set_note_term(e: Env, term: u64) -> Result<u64, VaultError>;
+
+    fn note_term(e: Env) -> Result<u64, VaultError>;
+
+    // Adds funds the admin provides for redeeming notes at par. Notes mode only. Returns the
+    // funds available for redemption
+    fn fund_notes(e: Env, amount: i128) -> Result<i128, VaultError>;
+
+    // Redeems a matured receipt of `to` at par, burning its shares. Returns the amount paid
+    fn withdraw_receipt(e: Env, to: Address, receipt_id: u32) -> Result<i128, VaultError>;
+
+    // Moves an unredeemed receipt of `from` and its shares to `to`, where it gets the next
+    // receipt id. Shares moved with the share token alone leave their receipt behind. Returns
+    // the receipt as held by `to`
+    fn transfer_receipt(e: Env, from: Address, to: Address, receipt_id: u32) -> Result<NoteReceipt, VaultError>;
+
+    // Returns a page of the receipts issued to `holder`, oldest first, with at most
+    // MAX_PAGE_SIZE receipts per page. Receipts transferred away are left out
+    fn get_receipts(e: Env, holder: Address, start: u32, limit: u32) -> Result<Vec<NoteReceipt>, VaultError>;
+
+    // Returns lifetime issuance counters. Redemptions include buy-backs, and depositors are
+    // the distinct holders shares were ever issued to
+    fn stats(e: Env) -> Result<IssuanceStats, VaultError>;
+
+    // Designates a beneficiary receiving `share_bps` basis points of the yield `holder` redeems,
+    // the redemption in excess of the cost basis released. Holders with no cost basis, having
+    // received their shares by transfer, donate nothing. A zero share removes the designation
+    fn set_beneficiary(e: Env, holder: Address, beneficiary: Address, share_bps: u32) -> Result<(), VaultError>;
+
+    // Returns the beneficiary designated by `holder` and its share of the yield
+    fn get_beneficiary(e: Env, holder: Address) -> Result<BeneficiaryShare, VaultError>;
+
+    fn available_redemption(e: Env) -> Result<i128, VaultError>;
+
+    fn admin(e: Env) -> Result<Address, VaultError>;
+
+    fn maturity(e: Env) -> Result<u64, VaultError>;
+
+    // Returns the lifecycle state of the series
+    fn state(e: Env) -> Result<VaultState, VaultError>;
+
+    // Returns when the series was created and went through each transition of its lifecycle
+    fn series_timeline(e: Env) -> Result<SeriesTimeline, VaultError>;
+
+    // Keeps the series Closed for `grace` seconds after maturity before the total redemption
+    // can be set. Only possible before maturity
+    fn set_maturity_grace(e: Env, grace: u64) -> Result<(), VaultError>;
+
+    fn maturity_grace(e: Env) -> u64;
+
+    // Sets the total deposit an offering must reach not to be cancellable. Zero removes it
+    fn set_soft_cap(e: Env, soft_cap: i128) -> Result<(), VaultError>;
+
+    fn soft_cap(e: Env) -> i128;
+
+    // Cancels a fixed quote offering that has not reached its soft cap, before maturity. The
+    // series turns Cancelled for good: deposits stop and holders get their deposits back
+    // through refund, once the treasury has returned them through fund_refunds
+    fn cancel_offering(e: Env) -> Result<(), VaultError>;
+
+    // Returns `amount` of deposits from `from`, e.g. the treasury, to the vault to be refunded
+    fn fund_refunds(e: Env, from: Address, amount: i128) -> Result<(), VaultError>;
+
+    // Burns all shares of `holder` in a cancelled offering and pays back their pro rata part of
+    // the total deposit. Returns the amount refunded
+    fn refund(e: Env, holder: Address) -> Result<i128, VaultError>;
+
+    // Commits `holder` to deposit `target` in installments at the current quote, which must be
+    // `expected_quote` and holds for every installment. Fixed quote series without price tiers
+    // only, one subscription per holder
+    fn subscribe(e: Env, holder: Address, target: i128, expected_quote: i128) -> Result<Subscription, VaultError>;
+
+    // Pays `amount` toward the subscription of `holder`, escrowed by the vault. The shares are
+    // issued once the target is fully paid, by the installment completing it
+    fn pay_installment(e: Env, holder: Address, amount: i128) -> Result<Subscription, VaultError>;
+
+    // Returns the installments of a subscription that was not fully paid once the series is no
+    // longer open, unless the vault migrated them to its successor. Returns the amount refunded
+    fn refund_subscription(e: Env, holder: Address) -> Result<i128, VaultError>;
+
+    fn subscription(e: Env, holder: Address) -> Option<Subscription>;
+
+    fn total_bonds(e: Env) -> Result<i128, VaultError>;
+
+    fn treasury_account(e: Env) -> Result<Address, VaultError>;
+
+    fn quote(e: Env) -> Result<i128, VaultError>;
+
+    // Returns the accounting mode selected at initialize
+    fn accounting_mode(e: Env) -> Result<AccountingMode, VaultError>;
+
+    // Opens the auction of `supply` shares until `close_time`, issuing at most `reserve_quote`
+    // shares per token. Auction mode only, once per series
+    fn start_auction(e: Env, supply: i128, reserve_quote: i128, close_time: u64) -> Result<Auction, VaultError>;
+
+    // Escrows `amount` of the bidder's tokens for shares at a quote of at least `min_quote`.
+    // Each bid must demand at least 1/MAX_AUCTION_BIDS of the supply at the reserve quote. Once
+    // MAX_AUCTION_BIDS bids are placed, a bid with a lower `min_quote` than the worst one
+    // replaces it and the worst bid is refunded. Returns the number of bids placed so far
+    fn bid(e: Env, bidder: Address, amount: i128, min_quote: i128) -> Result<u32, VaultError>;
+
+    // Settles the auction once closed. The bids are filled from the lowest minimum quote at the
+    // uniform clearing quote until the supply runs out, the proceeds go to the treasury and
+    // what was not filled is refunded. Callable by anyone. Returns the clearing quote
+    fn settle_auction(e: Env) -> Result<i128, VaultError>;
+
+    fn auction(e: Env) -> Option<Auction>;
+
+    fn auction_bids(e: Env) -> Vec<AuctionBid>;
+
+    // Sets the quote, which must be within the quote bounds, if any. A call repeating the
+    // `idempotency_key` of one applied within the last day returns its result and does nothing,
+    // as for the other admin operations taking one
+    fn set_quote(e: Env, amount: i128, idempotency_key: Option<BytesN<32>>) -> Result<i128, VaultError>;
+
+    // Limits the quotes the admin can set alone
+    fn set_quote_bounds(e: Env, bounds: QuoteBounds) -> Result<(), VaultError>;
+
+    fn quote_bounds(e: Env) -> Option<QuoteBounds>;
+
+    // Proposes a quote outside the quote bounds. It is set by cosign_quote or by execute_quote
+    // once the timelock has passed
+    fn propose_quote(e: Env, amount: i128) -> Result<PendingQuote, VaultError>;
+
+    // Sets the proposed quote with the approval of the guardian
+    fn cosign_quote(e: Env) -> Result<i128, VaultError>;
+
+    // Sets the proposed quote once its timelock has passed
+    fn execute_quote(e: Env) -> Result<i128, VaultError>;
+
+    fn cancel_quote(e: Env) -> Result<(), VaultError>;
+
+    fn pending_quote(e: Env) -> Result<PendingQuote, VaultError>;
+
+    // Hands pricing over to up to MAX_QUOTERS `quoters`. A quote becomes active once `quorum`
+    // of their submissions within the quote period agree within `max_deviation_bps` of their
+    // median, and the admin can no longer set the quote directly. An empty list hands pricing
+    // back to the admin
+    fn set_quoters(e: Env, quoters: Vec<Address>, quorum: u32, max_deviation_bps: u32) -> Result<(), VaultError>;
+
+    // Returns the quoters, the quorum and the deviation band
+    fn quoters(e: Env) -> Result<(Vec<Address>, u32, u32), VaultError>;
+
+    // Records the quote of one of the quoters and activates the aggregated quote once the
+    // submissions reach the quorum. Returns the active quote, or 0 while the quorum is not reached
+    fn submit_quote(e: Env, quoter: Address, amount: i128) -> Result<i128, VaultError>;
+
+    // Returns the quote the quoters' current submissions aggregate to
+    fn active_quote(e: Env) -> Result<i128, VaultError>;
+
+    // Switches deposits to bookbuilding: each tier's quote applies until the cumulative total
+    // deposit reaches its cap, and deposits beyond the last cap are rejected. Caps must be
+    // strictly increasing. An empty table switches back to the admin quote
+    fn set_price_tiers(e: Env, tiers: Vec<PriceTier>) -> Result<(), VaultError>;
+
+    // Returns the price tier table, empty when deposits use the admin quote
+    fn price_tiers(e: Env) -> Result<Vec<PriceTier>, VaultError>;
+
+    // Assigns the role setting the buy-back (bid) quote and funding its reserve. Defaults to
+    // the admin
+    fn set_bid_quoter(e: Env, quoter: Option<Address>) -> Result<(), VaultError>;
+
+    fn bid_quoter(e: Env) -> Result<Address, VaultError>;
+
+    // Sets the quote at which the vault buys back shares before maturity, in shares per
+    // underlying token like the subscription quote. It expires independently after the quote
+    // period
+    fn set_bid_quote(e: Env, amount: i128) -> Result<i128, VaultError>;
+
+    // Returns the buy-back quote, or 0 if none is in effect
+    fn bid_quote(e: Env) -> Result<i128, VaultError>;
+
+    // Moves funds between the bid quoter and the buy-back reserve until it holds `amount`
+    fn set_buyback_reserve(e: Env, amount: i128) -> Result<i128, VaultError>;
+
+    fn buyback_reserve(e: Env) -> Result<i128, VaultError>;
+
+    // Sells `shares` back to the vault at the bid quote, paid from the buy-back reserve. The
+    // repurchased shares are burned. Returns the amount paid
+    fn sell_back(e: Env, holder: Address, shares: i128, expected_bid: i128) -> Result<i128, VaultError>;
+
+    // Holds `buffer_bps` basis points of the deposits received from now on in the vault as a
+    // redemption buffer, the rest being drawn by the treasury through `treasury_draw`. The buffer
+    // can only be raised. Fixed quote mode only
+    fn set_covenant(e: Env, buffer_bps: u32) -> Result<Covenant, VaultError>;
+
+    fn covenant(e: Env) -> Result<Covenant, VaultError>;
+
+    // Transfers `amount` of the deposits held under the covenant to the treasury, up to what
+    // exceeds the buffer. Not once the offering is cancelled. Returns the amount drawn
+    fn treasury_draw(e: Env, amount: i128) -> Result<i128, VaultError>;
+
+    // Sets the total redemption, funded by the admin on top of the donations held by the vault.
+    // Exactly `amount` is pulled, and repeating the call with the amount already funded does
+    // nothing. Use top_up_redemption to add funds afterwards
+    fn set_total_redemption(
+        e: Env,
+        amount: i128,
+        idempotency_key: Option<BytesN<32>>,
+    ) -> Result<i128, VaultError>;
+
+    // Adds `amount` of the redemption token, funded by the admin, to the redemption of the
+    // holders who have not withdrawn yet. Returns the redemption now available
+    fn top_up_redemption(e: Env, amount: i128) -> Result<i128, VaultError>;
+
+    // Adds the covenant buffer still held by the vault to the redemption of the holders who
+    // have not withdrawn yet. Returns the redemption now available
+    fn release_covenant_buffer(e: Env) -> Result<i128, VaultError>;
+
+    // Returns the redemption funds the admin provided so far
+    fn redemption_funded(e: Env) -> i128;
+
+    // Makes the series redeem in `asset` instead of the deposit token. Only possible before
+    // maturity, after which the total redemption is set with settle_redemption
+    fn set_settlement_asset(e: Env, asset: Address) -> Result<(), VaultError>;
+
+    // Sets the total redemption of a series with a settlement asset. `amount` is in the deposit
+    // token, and the admin funds it in the settlement asset at `rate`. The donations and
+    // covenant buffer stay in the deposit token, and every redemption also pays its pro rata
+    // part of them. Returns the amount funded in the settlement asset
+    fn settle_redemption(e: Env, amount: i128, rate: i128) -> Result<i128, VaultError>;
+
+    fn settlement(e: Env) -> Result<Settlement, VaultError>;
+
+    // Sets how long withdrawals stay closed after the total redemption is set, giving the
+    // guardian time to correct a mistyped value. Zero opens withdrawals immediately
+    fn set_challenge_window(e: Env, window: u64) -> Result<u64, VaultError>;
+
+    fn challenge_window(e: Env) -> Result<u64, VaultError>;
+
+    // Lets the guardian replace the admin funded total redemption once while the challenge
+    // window is open. An increase is funded by the guardian, a decrease refunded to the admin
+    fn correct_total_redemption(e: Env, amount: i128) -> Result<i128, VaultError>;
+
+    fn redemption_challenge(e: Env) -> Result<Option<RedemptionChallenge>, VaultError>;
+
+    fn set_treasury(e: Env, treasury: Address) -> Result<Address, VaultError>;
+
+    // Routes `secondary_bps` of every deposit to `secondary`, the rest still going to the
+    // treasury in the same transaction. Zero basis points removes the split
+    fn set_treasury_split(e: Env, secondary: Address, secondary_bps: u32) -> Result<(), VaultError>;
+
+    fn treasury_split(e: Env) -> Option<TreasurySplit>;
+
+    // Lets a deposit arrive up to `tolerance_bps` short of the requested amount, for deposit
+    // tokens charging a fee on transfer. Shares are always issued for the amount received
+    fn set_transfer_tolerance(e: Env, tolerance_bps: u32) -> Result<(), VaultError>;
+
+    fn transfer_tolerance(e: Env) -> u32;
+
+    // Lets holders of at least `threshold` shares lock them for a non-fungible certificate
+    // minted by `certificate`, which must expose mint(to, series, maturity, locked) -> u32,
+    // burn(id) and get_certificate(id) with the vault as minter. Zero disables issuance
+    fn set_certificate(e: Env, certificate: Address, threshold: i128) -> Result<(), VaultError>;
+
+    fn certificate(e: Env) -> Option<CertificateConfig>;
+
+    // Locks `amount` of the holder's shares in the vault and mints them a certificate carrying
+    // the series, its maturity and the locked amount. Returns the id of the certificate
+    fn issue_certificate(e: Env, holder: Address, amount: i128) -> Result<u32, VaultError>;
+
+    // Burns a certificate and releases the shares it locks to its owner, to be redeemed like
+    // any other. Returns the shares released
+    fn redeem_certificate(e: Env, owner: Address, id: u32) -> Result<i128, VaultError>;
+
+    fn set_admin(e: Env, new_admin: Address) -> Result<Address, VaultError>;
+
+    fn set_contract_stopped(e: Env, stopped: bool, idempotency_key: Option<BytesN<32>>) -> Result<(), VaultError>;
+
+    // Sets how long holders have to redeem once the total redemption is set. Zero disables the deadline.
+    fn set_claim_period(e: Env, period: u64) -> Result<u64, VaultError>;
+
+    // Returns the redemption claim deadline, or 0 if none is in effect
+    fn claim_deadline(e: Env) -> Result<u64, VaultError>;
+
+    // Returns the redemption `holder` is entitled to for the shares they currently hold, 0
+    // before the total redemption is set
+    fn get_entitlement(e: Env, holder: Address) -> Result<i128, VaultError>;
+
+    // Sends the redemption funds left unclaimed after the claim deadline to the treasury.
+    // Returns the amount swept
+    fn sweep_unclaimed(e: Env) -> Result<i128, VaultError>;
+
+    // Sets aside the outstanding entitlement of each of `holders` once the claim deadline has
+    // passed, so that sweeping the unclaimed funds does not take it. The holders' shares are
+    // burnt under the allowance they gave the vault on the share token, holders without one
+    // are skipped. Returns the amount pushed
+    fn push_redemptions(e: Env, holders: Vec<Address>) -> Result<i128, VaultError>;
+
+    // Pays `holder` the redemption pushed to it. Callable by anyone
+    fn claim_pushed(e: Env, holder: Address) -> Result<i128, VaultError>;
+
+    fn pushed_redemption(e: Env, holder: Address) -> Result<i128, VaultError>;
+
+    // Reconciles the redemption funds, donations and funds escrowed for others (buy-back
+    // reserve, subscription installments, auction bids) the vault accounts for with its token
+    // balance. A surplus is recorded as a donation to the holders, a deficit is flagged and
+    // stops the contract once it exceeds the tolerance. Callable by anyone. In NAV mode the
+    // balance is the only counter, so there is nothing to reconcile
+    fn sync(e: Env) -> Result<SyncReport, VaultError>;
+
+    // Sets the deficit `sync` tolerates before stopping the contract
+    fn set_sync_tolerance(e: Env, tolerance: i128) -> Result<(), VaultError>;
+
+    // Returns the deficit flagged by the last `sync`, or 0 if the balance was sufficient
+    fn discrepancy(e: Env) -> Result<i128, VaultError>;
+
+    // Executes a sequence of the contract's admin setters in a single transaction, returning
+    // their results. Any failing call reverts the whole sequence. Supported functions are
+    // set_contract_stopped, set_quote, set_price_tiers, set_deposit_limits, set_treasury,
+    // set_claim_period, set_sync_tolerance, set_anchor and set_rating_agency
+    fn multicall(e: Env, calls: Vec<(Symbol, Vec<Val>)>) -> Result<Vec<Val>, VaultError>;
+
+    // Returns human readable price per bond, simple annualized yield (in percent) and ISO 8601
+    // maturity date, for wallets that cannot scale the 1e7 based integers themselves
+    fn get_display_info(e: Env) -> Result<DisplayInfo, VaultError>;
+
+    // Replaces the share token with one carrying the given metadata. Only possible before
+    // any bond has been issued, and the decimals must stay DECIMALS. Returns the new bond id
+    fn set_bond_metadata(e: Env, metadata: ShareMetadata) -> Result<Address, VaultError>;
+
+    // Returns the share token name, symbol and decimals along with maturity and underlying token
+    fn bond_metadata(e: Env) -> Result<BondMetadata, VaultError>;
+
+    // Returns the configuration and totals of the series in a single call
+    fn get_info(e: Env) -> Result<VaultInfo, VaultError>;
+
+    // Sets a series attribute such as an identifier, the issuer name or the jurisdiction. An
+    // empty value removes the attribute. Up to MAX_METADATA_ENTRIES attributes are kept, with
+    // keys of up to MAX_METADATA_KEY_LEN and values of up to MAX_METADATA_VALUE_LEN bytes
+    fn set_metadata(e: Env, key: String, value: String) -> Result<(), VaultError>;
+
+    fn get_metadata(e: Env, key: String) -> Result<String, VaultError>;
+
+    // Returns all series attributes
+    fn list_metadata(e: Env) -> Result<Map<String, String>, VaultError>;
+
+    // Returns up to `limit` registered holders starting at index `start`, with their current
+    // share balance and the underlying amount they deposited for it. Holders are registered
+    // when shares are minted to them, not when they receive shares by transfer, so this is
+    // the list of depositors rather than a full cap table, and may include emptied positions
+    fn get_holders(e: Env, start: u32, limit: u32) -> Result<Vec<HolderPosition>, VaultError>;
+
+    // Returns the number of registered holders, i.e. addresses shares were ever minted to
+    fn holder_count(e: Env) -> Result<u32, VaultError>;
+
+    // Returns up to `limit` records of a section of the vault state starting at index `start`,
+    // for migrations and audits. Admin only
+    fn export_state(e: Env, section: ExportSection, start: u32, limit: u32) -> Result<Vec<StateEntry>, VaultError>;
+
+    // Burns `amount` of vault shares bought back by the admin, reducing the outstanding
+    // obligations. Only possible before the total redemption is set
+    fn retire_shares(e: Env, amount: i128) -> Result<i128, VaultError>;
+
+    // Returns the total amount of shares retired by the issuer
+    fn retired_shares(e: Env) -> Result<i128, VaultError>;
+
+    // Sets the minimum and optional maximum amount of a single deposit, in base units of the token
+    fn set_deposit_limits(e: Env, min: u128, max: Option<u128>) -> Result<(), VaultError>;
+
+    // Returns the minimum and optional maximum amount of a single deposit
+    fn get_deposit_limits(e: Env) -> Result<(u128, Option<u128>), VaultError>;
+
+    // Sets or clears the guardian allowed to step in after the admin has been inactive for
+    // `inactivity_window` seconds
+    fn set_guardian(e: Env, guardian: Option<Address>, inactivity_window: u64) -> Result<(), VaultError>;
+
+    // Returns the guardian, its inactivity window and the time of the last admin action
+    fn guardian_info(e: Env) -> Result<GuardianInfo, VaultError>;
+
+    // Stops the contract on behalf of an inactive admin
+    fn guardian_stop(e: Env) -> Result<(), VaultError>;
+
+    // Replaces an inactive admin. The new admin starts with a fresh inactivity window
+    fn guardian_set_admin(e: Env, new_admin: Address) -> Result<Address, VaultError>;
+
+    // Sets or clears the rating agency allowed to post rating attestations for the series
+    fn set_rating_agency(e: Env, agency: Option<Address>) -> Result<(), VaultError>;
+
+    // Returns the registered rating agency
+    fn rating_agency(e: Env) -> Result<Address, VaultError>;
+
+    // Posts a new rating attestation from the rating agency. Earlier attestations are kept
+    // in the rating history. Informational only
+    fn post_rating(e: Env, rating: CreditRating, report_hash: BytesN<32>) -> Result<u32, VaultError>;
+
+    // Returns the latest rating attestation
+    fn current_rating(e: Env) -> Result<RatingAttestation, VaultError>;
+
+    // Returns up to `limit` rating attestations starting at index `start`, oldest first
+    fn rating_history(e: Env, start: u32, limit: u32) -> Result<Vec<RatingAttestation>, VaultError>;
+
+    // Returns the number of rating attestations posted
+    fn rating_count(e: Env) -> Result<u32, VaultError>;
+
+    // Sets the registry vouching for audited successor contracts. The registry must expose
+    // is_audited(contract) -> bool
+    fn set_migration_registry(e: Env, registry: Address) -> Result<(), VaultError>;
+
+    // Schedules an emergency migration to `successor`, which must be approved by the registry.
+    // It can be executed once MIGRATION_DELAY has passed
+    fn schedule_migration(e: Env, successor: Address) -> Result<PendingMigration, VaultError>;
+
+    fn cancel_migration(e: Env) -> Result<(), VaultError>;
+
+    fn pending_migration(e: Env) -> Result<PendingMigration, VaultError>;
+
+    // Last-resort recovery: stops the vault, transfers its whole token balance, and that of the
+    // settlement asset if any, to the scheduled successor and hands it a snapshot of the
+    // outstanding obligations, pushed redemptions included, through
+    // accept_migration(snapshot). The successor is checked against the registry again
+    fn emergency_migrate(e: Env, successor: Address) -> Result<MigrationSnapshot, VaultError>;
+
+    // Returns the snapshot handed to the successor by `emergency_migrate`
+    fn migration_snapshot(e: Env) -> Result<MigrationSnapshot, VaultError>;
+
+    // Links the series this vault follows on. `prev_vault` must have matured with its total
+    // redemption set; the redemption it paid per unit deposited is recorded as its realized rate
+    fn link_previous_series(e: Env, prev_vault: Address) -> Result<PreviousSeries, VaultError>;
+
+    fn previous_series(e: Env) -> Result<PreviousSeries, VaultError>;
+
+    // Suggests a quote repeating the previous series' realized return, prorated over the time
+    // left to maturity with the gap between the two maturities taken as the term
+    fn suggested_quote(e: Env) -> Result<i128, VaultError>;
+
+    // Registers this series with the protocol TVL registry. The registry must expose
+    // register_series(vault) and report_tvl(vault, delta: i128), and is told of every change in
+    // the value locked here so it can serve global_tvl() and series_count() for the whole
+    // protocol. Replacing the registry reports the whole value locked to the new one
+    fn set_tvl_registry(e: Env, registry: Address) -> Result<(), VaultError>;
+
+    fn tvl_registry(e: Env) -> Result<Address, VaultError>;
+}
+
+#[contract]
+struct Vault;
+
+#[contractimpl]
+impl VaultTrait for Vault {
+    fn initialize(
+        e: Env,
+        token_wasm_hash: BytesN<32>,
+        token: Address,
+        admin: Address,
+        start_time: u64,
+        end_time: u64,
+        quote_period: u64,
+        treasury: Address,
+        min_deposit: u128,
+        share_metadata: ShareMetadata,
+        mode: AccountingMode,
+    ) -> Result<String, VaultError> {
+        // Check if the contract is already initialized
+        if is_initialized(&e)? {
+            return Err(VaultError::AlreadyInitialized);
+        }
+
+        let share_contract_id = deploy_share_token(
+            &e,
+            token_wasm_hash.clone(),
+            &token,
+            0,
+            share_metadata,
+        )?;
+
+        put_token_wasm_hash(&e, &token_wasm_hash);
+        put_series_config(
+            &e,
+            &SeriesConfig {
+                token,
+                series: token::Client::new(&e, &share_contract_id).symbol(),
+                share_token: share_contract_id,
+                treasury,
+                start_time,
+                end_time,
+                mode,
+                min_deposit,
+                quote: 0,
+                quote_expiration: 0,
+            },
+        );
+        put_admin(&e, admin);
+        put_last_admin_action(&e, time(&e));
+        e.storage().instance().set(&DataKey::CreatedAt, &time(&e));
+        put_total_shares(&e, 0);
+        put_total_deposit(&e, 0);
+        put_available_redemption(&e, 0);
+        put_quote_period(&e, quote_period);
+
+        set_initialized(&e);
+        set_stopped(&e, false);
+
+        e.events().publish(
+            (symbol_short!("VAULT"), symbol_short!("init")),
+            (e.current_contract_address(), start_time, end_time),
+        );
+
+        Ok(String::from_str(&e, "Ok"))
+    }
+
+    fn set_contract_stopped(e: Env, stopped: bool, idempotency_key: Option<BytesN<32>>) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        if replayed_result(&e, symbol_short!("stop"), &idempotency_key).is_some() {
+            return Ok(());
+        }
+
+        set_stopped(&e, stopped);
+        record_idempotency_key(&e, symbol_short!("stop"), idempotency_key, stopped as i128);
+
+        e.events().publish(
+            (symbol_short!("VAULT"), symbol_short!("stopped")),
+            stopped,
+        );
+
+        Ok(())
+    }
+
+    fn quote(e: Env) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+        get_effective_quote(&e).or(Ok(0))
+    }
+
+    fn start_auction(e: Env, supply: i128, reserve_quote: i128, close_time: u64) -> Result<Auction, VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        if get_accounting_mode(&e) != AccountingMode::Auction {
+            return Err(VaultError::UnsupportedInMode);
+        }
+        if e.storage().instance().has(&DataKey::Auction) {
+            return Err(VaultError::InvalidCall);
+        }
+        if supply <= 0 || reserve_quote <= 0 {
+            return Err(VaultError::InvalidAmount);
+        }
+        if close_time <= e.ledger().timestamp() || close_time > get_end_time(&e)? {
+            return Err(VaultError::InvalidAmount);
+        }
+
+        let auction = Auction {
+            supply,
+            reserve_quote,
+            close_time,
+            clearing_quote: 0,
+        };
+        e.storage().instance().set(&DataKey::Auction, &auction);
+
+        e.events().publish(
+            (symbol_short!("AUCTION"), symbol_short!("started")),
+            (supply, reserve_quote, close_time),
+        );
+
+        Ok(auction)
+    }
+
+    fn bid(e: Env, bidder: Address, amount: i128, min_quote: i128) -> Result<u32, VaultError> {
+        bidder.require_auth();
+        extend_instance_ttl(&e);
+
+        if get_stopped(&e) {
+            return Err(VaultError::ContractStopped);
+        }
+        let auction = get_auction(&e)?;
+        match get_state(&e)? {
+            VaultState::Open => {}
+            VaultState::Pending => return Err(VaultError::NotOpenYet),
+            _ => return Err(VaultError::MaturityReached),
+        }
+        if e.ledger().timestamp() >= auction.close_time {
+            return Err(VaultError::InvalidCall);
+        }
+        // Each bid must demand a share of the supply at the reserve quote, so that filling every
+        // slot takes bids for the whole supply
+        if amount <= 0
+            || amount < get_min_deposit(&e)? as i128
+            || quote_shares(amount, auction.reserve_quote)? * (MAX_AUCTION_BIDS as i128) < auction.supply
+        {
+            return Err(VaultError::InvalidAmount);
+        }
+        if min_quote <= 0 || min_quote > auction.reserve_quote {
+            return Err(VaultError::QuoteOutOfBounds);
+        }
+
+        let mut bids = get_auction_bids(&e);
+        let mut evicted = None;
+        if bids.len() >= MAX_AUCTION_BIDS {
+            // A full book makes room for a better bid by refunding the worst one, the latest
+            // placed among equals
+            let mut worst = 0;
+            for (i, placed) in bids.iter().enumerate() {
+                if placed.min_quote >= bids.get_unchecked(worst).min_quote {
+                    worst = i as u32;
+                }
+            }
+            if min_quote >= bids.get_unchecked(worst).min_quote {
+                return Err(VaultError::InvalidCall);
+            }
+            evicted = Some(bids.get_unchecked(worst));
+            bids.remove(worst);
+        }
+        bids.push_back(AuctionBid {
+            bidder: bidder.clone(),
+            amount,
+            min_quote,
+        });
+        e.storage().persistent().set(&DataKey::AuctionBids, &bids);
+
+        let token_client = token::Client::new(&e, &get_token(&e)?);
+        token_client.transfer(&bidder, &e.current_contract_address(), &amount);
+        if let Some(evicted) = evicted {
+            token_client.transfer(&e.current_contract_address(), &evicted.bidder, &evicted.amount);
+            e.events().publish(
+                (symbol_short!("AUCTION"), symbol_short!("evicted")),
+                (evicted.bidder, evicted.amount, evicted.min_quote),
+            );
+        }
+
+        e.events().publish(
+            (symbol_short!("AUCTION"), symbol_short!("bid")),
+            (bidder, amount, min_quote),
+        );
+
+        Ok(bids.len())
+    }
+
+    fn settle_auction(e: Env) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+
+        let mut auction = get_auction(&e)?;
+        if auction.clearing_quote > 0 {
+            return Err(VaultError::SharesAlreadyIssued);
+        }
+        if e.ledger().timestamp() < auction.close_time {
+            return Err(VaultError::InvalidCall);
+        }
+        // The bids went to the successor, which settles or refunds them from then on
+        if is_migrated(&e) {
+            return Err(VaultError::ContractStopped);
+        }
+
+        let bids = sorted_auction_bids(&e);
+        let clearing_quote = clearing_quote(&bids, &auction)?;
+        auction.clearing_quote = clearing_quote;
+        e.storage().instance().set(&DataKey::Auction, &auction);
+
+        // Every bid is recorded before the proceeds and refunds are transferred
+        let mut remaining = auction.supply;
+        let mut proceeds = 0;
+        let mut refunds: Vec<(Address, i128)> = Vec::new(&e);
+        for bid in bids.iter() {
+            let mut paid = 0;
+            if bid.min_quote <= clearing_quote && remaining > 0 {
+                let demanded = quote_shares(bid.amount, clearing_quote)?;
+                let shares = core::cmp::min(demanded, remaining);
+                paid = if shares == demanded {
+                    bid.amount
+                } else {
+                    mul_div(shares, 10i128.pow(DECIMALS), clearing_quote)?
+                };
+                remaining -= shares;
+                proceeds += paid;
+
+                put_cost_basis(&e, bid.bidder.clone(), get_cost_basis(&e, bid.bidder.clone()) + paid);
+                mint_shares(&e, bid.bidder.clone(), shares)?;
+                publish_tx(&e, symbol_short!("deposit"), &bid.bidder, paid, shares, 0)?;
+            }
+            if bid.amount > paid {
+                refunds.push_back((bid.bidder, bid.amount - paid));
+            }
+        }
+        record_deposit(&e, proceeds)?;
+        if get_covenant_bps(&e) > 0 {
+            let covenant = get_covenant(&e);
+            e.storage()
+                .instance()
+                .set(&DataKey::CovenantDeposits, &(covenant.deposits + proceeds));
+            put_covenant_balance(&e, covenant.balance + proceeds);
+        }
+
+        let token_client = token::Client::new(&e, &get_token(&e)?);
+        for (destination, amount) in deposit_destinations(&e, proceeds)?.iter() {
+            if destination != e.current_contract_address() && amount > 0 {
+                token_client.transfer(&e.current_contract_address(), &destination, &amount);
+            }
+        }
+        for (bidder, refund) in refunds.iter() {
+            token_client.transfer(&e.current_contract_address(), &bidder, &refund);
+        }
+        report_tvl(&e);
+
+        e.events().publish(
+            (symbol_short!("AUCTION"), symbol_short!("settled")),
+            (clearing_quote, auction.supply - remaining, proceeds),
+        );
+
+        Ok(clearing_quote)
+    }
+
+    fn auction(e: Env) -> Option<Auction> {
+        extend_instance_ttl(&e);
+        e.storage().instance().get(&DataKey::Auction)
+    }
+
+    fn auction_bids(e: Env) -> Vec<AuctionBid> {
+        extend_instance_ttl(&e);
+        get_auction_bids(&e)
+    }
+
+    fn accounting_mode(e: Env) -> Result<AccountingMode, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_accounting_mode(&e))
+    }
+
+    fn set_quote(e: Env, amount: i128, idempotency_key: Option<BytesN<32>>) -> Result<i128, VaultError> {
+        require_admin(&e)?;
+        if let Some(result) = replayed_result(&e, symbol_short!("quote"), &idempotency_key) {
+            return Ok(result);
+        }
+        if !within_quote_bounds(&e, amount) {
+            return Err(VaultError::QuoteOutOfBounds);
+        }
+        let result = replace_quote(&e, amount)?;
+        record_idempotency_key(&e, symbol_short!("quote"), idempotency_key, result);
+        Ok(result)
+    }
+
+    fn set_quote_bounds(e: Env, bounds: QuoteBounds) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        require_quoted_mode(&e)?;
+        extend_instance_ttl(&e);
+
+        if bounds.max_deviation_bps > 10000
+            || bounds.min_quote < 0
+            || bounds.max_quote < 0
+            || (bounds.max_quote > 0 && bounds.max_quote < bounds.min_quote)
+        {
+            return Err(VaultError::InvalidAmount);
+        }
+        e.storage().instance().set(&DataKey::QuoteBounds, &bounds);
+
+        e.events()
+            .publish((symbol_short!("QUOTE"), symbol_short!("bounds")), bounds);
+
+        Ok(())
+    }
+
+    fn quote_bounds(e: Env) -> Option<QuoteBounds> {
+        extend_instance_ttl(&e);
+        get_quote_bounds(&e)
+    }
+
+    fn propose_quote(e: Env, amount: i128) -> Result<PendingQuote, VaultError> {
+        require_admin(&e)?;
+        require_quoted_mode(&e)?;
+        check_nonnegative_amount(amount)?;
+        extend_instance_ttl(&e);
+        check_before_maturity(&e)?;
+
+        let bounds = get_quote_bounds(&e).ok_or(VaultError::InvalidCall)?;
+        let pending = PendingQuote {
+            quote: amount,
+            eta: time(&e) + bounds.timelock,
+        };
+        e.storage().instance().set(&DataKey::PendingQuote, &pending);
+
+        e.events().publish(
+            (symbol_short!("QUOTE"), symbol_short!("proposed")),
+            (pending.quote, pending.eta),
+        );
+
+        Ok(pending)
+    }
+
+    fn cosign_quote(e: Env) -> Result<i128, VaultError> {
+        let guardian = get_guardian(&e).ok_or(VaultError::GuardianNotSet)?;
+        guardian.require_auth();
+        extend_instance_ttl(&e);
+
+        let pending = get_pending_quote(&e)?;
+        e.storage().instance().remove(&DataKey::PendingQuote);
+        replace_quote(&e, pending.quote)
+    }
+
+    fn execute_quote(e: Env) -> Result<i128, VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        // The proposed quote remains out of bounds until its timelock passes
+        let pending = get_pending_quote(&e)?;
+        if time(&e) < pending.eta {
+            return Err(VaultError::QuoteOutOfBounds);
+        }
+        e.storage().instance().remove(&DataKey::PendingQuote);
+        replace_quote(&e, pending.quote)
+    }
+
+    fn cancel_quote(e: Env) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        let pending = get_pending_quote(&e)?;
+        e.storage().instance().remove(&DataKey::PendingQuote);
+
+        e.events()
+            .publish((symbol_short!("QUOTE"), symbol_short!("cancelled")), pending.quote);
+
+        Ok(())
+    }
+
+    fn pending_quote(e: Env) -> Result<PendingQuote, VaultError> {
+        extend_instance_ttl(&e);
+        get_pending_quote(&e)
+    }
+
+    fn set_quoters(e: Env, quoters: Vec<Address>, quorum: u32, max_deviation_bps: u32) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        require_quoted_mode(&e)?;
+        extend_instance_ttl(&e);
+
+        if quoters.len() > MAX_QUOTERS || max_deviation_bps > 10000 {
+            return Err(VaultError::InvalidAmount);
+        }
+        if !quoters.is_empty() && (quorum == 0 || quorum > quoters.len()) {
+            return Err(VaultError::InvalidAmount);
+        }
+
+        e.storage().instance().set(&DataKey::Quoters, &quoters);
+        e.storage().instance().set(&DataKey::QuoteQuorum, &quorum);
+        e.storage().instance().set(&DataKey::QuoteDeviation, &max_deviation_bps);
+
+        e.events().publish(
+            (symbol_short!("QUOTE"), symbol_short!("quoters")),
+            (quoters, quorum, max_deviation_bps),
+        );
+
+        Ok(())
+    }
+
+    fn quoters(e: Env) -> Result<(Vec<Address>, u32, u32), VaultError> {
+        extend_instance_ttl(&e);
+        Ok((
+            get_quoters(&e),
+            e.storage().instance().get(&DataKey::QuoteQuorum).unwrap_or(0),
+            e.storage().instance().get(&DataKey::QuoteDeviation).unwrap_or(0),
+        ))
+    }
+
+    fn submit_quote(e: Env, quoter: Address, amount: i128) -> Result<i128, VaultError> {
+        quoter.require_auth();
+        check_nonnegative_amount(amount)?;
+        extend_instance_ttl(&e);
+        check_before_maturity(&e)?;
+
+        if !get_quoters(&e).contains(&quoter) {
+            return Err(VaultError::NotQuoter);
+        }
+        e.storage()
+            .persistent()
+            .set(&quote_submission_key(&quoter), &(amount, time(&e)));
+
+        e.events()
+            .publish((symbol_short!("QUOTE"), symbol_short!("submit")), (quoter, amount));
+
+        match aggregate_quote(&e) {
+            Ok(quote) => {
+                put_current_quote(&e, quote)?;
+
+                e.events()
+                    .publish((symbol_short!("QUOTE"), symbol_short!("set")), quote);
+
+                Ok(quote)
+            }
+            Err(VaultError::QuorumNotReached) => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn active_quote(e: Env) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+        aggregate_quote(&e)
+    }
+
+    fn set_price_tiers(e: Env, tiers: Vec<PriceTier>) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        require_quoted_mode(&e)?;
+        extend_instance_ttl(&e);
+
+        if tiers.len() > MAX_PRICE_TIERS {
+            return Err(VaultError::InvalidAmount);
+        }
+
+        let mut previous_cap = 0;
+        for tier in tiers.iter() {
+            if tier.cap <= previous_cap || tier.quote <= 0 {
+                return Err(VaultError::InvalidAmount);
+            }
+            previous_cap = tier.cap;
+        }
+
+        e.storage().instance().set(&DataKey::PriceTiers, &tiers);
+
+        e.events()
+            .publish((symbol_short!("QUOTE"), symbol_short!("tiers")), tiers);
+
+        Ok(())
+    }
+
+    fn set_bid_quoter(e: Env, quoter: Option<Address>) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        match quoter.clone() {
+            Some(quoter) => e.storage().instance().set(&DataKey::BidQuoter, &quoter),
+            None => e.storage().instance().remove(&DataKey::BidQuoter),
+        }
+
+        e.events()
+            .publish((symbol_short!("QUOTE"), symbol_short!("bidquoter")), quoter);
+
+        Ok(())
+    }
+
+    fn bid_quoter(e: Env) -> Result<Address, VaultError> {
+        extend_instance_ttl(&e);
+        get_bid_quoter(&e)
+    }
+
+    fn set_bid_quote(e: Env, amount: i128) -> Result<i128, VaultError> {
+        get_bid_quoter(&e)?.require_auth();
+        require_fixed_quote_mode(&e)?;
+        check_nonnegative_amount(amount)?;
+        extend_instance_ttl(&e);
+
+        if get_bid_quote(&e).is_ok() {
+            return Err(VaultError::QuoteStillValid);
+        }
+
+        e.storage().instance().set(&DataKey::BidQuote, &amount);
+        e.storage()
+            .instance()
+            .set(&DataKey::BidQuoteExpiration, &(time(&e) + get_quote_period(&e)?));
+
+        e.events()
+            .publish((symbol_short!("QUOTE"), symbol_short!("bid")), amount);
+
+        Ok(amount)
+    }
+
+    fn bid_quote(e: Env) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+        get_bid_quote(&e).or(Ok(0))
+    }
+
+    fn set_buyback_reserve(e: Env, amount: i128) -> Result<i128, VaultError> {
+        let quoter = get_bid_quoter(&e)?;
+        quoter.require_auth();
+        require_fixed_quote_mode(&e)?;
+        check_nonnegative_amount(amount)?;
+        extend_instance_ttl(&e);
+
+        let reserve = get_buyback_reserve(&e);
+        let token_client = token::Client::new(&e, &get_token(&e)?);
+        if amount > reserve {
+            token_client.transfer(&quoter, &e.current_contract_address(), &(amount - reserve));
+        } else if amount < reserve {
+            token_client.transfer(&e.current_contract_address(), &quoter, &(reserve - amount));
+        }
+        put_buyback_reserve(&e, amount);
+
+        e.events()
+            .publish((symbol_short!("BUYBACK"), symbol_short!("reserve")), amount);
+
+        Ok(amount)
+    }
+
+    fn buyback_reserve(e: Env) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_buyback_reserve(&e))
+    }
+
+    fn sell_back(e: Env, holder: Address, shares: i128, expected_bid: i128) -> Result<i128, VaultError> {
+        holder.require_auth();
+        require_fixed_quote_mode(&e)?;
+        check_nonnegative_amount(shares)?;
+        extend_instance_ttl(&e);
+
+        if get_stopped(&e) {
+            return Err(VaultError::ContractStopped);
+        }
+        check_before_maturity(&e)?;
+
+        let bid_quote = get_bid_quote(&e)?;
+        if bid_quote != expected_bid {
+            return Err(VaultError::QuoteChanged);
+        }
+
+        let asset_amount = mul_div(shares, 10i128.pow(DECIMALS), bid_quote)?;
+        let reserve = get_buyback_reserve(&e);
+        if asset_amount > reserve {
+            return Err(VaultError::InsufficientReserve);
+        }
+
+        // Release the cost basis attributable to the repurchased shares
+        let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
+        let balance = share_token_client.balance(&holder);
+        let cost_basis = get_cost_basis(&e, holder.clone());
+        if cost_basis > 0 && balance > 0 {
+            let remaining = balance - core::cmp::min(shares, balance);
+            put_cost_basis(&e, holder.clone(), pro_rata(cost_basis, remaining, balance)?);
+        }
+
+        share_token_client.transfer(&holder, &e.current_contract_address(), &shares);
+        burn_shares(&e, shares)?;
+        release_cost_basis(&e, shares)?;
+
+        put_buyback_reserve(&e, reserve - asset_amount);
+        record_redemption(&e, asset_amount);
+        token::Client::new(&e, &get_token(&e)?).transfer(&e.current_contract_address(), &holder, &asset_amount);
+
+        e.events().publish(
+            (symbol_short!("BUYBACK"), symbol_short!("sold")),
+            (holder.clone(), shares, asset_amount),
+        );
+        publish_tx(&e, symbol_short!("buyback"), &holder, asset_amount, shares, 0)?;
+
+        Ok(asset_amount)
+    }
+
+    fn price_tiers(e: Env) -> Result<Vec<PriceTier>, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_price_tiers(&e))
+    }
+
+    fn bond_id(e: Env) -> Result<Address, VaultError> {
+        extend_instance_ttl(&e);
+        get_token_share(&e)
+    }
+
+    fn deposit(
+        e: Env,
+        from: Address,
+        amount: i128,
+        expected_quote: i128,
+    ) -> Result<i128, VaultError> {
+        from.require_auth();
+
+        process_deposit(&e, deposit_transfer_contexts(&e, &from, amount)?, &from, &from, amount, expected_quote, 0)
+    }
+
+    fn deposit_with_authorization(
+        e: Env,
+        from: Address,
+        amount: i128,
+        deadline: u64,
+        nonce: u64,
+        signature: BytesN<64>,
+    ) -> Result<i128, VaultError> {
+        if time(&e) > deadline {
+            return Err(VaultError::PermitExpired);
+        }
+        if nonce != get_permit_nonce(&e, &from) {
+            return Err(VaultError::InvalidNonce);
+        }
+        let public_key = permit::account_public_key(&e, &from).ok_or(VaultError::InvalidSigner)?;
+
+        let quote = get_effective_quote(&e)?;
+        let payload = permit::payload(&e, &e.current_contract_address(), &from, amount, quote, deadline, nonce);
+        e.crypto().ed25519_verify(&public_key, &payload, &signature);
+
+        e.storage().persistent().set(&permit_nonce_key(&from), &(nonce + 1));
+
+        let quantity = process_deposit(&e, deposit_transfer_contexts(&e, &from, amount)?, &from, &from, amount, quote, 0)?;
+
+        e.events()
+            .publish((symbol_short!("PERMIT"), symbol_short!("deposit")), (from, nonce, quantity));
+
+        Ok(quantity)
+    }
+
+    fn permit_payload(e: Env, from: Address, amount: i128, deadline: u64, nonce: u64) -> Result<Bytes, VaultError> {
+        extend_instance_ttl(&e);
+        let quote = get_effective_quote(&e)?;
+        Ok(permit::payload(&e, &e.current_contract_address(), &from, amount, quote, deadline, nonce))
+    }
+
+    fn permit_nonce(e: Env, from: Address) -> Result<u64, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_permit_nonce(&e, &from))
+    }
+
+    fn set_anchor(e: Env, anchor: Option<Address>) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        match anchor.clone() {
+            Some(anchor) => e.storage().instance().set(&DataKey::Anchor, &anchor),
+            None => e.storage().instance().remove(&DataKey::Anchor),
+        }
+
+        e.events()
+            .publish((symbol_short!("ANCHOR"), symbol_short!("set")), anchor);
+
+        Ok(())
+    }
+
+    fn anchor(e: Env) -> Result<Address, VaultError> {
+        extend_instance_ttl(&e);
+        get_anchor(&e)
+    }
+
+    fn anchor_deposit(
+        e: Env,
+        holder: Address,
+        gross_amount: i128,
+        fee: i128,
+        expected_quote: i128,
+    ) -> Result<i128, VaultError> {
+        let anchor = get_anchor(&e)?;
+        anchor.require_auth();
+
+        check_nonnegative_amount(fee)?;
+        if fee >= gross_amount {
+            return Err(VaultError::InvalidAmount);
+        }
+
+        e.storage().persistent().set(
+            &anchor_fees_key(holder.clone()),
+            &(get_anchor_fees(&e, holder.clone()) + fee),
+        );
+        e.storage()
+            .instance()
+            .set(&DataKey::TotalAnchorFees, &(get_total_anchor_fees(&e) + fee));
+
+        let net_amount = gross_amount - fee;
+        let quantity = process_deposit(
+            &e,
+            deposit_transfer_contexts(&e, &anchor, net_amount)?,
+            &anchor,
+            &holder,
+            net_amount,
+            expected_quote,
+            fee,
+        )?;
+
+        e.events().publish(
+            (symbol_short!("ANCHOR"), symbol_short!("deposit")),
+            (holder, gross_amount, fee, net_amount, quantity),
+        );
+
+        Ok(quantity)
+    }
+
+    fn total_anchor_fees(e: Env) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_total_anchor_fees(&e))
+    }
+
+    fn last_tx_id(e: Env) -> Result<u64, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_last_tx_id(&e))
+    }
+
+    fn deposit_auth_entries(e: Env, from: Address, amount: i128) -> Result<Vec<InvokerContractAuthEntry>, VaultError> {
+        extend_instance_ttl(&e);
+        check_nonnegative_amount(amount)?;
+
+        let mut entries = Vec::new(&e);
+        for context in deposit_transfer_contexts(&e, &from, amount)?.iter() {
+            entries.push_back(InvokerContractAuthEntry::Contract(SubContractInvocation {
+                context,
+                sub_invocations: Vec::new(&e),
+            }));
+        }
+
+        Ok(entries)
+    }
+
+    fn withdraw(e: Env, owner: Address, receiver: Address, amount: i128) -> Result<i128, VaultError> {
+        owner.require_auth();
+        redeem_shares(&e, owner, receiver, amount, false)
+    }
+
+    fn set_operator(e: Env, holder: Address, operator: Address, approved: bool) -> Result<(), VaultError> {
+        holder.require_auth();
+        extend_instance_ttl(&e);
+
+        let key = operator_key(&holder, &operator);
+        if approved {
+            e.storage().persistent().set(&key, &true);
+            e.events()
+                .publish((symbol_short!("OPERATOR"), symbol_short!("approved")), (holder, operator));
+        } else {
+            e.storage().persistent().remove(&key);
+            e.events()
+                .publish((symbol_short!("OPERATOR"), symbol_short!("revoked")), (holder, operator));
+        }
+
+        Ok(())
+    }
+
+    fn is_operator(e: Env, holder: Address, operator: Address) -> bool {
+        extend_instance_ttl(&e);
+        is_operator(&e, &holder, &operator)
+    }
+
+    fn operator_withdraw(
+        e: Env,
+        operator: Address,
+        owner: Address,
+        receiver: Address,
+        amount: i128,
+    ) -> Result<i128, VaultError> {
+        operator.require_auth();
+
+        if !is_operator(&e, &owner, &operator) {
+            return Err(VaultError::InvalidSigner);
+        }
+        redeem_shares(&e, owner, receiver, amount, true)
+    }
+
+    fn set_note_term(e: Env, term: u64) -> Result<u64, VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        if !is_notes_mode(&e) {
+            return Err(VaultError::UnsupportedInMode);
+        }
+        if term == 0 {
+            return Err(VaultError::InvalidAmount);
+        }
+
+        e.storage().instance().set(&DataKey::NoteTerm, &term);
+
+        e.events()
+            .publish((symbol_short!("NOTE"), symbol_short!("term")), term);
+
+        Ok(term)
+    }
+
+    fn note_term(e: Env) -> Result<u64, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_note_term(&e))
+    }
+
+    fn fund_notes(e: Env, amount: i128) -> Result<i128, VaultError> {
+        let admin = require_admin(&e)?;
+        check_nonnegative_amount(amount)?;
+        extend_instance_ttl(&e);
+
+        if !is_notes_mode(&e) {
+            return Err(VaultError::UnsupportedInMode);
+        }
+
+        token::Client::new(&e, &get_token(&e)?).transfer(&admin, &e.current_contract_address(), &amount);
+        let available_redemption = get_available_redemption(&e)? + amount;
+        put_available_redemption(&e, available_redemption);
+
+        e.events()
+            .publish((symbol_short!("NOTE"), symbol_short!("funded")), amount);
+
+        Ok(available_redemption)
+    }
+
+    fn withdraw_receipt(e: Env, to: Address, receipt_id: u32) -> Result<i128, VaultError> {
+        to.require_auth();
+        extend_instance_ttl(&e);
+
+        if !is_notes_mode(&e) {
+            return Err(VaultError::UnsupportedInMode);
+        }
+
+        let mut receipt = get_receipt(&e, to.clone(), receipt_id)?;
+        if receipt.redeemed {
+            return Err(VaultError::ReceiptRedeemed);
+        }
+        if time(&e) < receipt.maturity {
+            return Err(VaultError::MaturityNotReached);
+        }
+
+        // Notes redeem at par, one underlying per share
+        let available_redemption = get_available_redemption(&e)?;
+        if receipt.shares > available_redemption {
+            return Err(VaultError::AvailableRedemptionNotSet);
+        }
+
+        let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
+        let balance = share_token_client.balance(&to);
+        let cost_basis = get_cost_basis(&e, to.clone());
+        if cost_basis > 0 && balance > 0 {
+            let remaining = balance - core::cmp::min(receipt.shares, balance);
+            put_cost_basis(&e, to.clone(), pro_rata(cost_basis, remaining, balance)?);
+        }
+
+        share_token_client.transfer(&to, &e.current_contract_address(), &receipt.shares);
+        burn_shares(&e, receipt.shares)?;
+        release_cost_basis(&e, receipt.shares)?;
+
+        put_available_redemption(&e, available_redemption - receipt.shares);
+        record_redemption(&e, receipt.shares);
+        receipt.redeemed = true;
+        put_receipt(&e, to.clone(), &receipt);
+
+        token::Client::new(&e, &get_token(&e)?).transfer(&e.current_contract_address(), &to, &receipt.shares);
+
+        e.events().publish(
+            (symbol_short!("NOTE"), symbol_short!("redeemed")),
+            (to.clone(), receipt_id, receipt.shares),
+        );
+        publish_tx(&e, symbol_short!("redeem"), &to, receipt.shares, receipt.shares, 0)?;
+
+        Ok(receipt.shares)
+    }
+
+    fn transfer_receipt(e: Env, from: Address, to: Address, receipt_id: u32) -> Result<NoteReceipt, VaultError> {
+        from.require_auth();
+        extend_instance_ttl(&e);
+
+        if !is_notes_mode(&e) {
+            return Err(VaultError::UnsupportedInMode);
+        }
+        if from == to {
+            return Err(VaultError::InvalidCall);
+        }
+
+        let receipt = get_receipt(&e, from.clone(), receipt_id)?;
+        if receipt.redeemed {
+            return Err(VaultError::ReceiptRedeemed);
+        }
+
+        // The cost basis of the shares follows them
+        let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
+        let balance = share_token_client.balance(&from);
+        if receipt.shares > balance {
+            return Err(VaultError::InvalidAmount);
+        }
+        let cost_basis = get_cost_basis(&e, from.clone());
+        let remaining_cost_basis = pro_rata(cost_basis, balance - receipt.shares, balance)?;
+        put_cost_basis(&e, from.clone(), remaining_cost_basis);
+        put_cost_basis(
+            &e,
+            to.clone(),
+            get_cost_basis(&e, to.clone()) + cost_basis - remaining_cost_basis,
+        );
+
+        e.storage().persistent().remove(&receipt_key(from.clone(), receipt_id));
+        let moved = append_receipt(&e, &to, receipt.amount, receipt.shares, receipt.maturity);
+        share_token_client.transfer(&from, &to, &receipt.shares);
+
+        e.events().publish(
+            (symbol_short!("NOTE"), symbol_short!("transfer")),
+            (from, to, receipt_id, moved.id),
+        );
+
+        Ok(moved)
+    }
+
+    fn get_receipts(e: Env, holder: Address, start: u32, limit: u32) -> Result<Vec<NoteReceipt>, VaultError> {
+        extend_instance_ttl(&e);
+
+        let end = core::cmp::min(
+            get_receipt_count(&e, holder.clone()),
+            start.saturating_add(core::cmp::min(limit, MAX_PAGE_SIZE)),
+        );
+
+        let mut receipts = Vec::new(&e);
+        for id in start..end {
+            if let Ok(receipt) = get_receipt(&e, holder.clone(), id) {
+                receipts.push_back(receipt);
+            }
+        }
+
+        Ok(receipts)
+    }
+
+    fn stats(e: Env) -> Result<IssuanceStats, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(IssuanceStats {
+            total_deposited_lifetime: get_total_deposited_lifetime(&e),
+            total_redeemed_lifetime: get_total_redeemed(&e),
+            unique_depositors_count: get_holder_count(&e),
+            largest_single_deposit: get_largest_deposit(&e),
+        })
+    }
+
+    fn total_deposit(e: Env) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+        get_total_deposit(&e)
+    }
+
+    fn set_beneficiary(e: Env, holder: Address, beneficiary: Address, share_bps: u32) -> Result<(), VaultError> {
+        holder.require_auth();
+        extend_instance_ttl(&e);
+
+        if share_bps > 10000 {
+            return Err(VaultError::InvalidAmount);
+        }
+
+        if share_bps == 0 {
+            e.storage().persistent().remove(&beneficiary_key(holder.clone()));
+        } else {
+            e.storage().persistent().set(
+                &beneficiary_key(holder.clone()),
+                &BeneficiaryShare {
+                    beneficiary: beneficiary.clone(),
+                    share_bps,
+                },
+            );
+        }
+
+        e.events().publish(
+            (symbol_short!("BENEFIT"), symbol_short!("set")),
+            (holder, beneficiary, share_bps),
+        );
+
+        Ok(())
+    }
+
+    fn get_beneficiary(e: Env, holder: Address) -> Result<BeneficiaryShare, VaultError> {
+        extend_instance_ttl(&e);
+        get_beneficiary(&e, holder).ok_or(VaultError::BeneficiaryNotSet)
+    }
+
+    fn available_redemption(e: Env) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+        get_available_redemption(&e)
+    }
+
+    fn set_covenant(e: Env, buffer_bps: u32) -> Result<Covenant, VaultError> {
+        require_admin(&e)?;
+        require_fixed_quote_mode(&e)?;
+        extend_instance_ttl(&e);
+
+        if buffer_bps > 10000 || buffer_bps < get_covenant_bps(&e) {
+            return Err(VaultError::InvalidAmount);
+        }
+        e.storage().instance().set(&DataKey::CovenantBps, &buffer_bps);
+
+        e.events()
+            .publish((symbol_short!("COVENANT"), symbol_short!("set")), buffer_bps);
+
+        Ok(get_covenant(&e))
+    }
+
+    fn covenant(e: Env) -> Result<Covenant, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_covenant(&e))
+    }
+
+    fn treasury_draw(e: Env, amount: i128) -> Result<i128, VaultError> {
+        let treasury = get_treasury(&e)?;
+        treasury.require_auth();
+        check_nonnegative_amount(amount)?;
+        extend_instance_ttl(&e);
+
+        // The deposits of a cancelled offering are refunded to the holders
+        if get_state(&e)? == VaultState::Cancelled {
+            return Err(VaultError::InvalidCall);
+        }
+        let covenant = get_covenant(&e);
+        if amount > covenant.drawable {
+            return Err(VaultError::InsufficientReserve);
+        }
+
+        token::Client::new(&e, &get_token(&e)?).transfer(&e.current_contract_address(), &treasury, &amount);
+        put_covenant_balance(&e, covenant.balance - amount);
+
+        e.events().publish(
+            (symbol_short!("COVENANT"), symbol_short!("draw")),
+            (treasury, amount, covenant.balance - amount),
+        );
+
+        Ok(amount)
+    }
+
+    fn set_total_redemption(
+        e: Env,
+        amount: i128,
+        idempotency_key: Option<BytesN<32>>,
+    ) -> Result<i128, VaultError> {
+        check_nonnegative_amount(amount)?;
+        require_fixed_quote_mode(&e)?;
+        extend_instance_ttl(&e);
+
+        if get_settlement_asset(&e).is_some() {
+            return Err(VaultError::UnsupportedInMode);
+        }
+        let admin = require_admin(&e)?;
+        if let Some(result) = replayed_result(&e, symbol_short!("redeem"), &idempotency_key) {
+            return Ok(result);
+        }
+        // A retried call must not fund the redemption twice
+        if get_available_redemption(&e)? > 0 && get_redemption_funded(&e) == amount {
+            return Ok(amount);
+        }
+        check_redemption_open(&e)?;
+
+        pull_redemption_funds(&e, &get_token(&e)?, &admin, amount)?;
+
+        // Donations received so far are redeemed along with the total redemption
+        let total_redemption = amount + get_donations(&e);
+        put_donations(&e, 0);
+
+        open_redemption(&e, amount, total_redemption)?;
+        record_idempotency_key(&e, symbol_short!("redeem"), idempotency_key, amount);
+
+        Ok(amount)
+    }
+
+    fn top_up_redemption(e: Env, amount: i128) -> Result<i128, VaultError> {
+        check_nonnegative_amount(amount)?;
+        require_fixed_quote_mode(&e)?;
+        extend_instance_ttl(&e);
+
+        let admin = require_admin(&e)?;
+        if e.storage().instance().get::<_, u64>(&DataKey::SettledAt).is_none() {
+            return Err(VaultError::AvailableRedemptionNotSet);
+        }
+        if claim_deadline_passed(&e) {
+            return Err(VaultError::ClaimDeadlinePassed);
+        }
+
+        pull_redemption_funds(&e, &get_redemption_token(&e)?, &admin, amount)?;
+        e.storage()
+            .instance()
+            .set(&DataKey::RedemptionFunded, &(get_redemption_funded(&e) + amount));
+
+        let available_redemption = get_available_redemption(&e)? + amount;
+        put_available_redemption(&e, available_redemption);
+        check_redemption_covered(&e)?;
+
+        e.events().publish(
+            (symbol_short!("REDEEM"), symbol_short!("topup")),
+            (admin, amount, available_redemption),
+        );
+
+        Ok(available_redemption)
+    }
+
+    fn release_covenant_buffer(e: Env) -> Result<i128, VaultError> {
+        let admin = require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        if e.storage().instance().get::<_, u64>(&DataKey::SettledAt).is_none() {
+            return Err(VaultError::AvailableRedemptionNotSet);
+        }
+        if claim_deadline_passed(&e) {
+            return Err(VaultError::ClaimDeadlinePassed);
+        }
+
+        let buffer = get_covenant_balance(&e);
+        put_covenant_balance(&e, 0);
+        let available_redemption = get_available_redemption(&e)? + buffer;
+        put_available_redemption(&e, available_redemption);
+
+        e.events().publish(
+            (symbol_short!("COVENANT"), symbol_short!("released")),
+            (admin, buffer, available_redemption),
+        );
+
+        Ok(available_redemption)
+    }
+
+    fn redemption_funded(e: Env) -> i128 {
+        extend_instance_ttl(&e);
+        get_redemption_funded(&e)
+    }
+
+    fn set_settlement_asset(e: Env, asset: Address) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        require_fixed_quote_mode(&e)?;
+        extend_instance_ttl(&e);
+
+        check_before_maturity(&e)?;
+        if asset == get_token(&e)? {
+            return Err(VaultError::InvalidCall);
+        }
+        // Fails unless `asset` implements the token interface
+        token::Client::new(&e, &asset).decimals();
+
+        e.storage().instance().set(&DataKey::SettlementAsset, &asset);
+
+        e.events()
+            .publish((symbol_short!("SETTLE"), symbol_short!("asset")), asset);
+
+        Ok(())
+    }
+
+    fn settle_redemption(e: Env, amount: i128, rate: i128) -> Result<i128, VaultError> {
+        check_nonnegative_amount(amount)?;
+        extend_instance_ttl(&e);
+
+        let asset = get_settlement_asset(&e).ok_or(VaultError::SettlementAssetNotSet)?;
+        if rate <= 0 {
+            return Err(VaultError::InvalidAmount);
+        }
+        check_redemption_open(&e)?;
+        let admin = require_admin(&e)?;
+
+        // The donations and covenant buffer stay with the holders, in the deposit token
+        let held = get_donations(&e) + get_covenant_balance(&e);
+        put_donations(&e, 0);
+        put_covenant_balance(&e, 0);
+        put_settlement_carry(&e, get_settlement_carry(&e) + held);
+
+        e.storage().instance().set(&DataKey::SettlementRate, &rate);
+        let total_redemption = to_redemption_units(&e, amount)?;
+        pull_redemption_funds(&e, &asset, &admin, total_redemption)?;
+
+        open_redemption(&e, total_redemption, total_redemption)?;
+
+        e.events().publish(
+            (symbol_short!("SETTLE"), symbol_short!("rate")),
+            (amount, rate, total_redemption),
+        );
+
+        Ok(total_redemption)
+    }
+
+    fn settlement(e: Env) -> Result<Settlement, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(Settlement {
+            asset: get_settlement_asset(&e).ok_or(VaultError::SettlementAssetNotSet)?,
+            rate: get_settlement_rate(&e).unwrap_or(0),
+        })
+    }
+
+    fn set_challenge_window(e: Env, window: u64) -> Result<u64, VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        if get_available_redemption(&e)? > 0 {
+            return Err(VaultError::AvailableRedemptionAlreadySet);
+        }
+        e.storage().instance().set(&DataKey::ChallengeWindow, &window);
+
+        e.events()
+            .publish((symbol_short!("REDEEM"), symbol_short!("window")), window);
+
+        Ok(window)
+    }
+
+    fn challenge_window(e: Env) -> Result<u64, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_challenge_window(&e))
+    }
+
+    fn correct_total_redemption(e: Env, amount: i128) -> Result<i128, VaultError> {
+        check_nonnegative_amount(amount)?;
+        extend_instance_ttl(&e);
+
+        // The guardian acts regardless of admin activity here, as the admin just set the value
+        let guardian = get_guardian(&e).ok_or(VaultError::GuardianNotSet)?;
+        guardian.require_auth();
+
+        let mut challenge = get_redemption_challenge(&e).ok_or(VaultError::ChallengeWindowClosed)?;
+        if time(&e) >= challenge.closes_at {
+            return Err(VaultError::ChallengeWindowClosed);
+        }
+        if challenge.corrected {
+            return Err(VaultError::AvailableRedemptionAlreadySet);
+        }
+
+        let token_client = token::Client::new(&e, &get_redemption_token(&e)?);
+        let previous = challenge.amount;
+        if amount > previous {
+            token_client.transfer(&guardian, &e.current_contract_address(), &(amount - previous));
+        } else if amount < previous {
+            token_client.transfer(&e.current_contract_address(), &get_admin(&e)?, &(previous - amount));
+        }
+
+        let total_redemption = get_available_redemption(&e)? - previous + amount;
+        put_available_redemption(&e, total_redemption);
+        e.storage()
+            .instance()
+            .set(&DataKey::RedemptionFunded, &(get_redemption_funded(&e) - previous + amount));
+
+        challenge.amount = amount;
+        challenge.corrected = true;
+        e.storage().instance().set(&DataKey::RedemptionChallenge, &challenge);
+
+        e.events().publish(
+            (symbol_short!("REDEEM"), symbol_short!("corrected")),
+            (guardian, previous, amount),
+        );
+
+        Ok(amount)
+    }
+
+    fn redemption_challenge(e: Env) -> Result<Option<RedemptionChallenge>, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_redemption_challenge(&e))
+    }
+
+    fn set_claim_period(e: Env, period: u64) -> Result<u64, VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        put_claim_period(&e, period);
+
+        e.events()
+            .publish((symbol_short!("CLAIM"), symbol_short!("period")), period);
+
+        Ok(period)
+    }
+
+    fn claim_deadline(e: Env) -> Result<u64, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_claim_deadline(&e).unwrap_or(0))
+    }
+
+    fn get_entitlement(e: Env, holder: Address) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+        get_entitlement(&e, holder)
+    }
+
+    fn sweep_unclaimed(e: Env) -> Result<i128, VaultError> {
+        require_admin(&e)?;
+        require_fixed_quote_mode(&e)?;
+        extend_instance_ttl(&e);
+
+        if !claim_deadline_passed(&e) {
+            return Err(VaultError::ClaimDeadlineNotReached);
+        }
+
+        let unclaimed = get_available_redemption(&e)? + get_donations(&e);
+        if unclaimed > 0 {
+            let token_client = token::Client::new(&e, &get_redemption_token(&e)?);
+            token_client.transfer(&e.current_contract_address(), &get_treasury(&e)?, &unclaimed);
+        }
+        put_available_redemption(&e, 0);
+        put_donations(&e, 0);
+
+        // What a series settled in another asset holds in the deposit token goes along
+        let carry = get_settlement_carry(&e);
+        if carry > 0 {
+            token::Client::new(&e, &get_token(&e)?).transfer(&e.current_contract_address(), &get_treasury(&e)?, &carry);
+            put_settlement_carry(&e, 0);
+        }
+
+        e.events()
+            .publish((symbol_short!("CLAIM"), symbol_short!("swept")), unclaimed);
+
+        Ok(unclaimed)
+    }
+
+    fn push_redemptions(e: Env, holders: Vec<Address>) -> Result<i128, VaultError> {
+        require_admin(&e)?;
+        require_fixed_quote_mode(&e)?;
+        extend_instance_ttl(&e);
+
+        if holders.len() > MAX_PAGE_SIZE {
+            return Err(VaultError::InvalidAmount);
+        }
+        if !claim_deadline_passed(&e) {
+            return Err(VaultError::ClaimDeadlineNotReached);
+        }
+
+        // Each holder's entitlement is taken against what is left, and their shares are burnt
+        // under the allowance they gave the vault so that they cannot be passed on and pushed
+        // again. Holders without a sufficient allowance are skipped
+        let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
+        let vault = e.current_contract_address();
+        let mut pushed = 0;
+        for holder in holders.iter() {
+            let shares = share_token_client.balance(&holder);
+            if shares <= 0 || share_token_client.allowance(&holder, &vault) < shares {
+                continue;
+            }
+            let amount = get_entitlement(&e, holder.clone())?;
+            if amount <= 0 {
+                continue;
+            }
+
+            share_token_client.transfer_from(&vault, &holder, &vault, &shares);
+            burn_shares(&e, shares)?;
+            release_cost_basis(&e, shares)?;
+            e.storage().persistent().set(
+                &pushed_redemption_key(holder.clone()),
+                &(get_pushed_redemption(&e, holder.clone()) + amount),
+            );
+            put_available_redemption(&e, get_available_redemption(&e)? - amount);
+            pushed += amount;
+        }
+
+        e.storage()
+            .instance()
+            .set(&DataKey::PushedTotal, &(get_pushed_total(&e) + pushed));
+
+        e.events()
+            .publish((symbol_short!("CLAIM"), symbol_short!("pushed")), pushed);
+
+        Ok(pushed)
+    }
+
+    fn claim_pushed(e: Env, holder: Address) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+
+        let amount = get_pushed_redemption(&e, holder.clone());
+        if amount == 0 {
+            return Err(VaultError::NothingPushed);
+        }
+
+        e.storage().persistent().remove(&pushed_redemption_key(holder.clone()));
+        e.storage()
+            .instance()
+            .set(&DataKey::PushedTotal, &(get_pushed_total(&e) - amount));
+
+        token::Client::new(&e, &get_redemption_token(&e)?).transfer(&e.current_contract_address(), &holder, &amount);
+        record_redemption(&e, to_deposit_units(&e, amount)?);
+
+        e.events().publish(
+            (symbol_short!("REDEEM"), symbol_short!("holder")),
+            (holder.clone(), holder.clone(), amount),
+        );
+        publish_tx(&e, symbol_short!("redeem"), &holder, amount, 0, 0)?;
+
+        Ok(amount)
+    }
+
+    fn pushed_redemption(e: Env, holder: Address) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_pushed_redemption(&e, holder))
+    }
+
+    fn sync(e: Env) -> Result<SyncReport, VaultError> {
+        extend_instance_ttl(&e);
+
+        let balance =
+            token::Client::new(&e, &get_redemption_token(&e)?).balance(&e.current_contract_address());
+        if is_nav_mode(&e) {
+            return Ok(SyncReport {
+                balance,
+                expected: balance,
+                surplus: 0,
+                deficit: 0,
+            });
+        }
+
+        let available_redemption = get_available_redemption(&e)?;
+        let donations = get_donations(&e);
+        let settled_in_asset = get_redemption_token(&e)? != get_token(&e)?;
+        let expected = if settled_in_asset {
+            // Only the redemption is held in the settlement asset
+            available_redemption + get_pushed_total(&e)
+        } else {
+            available_redemption
+                + donations
+                + get_escrowed_funds(&e)
+                + get_covenant_balance(&e)
+                + get_pushed_total(&e)
+        };
+        let surplus = core::cmp::max(balance - expected, 0);
+        let deficit = core::cmp::max(expected - balance, 0);
+
+        if surplus > 0 {
+            // Once the redemption is set, donations go straight to the holders redeeming.
+            // Donations are counted in the deposit token, so a surplus of the settlement asset
+            // is only reported while there is no open redemption to add it to
+            if available_redemption > 0 {
+                put_available_redemption(&e, available_redemption + surplus);
+            } else if !settled_in_asset {
+                put_donations(&e, donations + surplus);
+            }
+            e.events()
+                .publish((symbol_short!("SYNC"), symbol_short!("surplus")), surplus);
+        }
+
+        e.storage().instance().set(&DataKey::Discrepancy, &deficit);
+        if deficit > 0 {
+            e.events()
+                .publish((symbol_short!("SYNC"), symbol_short!("deficit")), deficit);
+
+            if deficit > get_sync_tolerance(&e) && !get_stopped(&e) {
+                set_stopped(&e, true);
+                e.events().publish(
+                    (symbol_short!("VAULT"), symbol_short!("stopped")),
+                    true,
+                );
+            }
+        }
+
+        Ok(SyncReport {
+            balance,
+            expected,
+            surplus,
+            deficit,
+        })
+    }
+
+    fn set_sync_tolerance(e: Env, tolerance: i128) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        check_nonnegative_amount(tolerance)?;
+        extend_instance_ttl(&e);
+
+        e.storage().instance().set(&DataKey::SyncTolerance, &tolerance);
+
+        e.events()
+            .publish((symbol_short!("SYNC"), symbol_short!("tolerance")), tolerance);
+
+        Ok(())
+    }
+
+    fn discrepancy(e: Env) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_discrepancy(&e))
+    }
+
+    fn multicall(e: Env, calls: Vec<(Symbol, Vec<Val>)>) -> Result<Vec<Val>, VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        // Cleared again below, or reverted along with everything else if a call fails
+        e.storage().instance().set(&DataKey::MulticallActive, &true);
+
+        let mut results = Vec::new(&e);
+        for (function, args) in calls.iter() {
+            results.push_back(dispatch_admin_call(&e, &function, &args)?);
+        }
+
+        e.storage().instance().remove(&DataKey::MulticallActive);
+
+        e.events().publish(
+            (symbol_short!("VAULT"), symbol_short!("multicall")),
+            calls.len(),
+        );
+
+        Ok(results)
+    }
+
+    fn get_display_info(e: Env) -> Result<DisplayInfo, VaultError> {
+        extend_instance_ttl(&e);
+
+        let end_time = get_end_time(&e)?;
+        let quote = get_effective_quote(&e).unwrap_or(0);
+        let scale = 10i128.pow(DECIMALS);
+
+        // Underlying tokens paid per bond, each bond redeeming one token at maturity
+        let price = if quote > 0 { scale * scale / quote } else { 0 };
+
+        // Annualized yield in percent with two decimals
+        let term = end_time.saturating_sub(time(&e));
+        let apy = if quote > 0 && term > 0 {
+            (quote - scale) * 10_000 * display::SECONDS_PER_YEAR as i128 / (scale * term as i128)
+        } else {
+            0
+        };
+
+        Ok(DisplayInfo {
+            price: display::format_fixed(&e, price, DECIMALS),
+            apy: display::format_fixed(&e, apy, 2),
+            maturity: display::format_iso8601(&e, end_time),
+        })
+    }
+
+    fn set_bond_metadata(e: Env, metadata: ShareMetadata) -> Result<Address, VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        if get_total_shares(&e)? > 0 {
+            return Err(VaultError::SharesAlreadyIssued);
+        }
+
+        let revision = get_share_token_revision(&e) + 1;
+        let share_contract_id = deploy_share_token(
+            &e,
+            get_token_wasm_hash(&e)?,
+            &get_token(&e)?,
+            revision,
+            metadata,
+        )?;
+        put_share_token_revision(&e, revision);
+        put_token_share(&e, share_contract_id.clone())?;
+
+        e.events().publish(
+            (symbol_short!("SHARES"), symbol_short!("metadata")),
+            share_contract_id.clone(),
+        );
+
+        Ok(share_contract_id)
+    }
+
+    fn set_metadata(e: Env, key: String, value: String) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        let mut metadata = get_metadata(&e);
+        if value.is_empty() {
+            metadata.remove(key.clone()).ok_or(VaultError::MetadataNotFound)?;
+        } else {
+            if key.is_empty() || key.len() > MAX_METADATA_KEY_LEN || value.len() > MAX_METADATA_VALUE_LEN {
+                return Err(VaultError::MetadataTooLarge);
+            }
+            if !metadata.contains_key(key.clone()) && metadata.len() >= MAX_METADATA_ENTRIES {
+                return Err(VaultError::MetadataTooLarge);
+            }
+            metadata.set(key.clone(), value.clone());
+        }
+        put_metadata(&e, &metadata);
+
+        e.events()
+            .publish((symbol_short!("METADATA"), symbol_short!("set")), (key, value));
+
+        Ok(())
+    }
+
+    fn get_metadata(e: Env, key: String) -> Result<String, VaultError> {
+        extend_instance_ttl(&e);
+        get_metadata(&e).get(key).ok_or(VaultError::MetadataNotFound)
+    }
+
+    fn list_metadata(e: Env) -> Result<Map<String, String>, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_metadata(&e))
+    }
+
+    fn get_holders(e: Env, start: u32, limit: u32) -> Result<Vec<HolderPosition>, VaultError> {
+        extend_instance_ttl(&e);
+
+        let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
+        let end = core::cmp::min(
+            get_holder_count(&e),
+            start.saturating_add(core::cmp::min(limit, MAX_PAGE_SIZE)),
+        );
+
+        let mut holders = Vec::new(&e);
+        for index in start..end {
+            if let Some(holder) = get_holder(&e, index) {
+                holders.push_back(holder_position(&e, &share_token_client, holder));
+            }
+        }
+
+        Ok(holders)
+    }
+
+    fn holder_count(e: Env) -> Result<u32, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_holder_count(&e))
+    }
+
+    fn export_state(e: Env, section: ExportSection, start: u32, limit: u32) -> Result<Vec<StateEntry>, VaultError> {
+        // A read does not count as admin activity for the guardian's inactivity timer
+        let admin = get_admin(&e)?;
+        admin.require_auth();
+        extend_instance_ttl(&e);
+
+        let mut entries = Vec::new(&e);
+        let limit = core::cmp::min(limit, MAX_PAGE_SIZE);
+
+        match section {
+            ExportSection::Config => {
+                if start == 0 && limit > 0 {
+                    entries.push_back(StateEntry::Config(VaultConfig {
+                        token: get_token(&e)?,
+                        share_token: get_token_share(&e)?,
+                        admin,
+                        treasury: get_treasury(&e)?,
+                        start_time: get_start_time(&e)?,
+                        end_time: get_end_time(&e)?,
+                        quote_period: get_quote_period(&e)?,
+                        min_deposit: get_min_deposit(&e)?,
+                        max_deposit: get_max_deposit(&e).unwrap_or(0),
+                        total_shares: get_total_shares(&e)?,
+                        total_deposit: get_total_deposit(&e)?,
+                        available_redemption: get_available_redemption(&e)?,
+                        retired_shares: get_retired_shares(&e),
+                        claim_deadline: get_claim_deadline(&e).unwrap_or(0),
+                        holder_count: get_holder_count(&e),
+                        stopped: get_stopped(&e),
+                    }));
+                }
+            }
+            ExportSection::Holders => {
+                let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
+                let end = core::cmp::min(get_holder_count(&e), start.saturating_add(limit));
+                for index in start..end {
+                    if let Some(holder) = get_holder(&e, index) {
+                        let position = holder_position(&e, &share_token_client, holder.clone());
+                        entries.push_back(StateEntry::Holder(position, get_entitlement(&e, holder)?));
+                    }
+                }
+            }
+            ExportSection::Ratings => {
+                let end = core::cmp::min(get_rating_count(&e), start.saturating_add(limit));
+                for index in start..end {
+                    if let Some(attestation) = get_rating(&e, index) {
+                        entries.push_back(StateEntry::Rating(index, attestation));
+                    }
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn retire_shares(e: Env, amount: i128) -> Result<i128, VaultError> {
+        let admin = require_admin(&e)?;
+
+        check_nonnegative_amount(amount)?;
+        extend_instance_ttl(&e);
+
+        if get_available_redemption(&e)? > 0 {
+            return Err(VaultError::AvailableRedemptionAlreadySet);
+        }
+
+        let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
+        share_token_client.transfer(&admin, &e.current_contract_address(), &amount);
+        burn_shares(&e, amount)?;
+
+        let retired = get_retired_shares(&e) + amount;
+        put_retired_shares(&e, retired);
+
+        e.events().publish(
+            (symbol_short!("SHARES"), symbol_short!("retired")),
+            (amount, retired),
+        );
+
+        Ok(retired)
+    }
+
+    fn retired_shares(e: Env) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_retired_shares(&e))
+    }
+
+    fn set_deposit_limits(e: Env, min: u128, max: Option<u128>) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        if max.is_some_and(|max| max < min) {
+            return Err(VaultError::InvalidAmount);
+        }
+
+        put_min_deposit(&e, min)?;
+        put_max_deposit(&e, max);
+
+        e.events()
+            .publish((symbol_short!("DEPOSIT"), symbol_short!("limits")), (min, max));
+
+        Ok(())
+    }
+
+    fn get_deposit_limits(e: Env) -> Result<(u128, Option<u128>), VaultError> {
+        extend_instance_ttl(&e);
+        Ok((get_min_deposit(&e)?, get_max_deposit(&e)))
+    }
+
+    fn bond_metadata(e: Env) -> Result<BondMetadata, VaultError> {
+        extend_instance_ttl(&e);
+
+        let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
+        Ok(BondMetadata {
+            name: share_token_client.name(),
+            symbol: share_token_client.symbol(),
+            decimals: share_token_client.decimals(),
+            maturity: get_end_time(&e)?,
+            underlying: get_token(&e)?,
+        })
+    }
+
+    fn get_info(e: Env) -> Result<VaultInfo, VaultError> {
+        extend_instance_ttl(&e);
+
+        let config = get_series_config(&e)?;
+        Ok(VaultInfo {
+            token: config.token,
+            symbol: config.series,
+            share_token: config.share_token,
+            admin: get_admin(&e)?,
+            treasury: config.treasury,
+            start_time: config.start_time,
+            end_time: config.end_time,
+            quote_period: get_quote_period(&e)?,
+            min_deposit: config.min_deposit,
+            total_fees: get_total_anchor_fees(&e),
+            state: get_state(&e)?,
+            total_shares: get_total_shares(&e)?,
+            total_reserve: token::Client::new(&e, &get_redemption_token(&e)?)
+                .balance(&e.current_contract_address()),
+        })
+    }
+
+    fn admin(e: Env) -> Result<Address, VaultError> {
+        extend_instance_ttl(&e);
+        get_admin(&e)
+    }
+
+    fn set_treasury(e: Env, treasury: Address) -> Result<Address, VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+        e.events().publish(
+            (symbol_short!("TREASURY"), symbol_short!("set")),
+            treasury.clone(),
+        );
+        put_treasury(&e, treasury.clone())?;
+
+        Ok(treasury)
+    }
+
+    fn set_treasury_split(e: Env, secondary: Address, secondary_bps: u32) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        if secondary_bps > 10000 {
+            return Err(VaultError::InvalidAmount);
+        }
+
+        if secondary_bps == 0 {
+            e.storage().instance().remove(&DataKey::TreasurySplit);
+        } else {
+            e.storage().instance().set(
+                &DataKey::TreasurySplit,
+                &TreasurySplit {
+                    secondary: secondary.clone(),
+                    secondary_bps,
+                },
+            );
+        }
+
+        e.events().publish(
+            (symbol_short!("TREASURY"), symbol_short!("split")),
+            (secondary, secondary_bps),
+        );
+
+        Ok(())
+    }
+
+    fn treasury_split(e: Env) -> Option<TreasurySplit> {
+        extend_instance_ttl(&e);
+        get_treasury_split(&e)
+    }
+
+    fn set_transfer_tolerance(e: Env, tolerance_bps: u32) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        if tolerance_bps > 10000 {
+            return Err(VaultError::InvalidAmount);
+        }
+        e.storage()
+            .instance()
+            .set(&DataKey::TransferTolerance, &tolerance_bps);
+
+        e.events().publish(
+            (symbol_short!("TRANSFER"), symbol_short!("tolerance")),
+            tolerance_bps,
+        );
+
+        Ok(())
+    }
+
+    fn transfer_tolerance(e: Env) -> u32 {
+        extend_instance_ttl(&e);
+        get_transfer_tolerance(&e)
+    }
+
+    fn set_certificate(e: Env, certificate: Address, threshold: i128) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        check_nonnegative_amount(threshold)?;
+
+        if threshold == 0 {
+            e.storage().instance().remove(&DataKey::Certificate);
+        } else {
+            e.storage().instance().set(
+                &DataKey::Certificate,
+                &CertificateConfig {
+                    certificate: certificate.clone(),
+                    threshold,
+                },
+            );
+        }
+
+        e.events().publish(
+            (symbol_short!("CERT"), symbol_short!("config")),
+            (certificate, threshold),
+        );
+
+        Ok(())
+    }
+
+    fn certificate(e: Env) -> Option<CertificateConfig> {
+        extend_instance_ttl(&e);
+        e.storage().instance().get(&DataKey::Certificate)
+    }
+
+    fn issue_certificate(e: Env, holder: Address, amount: i128) -> Result<u32, VaultError> {
+        holder.require_auth();
+        extend_instance_ttl(&e);
+
+        let config = get_certificate_config(&e)?;
+        if amount < config.threshold {
+            return Err(VaultError::InvalidAmount);
+        }
+
+        let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
+        let balance = share_token_client.balance(&holder);
+        if amount > balance {
+            return Err(VaultError::InvalidAmount);
+        }
+
+        // The cost basis of the locked shares travels with the certificate
+        let cost_basis = get_cost_basis(&e, holder.clone());
+        let locked_basis = pro_rata(cost_basis, amount, balance)?;
+        put_cost_basis(&e, holder.clone(), cost_basis - locked_basis);
+
+        share_token_client.transfer(&holder, &e.current_contract_address(), &amount);
+        let id: u32 = e.invoke_contract(
+            &config.certificate,
+            &Symbol::new(&e, "mint"),
+            (holder.clone(), share_token_client.symbol(), get_end_time(&e)?, amount).into_val(&e),
+        );
+        e.storage()
+            .persistent()
+            .set(&certificate_basis_key(id), &locked_basis);
+
+        e.events().publish(
+            (symbol_short!("CERT"), symbol_short!("issued")),
+            (holder, id, amount),
+        );
+
+        Ok(id)
+    }
+
+    fn redeem_certificate(e: Env, owner: Address, id: u32) -> Result<i128, VaultError> {
+        owner.require_auth();
+        extend_instance_ttl(&e);
+
+        let config = get_certificate_config(&e)?;
+        let certificate: BondCertificate = e.invoke_contract(
+            &config.certificate,
+            &Symbol::new(&e, "get_certificate"),
+            (id,).into_val(&e),
+        );
+        if certificate.owner != owner {
+            return Err(VaultError::InvalidCall);
+        }
+
+        let basis_key = certificate_basis_key(id);
+        let locked_basis: i128 = e.storage().persistent().get(&basis_key).unwrap_or(0);
+        e.storage().persistent().remove(&basis_key);
+        put_cost_basis(&e, owner.clone(), get_cost_basis(&e, owner.clone()) + locked_basis);
+        register_holder(&e, &owner);
+
+        e.invoke_contract::<BondCertificate>(
+            &config.certificate,
+            &Symbol::new(&e, "burn"),
+            (id,).into_val(&e),
+        );
+        let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
+        share_token_client.transfer(&e.current_contract_address(), &owner, &certificate.locked);
+
+        e.events().publish(
+            (symbol_short!("CERT"), symbol_short!("redeemed")),
+            (owner, id, certificate.locked),
+        );
+
+        Ok(certificate.locked)
+    }
+
+    fn set_admin(e: Env, new_admin: Address) -> Result<Address, VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+        e.events().publish(
+            (symbol_short!("ADMIN"), symbol_short!("changed")),
+            new_admin.clone(),
+        );
+        put_admin(&e, new_admin.clone());
+
+        Ok(new_admin)
+    }
+
+    fn set_guardian(e: Env, guardian: Option<Address>, inactivity_window: u64) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        match guardian.clone() {
+            Some(guardian) => {
+                if inactivity_window == 0 {
+                    return Err(VaultError::InvalidAmount);
+                }
+                e.storage().instance().set(&DataKey::Guardian, &guardian);
+                e.storage()
+                    .instance()
+                    .set(&DataKey::GuardianWindow, &inactivity_window);
+            }
+            None => {
+                e.storage().instance().remove(&DataKey::Guardian);
+                e.storage().instance().remove(&DataKey::GuardianWindow);
+            }
+        }
+
+        e.events().publish(
+            (symbol_short!("GUARDIAN"), symbol_short!("set")),
+            (guardian, inactivity_window),
+        );
+
+        Ok(())
+    }
+
+    fn guardian_info(e: Env) -> Result<GuardianInfo, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(GuardianInfo {
+            guardian: get_guardian(&e).ok_or(VaultError::GuardianNotSet)?,
+            inactivity_window: get_guardian_window(&e),
+            last_admin_action: get_last_admin_action(&e),
+        })
+    }
+
+    fn guardian_stop(e: Env) -> Result<(), VaultError> {
+        let guardian = require_guardian(&e)?;
+        extend_instance_ttl(&e);
+
+        set_stopped(&e, true);
+
+        e.events().publish(
+            (symbol_short!("GUARDIAN"), symbol_short!("stopped")),
+            guardian,
+        );
+
+        Ok(())
+    }
+
+    fn guardian_set_admin(e: Env, new_admin: Address) -> Result<Address, VaultError> {
+        let guardian = require_guardian(&e)?;
+        extend_instance_ttl(&e);
+
+        put_admin(&e, new_admin.clone());
+        put_last_admin_action(&e, time(&e));
+
+        e.events().publish(
+            (symbol_short!("GUARDIAN"), symbol_short!("admin")),
+            (guardian, new_admin.clone()),
+        );
+
+        Ok(new_admin)
+    }
+
+    fn set_rating_agency(e: Env, agency: Option<Address>) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        match agency.clone() {
+            Some(agency) => e.storage().instance().set(&DataKey::RatingAgency, &agency),
+            None => e.storage().instance().remove(&DataKey::RatingAgency),
+        }
+
+        e.events().publish(
+            (symbol_short!("RATING"), symbol_short!("agency")),
+            agency,
+        );
+
+        Ok(())
+    }
+
+    fn rating_agency(e: Env) -> Result<Address, VaultError> {
+        extend_instance_ttl(&e);
+        get_rating_agency(&e).ok_or(VaultError::RatingAgencyNotSet)
+    }
 
-    fn total_deposit(e: Env) -> Result<i128, VaultError>;
+    fn post_rating(e: Env, rating: CreditRating, report_hash: BytesN<32>) -> Result<u32, VaultError> {
+        let agency = get_rating_agency(&e).ok_or(VaultError::RatingAgencyNotSet)?;
+        agency.require_auth();
+        extend_instance_ttl(&e);
 
-    fn available_redemption(e: Env) -> Result<i128, VaultError>;
+        let attestation = RatingAttestation {
+            agency,
+            rating,
+            report_hash,
+            timestamp: time(&e),
+        };
+        push_rating(&e, &attestation);
 
-    fn admin(e: Env) -> Result<Address, VaultError>;
+        e.events().publish(
+            (symbol_short!("RATING"), symbol_short!("posted")),
+            attestation,
+        );
 
-    fn maturity(e: Env) -> Result<u64, VaultError>;
+        Ok(get_rating_count(&e) - 1)
+    }
 
-    fn total_bonds(e: Env) -> Result<i128, VaultError>;
+    fn current_rating(e: Env) -> Result<RatingAttestation, VaultError> {
+        extend_instance_ttl(&e);
+        get_rating_count(&e)
+            .checked_sub(1)
+            .and_then(|index| get_rating(&e, index))
+            .ok_or(VaultError::RatingNotFound)
+    }
 
-    fn treasury_account(e: Env) -> Result<Address, VaultError>;
+    fn rating_history(e: Env, start: u32, limit: u32) -> Result<Vec<RatingAttestation>, VaultError> {
+        extend_instance_ttl(&e);
 
-    fn quote(e: Env) -> Result<i128, VaultError>;
+        let end = core::cmp::min(
+            get_rating_count(&e),
+            start.saturating_add(core::cmp::min(limit, MAX_PAGE_SIZE)),
+        );
 
-    fn set_quote(e: Env, amount: i128) -> Result<i128, VaultError>;
+        let mut ratings = Vec::new(&e);
+        for index in start..end {
+            if let Some(attestation) = get_rating(&e, index) {
+                ratings.push_back(attestation);
+            }
+        }
 
-    fn set_total_redemption(e: Env, amount: i128) -> Result<i128, VaultError>;
+        Ok(ratings)
+    }
 
-    fn set_treasury(e: Env, treasury: Address) -> Result<Address, VaultError>;
+    fn rating_count(e: Env) -> Result<u32, VaultError> {
+        extend_instance_ttl(&e);
+        Ok(get_rating_count(&e))
+    }
 
-    fn set_admin(e: Env, new_admin: Address) -> Result<Address, VaultError>;
+    fn set_migration_registry(e: Env, registry: Address) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
 
-    fn set_contract_stopped(e: Env, stopped: bool) -> Result<(), VaultError>;
-}
+        e.storage().instance().set(&DataKey::MigrationRegistry, &registry);
 
-#[contract]
-struct Vault;
+        e.events()
+            .publish((symbol_short!("MIGRATE"), symbol_short!("registry")), registry);
 
-#[contractimpl]
-impl VaultTrait for Vault {
-    fn initialize(
-        e: Env,
-        token_wasm_hash: BytesN<32>,
-        token: Address,
-        admin: Address,
-        start_time: u64,
-        end_time: u64,
-        quote_period: u64,
-        treasury: Address,
-        min_deposit: u128,
-        bond_symbol: String,
-    ) -> Result<String, VaultError> {
-        // Check if the contract is already initialized
-        if is_initialized(&e)? {
-            return Err(VaultError::AlreadyInitialized);
-        }
+        Ok(())
+    }
 
-        let share_contract_id = create_contract(&e, token_wasm_hash, &token);
-        token::Client::new(&e, &share_contract_id).initialize(
-            &e.current_contract_address(),
-            &7u32,
-            &"bondHive".into_val(&e),
-            &bond_symbol.into_val(&e),
-        );
+    fn schedule_migration(e: Env, successor: Address) -> Result<PendingMigration, VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
 
-        put_token(&e, token);
-        put_token_share(&e, share_contract_id);
-        put_admin(&e, admin);
-        put_start_time(&e, start_time);
-        put_end_time(&e, end_time);
-        put_total_shares(&e, 0);
-        put_total_deposit(&e, 0);
-        put_available_redemption(&e, 0);
-        put_current_quote(&e, 0);
-        put_quote_period(&e, quote_period);
-        put_treasury(&e, treasury);
-        put_min_deposit(&e, min_deposit);
+        require_approved_successor(&e, &successor)?;
 
-        set_initialized(&e);
-        set_stopped(&e, false);
+        let pending = PendingMigration {
+            successor,
+            eta: time(&e) + MIGRATION_DELAY,
+        };
+        e.storage().instance().set(&DataKey::PendingMigration, &pending);
 
         e.events().publish(
-            (symbol_short!("VAULT"), symbol_short!("init")),
-            (e.current_contract_address(), start_time, end_time),
+            (symbol_short!("MIGRATE"), symbol_short!("scheduled")),
+            (pending.successor.clone(), pending.eta),
         );
 
-        Ok(String::from_str(&e, "Ok"))
+        Ok(pending)
     }
 
-    fn set_contract_stopped(e: Env, stopped: bool) -> Result<(), VaultError> {
-        let admin = get_admin(&e)?;
-        admin.require_auth();
+    fn cancel_migration(e: Env) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
 
-        set_stopped(&e, stopped);
+        let pending = get_pending_migration(&e)?;
+        e.storage().instance().remove(&DataKey::PendingMigration);
 
         e.events().publish(
-            (symbol_short!("VAULT"), symbol_short!("stopped")),
-            stopped,
+            (symbol_short!("MIGRATE"), symbol_short!("cancelled")),
+            pending.successor,
         );
 
         Ok(())
     }
 
-    fn quote(e: Env) -> Result<i128, VaultError> {
+    fn pending_migration(e: Env) -> Result<PendingMigration, VaultError> {
         extend_instance_ttl(&e);
-        get_current_quote(&e).or_else(|_| Ok(0))
+        get_pending_migration(&e)
     }
 
-    fn set_quote(e: Env, amount: i128) -> Result<i128, VaultError> {
-        let admin = get_admin(&e)?;
-        admin.require_auth();
-    
-        // Attempt to get the current quote, handle specific errors
-        match get_current_quote(&e) {
-            Ok(_) => {
-                // If the current quote is valid, return an error
-                return Err(VaultError::QuoteStillValid);
-            },
-            Err(VaultError::NotInitialized) | Err(VaultError::QuoteRequired) | Err(VaultError::QuoteExpired) => {
-                // Proceed with setting the new quote if the current one is not initialized or required
-                check_nonnegative_amount(amount)?;
-                extend_instance_ttl(&e);
-                put_current_quote(&e, amount);
-                put_quote_expiration(&e)?;
+    fn emergency_migrate(e: Env, successor: Address) -> Result<MigrationSnapshot, VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
 
-                e.events()
-                    .publish((symbol_short!("QUOTE"), symbol_short!("set")), amount);
+        let pending = get_pending_migration(&e)?;
+        if pending.successor != successor {
+            return Err(VaultError::MigrationNotScheduled);
+        }
+        if time(&e) < pending.eta {
+            return Err(VaultError::MigrationTimelocked);
+        }
+        require_approved_successor(&e, &successor)?;
 
-                Ok(amount)
-            },
-            Err(e) => Err(e),
+        set_stopped(&e, true);
+        e.events().publish(
+            (symbol_short!("VAULT"), symbol_short!("stopped")),
+            true,
+        );
+
+        let token = get_token(&e)?;
+        let token_client = token::Client::new(&e, &token);
+        let balance = token_client.balance(&e.current_contract_address());
+        // A series settled in another asset holds its redemption in that asset
+        let redemption_token = get_redemption_token(&e)?;
+        let redemption_client = token::Client::new(&e, &redemption_token);
+        let redemption_balance = if redemption_token != token {
+            redemption_client.balance(&e.current_contract_address())
+        } else {
+            0
+        };
+        let snapshot = MigrationSnapshot {
+            successor: successor.clone(),
+            balance,
+            total_shares: get_total_shares(&e)?,
+            total_deposit: get_total_deposit(&e)?,
+            available_redemption: get_available_redemption(&e)?,
+            donations: get_donations(&e) + get_settlement_carry(&e),
+            buyback_reserve: get_buyback_reserve(&e),
+            holder_count: get_holder_count(&e),
+            timestamp: time(&e),
+            redemption_balance,
+            pushed_total: get_pushed_total(&e),
+            subscription_escrow: get_subscription_escrow(&e),
+            auction_escrow: get_auction_escrow(&e),
+        };
+
+        if balance > 0 {
+            token_client.transfer(&e.current_contract_address(), &successor, &balance);
         }
+        if redemption_balance > 0 {
+            redemption_client.transfer(&e.current_contract_address(), &successor, &redemption_balance);
+        }
+        e.events().publish(
+            (symbol_short!("MIGRATE"), symbol_short!("assets")),
+            (successor.clone(), balance, redemption_balance),
+        );
+
+        // The successor now holds the funds backing these obligations, pushed redemptions, the
+        // installments of subscriptions and the bids of the auction included
+        put_available_redemption(&e, 0);
+        put_donations(&e, 0);
+        put_settlement_carry(&e, 0);
+        put_buyback_reserve(&e, 0);
+        put_covenant_balance(&e, 0);
+        put_subscription_escrow(&e, 0);
+        e.storage().instance().set(&DataKey::PushedTotal, &0_i128);
+        e.storage().instance().remove(&DataKey::PendingMigration);
+        e.storage().instance().set(&DataKey::MigrationSnapshot, &snapshot);
+
+        e.invoke_contract::<()>(
+            &successor,
+            &Symbol::new(&e, "accept_migration"),
+            (snapshot.clone(),).into_val(&e),
+        );
+        e.events().publish(
+            (symbol_short!("MIGRATE"), symbol_short!("snapshot")),
+            snapshot.clone(),
+        );
+
+        Ok(snapshot)
     }
 
-    fn bond_id(e: Env) -> Result<Address, VaultError> {
+    fn migration_snapshot(e: Env) -> Result<MigrationSnapshot, VaultError> {
         extend_instance_ttl(&e);
-        get_token_share(&e)
+        e.storage()
+            .instance()
+            .get(&DataKey::MigrationSnapshot)
+            .ok_or(VaultError::MigrationNotScheduled)
     }
 
-    fn deposit(
-        e: Env,
-        from: Address,
-        amount: i128,
-        expected_quote: i128,
-    ) -> Result<i128, VaultError> {
-        from.require_auth();
-
-        if get_stopped(&e) {
-            return Err(VaultError::ContractStopped);
-        }
-
-        check_nonnegative_amount(amount)?;
+    fn link_previous_series(e: Env, prev_vault: Address) -> Result<PreviousSeries, VaultError> {
+        require_admin(&e)?;
+        require_quoted_mode(&e)?;
         extend_instance_ttl(&e);
 
-        if time(&e) > get_end_time(&e)? {
-            return Err(VaultError::MaturityReached);
+        let maturity: u64 = e.invoke_contract(&prev_vault, &symbol_short!("maturity"), ().into_val(&e));
+        if maturity > time(&e) {
+            return Err(VaultError::MaturityNotReached);
         }
+        check_before_maturity(&e)?;
 
-        if time(&e) < get_start_time(&e)? {
-            return Err(VaultError::NotOpenYet);
+        // A settled series may have nothing left available, once every holder has withdrawn or
+        // when it defaulted, so settlement is read from its state
+        let state: VaultState = e.invoke_contract(&prev_vault, &symbol_short!("state"), ().into_val(&e));
+        if state != VaultState::Settled {
+            return Err(VaultError::AvailableRedemptionNotSet);
         }
 
-        if amount < get_min_deposit(&e)? as i128 {
-            return Err(VaultError::InvalidAmount);
+        // Redemptions paid so far plus the funds still available make up the total redemption
+        let available_redemption: i128 = e.invoke_contract(
+            &prev_vault,
+            &Symbol::new(&e, "available_redemption"),
+            ().into_val(&e),
+        );
+        let stats: IssuanceStats = e.invoke_contract(&prev_vault, &symbol_short!("stats"), ().into_val(&e));
+        if stats.total_deposited_lifetime == 0 {
+            return Err(VaultError::InvalidCall);
         }
+        let total_redemption = available_redemption + stats.total_redeemed_lifetime;
 
-        let current_quote = get_current_quote(&e)?;
+        let previous = PreviousSeries {
+            vault: prev_vault,
+            maturity,
+            realized_rate: mul_div(total_redemption, 10i128.pow(DECIMALS), stats.total_deposited_lifetime)?,
+        };
+        e.storage().instance().set(&DataKey::PreviousSeries, &previous);
 
-        if current_quote != expected_quote {
-            return Err(VaultError::QuoteChanged);
+        e.events().publish(
+            (symbol_short!("SERIES"), symbol_short!("linked")),
+            (previous.vault.clone(), previous.realized_rate),
+        );
+
+        Ok(previous)
+    }
+
+    fn previous_series(e: Env) -> Result<PreviousSeries, VaultError> {
+        extend_instance_ttl(&e);
+        get_previous_series(&e)
+    }
+
+    fn suggested_quote(e: Env) -> Result<i128, VaultError> {
+        extend_instance_ttl(&e);
+
+        let previous = get_previous_series(&e)?;
+        check_before_maturity(&e)?;
+        let end_time = get_end_time(&e)?;
+        let now = time(&e);
+
+        let scale = 10i128.pow(DECIMALS);
+        let term = (end_time - previous.maturity) as i128;
+        let remaining = core::cmp::min(end_time - now, end_time - previous.maturity) as i128;
+        Ok(scale + (previous.realized_rate - scale) * remaining / term)
+    }
+
+    fn set_tvl_registry(e: Env, registry: Address) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        if get_tvl_registry(&e).is_ok_and(|current| current == registry) {
+            return Err(VaultError::InvalidCall);
         }
+        e.storage().instance().set(&DataKey::TvlRegistry, &registry);
+        e.storage().instance().set(&DataKey::ReportedTvl, &0i128);
 
-        let quantity = amount * current_quote / 10i128.pow(DECIMALS);
+        e.invoke_contract::<()>(
+            &registry,
+            &Symbol::new(&e, "register_series"),
+            (e.current_contract_address(),).into_val(&e),
+        );
+        // Deposits taken before registering are reported in one go
+        report_tvl(&e);
 
-        let token_client = token::Client::new(&e, &get_token(&e)?);
-        token_client.transfer(&from, &get_treasury(&e)?, &amount);
+        e.events()
+            .publish((symbol_short!("TVL"), symbol_short!("registry")), registry);
 
-        mint_shares(&e, from, quantity)?;
-        put_total_deposit(&e, get_total_deposit(&e)? + amount);
+        Ok(())
+    }
 
-        Ok(quantity)
+    fn tvl_registry(e: Env) -> Result<Address, VaultError> {
+        extend_instance_ttl(&e);
+        get_tvl_registry(&e)
     }
 
-    fn withdraw(e: Env, to: Address, amount: i128) -> Result<i128, VaultError> {
-        to.require_auth();
+    fn maturity(e: Env) -> Result<u64, VaultError> {
+        extend_instance_ttl(&e);
+        get_end_time(&e)
+    }
 
-        check_nonnegative_amount(amount)?;
+    fn state(e: Env) -> Result<VaultState, VaultError> {
         extend_instance_ttl(&e);
+        get_state(&e)
+    }
 
-        if time(&e) < get_end_time(&e)? {
-            return Err(VaultError::MaturityNotReached);
-        }
+    fn series_timeline(e: Env) -> Result<SeriesTimeline, VaultError> {
+        extend_instance_ttl(&e);
 
-        let available_redemption = get_available_redemption(&e)?;
-        if available_redemption == 0 {
-            return Err(VaultError::AvailableRedemptionNotSet);
-        }
+        let storage = e.storage().instance();
+        let cancelled_at: Option<u64> = storage.get(&DataKey::CancelledAt);
+        // Scheduled transitions happen once their time has passed, unless cancelled before
+        let now = time(&e);
+        let reached = |at: u64| {
+            if now >= at && cancelled_at.is_none_or(|cancelled_at| cancelled_at >= at) {
+                at
+            } else {
+                0
+            }
+        };
+        let end_time = get_end_time(&e)?;
 
-        // First transfer the vault shares that need to be redeemed
-        let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
-        share_token_client.transfer(&to, &e.current_contract_address(), &amount);
+        Ok(SeriesTimeline {
+            created_at: storage.get(&DataKey::CreatedAt).unwrap_or(0),
+            open_at: reached(get_start_time(&e)?),
+            close_at: reached(end_time),
+            matured_at: reached(end_time.saturating_add(get_maturity_grace(&e))),
+            settled_at: storage.get(&DataKey::SettledAt).unwrap_or(0),
+            cancelled_at: cancelled_at.unwrap_or(0),
+        })
+    }
 
-        // Calculate total amount including yield
-        let asset_amount = available_redemption * amount / get_total_shares(&e)?;
+    fn set_maturity_grace(e: Env, grace: u64) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+        check_before_maturity(&e)?;
 
-        let token_client = token::Client::new(&e, &get_token(&e)?);
-        token_client.transfer(&e.current_contract_address(), &to, &asset_amount);
+        e.storage().instance().set(&DataKey::MaturityGrace, &grace);
 
-        burn_shares(&e, amount)?;
-        put_available_redemption(&e, available_redemption - asset_amount);
+        e.events()
+            .publish((symbol_short!("VAULT"), symbol_short!("grace")), grace);
 
-        Ok(asset_amount)
+        Ok(())
     }
 
-    fn total_deposit(e: Env) -> Result<i128, VaultError> {
+    fn maturity_grace(e: Env) -> u64 {
         extend_instance_ttl(&e);
-        get_total_deposit(&e)
+        get_maturity_grace(&e)
     }
 
-    fn available_redemption(e: Env) -> Result<i128, VaultError> {
+    fn set_soft_cap(e: Env, soft_cap: i128) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        check_nonnegative_amount(soft_cap)?;
         extend_instance_ttl(&e);
-        get_available_redemption(&e)
+
+        if soft_cap == 0 {
+            e.storage().instance().remove(&DataKey::SoftCap);
+        } else {
+            e.storage().instance().set(&DataKey::SoftCap, &soft_cap);
+        }
+
+        e.events()
+            .publish((symbol_short!("OFFERING"), symbol_short!("softcap")), soft_cap);
+
+        Ok(())
     }
 
-    fn set_total_redemption(e: Env, amount: i128) -> Result<i128, VaultError> {
-        check_nonnegative_amount(amount)?;
+    fn soft_cap(e: Env) -> i128 {
         extend_instance_ttl(&e);
+        get_soft_cap(&e)
+    }
 
-        if time(&e) < get_end_time(&e)? {
-            return Err(VaultError::MaturityNotReached);
+    fn cancel_offering(e: Env) -> Result<(), VaultError> {
+        require_admin(&e)?;
+        extend_instance_ttl(&e);
+
+        if get_accounting_mode(&e) != AccountingMode::FixedQuote {
+            return Err(VaultError::UnsupportedInMode);
         }
-        if get_available_redemption(&e)? > 0 {
-            return Err(VaultError::AvailableRedemptionAlreadySet);
+        match get_state(&e)? {
+            VaultState::Pending | VaultState::Open => {}
+            VaultState::Cancelled => return Err(VaultError::InvalidCall),
+            _ => return Err(VaultError::MaturityReached),
+        }
+
+        let soft_cap = get_soft_cap(&e);
+        let total_deposit = get_total_deposit(&e)?;
+        if soft_cap == 0 || total_deposit >= soft_cap {
+            return Err(VaultError::InvalidCall);
+        }
+
+        e.storage().instance().set(&DataKey::CancelledAt, &time(&e));
+
+        e.events().publish(
+            (symbol_short!("OFFERING"), symbol_short!("cancelled")),
+            (total_deposit, soft_cap),
+        );
+
+        Ok(())
+    }
+
+    fn fund_refunds(e: Env, from: Address, amount: i128) -> Result<(), VaultError> {
+        from.require_auth();
+        extend_instance_ttl(&e);
+
+        if amount <= 0 {
+            return Err(VaultError::InvalidAmount);
+        }
+        if get_state(&e)? != VaultState::Cancelled {
+            return Err(VaultError::InvalidCall);
+        }
+        pull_redemption_funds(&e, &get_token(&e)?, &from, amount)?;
+
+        e.events()
+            .publish((symbol_short!("OFFERING"), symbol_short!("funded")), (from, amount));
+
+        Ok(())
+    }
+
+    fn refund(e: Env, holder: Address) -> Result<i128, VaultError> {
+        holder.require_auth();
+        extend_instance_ttl(&e);
+
+        if get_state(&e)? != VaultState::Cancelled {
+            return Err(VaultError::InvalidCall);
+        }
+
+        let share_token_client = token::Client::new(&e, &get_token_share(&e)?);
+        let shares = share_token_client.balance(&holder);
+        if shares <= 0 {
+            return Err(VaultError::InvalidAmount);
+        }
+        // Shares are refunded pro rata, so holders who received them by transfer get the
+        // deposits they stand for and a seller cannot claim them back
+        let total_deposit = get_total_deposit(&e)?;
+        let amount = mul_div(total_deposit, shares, get_total_shares(&e)?)?;
+        if amount <= 0 {
+            return Err(VaultError::InvalidAmount);
         }
-        let admin = get_admin(&e)?;
-        admin.require_auth();
 
+        // Funds escrowed for others, such as the installments of subscriptions, stay set aside
         let token_client = token::Client::new(&e, &get_token(&e)?);
-        token_client.transfer(&admin, &e.current_contract_address(), &amount);
+        let available = token_client.balance(&e.current_contract_address()) - get_escrowed_funds(&e);
+        if available < amount {
+            return Err(VaultError::InsufficientReserve);
+        }
+
+        enter_guard(&e)?;
+        put_cost_basis(&e, holder.clone(), 0);
+        put_total_deposit(&e, total_deposit - amount);
+        // Refunded deposits no longer count toward the covenant, nor is the vault holding them
+        let covenant = get_covenant(&e);
+        if covenant.deposits > 0 {
+            e.storage()
+                .instance()
+                .set(&DataKey::CovenantDeposits, &core::cmp::max(covenant.deposits - amount, 0));
+            put_covenant_balance(&e, core::cmp::max(covenant.balance - amount, 0));
+        }
+        share_token_client.transfer(&holder, &e.current_contract_address(), &shares);
+        burn_shares(&e, shares)?;
+        publish_tx(&e, symbol_short!("refund"), &holder, amount, shares, 0)?;
+
+        token_client.transfer(&e.current_contract_address(), &holder, &amount);
+        report_tvl(&e);
+        exit_guard(&e);
 
-        put_available_redemption(&e, amount);
         Ok(amount)
     }
 
-    fn admin(e: Env) -> Result<Address, VaultError> {
+    fn subscribe(e: Env, holder: Address, target: i128, expected_quote: i128) -> Result<Subscription, VaultError> {
+        holder.require_auth();
         extend_instance_ttl(&e);
-        get_admin(&e)
+
+        if get_stopped(&e) {
+            return Err(VaultError::ContractStopped);
+        }
+        let config = get_series_config(&e)?;
+        if config.mode != AccountingMode::FixedQuote || !get_price_tiers(&e).is_empty() {
+            return Err(VaultError::UnsupportedInMode);
+        }
+        match series_state(&e, &config)? {
+            VaultState::Open => {}
+            VaultState::Pending => return Err(VaultError::NotOpenYet),
+            VaultState::Cancelled => return Err(VaultError::InvalidCall),
+            _ => return Err(VaultError::MaturityReached),
+        }
+        if target < config.min_deposit as i128
+            || get_max_deposit(&e).is_some_and(|max_deposit| target as u128 > max_deposit)
+        {
+            return Err(VaultError::InvalidAmount);
+        }
+        if e.storage().persistent().has(&subscription_key(&holder)) {
+            return Err(VaultError::InvalidCall);
+        }
+
+        let quote = effective_quote(&e, &config)?;
+        if quote != expected_quote {
+            return Err(VaultError::QuoteChanged);
+        }
+
+        let subscription = Subscription { target, paid: 0, quote };
+        e.storage().persistent().set(&subscription_key(&holder), &subscription);
+
+        e.events().publish(
+            (symbol_short!("SUBSCRIBE"), symbol_short!("committed")),
+            (holder, target, quote),
+        );
+
+        Ok(subscription)
     }
 
-    fn set_treasury(e: Env, treasury: Address) -> Result<Address, VaultError> {
-        let admin = get_admin(&e)?;
-        admin.require_auth();
+    fn pay_installment(e: Env, holder: Address, amount: i128) -> Result<Subscription, VaultError> {
+        holder.require_auth();
         extend_instance_ttl(&e);
+
+        if get_stopped(&e) {
+            return Err(VaultError::ContractStopped);
+        }
+        if get_state(&e)? != VaultState::Open {
+            return Err(VaultError::InvalidCall);
+        }
+        let mut subscription = get_subscription(&e, &holder)?;
+        if amount <= 0 || subscription.paid + amount > subscription.target {
+            return Err(VaultError::InvalidAmount);
+        }
+
+        enter_guard(&e)?;
+        subscription.paid += amount;
+        put_subscription_escrow(&e, get_subscription_escrow(&e) + amount);
+        e.storage().persistent().set(&subscription_key(&holder), &subscription);
+
+        token::Client::new(&e, &get_token(&e)?).transfer(&holder, &e.current_contract_address(), &amount);
+
         e.events().publish(
-            (symbol_short!("TREASURY"), symbol_short!("set")),
-            treasury.clone(),
+            (symbol_short!("SUBSCRIBE"), symbol_short!("paid")),
+            (holder.clone(), amount, subscription.paid),
         );
-        put_treasury(&e, treasury.clone());
 
-        Ok(treasury)
+        if subscription.paid == subscription.target {
+            issue_subscription(&e, &holder, &subscription)?;
+        }
+        exit_guard(&e);
+
+        Ok(subscription)
     }
 
-    fn set_admin(e: Env, new_admin: Address) -> Result<Address, VaultError> {
-        let admin = get_admin(&e)?;
-        admin.require_auth();
+    fn refund_subscription(e: Env, holder: Address) -> Result<i128, VaultError> {
+        holder.require_auth();
         extend_instance_ttl(&e);
+
+        if matches!(get_state(&e)?, VaultState::Pending | VaultState::Open) {
+            return Err(VaultError::InvalidCall);
+        }
+        // The installments went to the successor, which refunds them from then on
+        if is_migrated(&e) {
+            return Err(VaultError::ContractStopped);
+        }
+        let subscription = get_subscription(&e, &holder)?;
+
+        enter_guard(&e)?;
+        e.storage().persistent().remove(&subscription_key(&holder));
+        put_subscription_escrow(&e, get_subscription_escrow(&e) - subscription.paid);
+        if subscription.paid > 0 {
+            token::Client::new(&e, &get_token(&e)?).transfer(
+                &e.current_contract_address(),
+                &holder,
+                &subscription.paid,
+            );
+        }
+        exit_guard(&e);
+
         e.events().publish(
-            (symbol_short!("ADMIN"), symbol_short!("changed")),
-            new_admin.clone(),
+            (symbol_short!("SUBSCRIBE"), symbol_short!("refunded")),
+            (holder, subscription.paid),
         );
-        put_admin(&e, new_admin.clone());
 
-        Ok(new_admin)
+        Ok(subscription.paid)
     }
 
-    fn maturity(e: Env) -> Result<u64, VaultError> {
+    fn subscription(e: Env, holder: Address) -> Option<Subscription> {
         extend_instance_ttl(&e);
-        get_end_time(&e)
+        e.storage().persistent().get(&subscription_key(&holder))
     }
 
     fn total_bonds(e: Env) -> Result<i128, VaultError> {
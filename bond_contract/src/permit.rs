@@ -0,0 +1,66 @@
+use soroban_sdk::{xdr::ToXdr, Address, Bytes, BytesN, Env};
+
+// Length of a Stellar account strkey ("G..."), the base32 encoding of a version byte, the
+// ed25519 public key and a two byte checksum
+const ACCOUNT_STRKEY_LEN: usize = 56;
+const ACCOUNT_VERSION_BYTE: u8 = 6 << 3;
+
+// Returns the ed25519 public key controlling a Stellar account address, or None for contract
+// addresses, which cannot sign permits
+pub(crate) fn account_public_key(e: &Env, address: &Address) -> Option<BytesN<32>> {
+    let strkey = address.to_string();
+    if strkey.len() as usize != ACCOUNT_STRKEY_LEN {
+        return None;
+    }
+    let mut chars = [0u8; ACCOUNT_STRKEY_LEN];
+    strkey.copy_into_slice(&mut chars);
+
+    // Base32 (RFC 4648) decodes 8 characters into 5 bytes
+    let mut decoded = [0u8; ACCOUNT_STRKEY_LEN * 5 / 8];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    let mut pos = 0;
+    for c in chars {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded[pos] = (buffer >> bits) as u8;
+            pos += 1;
+        }
+    }
+
+    if decoded[0] != ACCOUNT_VERSION_BYTE {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&decoded[1..33]);
+    Some(BytesN::from_array(e, &key))
+}
+
+// Prefix of every permit message, so that a signature over it cannot be mistaken for one over
+// a transaction or any other message signed with the account key
+const PERMIT_DOMAIN: &[u8] = b"bondhive:vault:deposit_permit:v1";
+
+// Message `from` signs to authorize a deposit of `amount` at `quote` through `vault`. The domain
+// prefix and network id scope it to vault permits on this network, and binding the quote makes a
+// permit signed before a quote change fail to verify
+pub(crate) fn payload(
+    e: &Env,
+    vault: &Address,
+    from: &Address,
+    amount: i128,
+    quote: i128,
+    deadline: u64,
+    nonce: u64,
+) -> Bytes {
+    let mut payload = Bytes::from_slice(e, PERMIT_DOMAIN);
+    payload.append(&e.ledger().network_id().into());
+    payload.append(&(vault.clone(), from.clone(), amount, quote, deadline, nonce).to_xdr(e));
+    payload
+}
@@ -3,14 +3,22 @@ extern crate std;
 
 use super::*;
 use soroban_sdk::{
-    testutils::{Address as _, Ledger, MockAuth, MockAuthInvoke},
-    Address, BytesN, Env, String, IntoVal
+    testutils::{Address as _, AuthorizedFunction, AuthorizedInvocation, Events, Ledger, MockAuth, MockAuthInvoke},
+    Address, Bytes, BytesN, Env, String, IntoVal, Symbol, Val
 };
 
 fn create_token_contract<'a>(e: &Env, admin: &Address) -> token::Client<'a> {
     token::Client::new(e, &e.register_stellar_asset_contract(admin.clone()))
 }
 
+fn share_metadata(e: &Env) -> ShareMetadata {
+    ShareMetadata {
+        name: String::from_str(e, "Bond Hive"),
+        symbol: String::from_str(e, "BOND"),
+        decimals: 7,
+    }
+}
+
 fn install_token_wasm(e: &Env) -> BytesN<32> {
     // Ensure the path is correct relative to the current file
     soroban_sdk::contractimport!(file = "../soroban_token_contract.wasm");
@@ -41,7 +49,8 @@ fn test_not_double_initialization() {
         &300,
         &admin,
         &100,
-        &String::from_str(&e, "BOND"),
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
     );
 
     let expected = String::from_str(&e, "Ok");
@@ -61,7 +70,8 @@ fn test_not_double_initialization() {
         &300,
         &admin,
         &100,
-        &String::from_str(&e, "BOND"),
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
     );
 }
 
@@ -89,7 +99,8 @@ fn try_deposit_without_quote() {
         &300,
         &admin,
         &100,
-        &String::from_str(&e, "BOND"),
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
     );
 
     let expected = String::from_str(&e, "Ok");
@@ -131,18 +142,19 @@ fn test_withdraw_before_maturity() {
         &300,
         &admin,
         &100,
-        &String::from_str(&e, "BOND"),
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
     );
 
     // Mint tokens to the user
     token_client.mint(&user, &1000);
 
     // Set the quote and deposit
-    vault.set_quote(&10000000);
+    vault.set_quote(&10000000, &None);
     vault.deposit(&user, &200, &10000000);
 
     // Attempt to withdraw before maturity, which should fail
-    vault.withdraw(&user, &200);
+    vault.withdraw(&user, &user, &200);
 }
 
 #[test]
@@ -168,16 +180,17 @@ fn test_contract_pause_deposit() {
         &300,
         &admin,
         &100,
-        &String::from_str(&e, "BOND"),
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
     );
 
     // Mint tokens to the user
     token_client.mint(&user, &1000);
 
     // Set the quote and deposit
-    vault.set_quote(&10000000);
+    vault.set_quote(&10000000, &None);
     vault.deposit(&user, &200, &10000000);
-    vault.set_contract_stopped(&true);
+    vault.set_contract_stopped(&true, &None);
     vault.deposit(&user, &200, &10000000);
 }
 
@@ -204,15 +217,16 @@ fn test_override_quote() {
         &300,
         &admin,
         &100,
-        &String::from_str(&e, "BOND"),
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
     );
 
     // Mint tokens to the user
     token_client.mint(&user, &1000);
 
     // Set the quote and deposit
-    vault.set_quote(&10000000);
-    vault.set_quote(&20000000);
+    vault.set_quote(&10000000, &None);
+    vault.set_quote(&20000000, &None);
 }
 
 #[test]
@@ -238,7 +252,8 @@ fn test_full() {
         &300,
         &admin,
         &100,
-        &String::from_str(&e, "BOND"),
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
     );
 
     let expected = String::from_str(&e, "Ok");
@@ -250,7 +265,7 @@ fn test_full() {
     token_client.mint(&user, &1000);
 
     // Set the quote
-    let set_quote_result = vault.set_quote(&10000000);
+    let set_quote_result = vault.set_quote(&10000000, &None);
     assert_eq!(set_quote_result, 10000000);
 
     let deposit_amount = 200;
@@ -278,14 +293,14 @@ fn test_full() {
     token_client.mint(&admin, &1000);
 
     // Set total redemption value
-    let set_redemption_result = vault.set_total_redemption(&300);
+    let set_redemption_result = vault.set_total_redemption(&300, &None);
     assert_eq!(set_redemption_result, 300);
 
     let share_balance = share_client.balance(&user);
     assert_eq!(share_balance, 200);
     
     // Withdraw funds by burning shares and getting back principal + yield
-    let withdraw_result = vault.withdraw(&user, &share_balance);
+    let withdraw_result = vault.withdraw(&user, &user, &share_balance);
     assert_eq!(withdraw_result, 300);
 }
 
@@ -309,7 +324,8 @@ fn try_mint_bonds_directly() {
         &300,
         &admin,
         &100,
-        &String::from_str(&e, "BOND"),
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
     );
 
     let expected = String::from_str(&e, "Ok");
@@ -355,7 +371,8 @@ fn try_reinitialize_after_maturity() {
         &300,
         &admin,
         &100,
-        &String::from_str(&e, "BOND"),
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
     );
 
     // Simulate the time passing beyond maturity
@@ -371,7 +388,8 @@ fn try_reinitialize_after_maturity() {
         &300,
         &admin,
         &100,
-        &String::from_str(&e, "BOND"),
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
     );
 }
 
@@ -398,14 +416,15 @@ fn send_bonds_to_another_wallet_and_withdraw() {
         &300,
         &admin,
         &100,
-        &String::from_str(&e, "BOND"),
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
     );
 
     // Mint tokens to the first user
     token_client.mint(&user1, &1000);
 
     // Set the quote and deposit from user1
-    vault.set_quote(&10000000);
+    vault.set_quote(&10000000, &None);
     vault.deposit(&user1, &200, &10000000);
 
     // Transfer bonds from user1 to user2
@@ -420,12 +439,2854 @@ fn send_bonds_to_another_wallet_and_withdraw() {
     token_client.mint(&admin, &1000);
 
     // Set total redemption value
-    vault.set_total_redemption(&300);
+    vault.set_total_redemption(&300, &None);
 
     // Withdraw funds by user2, who now holds the bonds
     let share_balance = bond_client.balance(&user2);
     assert_eq!(share_balance, 200);
 
-    let withdraw_result = vault.withdraw(&user2, &share_balance);
+    let withdraw_result = vault.withdraw(&user2, &user2, &share_balance);
     assert_eq!(withdraw_result, 300);
 }
+
+fn setup_vault<'a>(e: &Env, admin: &Address) -> (VaultClient<'a>, token::Client<'a>) {
+    let token = create_token_contract(e, admin);
+    let vault = VaultClient::new(e, &e.register_contract(None, crate::Vault {}));
+
+    vault.initialize(
+        &install_token_wasm(e),
+        &token.address,
+        admin,
+        &(e.ledger().timestamp()),
+        &(e.ledger().timestamp() + 600),
+        &300,
+        admin,
+        &100,
+        &share_metadata(e),
+        &AccountingMode::FixedQuote,
+    );
+
+    (vault, token)
+}
+
+#[test]
+fn test_entitlements_and_sweep_unclaimed() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let treasury = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    vault.set_treasury(&treasury);
+    vault.set_claim_period(&1000);
+
+    token_client.mint(&user1, &1000);
+    token_client.mint(&user2, &1000);
+
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user1, &200, &10000000);
+    vault.deposit(&user2, &600, &10000000);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    token_client.mint(&admin, &1000);
+    vault.set_total_redemption(&1000, &None);
+
+    assert_eq!(vault.claim_deadline(), e.ledger().timestamp() + 1000);
+    assert_eq!(vault.get_entitlement(&user1), 250);
+    assert_eq!(vault.get_entitlement(&user2), 750);
+
+    assert_eq!(vault.withdraw(&user1, &user1, &200), 250);
+    assert_eq!(vault.get_entitlement(&user1), 0);
+
+    // Sweeping is not possible before the deadline
+    assert_eq!(
+        vault.try_sweep_unclaimed(),
+        Err(Ok(VaultError::ClaimDeadlineNotReached))
+    );
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 1001);
+
+    assert_eq!(
+        vault.try_withdraw(&user2, &user2, &600),
+        Err(Ok(VaultError::ClaimDeadlinePassed))
+    );
+
+    assert_eq!(vault.sweep_unclaimed(), 750);
+    assert_eq!(token_client.balance(&treasury), 800 + 750);
+    assert_eq!(vault.available_redemption(), 0);
+}
+
+#[test]
+fn test_top_up_redemption() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let treasury = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    vault.set_treasury(&treasury);
+    token_client.mint(&user1, &1000);
+    token_client.mint(&user2, &1000);
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user1, &200, &10000000);
+    vault.deposit(&user2, &600, &10000000);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    assert_eq!(vault.try_top_up_redemption(&100), Err(Ok(VaultError::AvailableRedemptionNotSet)));
+    token_client.mint(&admin, &2000);
+    vault.set_total_redemption(&800, &None);
+
+    // Retrying with the funded amount pulls nothing, another amount needs a top-up
+    assert_eq!(vault.set_total_redemption(&800, &None), 800);
+    assert_eq!(token_client.balance(&admin), 1200);
+    assert_eq!(
+        vault.try_set_total_redemption(&900, &None),
+        Err(Ok(VaultError::AvailableRedemptionAlreadySet))
+    );
+
+    assert_eq!(vault.withdraw(&user1, &user1, &200), 200);
+
+    // Funds held in escrow do not cover redemptions
+    e.as_contract(&vault.address, || {
+        e.storage().instance().set(&DataKey::BuybackReserve, &300_i128)
+    });
+    assert_eq!(vault.try_top_up_redemption(&300), Err(Ok(VaultError::InsufficientReserve)));
+    e.as_contract(&vault.address, || {
+        e.storage().instance().set(&DataKey::BuybackReserve, &0_i128)
+    });
+    assert_eq!(vault.top_up_redemption(&300), 900);
+    assert_eq!(vault.redemption_funded(), 1100);
+    assert_eq!(vault.get_entitlement(&user2), 900);
+    assert_eq!(vault.withdraw(&user2, &user2, &600), 900);
+    assert_eq!(token_client.balance(&vault.address), 0);
+}
+
+#[test]
+fn test_withdraw_to_receiver() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let owner = Address::generate(&e);
+    let cold_wallet = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&owner, &1000);
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&owner, &400, &10000000);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    token_client.mint(&admin, &500);
+    vault.set_total_redemption(&500, &None);
+
+    // The owner's shares are burnt and the redemption paid to the receiver
+    assert_eq!(vault.withdraw(&owner, &cold_wallet, &400), 500);
+    assert_eq!(e.auths()[0].0, owner);
+    assert_eq!(token_client.balance(&cold_wallet), 500);
+    assert_eq!(token_client.balance(&owner), 600);
+    assert_eq!(vault.get_entitlement(&owner), 0);
+}
+
+#[test]
+fn test_push_redemptions() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let treasury = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+    let user3 = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    vault.set_treasury(&treasury);
+    vault.set_claim_period(&1000);
+
+    token_client.mint(&user1, &1000);
+    token_client.mint(&user2, &1000);
+    token_client.mint(&user3, &1000);
+
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user1, &200, &10000000);
+    vault.deposit(&user2, &600, &10000000);
+    vault.deposit(&user3, &200, &10000000);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    token_client.mint(&admin, &1200);
+    vault.set_total_redemption(&1200, &None);
+    vault.withdraw(&user1, &user1, &200);
+
+    // Shares received by transfer carry their entitlement along
+    let share_client = token::Client::new(&e, &vault.bond_id());
+    let buyer = Address::generate(&e);
+    share_client.transfer(&user3, &buyer, &100);
+    assert_eq!(vault.get_entitlement(&buyer), 120);
+
+    let holders = Vec::from_array(
+        &e,
+        [user1.clone(), user2.clone(), buyer.clone(), user3.clone()],
+    );
+    assert_eq!(
+        vault.try_push_redemptions(&holders),
+        Err(Ok(VaultError::ClaimDeadlineNotReached))
+    );
+
+    // Only the entitlement left by holders who never withdrew and let the vault take their
+    // shares is pushed
+    e.budget().reset_unlimited();
+    let expiration = e.ledger().sequence() + 1000;
+    share_client.approve(&user2, &vault.address, &600, &expiration);
+    share_client.approve(&buyer, &vault.address, &100, &expiration);
+    e.ledger().set_timestamp(e.ledger().timestamp() + 1001);
+    assert_eq!(vault.push_redemptions(&holders), 840);
+    assert_eq!(vault.pushed_redemption(&user1), 0);
+    assert_eq!(vault.pushed_redemption(&user2), 720);
+    assert_eq!(vault.pushed_redemption(&buyer), 120);
+    assert_eq!(vault.pushed_redemption(&user3), 0);
+    assert_eq!(vault.get_entitlement(&user3), 120);
+    assert_eq!(vault.get_entitlement(&user2), 0);
+    assert_eq!(share_client.balance(&user2), 0);
+    assert_eq!(vault.push_redemptions(&holders), 0);
+    assert_eq!(vault.sync().deficit, 0);
+
+    // Pushed shares are gone, so they cannot be passed on and pushed again, while shares that
+    // were never pushed still can be
+    assert!(share_client.try_transfer(&buyer, &user3, &100).is_err());
+    share_client.transfer(&user3, &buyer, &100);
+    share_client.approve(&buyer, &vault.address, &100, &expiration);
+    assert_eq!(vault.push_redemptions(&Vec::from_array(&e, [buyer.clone()])), 120);
+    assert_eq!(vault.pushed_redemption(&buyer), 240);
+    assert_eq!(vault.get_entitlement(&user3), 0);
+
+    // Sweeping leaves the pushed funds in place
+    assert_eq!(vault.sweep_unclaimed(), 0);
+    assert_eq!(vault.claim_pushed(&user2), 720);
+    let topics: Vec<Val> = (symbol_short!("REDEEM"), symbol_short!("holder")).into_val(&e);
+    let (_, _, data) = e
+        .events()
+        .all()
+        .iter()
+        .filter(|(_, event_topics, _)| *event_topics == topics)
+        .last()
+        .unwrap();
+    assert_eq!(
+        <(Address, Address, i128)>::try_from_val(&e, &data).unwrap(),
+        (user2.clone(), user2.clone(), 720)
+    );
+    assert_eq!(token_client.balance(&user2), 400 + 720);
+    assert_eq!(vault.try_claim_pushed(&user2), Err(Ok(VaultError::NothingPushed)));
+    assert_eq!(vault.claim_pushed(&buyer), 240);
+    assert_eq!(token_client.balance(&vault.address), 0);
+}
+
+#[test]
+fn test_display_info() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().set_timestamp(1727424000 - display::SECONDS_PER_YEAR);
+
+    let admin = Address::generate(&e);
+    let token = create_token_contract(&e, &admin);
+    let vault = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+
+    vault.initialize(
+        &install_token_wasm(&e),
+        &token.address,
+        &admin,
+        &(e.ledger().timestamp()),
+        &1727424000,
+        &300,
+        &admin,
+        &100,
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
+    );
+
+    let info = vault.get_display_info();
+    assert_eq!(info.price, String::from_str(&e, "0.0000000"));
+    assert_eq!(info.apy, String::from_str(&e, "0.00"));
+    assert_eq!(info.maturity, String::from_str(&e, "2024-09-27T08:00:00Z"));
+
+    vault.set_quote(&10800000, &None);
+
+    let info = vault.get_display_info();
+    assert_eq!(info.price, String::from_str(&e, "0.9259259"));
+    assert_eq!(info.apy, String::from_str(&e, "8.00"));
+}
+
+#[test]
+fn test_bond_metadata() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let (vault, token_client) = setup_vault(&e, &admin);
+
+    let metadata = vault.bond_metadata();
+    assert_eq!(metadata.name, String::from_str(&e, "Bond Hive"));
+    assert_eq!(metadata.symbol, String::from_str(&e, "BOND"));
+    assert_eq!(metadata.decimals, 7);
+    assert_eq!(metadata.maturity, e.ledger().timestamp() + 600);
+    assert_eq!(metadata.underlying, token_client.address);
+
+    // Rebrand the series before the first deposit, keeping the decimals the vault prices with
+    assert_eq!(
+        vault.try_set_bond_metadata(&ShareMetadata {
+            name: String::from_str(&e, "Bond Hive BTC Dec 24"),
+            symbol: String::from_str(&e, "BTCDEC24"),
+            decimals: 6,
+        }),
+        Err(Ok(VaultError::InvalidAmount))
+    );
+    let old_bond_id = vault.bond_id();
+    let new_bond_id = vault.set_bond_metadata(&ShareMetadata {
+        name: String::from_str(&e, "Bond Hive BTC Dec 24"),
+        symbol: String::from_str(&e, "BTCDEC24"),
+        decimals: 7,
+    });
+    assert_ne!(old_bond_id, new_bond_id);
+    assert_eq!(vault.bond_id(), new_bond_id);
+
+    let metadata = vault.bond_metadata();
+    assert_eq!(metadata.name, String::from_str(&e, "Bond Hive BTC Dec 24"));
+    assert_eq!(metadata.symbol, String::from_str(&e, "BTCDEC24"));
+    assert_eq!(metadata.decimals, 7);
+
+    // Once bonds are issued the metadata is locked
+    token_client.mint(&user, &1000);
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user, &200, &10000000);
+    assert_eq!(token::Client::new(&e, &new_bond_id).balance(&user), 200);
+
+    assert_eq!(
+        vault.try_set_bond_metadata(&share_metadata(&e)),
+        Err(Ok(VaultError::SharesAlreadyIssued))
+    );
+}
+
+#[test]
+fn test_get_info() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let (vault, token_client) = setup_vault(&e, &admin);
+    let start = e.ledger().timestamp();
+
+    token_client.mint(&user, &1000);
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user, &200, &10000000);
+    token_client.mint(&vault.address, &50);
+
+    assert_eq!(
+        vault.get_info(),
+        VaultInfo {
+            token: token_client.address.clone(),
+            share_token: vault.bond_id(),
+            symbol: String::from_str(&e, "BOND"),
+            admin: admin.clone(),
+            treasury: admin.clone(),
+            start_time: start,
+            end_time: start + 600,
+            quote_period: 300,
+            min_deposit: 100,
+            total_fees: 0,
+            state: VaultState::Open,
+            total_shares: 200,
+            total_reserve: 50,
+        }
+    );
+}
+
+#[test]
+fn test_cap_table_pages() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let (vault, token_client) = setup_vault(&e, &admin);
+    vault.set_quote(&12000000, &None);
+
+    let users = [
+        Address::generate(&e),
+        Address::generate(&e),
+        Address::generate(&e),
+    ];
+    for (i, user) in users.iter().enumerate() {
+        token_client.mint(user, &10000);
+        vault.deposit(user, &(1000 * (i as i128 + 1)), &12000000);
+    }
+    // A second deposit accumulates on the existing entry
+    vault.deposit(&users[0], &500, &12000000);
+
+    assert_eq!(vault.holder_count(), 3);
+
+    let page = vault.get_holders(&0, &2);
+    assert_eq!(page.len(), 2);
+    let first = page.get(0).unwrap();
+    assert_eq!(first.holder, users[0]);
+    assert_eq!(first.shares, 1800);
+    assert_eq!(first.cost_basis, 1500);
+
+    let page = vault.get_holders(&2, &10);
+    assert_eq!(page.len(), 1);
+    let last = page.get(0).unwrap();
+    assert_eq!(last.holder, users[2]);
+    assert_eq!(last.shares, 3600);
+    assert_eq!(last.cost_basis, 3000);
+
+    assert_eq!(vault.get_holders(&3, &10).len(), 0);
+
+    // Shares received by transfer do not register their recipient
+    let share_client = token::Client::new(&e, &vault.bond_id());
+    share_client.transfer(&users[2], &Address::generate(&e), &600);
+    assert_eq!(vault.holder_count(), 3);
+
+    // Redeeming half of the shares releases half of the cost basis
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    token_client.mint(&admin, &10000);
+    vault.set_total_redemption(&7200, &None);
+    vault.withdraw(&users[0], &users[0], &900);
+
+    let first = vault.get_holders(&0, &1).get(0).unwrap();
+    assert_eq!(first.shares, 900);
+    assert_eq!(first.cost_basis, 750);
+}
+
+#[test]
+fn test_retire_shares() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    let bond_client = token::Client::new(&e, &vault.bond_id());
+
+    token_client.mint(&user1, &1000);
+    token_client.mint(&user2, &1000);
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user1, &400, &10000000);
+    vault.deposit(&user2, &400, &10000000);
+
+    // The issuer buys back user1's bonds on the secondary market and retires them
+    bond_client.transfer(&user1, &admin, &400);
+    assert_eq!(vault.retire_shares(&400), 400);
+    assert_eq!(vault.retired_shares(), 400);
+    assert_eq!(vault.total_bonds(), 400);
+    assert_eq!(bond_client.balance(&admin), 0);
+
+    // Redemption only has to cover the remaining supply
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    token_client.mint(&admin, &1000);
+    vault.set_total_redemption(&440, &None);
+    assert_eq!(
+        vault.try_retire_shares(&0),
+        Err(Ok(VaultError::AvailableRedemptionAlreadySet))
+    );
+    assert_eq!(vault.withdraw(&user2, &user2, &400), 440);
+}
+
+#[test]
+fn test_deposit_limits() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user, &10000);
+    vault.set_quote(&10000000, &None);
+
+    assert_eq!(vault.get_deposit_limits(), (100, None));
+
+    assert_eq!(
+        vault.try_set_deposit_limits(&500, &Some(400)),
+        Err(Ok(VaultError::InvalidAmount))
+    );
+
+    vault.set_deposit_limits(&200, &Some(1000));
+    assert_eq!(vault.get_deposit_limits(), (200, Some(1000)));
+
+    assert_eq!(
+        vault.try_deposit(&user, &150, &10000000),
+        Err(Ok(VaultError::InvalidAmount))
+    );
+    assert_eq!(
+        vault.try_deposit(&user, &1001, &10000000),
+        Err(Ok(VaultError::InvalidAmount))
+    );
+    assert_eq!(vault.deposit(&user, &1000, &10000000), 1000);
+
+    // Lifting the cap allows larger tickets
+    vault.set_deposit_limits(&200, &None);
+    assert_eq!(vault.deposit(&user, &5000, &10000000), 5000);
+}
+
+#[test]
+fn test_guardian_recovery() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let guardian = Address::generate(&e);
+    let new_admin = Address::generate(&e);
+
+    let (vault, _) = setup_vault(&e, &admin);
+
+    assert_eq!(
+        vault.try_guardian_stop(),
+        Err(Ok(VaultError::GuardianNotSet))
+    );
+    assert_eq!(
+        vault.try_set_guardian(&Some(guardian.clone()), &0),
+        Err(Ok(VaultError::InvalidAmount))
+    );
+
+    vault.set_guardian(&Some(guardian.clone()), &1000);
+    let info = vault.guardian_info();
+    assert_eq!(info.guardian, guardian);
+    assert_eq!(info.inactivity_window, 1000);
+    assert_eq!(info.last_admin_action, e.ledger().timestamp());
+
+    // Any admin action restarts the inactivity window
+    e.ledger().set_timestamp(e.ledger().timestamp() + 900);
+    vault.set_treasury(&admin);
+    e.ledger().set_timestamp(e.ledger().timestamp() + 900);
+    assert_eq!(
+        vault.try_guardian_set_admin(&new_admin),
+        Err(Ok(VaultError::AdminStillActive))
+    );
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 100);
+    vault.guardian_stop();
+    assert_eq!(vault.guardian_set_admin(&new_admin), new_admin);
+    assert_eq!(vault.admin(), new_admin);
+    assert_eq!(
+        vault.guardian_info().last_admin_action,
+        e.ledger().timestamp()
+    );
+
+    // The new admin gets a fresh window before the guardian can step in again
+    assert_eq!(
+        vault.try_guardian_stop(),
+        Err(Ok(VaultError::AdminStillActive))
+    );
+    vault.set_contract_stopped(&false, &None);
+}
+
+#[test]
+fn test_rating_attestations() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let agency = Address::generate(&e);
+
+    let (vault, _) = setup_vault(&e, &admin);
+
+    let report1 = BytesN::from_array(&e, &[1; 32]);
+    let report2 = BytesN::from_array(&e, &[2; 32]);
+
+    assert_eq!(
+        vault.try_post_rating(&CreditRating::Aa, &report1),
+        Err(Ok(VaultError::RatingAgencyNotSet))
+    );
+    assert_eq!(
+        vault.try_current_rating(),
+        Err(Ok(VaultError::RatingNotFound))
+    );
+
+    vault.set_rating_agency(&Some(agency.clone()));
+    assert_eq!(vault.rating_agency(), agency);
+
+    assert_eq!(vault.post_rating(&CreditRating::Aa, &report1), 0);
+    assert_eq!(e.auths()[0].0, agency);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 100);
+    assert_eq!(vault.post_rating(&CreditRating::Bbb, &report2), 1);
+
+    let current = vault.current_rating();
+    assert_eq!(current.rating, CreditRating::Bbb);
+    assert_eq!(current.report_hash, report2);
+    assert_eq!(current.timestamp, e.ledger().timestamp());
+
+    // Downgrades keep the earlier attestations in the history
+    let history = vault.rating_history(&0, &10);
+    assert_eq!(vault.rating_count(), 2);
+    assert_eq!(history.len(), 2);
+    assert_eq!(history.get(0).unwrap().rating, CreditRating::Aa);
+    assert_eq!(history.get(0).unwrap().report_hash, report1);
+
+    vault.set_rating_agency(&None);
+    assert_eq!(
+        vault.try_post_rating(&CreditRating::A, &report1),
+        Err(Ok(VaultError::RatingAgencyNotSet))
+    );
+}
+
+mod aggregator {
+    use crate::VaultClient;
+    use soroban_sdk::{contract, contractimpl, Address, Env};
+
+    #[contract]
+    pub struct Aggregator;
+
+    #[contractimpl]
+    impl Aggregator {
+        pub fn deposit(e: Env, vault: Address, amount: i128, expected_quote: i128) -> i128 {
+            let vault = VaultClient::new(&e, &vault);
+            let from = e.current_contract_address();
+            e.authorize_as_current_contract(vault.deposit_auth_entries(&from, &amount));
+            vault.deposit(&from, &amount, &expected_quote)
+        }
+    }
+}
+
+#[test]
+fn test_aggregator_deposit() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let (vault, token_client) = setup_vault(&e, &admin);
+    vault.set_quote(&10000000, &None);
+
+    let aggregator = aggregator::AggregatorClient::new(&e, &e.register_contract(None, aggregator::Aggregator));
+    token_client.mint(&aggregator.address, &1000);
+
+    // From here on only the contract's own authorizations count
+    e.set_auths(&[]);
+
+    let entries = vault.deposit_auth_entries(&aggregator.address, &500);
+    assert_eq!(entries.len(), 1);
+
+    assert_eq!(aggregator.deposit(&vault.address, &500, &10000000), 500);
+    assert_eq!(token_client.balance(&aggregator.address), 500);
+    assert_eq!(token_client.balance(&admin), 500);
+}
+
+#[test]
+fn test_treasury_split() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let user = Address::generate(&e);
+    let (vault, token_client) = setup_vault(&e, &admin);
+    vault.set_quote(&10000000, &None);
+    token_client.mint(&user, &1000);
+
+    assert_eq!(
+        vault.try_set_treasury_split(&insurance, &10001),
+        Err(Ok(VaultError::InvalidAmount))
+    );
+    vault.set_treasury_split(&insurance, &1000);
+    assert_eq!(
+        vault.treasury_split(),
+        Some(TreasurySplit {
+            secondary: insurance.clone(),
+            secondary_bps: 1000,
+        })
+    );
+
+    // Both treasuries are paid within the deposit
+    assert_eq!(vault.deposit(&user, &500, &10000000), 500);
+    assert_eq!(token_client.balance(&admin), 450);
+    assert_eq!(token_client.balance(&insurance), 50);
+
+    // Contract callers pre-authorize one transfer per treasury
+    let aggregator = aggregator::AggregatorClient::new(&e, &e.register_contract(None, aggregator::Aggregator));
+    token_client.mint(&aggregator.address, &200);
+    e.set_auths(&[]);
+    assert_eq!(vault.deposit_auth_entries(&aggregator.address, &200).len(), 2);
+    assert_eq!(aggregator.deposit(&vault.address, &200, &10000000), 200);
+    assert_eq!(token_client.balance(&admin), 630);
+    assert_eq!(token_client.balance(&insurance), 70);
+
+    e.mock_all_auths();
+    vault.set_treasury_split(&insurance, &0);
+    assert_eq!(vault.treasury_split(), None);
+    vault.deposit(&user, &500, &10000000);
+    assert_eq!(token_client.balance(&admin), 1130);
+    assert_eq!(token_client.balance(&insurance), 70);
+}
+
+#[test]
+fn test_price_tiers() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user, &10000);
+
+    let tier = |cap: i128, quote: i128| PriceTier { cap, quote };
+
+    assert_eq!(
+        vault.try_set_price_tiers(&Vec::from_array(&e, [tier(1000, 10000000), tier(1000, 9900990)])),
+        Err(Ok(VaultError::InvalidAmount))
+    );
+
+    // First 1000 at par, next 1000 at 1.01
+    vault.set_price_tiers(&Vec::from_array(&e, [tier(1000, 10000000), tier(2000, 9900990)]));
+    assert_eq!(vault.quote(), 10000000);
+
+    assert_eq!(vault.deposit(&user, &800, &10000000), 800);
+
+    // A deposit crossing the threshold is priced per tier
+    assert_eq!(vault.deposit(&user, &400, &10000000), 200 + 198);
+    assert_eq!(vault.quote(), 9900990);
+    assert_eq!(
+        vault.try_deposit(&user, &200, &10000000),
+        Err(Ok(VaultError::QuoteChanged))
+    );
+
+    // The book is full once the last cap is reached
+    assert_eq!(
+        vault.try_deposit(&user, &801, &9900990),
+        Err(Ok(VaultError::TierCapacityExceeded))
+    );
+    assert_eq!(vault.deposit(&user, &800, &9900990), 792);
+    assert_eq!(vault.try_quote(), Ok(Ok(0)));
+
+    // Clearing the tiers falls back to the admin quote
+    vault.set_price_tiers(&Vec::new(&e));
+    vault.set_quote(&10000000, &None);
+    assert_eq!(vault.deposit(&user, &100, &10000000), 100);
+}
+
+#[test]
+fn test_export_state() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let agency = Address::generate(&e);
+    let guardian = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user1, &1000);
+    token_client.mint(&user2, &1000);
+
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user1, &200, &10000000);
+    vault.deposit(&user2, &300, &10000000);
+
+    vault.set_rating_agency(&Some(agency));
+    vault.post_rating(&CreditRating::A, &BytesN::from_array(&e, &[7; 32]));
+    vault.set_guardian(&Some(guardian), &1000);
+    let last_admin_action = vault.guardian_info().last_admin_action;
+    e.ledger().set_timestamp(e.ledger().timestamp() + 100);
+
+    let config = vault.export_state(&ExportSection::Config, &0, &10);
+    assert_eq!(config.len(), 1);
+    match config.get(0).unwrap() {
+        StateEntry::Config(config) => {
+            assert_eq!(config.admin, admin);
+            assert_eq!(config.total_deposit, 500);
+            assert_eq!(config.holder_count, 2);
+            assert_eq!(config.max_deposit, 0);
+        }
+        _ => panic!("expected the config"),
+    }
+    assert_eq!(vault.export_state(&ExportSection::Config, &1, &10).len(), 0);
+
+    let holders = vault.export_state(&ExportSection::Holders, &1, &10);
+    assert_eq!(holders.len(), 1);
+    match holders.get(0).unwrap() {
+        StateEntry::Holder(position, entitlement) => {
+            assert_eq!(position.holder, user2);
+            assert_eq!(position.shares, 300);
+            assert_eq!(entitlement, 0);
+        }
+        _ => panic!("expected a holder"),
+    }
+
+    let ratings = vault.export_state(&ExportSection::Ratings, &0, &10);
+    assert_eq!(ratings.len(), 1);
+    match ratings.get(0).unwrap() {
+        StateEntry::Rating(index, attestation) => {
+            assert_eq!(index, 0);
+            assert_eq!(attestation.rating, CreditRating::A);
+        }
+        _ => panic!("expected a rating"),
+    }
+
+    // Reading the state does not count as admin activity
+    assert_eq!(vault.guardian_info().last_admin_action, last_admin_action);
+}
+
+#[test]
+fn test_beneficiary_yield_split() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let charity = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user1, &1000);
+    token_client.mint(&user2, &1000);
+
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user1, &500, &10000000);
+    vault.deposit(&user2, &500, &10000000);
+
+    assert_eq!(
+        vault.try_get_beneficiary(&user1),
+        Err(Ok(VaultError::BeneficiaryNotSet))
+    );
+    assert_eq!(
+        vault.try_set_beneficiary(&user1, &charity, &10001),
+        Err(Ok(VaultError::InvalidAmount))
+    );
+    vault.set_beneficiary(&user1, &charity, &2500);
+    assert_eq!(
+        vault.get_beneficiary(&user1),
+        BeneficiaryShare {
+            beneficiary: charity.clone(),
+            share_bps: 2500
+        }
+    );
+
+    // 10% yield at maturity
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    token_client.mint(&admin, &1100);
+    vault.set_total_redemption(&1100, &None);
+
+    // A quarter of the 50 yield goes to the charity, in two redemptions
+    assert_eq!(vault.withdraw(&user1, &user1, &200), 220 - 5);
+    assert_eq!(vault.withdraw(&user1, &user1, &300), 330 - 7);
+    assert_eq!(token_client.balance(&charity), 12);
+    assert_eq!(token_client.balance(&user1), 500 + 538);
+
+    // Holders without a designation redeem in full
+    assert_eq!(vault.withdraw(&user2, &user2, &400), 440);
+
+    // Shares received by transfer have no cost basis, so no yield to donate
+    let buyer = Address::generate(&e);
+    token::Client::new(&e, &vault.bond_id()).transfer(&user2, &buyer, &100);
+    vault.set_beneficiary(&buyer, &charity, &2500);
+    assert_eq!(vault.withdraw(&buyer, &buyer, &100), 110);
+    assert_eq!(token_client.balance(&charity), 12);
+}
+
+#[test]
+fn test_anchor_deposit() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let anchor = Address::generate(&e);
+    let customer = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&anchor, &10000);
+    vault.set_quote(&10000000, &None);
+
+    assert_eq!(
+        vault.try_anchor_deposit(&customer, &1000, &15, &10000000),
+        Err(Ok(VaultError::AnchorNotSet))
+    );
+
+    vault.set_anchor(&Some(anchor.clone()));
+    assert_eq!(vault.anchor(), anchor);
+    assert_eq!(
+        vault.try_anchor_deposit(&customer, &1000, &1000, &10000000),
+        Err(Ok(VaultError::InvalidAmount))
+    );
+
+    assert_eq!(vault.anchor_deposit(&customer, &1000, &15, &10000000), 985);
+    assert_eq!(vault.anchor_deposit(&customer, &500, &10, &10000000), 490);
+    assert_eq!(e.auths()[0].0, anchor);
+
+    // The anchor only transfers the net principal
+    assert_eq!(token_client.balance(&anchor), 10000 - 985 - 490);
+    assert_eq!(token_client.balance(&admin), 985 + 490);
+    assert_eq!(vault.total_deposit(), 985 + 490);
+    assert_eq!(vault.total_anchor_fees(), 25);
+
+    let position = vault.get_holders(&0, &1).get(0).unwrap();
+    assert_eq!(position.holder, customer);
+    assert_eq!(position.shares, 985 + 490);
+    assert_eq!(position.cost_basis, 985 + 490);
+    assert_eq!(position.anchor_fees, 25);
+}
+
+// Returns the payload of the latest TX event with `action`
+fn tx_record(e: &Env, action: Symbol) -> TxRecord {
+    let topics: Vec<Val> = (symbol_short!("TX"), action).into_val(e);
+    let (_, _, data) = e
+        .events()
+        .all()
+        .iter()
+        .filter(|(_, event_topics, _)| *event_topics == topics)
+        .last()
+        .unwrap();
+    TxRecord::try_from_val(e, &data).unwrap()
+}
+
+#[test]
+fn test_tx_events() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let anchor = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user, &1000);
+    token_client.mint(&anchor, &1000);
+    vault.set_quote(&12000000, &None);
+    vault.set_anchor(&Some(anchor));
+    assert_eq!(vault.last_tx_id(), 0);
+
+    vault.deposit(&user, &500, &12000000);
+    assert_eq!(
+        tx_record(&e, symbol_short!("deposit")),
+        TxRecord {
+            tx_id: 1,
+            series: String::from_str(&e, "BOND"),
+            holder: user.clone(),
+            amount: 500,
+            shares: 600,
+            quote: 12000000,
+            fee: 0,
+        }
+    );
+
+    vault.anchor_deposit(&user, &510, &10, &12000000);
+    let record = tx_record(&e, symbol_short!("deposit"));
+    assert_eq!((record.tx_id, record.amount, record.fee), (2, 500, 10));
+
+    // Redemptions share the same sequence
+    e.ledger().set_timestamp(e.ledger().timestamp() + 600);
+    token_client.mint(&admin, &1200);
+    vault.set_total_redemption(&1200, &None);
+    vault.withdraw(&user, &user, &600);
+    let record = tx_record(&e, symbol_short!("redeem"));
+    assert_eq!((record.tx_id, record.amount, record.shares, record.quote), (3, 600, 600, 10000000));
+    assert_eq!(vault.last_tx_id(), 3);
+}
+
+#[test]
+fn test_nav_accounting() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let token_client = create_token_contract(&e, &admin);
+    let vault = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+    vault.initialize(
+        &install_token_wasm(&e),
+        &token_client.address,
+        &admin,
+        &(e.ledger().timestamp()),
+        &(e.ledger().timestamp() + 600),
+        &300,
+        &admin,
+        &100,
+        &share_metadata(&e),
+        &AccountingMode::Nav,
+    );
+    token_client.mint(&user1, &2000);
+    token_client.mint(&user2, &2000);
+
+    assert_eq!(vault.accounting_mode(), AccountingMode::Nav);
+    assert_eq!(
+        vault.try_set_quote(&10000000, &None),
+        Err(Ok(VaultError::UnsupportedInMode))
+    );
+
+    // Deposits stay in the vault, at one share per token to start with
+    assert_eq!(vault.quote(), 10000000);
+    assert_eq!(vault.deposit(&user1, &1000, &10000000), 1000);
+    assert_eq!(token_client.balance(&vault.address), 1000);
+
+    // Yield paid into the vault raises the share price
+    token_client.mint(&vault.address, &100);
+    let quote = vault.quote();
+    assert_eq!(quote, 1001 * 10000000 / 1101);
+    assert_eq!(
+        vault.try_deposit(&user2, &1100, &(quote + 1)),
+        Err(Ok(VaultError::QuoteChanged))
+    );
+    assert_eq!(vault.deposit(&user2, &1100, &9000000), 1000);
+
+    // Withdrawals are open before maturity and redeem at the current share price
+    assert_eq!(vault.withdraw(&user1, &user1, &1000), 1000 * 2201 / 2001);
+    assert_eq!(vault.total_bonds(), 1000);
+    assert_eq!(vault.withdraw(&user2, &user2, &1000), 1000 * 1102 / 1001);
+    assert_eq!(token_client.balance(&vault.address), 1);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    assert_eq!(
+        vault.try_set_total_redemption(&0, &None),
+        Err(Ok(VaultError::UnsupportedInMode))
+    );
+}
+
+#[test]
+fn test_sync() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user1, &1000);
+
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user1, &800, &10000000);
+
+    // Tokens sent to the vault before the redemption is set are recorded as donations
+    token_client.mint(&vault.address, &200);
+    let report = vault.sync();
+    assert_eq!(report.balance, 200);
+    assert_eq!(report.expected, 0);
+    assert_eq!(report.surplus, 200);
+    assert_eq!(report.deficit, 0);
+
+    // Syncing again is a no-op
+    let report = vault.sync();
+    assert_eq!(report.expected, 200);
+    assert_eq!(report.surplus, 0);
+
+    // Donations are redeemed along with the total redemption
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    token_client.mint(&admin, &1000);
+    vault.set_total_redemption(&1000, &None);
+    assert_eq!(vault.available_redemption(), 1200);
+
+    // A deficit within the tolerance is only flagged
+    vault.set_sync_tolerance(&50);
+    token_client.burn(&vault.address, &40);
+    let report = vault.sync();
+    assert_eq!(report.deficit, 40);
+    assert_eq!(vault.discrepancy(), 40);
+    assert_eq!(vault.withdraw(&user1, &user1, &400), 600);
+
+    // Beyond the tolerance the contract is stopped
+    token_client.burn(&vault.address, &20);
+    assert_eq!(vault.sync().deficit, 60);
+    assert_eq!(
+        vault.try_deposit(&user2, &100, &10000000),
+        Err(Ok(VaultError::ContractStopped))
+    );
+
+    // A later surplus after the redemption is set goes to the holders redeeming
+    token_client.mint(&vault.address, &100);
+    let report = vault.sync();
+    assert_eq!(report.surplus, 40);
+    assert_eq!(vault.discrepancy(), 0);
+    assert_eq!(vault.available_redemption(), 640);
+}
+
+#[test]
+fn test_multicall() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let treasury = Address::generate(&e);
+
+    let (vault, _token_client) = setup_vault(&e, &admin);
+
+    let call = |function: &str, args: Vec<Val>| (Symbol::new(&e, function), args);
+
+    // Pause, reprice and resume in a single transaction, authorized once by the admin
+    let calls = Vec::from_array(
+        &e,
+        [
+            call("set_contract_stopped", Vec::from_array(&e, [true.into_val(&e)])),
+            call("set_quote", Vec::from_array(&e, [9900000i128.into_val(&e)])),
+            call("set_treasury", Vec::from_array(&e, [treasury.into_val(&e)])),
+            call("set_contract_stopped", Vec::from_array(&e, [false.into_val(&e)])),
+        ],
+    );
+    e.set_auths(&[]);
+    let results = vault
+        .mock_auths(&[MockAuth {
+            address: &admin,
+            invoke: &MockAuthInvoke {
+                contract: &vault.address,
+                fn_name: "multicall",
+                args: (calls.clone(),).into_val(&e),
+                sub_invokes: &[],
+            },
+        }])
+        .multicall(&calls);
+    assert_eq!(results.len(), 4);
+    assert_eq!(vault.quote(), 9900000);
+    assert_eq!(vault.treasury_account(), treasury);
+
+    // A failing call reverts the calls before it
+    e.mock_all_auths();
+    let calls = Vec::from_array(
+        &e,
+        [
+            call("set_treasury", Vec::from_array(&e, [admin.into_val(&e)])),
+            call(
+                "set_deposit_limits",
+                Vec::from_array(&e, [10u128.into_val(&e), Some(5u128).into_val(&e)]),
+            ),
+        ],
+    );
+    assert_eq!(vault.try_multicall(&calls), Err(Ok(VaultError::InvalidAmount)));
+    assert_eq!(vault.treasury_account(), treasury);
+
+    // Only the admin setters can be called, with their exact arguments
+    let calls = Vec::from_array(&e, [call("withdraw", Vec::new(&e))]);
+    assert_eq!(vault.try_multicall(&calls), Err(Ok(VaultError::InvalidCall)));
+    let calls = Vec::from_array(&e, [call("set_quote", Vec::from_array(&e, [true.into_val(&e)]))]);
+    assert_eq!(vault.try_multicall(&calls), Err(Ok(VaultError::InvalidCall)));
+}
+
+#[test]
+fn test_bid_ask_quotes() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let desk = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user, &1000);
+    token_client.mint(&desk, &1000);
+
+    assert_eq!(vault.bid_quoter(), admin);
+    vault.set_bid_quoter(&Some(desk.clone()));
+    assert_eq!(vault.bid_quoter(), desk);
+
+    // Subscriptions at the ask, buy-backs at a bid 5% lower in price
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user, &800, &10000000);
+    assert_eq!(vault.bid_quote(), 0);
+    assert_eq!(
+        vault.try_sell_back(&user, &100, &10500000),
+        Err(Ok(VaultError::QuoteRequired))
+    );
+
+    vault.set_bid_quote(&10500000);
+    assert_eq!(vault.bid_quote(), 10500000);
+    assert_eq!(vault.try_set_bid_quote(&10400000), Err(Ok(VaultError::QuoteStillValid)));
+    assert_eq!(
+        vault.try_sell_back(&user, &210, &10500000),
+        Err(Ok(VaultError::InsufficientReserve))
+    );
+
+    vault.set_buyback_reserve(&300);
+    assert_eq!(token_client.balance(&desk), 700);
+    assert_eq!(
+        vault.try_sell_back(&user, &210, &10400000),
+        Err(Ok(VaultError::QuoteChanged))
+    );
+    assert_eq!(vault.sell_back(&user, &210, &10500000), 200);
+    assert_eq!(token_client.balance(&user), 400);
+    assert_eq!(vault.total_bonds(), 590);
+    assert_eq!(vault.buyback_reserve(), 100);
+    assert_eq!(vault.sync().deficit, 0);
+
+    // The bid expires independently of the ask
+    e.ledger().set_timestamp(e.ledger().timestamp() + 301);
+    assert_eq!(vault.bid_quote(), 0);
+    vault.set_bid_quote(&10400000);
+
+    // The desk takes back what is left of the reserve
+    vault.set_buyback_reserve(&0);
+    assert_eq!(token_client.balance(&desk), 800);
+}
+
+#[test]
+fn test_issuance_stats() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user1, &1000);
+    token_client.mint(&user2, &1000);
+
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user1, &200, &10000000);
+    vault.deposit(&user2, &500, &10000000);
+    vault.deposit(&user1, &300, &10000000);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    token_client.mint(&admin, &1100);
+    vault.set_total_redemption(&1100, &None);
+    vault.withdraw(&user2, &user2, &500);
+
+    assert_eq!(
+        vault.stats(),
+        IssuanceStats {
+            total_deposited_lifetime: 1000,
+            total_redeemed_lifetime: 550,
+            unique_depositors_count: 2,
+            largest_single_deposit: 500,
+        }
+    );
+}
+
+mod migration {
+    use crate::MigrationSnapshot;
+    use soroban_sdk::{contract, contractimpl, symbol_short, Address, Env};
+
+    #[contract]
+    pub struct Registry;
+
+    #[contractimpl]
+    impl Registry {
+        pub fn approve(e: Env, contract: Address) {
+            e.storage().instance().set(&contract, &true);
+        }
+
+        pub fn is_audited(e: Env, contract: Address) -> bool {
+            e.storage().instance().get(&contract).unwrap_or(false)
+        }
+    }
+
+    #[contract]
+    pub struct Successor;
+
+    #[contractimpl]
+    impl Successor {
+        pub fn accept_migration(e: Env, snapshot: MigrationSnapshot) {
+            e.storage().instance().set(&symbol_short!("snapshot"), &snapshot);
+        }
+
+        pub fn snapshot(e: Env) -> MigrationSnapshot {
+            e.storage().instance().get(&symbol_short!("snapshot")).unwrap()
+        }
+    }
+}
+
+#[test]
+fn test_emergency_migration() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let subscriber = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user, &1000);
+    token_client.mint(&subscriber, &1000);
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user, &800, &10000000);
+    vault.subscribe(&subscriber, &500, &10000000);
+    vault.pay_installment(&subscriber, &100);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    token_client.mint(&admin, &1000);
+    vault.set_total_redemption(&1000, &None);
+
+    let registry = migration::RegistryClient::new(&e, &e.register_contract(None, migration::Registry));
+    let successor = migration::SuccessorClient::new(&e, &e.register_contract(None, migration::Successor));
+
+    assert_eq!(
+        vault.try_schedule_migration(&successor.address),
+        Err(Ok(VaultError::MigrationRegistryNotSet))
+    );
+    vault.set_migration_registry(&registry.address);
+    assert_eq!(
+        vault.try_schedule_migration(&successor.address),
+        Err(Ok(VaultError::SuccessorNotApproved))
+    );
+
+    registry.approve(&successor.address);
+    let pending = vault.schedule_migration(&successor.address);
+    assert_eq!(pending.eta, e.ledger().timestamp() + MIGRATION_DELAY);
+    assert_eq!(vault.pending_migration(), pending);
+    assert_eq!(
+        vault.try_emergency_migrate(&successor.address),
+        Err(Ok(VaultError::MigrationTimelocked))
+    );
+
+    e.ledger().set_timestamp(pending.eta);
+    assert_eq!(
+        vault.try_emergency_migrate(&admin),
+        Err(Ok(VaultError::MigrationNotScheduled))
+    );
+    let snapshot = vault.emergency_migrate(&successor.address);
+    assert_eq!(snapshot.balance, 1100);
+    assert_eq!(snapshot.subscription_escrow, 100);
+    assert_eq!(snapshot.redemption_balance, 0);
+    assert_eq!(snapshot.total_shares, 800);
+    assert_eq!(snapshot.available_redemption, 1000);
+    assert_eq!(snapshot.holder_count, 1);
+    assert_eq!(successor.snapshot(), snapshot);
+    assert_eq!(vault.migration_snapshot(), snapshot);
+
+    assert_eq!(token_client.balance(&successor.address), 1100);
+    assert_eq!(vault.available_redemption(), 0);
+    assert_eq!(vault.sync().surplus, 0);
+
+    // The installments moved with the snapshot, so the successor refunds them
+    assert_eq!(
+        vault.try_refund_subscription(&subscriber),
+        Err(Ok(VaultError::ContractStopped))
+    );
+    assert_eq!(vault.subscription(&subscriber).unwrap().paid, 100);
+    assert_eq!(
+        vault.try_withdraw(&user, &user, &800),
+        Err(Ok(VaultError::AvailableRedemptionNotSet))
+    );
+    assert_eq!(
+        vault.try_pending_migration(),
+        Err(Ok(VaultError::MigrationNotScheduled))
+    );
+}
+
+#[test]
+fn test_emergency_migration_settlement_asset() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    let settlement_client = create_token_contract(&e, &admin);
+    vault.set_settlement_asset(&settlement_client.address);
+    vault.set_claim_period(&1000);
+    token_client.mint(&user1, &1000);
+    token_client.mint(&user2, &1000);
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user1, &400, &10000000);
+    vault.deposit(&user2, &600, &10000000);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    settlement_client.mint(&admin, &2000);
+    vault.settle_redemption(&1000, &20000000);
+    e.ledger().set_timestamp(e.ledger().timestamp() + 1001);
+    token::Client::new(&e, &vault.bond_id()).approve(
+        &user1,
+        &vault.address,
+        &400,
+        &(e.ledger().sequence() + 100),
+    );
+    assert_eq!(vault.push_redemptions(&Vec::from_array(&e, [user1.clone()])), 800);
+
+    let registry = migration::RegistryClient::new(&e, &e.register_contract(None, migration::Registry));
+    let successor = migration::SuccessorClient::new(&e, &e.register_contract(None, migration::Successor));
+    vault.set_migration_registry(&registry.address);
+    registry.approve(&successor.address);
+    let pending = vault.schedule_migration(&successor.address);
+    e.ledger().set_timestamp(pending.eta);
+
+    // The settlement asset moves with the obligations it backs, pushed redemptions included
+    let snapshot = vault.emergency_migrate(&successor.address);
+    assert_eq!(snapshot.balance, 0);
+    assert_eq!(snapshot.redemption_balance, 2000);
+    assert_eq!(snapshot.available_redemption, 1200);
+    assert_eq!(snapshot.pushed_total, 800);
+    assert_eq!(settlement_client.balance(&successor.address), 2000);
+    assert_eq!(settlement_client.balance(&vault.address), 0);
+    assert_eq!(vault.sync().surplus, 0);
+}
+
+#[test]
+fn test_rolling_notes() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let token_client = create_token_contract(&e, &admin);
+    let vault = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+    vault.initialize(
+        &install_token_wasm(&e),
+        &token_client.address,
+        &admin,
+        &(e.ledger().timestamp()),
+        &(e.ledger().timestamp() + 10000),
+        &10000,
+        &admin,
+        &100,
+        &share_metadata(&e),
+        &AccountingMode::Notes,
+    );
+    token_client.mint(&user, &1000);
+    token_client.mint(&admin, &2000);
+
+    vault.set_quote(&10500000, &None);
+    assert_eq!(
+        vault.try_deposit(&user, &200, &10500000),
+        Err(Ok(VaultError::NoteTermNotSet))
+    );
+
+    // Each deposit is a tranche with its own maturity
+    vault.set_note_term(&900);
+    assert_eq!(vault.deposit(&user, &200, &10500000), 210);
+    e.ledger().set_timestamp(e.ledger().timestamp() + 500);
+    assert_eq!(vault.deposit(&user, &400, &10500000), 420);
+
+    let receipts = vault.get_receipts(&user, &0, &10);
+    assert_eq!(receipts.len(), 2);
+    assert_eq!(
+        receipts.get(1).unwrap(),
+        NoteReceipt {
+            id: 1,
+            amount: 400,
+            shares: 420,
+            maturity: e.ledger().timestamp() + 900,
+            redeemed: false,
+        }
+    );
+    assert_eq!(vault.get_receipts(&user, &1, &10).len(), 1);
+
+    assert_eq!(vault.try_withdraw(&user, &user, &210), Err(Ok(VaultError::UnsupportedInMode)));
+    assert_eq!(
+        vault.try_set_total_redemption(&1000, &None),
+        Err(Ok(VaultError::UnsupportedInMode))
+    );
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 400);
+    assert_eq!(
+        vault.try_withdraw_receipt(&user, &0),
+        Err(Ok(VaultError::AvailableRedemptionNotSet))
+    );
+    vault.fund_notes(&1000);
+    assert_eq!(
+        vault.try_withdraw_receipt(&user, &1),
+        Err(Ok(VaultError::MaturityNotReached))
+    );
+
+    // The first tranche has matured and redeems at par
+    assert_eq!(vault.withdraw_receipt(&user, &0), 210);
+    assert_eq!(token_client.balance(&user), 610);
+    assert_eq!(vault.total_bonds(), 420);
+    assert_eq!(vault.available_redemption(), 790);
+    assert!(vault.get_receipts(&user, &0, &1).get(0).unwrap().redeemed);
+    assert_eq!(
+        vault.try_withdraw_receipt(&user, &0),
+        Err(Ok(VaultError::ReceiptRedeemed))
+    );
+    assert_eq!(
+        vault.try_withdraw_receipt(&user, &2),
+        Err(Ok(VaultError::ReceiptNotFound))
+    );
+
+    // A receipt moves with its shares, keeping its maturity
+    let buyer = Address::generate(&e);
+    assert_eq!(
+        vault.try_transfer_receipt(&user, &buyer, &0),
+        Err(Ok(VaultError::ReceiptRedeemed))
+    );
+    let moved = vault.transfer_receipt(&user, &buyer, &1);
+    assert_eq!(moved.id, 0);
+    assert_eq!(moved.shares, 420);
+    assert_eq!(vault.get_receipts(&user, &0, &10).len(), 1);
+    assert_eq!(vault.get_holders(&0, &1).get(0).unwrap().cost_basis, 0);
+    assert_eq!(
+        vault.try_withdraw_receipt(&user, &1),
+        Err(Ok(VaultError::ReceiptNotFound))
+    );
+    e.ledger().set_timestamp(e.ledger().timestamp() + 500);
+    assert_eq!(vault.withdraw_receipt(&buyer, &0), 420);
+    assert_eq!(token_client.balance(&buyer), 420);
+}
+
+#[test]
+fn test_share_token_standard() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let spender = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user, &1000);
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user, &500, &10000000);
+
+    // The bond token works through the SDK's SEP-41 token interface, allowances included
+    let share_token = soroban_sdk::token::TokenClient::new(&e, &vault.bond_id());
+    assert_eq!(share_token.decimals(), 7);
+    assert_eq!(share_token.symbol(), String::from_str(&e, "BOND"));
+
+    share_token.approve(&user, &spender, &300, &(e.ledger().sequence() + 100));
+    assert_eq!(share_token.allowance(&user, &spender), 300);
+    share_token.transfer_from(&spender, &user, &spender, &200);
+    share_token.burn_from(&spender, &user, &100);
+    assert_eq!(share_token.allowance(&user, &spender), 0);
+    assert_eq!(share_token.balance(&user), 200);
+    assert_eq!(share_token.balance(&spender), 200);
+}
+
+#[test]
+fn test_chained_series() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (prev, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user1, &1000);
+    token_client.mint(&user2, &1000);
+    prev.set_quote(&10000000, &None);
+    prev.deposit(&user1, &400, &10000000);
+    prev.deposit(&user2, &600, &10000000);
+
+    // The next monthly issue matures one term after the previous one
+    let next = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+    next.initialize(
+        &install_token_wasm(&e),
+        &token_client.address,
+        &admin,
+        &(e.ledger().timestamp() + 600),
+        &(e.ledger().timestamp() + 1200),
+        &300,
+        &admin,
+        &100,
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
+    );
+    assert_eq!(next.try_suggested_quote(), Err(Ok(VaultError::SeriesNotLinked)));
+
+    // The previous series must have matured and set its total redemption
+    assert_eq!(
+        next.try_link_previous_series(&prev.address),
+        Err(Ok(VaultError::MaturityNotReached))
+    );
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 600);
+    assert_eq!(
+        next.try_link_previous_series(&prev.address),
+        Err(Ok(VaultError::AvailableRedemptionNotSet))
+    );
+
+    token_client.mint(&admin, &1020);
+    prev.set_total_redemption(&1020, &None);
+    assert_eq!(prev.withdraw(&user1, &user1, &400), 408);
+    assert_eq!(prev.withdraw(&user2, &user2, &600), 612);
+    assert_eq!(prev.available_redemption(), 0);
+
+    // Redemptions already paid count towards the realized rate, even once nothing is left
+    let previous = next.link_previous_series(&prev.address);
+    assert_eq!(
+        previous,
+        PreviousSeries {
+            vault: prev.address.clone(),
+            maturity: prev.maturity(),
+            realized_rate: 10200000,
+        }
+    );
+    assert_eq!(next.previous_series(), previous);
+
+    // The realized 2% applies over the full term and shrinks with the time left
+    assert_eq!(next.suggested_quote(), 10200000);
+    e.ledger().set_timestamp(e.ledger().timestamp() + 300);
+    assert_eq!(next.suggested_quote(), 10100000);
+
+    next.set_quote(&next.suggested_quote(), &None);
+    assert_eq!(next.quote(), 10100000);
+}
+
+#[test]
+fn test_deposit_with_authorization() {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let signer = SigningKey::from_bytes(&[7; 32]);
+    let strkey = stellar_strkey::ed25519::PublicKey(signer.verifying_key().to_bytes()).to_string();
+    let from = Address::from_string(&String::from_str(&e, &strkey));
+
+    // Stellar asset balances of accounts need trustlines, so deposit a contract token instead
+    let token_client = token::Client::new(&e, &e.register_contract_wasm(None, token::WASM));
+    token_client.initialize(&admin, &7, &String::from_str(&e, "USD"), &String::from_str(&e, "USD"));
+    let vault = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+    vault.initialize(
+        &install_token_wasm(&e),
+        &token_client.address,
+        &admin,
+        &(e.ledger().timestamp()),
+        &(e.ledger().timestamp() + 600),
+        &300,
+        &admin,
+        &100,
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
+    );
+    token_client.mint(&from, &1000);
+    vault.set_quote(&10000000, &None);
+
+    let sign = |amount: i128, deadline: u64, nonce: u64| {
+        let payload = vault.permit_payload(&from, &amount, &deadline, &nonce);
+        let mut message = [0u8; 256];
+        let len = payload.len() as usize;
+        payload.copy_into_slice(&mut message[..len]);
+        BytesN::from_array(&e, &signer.sign(&message[..len]).to_bytes())
+    };
+
+    // The relayer submits the deposit along with the depositor's signed authorization of the
+    // token transfer alone, without any prior approve
+    let deadline = e.ledger().timestamp() + 100;
+    assert_eq!(vault.permit_nonce(&from), 0);
+    let signature = sign(300, deadline, 0);
+    e.mock_all_auths_allowing_non_root_auth();
+    assert_eq!(vault.deposit_with_authorization(&from, &300, &deadline, &0, &signature), 300);
+    assert_eq!(
+        e.auths(),
+        std::vec![(
+            from.clone(),
+            AuthorizedInvocation {
+                function: AuthorizedFunction::Contract((
+                    token_client.address.clone(),
+                    Symbol::new(&e, "transfer"),
+                    (&from, &admin, 300i128).into_val(&e),
+                )),
+                sub_invocations: std::vec![],
+            }
+        )]
+    );
+    assert_eq!(token_client.balance(&from), 700);
+    assert_eq!(vault.permit_nonce(&from), 1);
+    e.set_auths(&[]);
+
+    // The message is scoped to vault permits on this network
+    let payload = vault.permit_payload(&from, &300, &deadline, &1);
+    let domain = Bytes::from_slice(&e, b"bondhive:vault:deposit_permit:v1");
+    assert_eq!(payload.slice(..domain.len()), domain);
+    assert_eq!(
+        payload.slice(domain.len()..domain.len() + 32),
+        Bytes::from(e.ledger().network_id())
+    );
+
+    // Permits cannot be replayed or used after their deadline
+    assert_eq!(
+        vault.try_deposit_with_authorization(&from, &300, &deadline, &0, &signature),
+        Err(Ok(VaultError::InvalidNonce))
+    );
+    let signature = sign(300, deadline, 1);
+    e.ledger().set_timestamp(deadline + 1);
+    assert_eq!(
+        vault.try_deposit_with_authorization(&from, &300, &deadline, &1, &signature),
+        Err(Ok(VaultError::PermitExpired))
+    );
+
+    // Contract addresses have no account key to sign with
+    assert_eq!(
+        vault.try_deposit_with_authorization(&vault.address, &300, &(deadline + 100), &0, &signature),
+        Err(Ok(VaultError::InvalidSigner))
+    );
+}
+
+#[test]
+fn test_treasury_covenant() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user, &1000);
+    vault.set_quote(&10000000, &None);
+
+    assert_eq!(vault.set_covenant(&2000).buffer_bps, 2000);
+    vault.deposit(&user, &1000, &10000000);
+
+    // Deposits stay in the vault until the treasury draws the excess over the buffer
+    assert_eq!(token_client.balance(&vault.address), 1000);
+    assert_eq!(
+        vault.covenant(),
+        Covenant {
+            buffer_bps: 2000,
+            deposits: 1000,
+            balance: 1000,
+            drawable: 800,
+        }
+    );
+    assert_eq!(vault.try_set_covenant(&1000), Err(Ok(VaultError::InvalidAmount)));
+    assert_eq!(vault.try_treasury_draw(&801), Err(Ok(VaultError::InsufficientReserve)));
+    assert_eq!(vault.treasury_draw(&800), 800);
+    assert_eq!(token_client.balance(&admin), 800);
+    assert_eq!(vault.covenant().drawable, 0);
+    assert_eq!(vault.sync().deficit, 0);
+
+    // The total redemption is what the admin funds, the buffer is added to it on request
+    e.ledger().set_timestamp(e.ledger().timestamp() + 601);
+    assert_eq!(
+        vault.try_release_covenant_buffer(),
+        Err(Ok(VaultError::AvailableRedemptionNotSet))
+    );
+    token_client.mint(&admin, &200);
+    vault.set_total_redemption(&1000, &None);
+    assert_eq!(vault.available_redemption(), 1000);
+    assert_eq!(vault.covenant().balance, 200);
+    assert_eq!(vault.sync().surplus, 0);
+    assert_eq!(vault.release_covenant_buffer(), 1200);
+    assert_eq!(vault.covenant().balance, 0);
+    assert_eq!(vault.withdraw(&user, &user, &1000), 1200);
+}
+
+#[test]
+fn test_cancel_offering_covenant() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user, &1000);
+    vault.set_quote(&10000000, &None);
+    vault.set_covenant(&2000);
+    vault.deposit(&user, &500, &10000000);
+    assert_eq!(vault.treasury_draw(&200), 200);
+
+    // Once cancelled, what the vault still holds belongs to the refunding holders
+    vault.set_soft_cap(&1000);
+    vault.cancel_offering();
+    assert_eq!(vault.try_treasury_draw(&100), Err(Ok(VaultError::InvalidCall)));
+    vault.fund_refunds(&admin, &200);
+    assert_eq!(vault.refund(&user), 500);
+    assert_eq!(vault.covenant().deposits, 0);
+    assert_eq!(vault.covenant().balance, 0);
+    assert_eq!(token_client.balance(&vault.address), 0);
+    assert_eq!(vault.sync().deficit, 0);
+}
+
+#[test]
+fn test_quorum_quotes() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let quoter1 = Address::generate(&e);
+    let quoter2 = Address::generate(&e);
+    let quoter3 = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user, &1000);
+
+    let quoters = Vec::from_array(&e, [quoter1.clone(), quoter2.clone(), quoter3.clone()]);
+    assert_eq!(
+        vault.try_set_quoters(&quoters, &4, &100),
+        Err(Ok(VaultError::InvalidAmount))
+    );
+    vault.set_quoters(&quoters, &2, &100);
+    assert_eq!(vault.quoters(), (quoters, 2, 100));
+
+    // The admin no longer prices the issue, and only quoters submit quotes
+    assert_eq!(vault.try_set_quote(&10000000, &None), Err(Ok(VaultError::UnsupportedInMode)));
+    assert_eq!(vault.try_submit_quote(&user, &10000000), Err(Ok(VaultError::NotQuoter)));
+
+    assert_eq!(vault.submit_quote(&quoter1, &10000000), 0);
+    assert_eq!(vault.try_active_quote(), Err(Ok(VaultError::QuorumNotReached)));
+
+    // A compromised quoter cannot move the quote on its own
+    assert_eq!(vault.submit_quote(&quoter2, &15000000), 0);
+    assert_eq!(vault.quote(), 0);
+
+    assert_eq!(vault.submit_quote(&quoter3, &10050000), 10000000);
+    assert_eq!(vault.active_quote(), 10000000);
+    assert_eq!(vault.quote(), 10000000);
+    assert_eq!(vault.deposit(&user, &500, &10000000), 500);
+
+    // Submissions older than the quote period no longer count
+    e.ledger().set_timestamp(e.ledger().timestamp() + 301);
+    assert_eq!(vault.try_active_quote(), Err(Ok(VaultError::QuorumNotReached)));
+}
+
+#[test]
+fn test_series_metadata() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let (vault, _) = setup_vault(&e, &admin);
+
+    let key = |name: &str| String::from_str(&e, name);
+    vault.set_metadata(&key("issuer"), &key("BondHive"));
+    vault.set_metadata(&key("jurisdiction"), &key("BVI"));
+    vault.set_metadata(&key("issuer"), &key("BondHive Ltd"));
+
+    assert_eq!(vault.get_metadata(&key("issuer")), key("BondHive Ltd"));
+    let metadata = vault.list_metadata();
+    assert_eq!(metadata.len(), 2);
+    assert_eq!(metadata.get(key("jurisdiction")), Some(key("BVI")));
+
+    // An empty value removes the attribute
+    vault.set_metadata(&key("jurisdiction"), &key(""));
+    assert_eq!(
+        vault.try_get_metadata(&key("jurisdiction")),
+        Err(Ok(VaultError::MetadataNotFound))
+    );
+
+    // Keys, values and the number of attributes are capped
+    let long = "x".repeat(257);
+    assert_eq!(
+        vault.try_set_metadata(&key("isin"), &key(&long)),
+        Err(Ok(VaultError::MetadataTooLarge))
+    );
+    assert_eq!(
+        vault.try_set_metadata(&key(&long[..33]), &key("value")),
+        Err(Ok(VaultError::MetadataTooLarge))
+    );
+    for index in 1..20 {
+        vault.set_metadata(&key(&std::format!("key{}", index)), &key("value"));
+    }
+    assert_eq!(
+        vault.try_set_metadata(&key("isin"), &key("XS0000000000")),
+        Err(Ok(VaultError::MetadataTooLarge))
+    );
+    // The attributes stay out of the instance entry loaded on every call
+    e.as_contract(&vault.address, || {
+        assert!(!e.storage().instance().has(&DataKey::Metadata));
+        assert!(e.storage().persistent().has(&DataKey::Metadata));
+    });
+}
+
+mod malicious {
+    use soroban_sdk::{contract, contractimpl, symbol_short, Address, Env, IntoVal, Symbol, Val};
+
+    // Token calling back into the vault from every transfer, recording whether it got in
+    #[contract]
+    pub struct ReentrantToken;
+
+    #[contractimpl]
+    impl ReentrantToken {
+        pub fn set_target(e: Env, vault: Address, from: Address) {
+            e.storage().instance().set(&symbol_short!("vault"), &vault);
+            e.storage().instance().set(&symbol_short!("from"), &from);
+        }
+
+        pub fn mint(e: Env, to: Address, amount: i128) {
+            let balance = Self::balance(e.clone(), to.clone());
+            e.storage().persistent().set(&to, &(balance + amount));
+        }
+
+        pub fn balance(e: Env, id: Address) -> i128 {
+            e.storage().persistent().get(&id).unwrap_or(0)
+        }
+
+        pub fn transfer(e: Env, from: Address, to: Address, amount: i128) {
+            if let Some(vault) = e.storage().instance().get::<_, Address>(&symbol_short!("vault")) {
+                let victim: Address = e.storage().instance().get(&symbol_short!("from")).unwrap();
+                let reentered = e
+                    .try_invoke_contract::<Val, soroban_sdk::Error>(
+                        &vault,
+                        &Symbol::new(&e, "deposit"),
+                        (victim, amount, 10000000i128).into_val(&e),
+                    )
+                    .is_ok();
+                e.storage().instance().set(&symbol_short!("reenter"), &reentered);
+            }
+            Self::mint(e.clone(), from.clone(), -amount);
+            Self::mint(e, to, amount);
+        }
+
+        pub fn reentered(e: Env) -> bool {
+            e.storage().instance().get(&symbol_short!("reenter")).unwrap_or(false)
+        }
+    }
+}
+
+mod fee_token {
+    use soroban_sdk::{contract, contractimpl, Address, Env};
+
+    // Token burning 1% of every transfer
+    #[contract]
+    pub struct FeeToken;
+
+    #[contractimpl]
+    impl FeeToken {
+        pub fn mint(e: Env, to: Address, amount: i128) {
+            let balance = Self::balance(e.clone(), to.clone());
+            e.storage().persistent().set(&to, &(balance + amount));
+        }
+
+        pub fn balance(e: Env, id: Address) -> i128 {
+            e.storage().persistent().get(&id).unwrap_or(0)
+        }
+
+        pub fn transfer(e: Env, from: Address, to: Address, amount: i128) {
+            Self::mint(e.clone(), from, -amount);
+            Self::mint(e, to, amount - amount / 100);
+        }
+    }
+}
+
+#[test]
+fn test_reentrant_token() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let victim = Address::generate(&e);
+
+    let token = malicious::ReentrantTokenClient::new(&e, &e.register_contract(None, malicious::ReentrantToken));
+    let vault = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+    vault.initialize(
+        &install_token_wasm(&e),
+        &token.address,
+        &admin,
+        &(e.ledger().timestamp()),
+        &(e.ledger().timestamp() + 600),
+        &300,
+        &admin,
+        &100,
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
+    );
+    vault.set_quote(&10000000, &None);
+    token.mint(&user, &1000);
+    token.mint(&victim, &1000);
+    token.set_target(&vault.address, &victim);
+
+    // The deposit goes through while the token's attempt to deposit again is rejected
+    assert_eq!(vault.deposit(&user, &500, &10000000), 500);
+    assert!(!token.reentered());
+    assert_eq!(token.balance(&admin), 500);
+    assert_eq!(token.balance(&victim), 1000);
+    assert_eq!(vault.total_bonds(), 500);
+    assert_eq!(vault.total_deposit(), 500);
+}
+
+#[test]
+fn test_fee_on_transfer_deposit() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let secondary = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let token = fee_token::FeeTokenClient::new(&e, &e.register_contract(None, fee_token::FeeToken));
+    let vault = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+    vault.initialize(
+        &install_token_wasm(&e),
+        &token.address,
+        &admin,
+        &(e.ledger().timestamp()),
+        &(e.ledger().timestamp() + 600),
+        &300,
+        &admin,
+        &100,
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
+    );
+    vault.set_quote(&10000000, &None);
+    token.mint(&user, &2000);
+
+    // Without a tolerance the fee is a shortfall the deposit refuses
+    assert_eq!(vault.transfer_tolerance(), 0);
+    assert_eq!(
+        vault.try_deposit(&user, &500, &10000000),
+        Err(Ok(VaultError::ReceivedLessThanExpected))
+    );
+    assert_eq!(
+        vault.try_set_transfer_tolerance(&10001),
+        Err(Ok(VaultError::InvalidAmount))
+    );
+
+    // Within the tolerance the shares are issued for what the treasury received
+    vault.set_transfer_tolerance(&100);
+    assert_eq!(vault.deposit(&user, &500, &10000000), 495);
+    assert_eq!(token.balance(&admin), 495);
+    assert_eq!(vault.total_bonds(), 495);
+    assert_eq!(vault.total_deposit(), 495);
+
+    // Split deposits sum what every destination received
+    vault.set_treasury_split(&secondary, &5000);
+    assert_eq!(vault.deposit(&user, &500, &10000000), 496);
+    assert_eq!(token.balance(&admin), 495 + 248);
+    assert_eq!(token.balance(&secondary), 248);
+    assert_eq!(vault.total_deposit(), 991);
+
+    vault.set_transfer_tolerance(&50);
+    assert_eq!(
+        vault.try_deposit(&user, &500, &10000000),
+        Err(Ok(VaultError::ReceivedLessThanExpected))
+    );
+}
+
+mod tvl {
+    use soroban_sdk::{contract, contractimpl, symbol_short, Address, Env, Error};
+
+    #[contract]
+    pub struct Registry;
+
+    #[contractimpl]
+    impl Registry {
+        pub fn register_series(e: Env, vault: Address) {
+            e.storage().instance().set(&vault, &0i128);
+            let count: u32 = e.storage().instance().get(&symbol_short!("count")).unwrap_or(0);
+            e.storage().instance().set(&symbol_short!("count"), &(count + 1));
+        }
+
+        pub fn set_failing(e: Env, failing: bool) {
+            e.storage().instance().set(&symbol_short!("failing"), &failing);
+        }
+
+        pub fn report_tvl(e: Env, vault: Address, delta: i128) -> Result<(), Error> {
+            if e.storage().instance().get(&symbol_short!("failing")).unwrap_or(false) {
+                return Err(Error::from_contract_error(1));
+            }
+            let series: i128 = e.storage().instance().get(&vault).unwrap();
+            e.storage().instance().set(&vault, &(series + delta));
+            let tvl = Self::global_tvl(e.clone());
+            e.storage().instance().set(&symbol_short!("tvl"), &(tvl + delta));
+            Ok(())
+        }
+
+        pub fn global_tvl(e: Env) -> i128 {
+            e.storage().instance().get(&symbol_short!("tvl")).unwrap_or(0)
+        }
+
+        pub fn series_count(e: Env) -> u32 {
+            e.storage().instance().get(&symbol_short!("count")).unwrap_or(0)
+        }
+    }
+}
+
+#[test]
+fn test_tvl_registry() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let registry = tvl::RegistryClient::new(&e, &e.register_contract(None, tvl::Registry));
+
+    let (vault1, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user1, &1000);
+    token_client.mint(&user2, &1000);
+    vault1.set_quote(&10000000, &None);
+    assert_eq!(vault1.try_tvl_registry(), Err(Ok(VaultError::TvlRegistryNotSet)));
+
+    // Deposits taken before registering are reported on registration
+    vault1.deposit(&user1, &400, &10000000);
+    vault1.set_tvl_registry(&registry.address);
+    assert_eq!(vault1.tvl_registry(), registry.address);
+    assert_eq!(registry.series_count(), 1);
+    assert_eq!(registry.global_tvl(), 400);
+    assert_eq!(
+        vault1.try_set_tvl_registry(&registry.address),
+        Err(Ok(VaultError::InvalidCall))
+    );
+
+    let vault2 = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+    vault2.initialize(
+        &install_token_wasm(&e),
+        &token_client.address,
+        &admin,
+        &(e.ledger().timestamp()),
+        &(e.ledger().timestamp() + 1200),
+        &300,
+        &admin,
+        &100,
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
+    );
+    vault2.set_quote(&10000000, &None);
+    vault2.set_tvl_registry(&registry.address);
+    vault2.deposit(&user2, &600, &10000000);
+    assert_eq!(registry.series_count(), 2);
+    assert_eq!(registry.global_tvl(), 1000);
+
+    // Redemptions release the deposits the redeemed shares stand for, not the yield paid on them
+    e.ledger().set_timestamp(e.ledger().timestamp() + 600);
+    token_client.mint(&admin, &440);
+    vault1.set_total_redemption(&440, &None);
+    vault1.withdraw(&user1, &user1, &200);
+    assert_eq!(registry.global_tvl(), 800);
+    vault1.withdraw(&user1, &user1, &200);
+    assert_eq!(registry.global_tvl(), 600);
+
+    // A failing registry does not block deposits, the missed change is reported later
+    registry.set_failing(&true);
+    vault2.set_quote(&10000000, &None);
+    token_client.mint(&user2, &500);
+    vault2.deposit(&user2, &300, &10000000);
+    assert_eq!(registry.global_tvl(), 600);
+    registry.set_failing(&false);
+    vault2.deposit(&user2, &100, &10000000);
+    assert_eq!(registry.global_tvl(), 1000);
+
+    // A replacement registry is told the whole value locked in the series
+    let replacement = tvl::RegistryClient::new(&e, &e.register_contract(None, tvl::Registry));
+    vault2.set_tvl_registry(&replacement.address);
+    assert_eq!(vault2.tvl_registry(), replacement.address);
+    assert_eq!(replacement.series_count(), 1);
+    assert_eq!(replacement.global_tvl(), 1000);
+}
+
+#[test]
+fn test_redemption_challenge() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let guardian = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    token_client.mint(&user, &1000);
+    vault.set_quote(&10000000, &None);
+    vault.deposit(&user, &1000, &10000000);
+    vault.set_guardian(&Some(guardian.clone()), &1000);
+    vault.set_challenge_window(&100);
+    vault.set_claim_period(&500);
+
+    // A mistyped total redemption keeps withdrawals closed for the window
+    e.ledger().set_timestamp(e.ledger().timestamp() + 600);
+    token_client.mint(&admin, &10500);
+    let admin_balance = token_client.balance(&admin);
+    vault.set_total_redemption(&10500, &None);
+    assert_eq!(vault.try_set_challenge_window(&0), Err(Ok(VaultError::AvailableRedemptionAlreadySet)));
+    assert_eq!(vault.try_withdraw(&user, &user, &1000), Err(Ok(VaultError::ChallengeWindowOpen)));
+    assert_eq!(vault.claim_deadline(), e.ledger().timestamp() + 600);
+
+    // The guardian corrects it once, refunding the excess to the admin
+    assert_eq!(vault.correct_total_redemption(&1050), 1050);
+    assert_eq!(token_client.balance(&admin), admin_balance - 1050);
+    assert_eq!(vault.available_redemption(), 1050);
+    assert_eq!(vault.get_entitlement(&user), 1050);
+    assert_eq!(
+        vault.redemption_challenge(),
+        Some(RedemptionChallenge {
+            amount: 1050,
+            closes_at: e.ledger().timestamp() + 100,
+            corrected: true,
+        })
+    );
+    assert_eq!(
+        vault.try_correct_total_redemption(&1100),
+        Err(Ok(VaultError::AvailableRedemptionAlreadySet))
+    );
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 100);
+    assert_eq!(
+        vault.try_correct_total_redemption(&1100),
+        Err(Ok(VaultError::ChallengeWindowClosed))
+    );
+    assert_eq!(vault.withdraw(&user, &user, &1000), 1050);
+}
+
+#[test]
+fn test_settlement_asset() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    let settlement_client = create_token_contract(&e, &admin);
+    assert_eq!(vault.try_settlement(), Err(Ok(VaultError::SettlementAssetNotSet)));
+    assert_eq!(vault.try_set_settlement_asset(&token_client.address), Err(Ok(VaultError::InvalidCall)));
+    vault.set_settlement_asset(&settlement_client.address);
+    assert_eq!(
+        vault.settlement(),
+        Settlement { asset: settlement_client.address.clone(), rate: 0 }
+    );
+
+    token_client.mint(&user, &1000);
+    vault.set_quote(&10000000, &None);
+    vault.set_covenant(&2000);
+    vault.deposit(&user, &1000, &10000000);
+    vault.treasury_draw(&800);
+
+    // The redemption is funded in the settlement asset at 2 units per deposit token
+    e.ledger().set_timestamp(e.ledger().timestamp() + 600);
+    assert_eq!(vault.try_set_total_redemption(&1050, &None), Err(Ok(VaultError::UnsupportedInMode)));
+    assert_eq!(vault.try_settle_redemption(&1050, &0), Err(Ok(VaultError::InvalidAmount)));
+    settlement_client.mint(&admin, &2100);
+    assert_eq!(vault.settle_redemption(&1050, &20000000), 2100);
+    assert_eq!(settlement_client.balance(&admin), 0);
+    assert_eq!(token_client.balance(&admin), 800);
+    assert_eq!(vault.available_redemption(), 2100);
+    assert_eq!(vault.get_entitlement(&user), 2100);
+    assert_eq!(
+        vault.try_set_settlement_asset(&Address::generate(&e)),
+        Err(Ok(VaultError::MaturityReached))
+    );
+
+    // The covenant buffer stays in the deposit token and is paid out with each redemption
+    assert_eq!(vault.withdraw(&user, &user, &400), 840);
+    assert_eq!(token_client.balance(&user), 80);
+    assert_eq!(vault.withdraw(&user, &user, &600), 1260);
+    assert_eq!(settlement_client.balance(&user), 2100);
+    assert_eq!(token_client.balance(&user), 200);
+    assert_eq!(token_client.balance(&vault.address), 0);
+    assert_eq!(vault.stats().total_redeemed_lifetime, 1050);
+
+    // A surplus of the settlement asset with no open redemption is reported, not recorded
+    settlement_client.mint(&vault.address, &50);
+    assert_eq!(vault.sync().surplus, 50);
+    assert_eq!(vault.sync().surplus, 50);
+    let donations: Option<i128> = e.as_contract(&vault.address, || e.storage().instance().get(&DataKey::Donations));
+    assert_eq!(donations.unwrap_or(0), 0);
+}
+
+#[test]
+fn test_quote_bounds() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let guardian = Address::generate(&e);
+
+    // Runs past the maturity of setup_vault, after which quotes are frozen
+    let token = create_token_contract(&e, &admin);
+    let vault = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+    vault.initialize(
+        &install_token_wasm(&e),
+        &token.address,
+        &admin,
+        &(e.ledger().timestamp()),
+        &(e.ledger().timestamp() + 10000),
+        &300,
+        &admin,
+        &100,
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
+    );
+    let bounds = QuoteBounds {
+        max_deviation_bps: 500,
+        min_quote: 5000000,
+        max_quote: 20000000,
+        timelock: 200,
+    };
+    assert_eq!(vault.try_propose_quote(&1000000), Err(Ok(VaultError::InvalidCall)));
+    vault.set_quote_bounds(&bounds);
+    assert_eq!(vault.quote_bounds(), Some(bounds));
+
+    // Outside the absolute band, then beyond the deviation from the previous quote
+    assert_eq!(vault.try_set_quote(&1000000, &None), Err(Ok(VaultError::QuoteOutOfBounds)));
+    vault.set_quote(&10000000, &None);
+    e.ledger().set_timestamp(e.ledger().timestamp() + 301);
+    assert_eq!(vault.try_set_quote(&10600000, &None), Err(Ok(VaultError::QuoteOutOfBounds)));
+    assert_eq!(vault.set_quote(&10500000, &None), 10500000);
+
+    // The guardian co-signs an out of bounds quote
+    e.ledger().set_timestamp(e.ledger().timestamp() + 301);
+    assert_eq!(vault.try_cosign_quote(), Err(Ok(VaultError::GuardianNotSet)));
+    vault.set_guardian(&Some(guardian.clone()), &1000);
+    vault.propose_quote(&12000000);
+    assert_eq!(vault.cosign_quote(), 12000000);
+    assert_eq!(vault.quote(), 12000000);
+    assert_eq!(vault.try_pending_quote(), Err(Ok(VaultError::QuoteNotProposed)));
+
+    // Or it takes effect once the timelock passes
+    e.ledger().set_timestamp(e.ledger().timestamp() + 301);
+    let pending = vault.propose_quote(&4000000);
+    assert_eq!(pending, PendingQuote { quote: 4000000, eta: e.ledger().timestamp() + 200 });
+    assert_eq!(vault.try_execute_quote(), Err(Ok(VaultError::QuoteOutOfBounds)));
+    e.ledger().set_timestamp(pending.eta);
+    assert_eq!(vault.execute_quote(), 4000000);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 301);
+    vault.propose_quote(&30000000);
+    vault.cancel_quote();
+    assert_eq!(vault.try_execute_quote(), Err(Ok(VaultError::QuoteNotProposed)));
+}
+
+#[test]
+fn test_lifecycle_states() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let token_client = create_token_contract(&e, &admin);
+    let vault = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+    let start = e.ledger().timestamp() + 100;
+    vault.initialize(
+        &install_token_wasm(&e),
+        &token_client.address,
+        &admin,
+        &start,
+        &(start + 600),
+        &300,
+        &admin,
+        &100,
+        &share_metadata(&e),
+        &AccountingMode::FixedQuote,
+    );
+    token_client.mint(&user, &2000);
+    assert_eq!(vault.state(), VaultState::Pending);
+    vault.set_quote(&10000000, &None);
+    assert_eq!(
+        vault.try_deposit(&user, &1000, &10000000),
+        Err(Ok(VaultError::NotOpenYet))
+    );
+
+    e.ledger().set_timestamp(start);
+    assert_eq!(vault.state(), VaultState::Open);
+    vault.deposit(&user, &1000, &10000000);
+    vault.set_maturity_grace(&200);
+
+    // Deposits close in the maturity second, and quotes stay frozen through the grace period
+    e.ledger().set_timestamp(start + 600);
+    assert_eq!(vault.state(), VaultState::Closed);
+    assert_eq!(
+        vault.try_deposit(&user, &1000, &10000000),
+        Err(Ok(VaultError::MaturityReached))
+    );
+    assert_eq!(vault.try_set_quote(&10000000, &None), Err(Ok(VaultError::MaturityReached)));
+    assert_eq!(vault.try_set_maturity_grace(&0), Err(Ok(VaultError::MaturityReached)));
+    token_client.mint(&admin, &1050);
+    assert_eq!(
+        vault.try_set_total_redemption(&1050, &None),
+        Err(Ok(VaultError::MaturityNotReached))
+    );
+    assert_eq!(vault.try_withdraw(&user, &user, &1000), Err(Ok(VaultError::MaturityNotReached)));
+
+    e.ledger().set_timestamp(start + 800);
+    assert_eq!(vault.state(), VaultState::Matured);
+    e.ledger().set_timestamp(start + 900);
+    vault.set_total_redemption(&1050, &None);
+    assert_eq!(vault.state(), VaultState::Settled);
+    assert_eq!(vault.withdraw(&user, &user, &1000), 1050);
+    assert_eq!(vault.state(), VaultState::Settled);
+    assert_eq!(
+        vault.series_timeline(),
+        SeriesTimeline {
+            created_at: start - 100,
+            open_at: start,
+            close_at: start + 600,
+            matured_at: start + 800,
+            settled_at: start + 900,
+            cancelled_at: 0,
+        }
+    );
+}
+
+#[test]
+fn test_checked_arithmetic_extremes() {
+    let scale = 10i128.pow(DECIMALS);
+    let values = [
+        0,
+        1,
+        scale,
+        10i128.pow(18),
+        i128::MAX / scale,
+        i128::MAX / scale + 1,
+        i128::MAX / 2,
+        i128::MAX,
+    ];
+
+    for amount in values {
+        for quote in values {
+            // Either the exact result or MathOverflow, never a wrapped or panicking value
+            let expected = amount
+                .checked_mul(quote)
+                .map(|product| product / scale)
+                .ok_or(VaultError::MathOverflow);
+            assert_eq!(quote_shares(amount, quote), expected);
+
+            if quote > 0 {
+                match pro_rata(quote, amount, amount) {
+                    Ok(value) => assert_eq!(value, quote),
+                    Err(err) => {
+                        assert_eq!(err, VaultError::MathOverflow);
+                        assert!(amount == 0 || quote.checked_mul(amount).is_none());
+                    }
+                }
+            }
+        }
+    }
+    assert_eq!(mul_div(1, 1, 0), Err(VaultError::MathOverflow));
+}
+
+#[test]
+fn test_deposit_overflow() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (vault, token_client) = setup_vault(&e, &admin);
+    let quote = 10i128.pow(30);
+    token_client.mint(&user, &10i128.pow(10));
+    vault.set_quote(&quote, &None);
+    assert_eq!(
+        vault.try_deposit(&user, &10i128.pow(10), &quote),
+        Err(Ok(VaultError::MathOverflow))
+    );
+    assert_eq!(vault.deposit(&user, &10i128.pow(7), &quote), 10i128.pow(30));
+    assert_eq!(vault.total_bonds(), 10i128.pow(30));
+}
+
+#[test]
+fn test_bond_certificate() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let custodian = Address::generate(&e);
+    let (vault, token_client) = setup_vault(&e, &admin);
+    let share_client = token::Client::new(&e, &vault.bond_id());
+    let certificate = bondhive_certificate::CertificateClient::new(
+        &e,
+        &e.register_contract(None, bondhive_certificate::Certificate),
+    );
+    certificate.initialize(&vault.address);
+    vault.set_quote(&10000000, &None);
+    token_client.mint(&user, &1000);
+    vault.deposit(&user, &1000, &10000000);
+
+    assert_eq!(vault.try_issue_certificate(&user, &600), Err(Ok(VaultError::InvalidCall)));
+    vault.set_certificate(&certificate.address, &500);
+    assert_eq!(
+        vault.certificate(),
+        Some(CertificateConfig {
+            certificate: certificate.address.clone(),
+            threshold: 500,
+        })
+    );
+    assert_eq!(vault.try_issue_certificate(&user, &400), Err(Ok(VaultError::InvalidAmount)));
+    assert_eq!(vault.try_issue_certificate(&user, &1100), Err(Ok(VaultError::InvalidAmount)));
+
+    // The shares are locked in the vault along with their cost basis
+    let id = vault.issue_certificate(&user, &600);
+    assert_eq!(
+        certificate.get_certificate(&id),
+        bondhive_certificate::BondCertificate {
+            owner: user.clone(),
+            series: String::from_str(&e, "BOND"),
+            maturity: e.ledger().timestamp() + 600,
+            locked: 600,
+        }
+    );
+    assert_eq!(share_client.balance(&user), 400);
+    assert_eq!(share_client.balance(&vault.address), 600);
+    assert_eq!(vault.get_holders(&0, &1).get(0).unwrap().cost_basis, 400);
+    assert_eq!(vault.total_bonds(), 1000);
+
+    // Once in custody only the custodian can redeem it
+    certificate.transfer(&user, &custodian, &id);
+    assert_eq!(vault.try_redeem_certificate(&user, &id), Err(Ok(VaultError::InvalidCall)));
+    assert_eq!(vault.redeem_certificate(&custodian, &id), 600);
+    assert_eq!(share_client.balance(&custodian), 600);
+    assert_eq!(share_client.balance(&vault.address), 0);
+    assert_eq!(vault.get_holders(&1, &1).get(0).unwrap().cost_basis, 600);
+
+    vault.set_certificate(&certificate.address, &0);
+    assert_eq!(vault.certificate(), None);
+}
+
+#[test]
+fn test_uniform_price_auction() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let bidder1 = Address::generate(&e);
+    let bidder2 = Address::generate(&e);
+    let bidder3 = Address::generate(&e);
+    let bidder4 = Address::generate(&e);
+    let token_client = create_token_contract(&e, &admin);
+    let vault = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+    let start = e.ledger().timestamp();
+    vault.initialize(
+        &install_token_wasm(&e),
+        &token_client.address,
+        &admin,
+        &start,
+        &(start + 600),
+        &300,
+        &admin,
+        &100,
+        &share_metadata(&e),
+        &AccountingMode::Auction,
+    );
+    for bidder in [&bidder1, &bidder2, &bidder3, &bidder4] {
+        token_client.mint(bidder, &1000);
+    }
+
+    assert_eq!(
+        vault.try_bid(&bidder1, &500, &10000000),
+        Err(Ok(VaultError::UnsupportedInMode))
+    );
+    vault.start_auction(&1000, &12000000, &(start + 300));
+    assert_eq!(
+        vault.try_bid(&bidder1, &500, &13000000),
+        Err(Ok(VaultError::QuoteOutOfBounds))
+    );
+    assert_eq!(
+        vault.try_deposit(&bidder1, &500, &10000000),
+        Err(Ok(VaultError::UnsupportedInMode))
+    );
+
+    vault.bid(&bidder1, &500, &10000000);
+    vault.bid(&bidder2, &400, &11000000);
+    vault.bid(&bidder3, &300, &12000000);
+    assert_eq!(vault.bid(&bidder4, &200, &10500000), 4);
+    assert_eq!(token_client.balance(&vault.address), 1400);
+
+    // The escrowed bids are not mistaken for donations
+    assert_eq!(vault.sync().surplus, 0);
+    assert_eq!(vault.try_settle_auction(), Err(Ok(VaultError::InvalidCall)));
+
+    // The bids limited to 1.1 shares per token demand the whole supply, so it clears there:
+    // the best two bids are filled, the third partly and the last one refunded
+    e.ledger().set_timestamp(start + 300);
+    assert_eq!(vault.try_bid(&bidder1, &500, &10000000), Err(Ok(VaultError::InvalidCall)));
+    assert_eq!(vault.settle_auction(), 11000000);
+    let share_client = token::Client::new(&e, &vault.bond_id());
+    assert_eq!(share_client.balance(&bidder1), 550);
+    assert_eq!(share_client.balance(&bidder4), 220);
+    assert_eq!(share_client.balance(&bidder2), 230);
+    assert_eq!(share_client.balance(&bidder3), 0);
+    assert_eq!(token_client.balance(&bidder2), 600 + 191);
+    assert_eq!(token_client.balance(&bidder3), 1000);
+    assert_eq!(token_client.balance(&admin), 909);
+    assert_eq!(token_client.balance(&vault.address), 0);
+    assert_eq!(vault.total_bonds(), 1000);
+    assert_eq!(vault.total_deposit(), 909);
+    assert_eq!(vault.auction().unwrap().clearing_quote, 11000000);
+    assert_eq!(vault.try_settle_auction(), Err(Ok(VaultError::SharesAlreadyIssued)));
+}
+
+#[test]
+fn test_auction_bid_limits() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let spammer = Address::generate(&e);
+    let bidder = Address::generate(&e);
+    let token_client = create_token_contract(&e, &admin);
+    let vault = VaultClient::new(&e, &e.register_contract(None, crate::Vault {}));
+    let start = e.ledger().timestamp();
+    vault.initialize(
+        &install_token_wasm(&e),
+        &token_client.address,
+        &admin,
+        &start,
+        &(start + 600),
+        &300,
+        &admin,
+        &0,
+        &share_metadata(&e),
+        &AccountingMode::Auction,
+    );
+    token_client.mint(&spammer, &1000);
+    token_client.mint(&bidder, &1000);
+    vault.start_auction(&1000, &12000000, &(start + 300));
+
+    // Without a minimum deposit, bids still have to demand 20 of the 1000 shares
+    assert_eq!(vault.try_bid(&spammer, &0, &12000000), Err(Ok(VaultError::InvalidAmount)));
+    assert_eq!(vault.try_bid(&spammer, &16, &12000000), Err(Ok(VaultError::InvalidAmount)));
+
+    e.budget().reset_unlimited();
+    for _ in 0..MAX_AUCTION_BIDS {
+        vault.bid(&spammer, &17, &12000000);
+    }
+    assert_eq!(
+        vault.try_bid(&bidder, &500, &12000000),
+        Err(Ok(VaultError::InvalidCall))
+    );
+
+    // A better bid takes the place of the worst one, which is refunded
+    assert_eq!(vault.bid(&bidder, &500, &11000000), MAX_AUCTION_BIDS);
+    assert_eq!(token_client.balance(&spammer), 1000 - 17 * 49);
+    assert_eq!(token_client.balance(&vault.address), 17 * 49 + 500);
+    assert_eq!(vault.auction_bids().last().unwrap().bidder, bidder);
+
+    // The escrowed bids move with the snapshot, and the successor settles them
+    let registry = migration::RegistryClient::new(&e, &e.register_contract(None, migration::Registry));
+    let successor = migration::SuccessorClient::new(&e, &e.register_contract(None, migration::Successor));
+    vault.set_migration_registry(&registry.address);
+    registry.approve(&successor.address);
+    let pending = vault.schedule_migration(&successor.address);
+    e.ledger().set_timestamp(pending.eta);
+    let snapshot = vault.emergency_migrate(&successor.address);
+    assert_eq!(snapshot.balance, 17 * 49 + 500);
+    assert_eq!(snapshot.auction_escrow, 17 * 49 + 500);
+    assert_eq!(vault.sync().surplus, 0);
+    assert_eq!(vault.try_settle_auction(), Err(Ok(VaultError::ContractStopped)));
+}
+
+#[test]
+fn test_cancel_offering_refunds() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+    let subscriber = Address::generate(&e);
+    let (vault, token_client) = setup_vault(&e, &admin);
+    let share_client = token::Client::new(&e, &vault.bond_id());
+    vault.set_quote(&10000000, &None);
+    token_client.mint(&user1, &1000);
+    token_client.mint(&user2, &1000);
+    token_client.mint(&subscriber, &1000);
+    vault.deposit(&user1, &200, &10000000);
+    vault.deposit(&user2, &600, &10000000);
+    vault.subscribe(&subscriber, &500, &10000000);
+    vault.pay_installment(&subscriber, &300);
+
+    // Only an offering short of its soft cap can be cancelled
+    assert_eq!(vault.try_cancel_offering(), Err(Ok(VaultError::InvalidCall)));
+    vault.set_soft_cap(&800);
+    assert_eq!(vault.try_cancel_offering(), Err(Ok(VaultError::InvalidCall)));
+    vault.set_soft_cap(&1000);
+    vault.cancel_offering();
+    assert_eq!(vault.state(), VaultState::Cancelled);
+    assert_eq!(
+        vault.try_deposit(&user1, &200, &10000000),
+        Err(Ok(VaultError::InvalidCall))
+    );
+
+    // Deposits went to the treasury, which returns them before holders are refunded. The
+    // escrowed installments are not theirs to take
+    assert_eq!(vault.try_refund(&user1), Err(Ok(VaultError::InsufficientReserve)));
+    vault.fund_refunds(&admin, &800);
+    assert_eq!(vault.refund(&user1), 200);
+
+    // Shares transferred after the deposit are refunded to their holder, not the depositor
+    let buyer = Address::generate(&e);
+    share_client.transfer(&user2, &buyer, &150);
+    assert_eq!(vault.refund(&buyer), 150);
+    assert_eq!(vault.refund(&user2), 450);
+    token_client.transfer(&buyer, &user2, &150);
+    assert_eq!(vault.refund_subscription(&subscriber), 300);
+    assert_eq!(token_client.balance(&subscriber), 1000);
+    assert_eq!(vault.try_refund(&user2), Err(Ok(VaultError::InvalidAmount)));
+    assert_eq!(token_client.balance(&user1), 1000);
+    assert_eq!(token_client.balance(&user2), 1000);
+    assert_eq!(share_client.balance(&user2), 0);
+    assert_eq!(vault.total_bonds(), 0);
+    assert_eq!(vault.total_deposit(), 0);
+    // Refunds leave the lifetime deposits untouched
+    assert_eq!(vault.stats().total_deposited_lifetime, 800);
+
+    // The series never settles
+    let cancelled_at = e.ledger().timestamp();
+    e.ledger().set_timestamp(cancelled_at + 600);
+    assert_eq!(vault.state(), VaultState::Cancelled);
+    assert_eq!(
+        vault.series_timeline(),
+        SeriesTimeline {
+            created_at: cancelled_at,
+            open_at: cancelled_at,
+            close_at: 0,
+            matured_at: 0,
+            settled_at: 0,
+            cancelled_at,
+        }
+    );
+    assert_eq!(vault.try_set_total_redemption(&0, &None), Err(Ok(VaultError::InvalidCall)));
+}
+
+#[test]
+fn test_operator_withdraw() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let holder = Address::generate(&e);
+    let operator = Address::generate(&e);
+    let custody = Address::generate(&e);
+    let (vault, token_client) = setup_vault(&e, &admin);
+    let share_client = token::Client::new(&e, &vault.bond_id());
+    vault.set_quote(&10000000, &None);
+    token_client.mint(&holder, &1000);
+    vault.deposit(&holder, &1000, &10000000);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 600);
+    token_client.mint(&admin, &1050);
+    vault.set_total_redemption(&1050, &None);
+
+    assert_eq!(
+        vault.try_operator_withdraw(&operator, &holder, &custody, &400),
+        Err(Ok(VaultError::InvalidSigner))
+    );
+    vault.set_operator(&holder, &operator, &true);
+    assert!(vault.is_operator(&holder, &operator));
+    share_client.approve(&holder, &vault.address, &400, &(e.ledger().sequence() + 100));
+
+    // The operator redeems under its own auth, against the allowance the holder gave the vault
+    assert_eq!(vault.operator_withdraw(&operator, &holder, &custody, &400), 420);
+    assert_eq!(e.auths().len(), 1);
+    assert_eq!(e.auths()[0].0, operator);
+    assert_eq!(token_client.balance(&custody), 420);
+    assert_eq!(share_client.balance(&holder), 600);
+
+    vault.set_operator(&holder, &operator, &false);
+    assert_eq!(
+        vault.try_operator_withdraw(&operator, &holder, &custody, &100),
+        Err(Ok(VaultError::InvalidSigner))
+    );
+    assert_eq!(vault.withdraw(&holder, &holder, &600), 630);
+}
+
+#[test]
+fn test_series_config_budget() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let (vault, token_client) = setup_vault(&e, &admin);
+    vault.set_quote(&10000000, &None);
+    token_client.mint(&user, &1000);
+    vault.deposit(&user, &100, &10000000);
+
+    // Setters write the stored configuration, which deposits read back
+    let treasury = Address::generate(&e);
+    vault.set_treasury(&treasury);
+
+    // Bounds sit about 10% above the measured costs, to catch regressions on the hot paths
+    e.budget().reset_default();
+    vault.deposit(&user, &100, &10000000);
+    assert!(e.budget().cpu_instruction_cost() < 2_080_000);
+    assert!(e.budget().memory_bytes_cost() < 1_610_000);
+    assert_eq!(token_client.balance(&treasury), 100);
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 600);
+    token_client.mint(&admin, &210);
+    vault.set_total_redemption(&210, &None);
+    e.budget().reset_default();
+    vault.withdraw(&user, &user, &100);
+    assert!(e.budget().cpu_instruction_cost() < 5_120_000);
+    assert!(e.budget().memory_bytes_cost() < 4_660_000);
+}
+
+#[test]
+fn test_subscription_installments() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let buyer = Address::generate(&e);
+    let other = Address::generate(&e);
+    let (vault, token_client) = setup_vault(&e, &admin);
+    let start = e.ledger().timestamp();
+    let share_client = token::Client::new(&e, &vault.bond_id());
+    vault.set_quote(&10000000, &None);
+    token_client.mint(&buyer, &1000);
+    token_client.mint(&other, &1000);
+
+    assert_eq!(vault.try_subscribe(&buyer, &50, &10000000), Err(Ok(VaultError::InvalidAmount)));
+    assert_eq!(vault.try_subscribe(&buyer, &600, &20000000), Err(Ok(VaultError::QuoteChanged)));
+    vault.subscribe(&buyer, &600, &10000000);
+    vault.subscribe(&other, &500, &10000000);
+    assert_eq!(vault.try_subscribe(&buyer, &600, &10000000), Err(Ok(VaultError::InvalidCall)));
+
+    // Installments are escrowed and issue nothing until the target is paid
+    vault.pay_installment(&buyer, &200);
+    vault.pay_installment(&other, &100);
+    assert_eq!(token_client.balance(&vault.address), 300);
+    assert_eq!(share_client.balance(&buyer), 0);
+    assert_eq!(vault.sync().surplus, 0);
+    assert_eq!(vault.try_pay_installment(&buyer, &500), Err(Ok(VaultError::InvalidAmount)));
+
+    // The last installment issues the shares at the quote of the commitment
+    e.ledger().set_timestamp(start + 301);
+    vault.set_quote(&20000000, &None);
+    let subscription = vault.pay_installment(&buyer, &400);
+    assert_eq!(subscription.paid, subscription.target);
+    assert_eq!(share_client.balance(&buyer), 600);
+    assert_eq!(vault.subscription(&buyer), None);
+    assert_eq!(vault.total_deposit(), 600);
+    assert_eq!(token_client.balance(&admin), 600);
+    assert_eq!(token_client.balance(&vault.address), 100);
+    assert_eq!(vault.sync().surplus, 0);
+
+    // An unfinished subscription is refundable once the series closes
+    assert_eq!(vault.try_refund_subscription(&other), Err(Ok(VaultError::InvalidCall)));
+    e.ledger().set_timestamp(start + 600);
+    assert_eq!(vault.try_pay_installment(&other, &400), Err(Ok(VaultError::InvalidCall)));
+    assert_eq!(vault.refund_subscription(&other), 100);
+    assert_eq!(token_client.balance(&other), 1000);
+    assert_eq!(token_client.balance(&vault.address), 0);
+    assert_eq!(vault.try_refund_subscription(&other), Err(Ok(VaultError::InvalidCall)));
+}
+
+#[test]
+fn test_idempotency_keys() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let holder = Address::generate(&e);
+    let (vault, token_client) = setup_vault(&e, &admin);
+    let key1 = Some(BytesN::from_array(&e, &[1; 32]));
+    let key2 = Some(BytesN::from_array(&e, &[2; 32]));
+    let key3 = Some(BytesN::from_array(&e, &[3; 32]));
+
+    // A retried quote is not applied again, even once the quote could be replaced
+    assert_eq!(vault.set_quote(&10000000, &key1), 10000000);
+    let start = e.ledger().timestamp();
+    e.ledger().set_timestamp(start + 301);
+    assert_eq!(vault.set_quote(&10000000, &key1), 10000000);
+    assert_eq!(vault.set_quote(&9000000, &key2), 9000000);
+    assert_eq!(vault.set_quote(&9000000, &key2), 9000000);
+    assert_eq!(vault.quote(), 9000000);
+
+    // Keys are scoped to their operation
+    vault.set_contract_stopped(&true, &key1);
+    vault.set_contract_stopped(&false, &key3);
+    vault.set_contract_stopped(&true, &key1);
+    assert!(vault.try_subscribe(&holder, &100, &9000000).is_ok());
+
+    e.ledger().set_timestamp(start + 600);
+    token_client.mint(&admin, &1050);
+    assert_eq!(vault.set_total_redemption(&1050, &key1), 1050);
+    token_client.mint(&admin, &1050);
+    assert_eq!(vault.set_total_redemption(&1050, &key1), 1050);
+    assert_eq!(token_client.balance(&admin), 1050);
+
+    // Keys expire after a day
+    e.ledger().with_mut(|li| li.sequence_number += DAY_IN_LEDGERS + 1);
+    vault.set_contract_stopped(&true, &key1);
+    assert_eq!(
+        vault.try_subscribe(&holder, &100, &9000000),
+        Err(Ok(VaultError::ContractStopped))
+    );
+}
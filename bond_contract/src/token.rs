@@ -1,3 +1,3 @@
 #![allow(unused)]
 
-soroban_sdk::contractimport!(file = "../soroban_token_contract.wasm");
\ No newline at end of file
+soroban_sdk::contractimport!(file = "../soroban_token_contract.wasm");
@@ -0,0 +1,228 @@
+#![no_std]
+
+use soroban_sdk::{
+    contract, contractclient, contracterror, contractimpl, contracttype, symbol_short, token,
+    Address, Env, String,
+};
+
+pub(crate) const DAY_IN_LEDGERS: u32 = 17280;
+pub(crate) const MAX_TTL: u32 = 3110400;
+
+#[derive(Clone)]
+#[contracttype]
+pub enum DataKey {
+    Vault,
+    Initialized,
+    OrderCounter,
+    Order(u32),
+}
+
+#[contracterror]
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum OtcError {
+    NotInitialized = 1,
+    AlreadyInitialized = 2,
+    InvalidAmount = 3,
+    OrderNotFound = 4,
+    OrderExpired = 5,
+}
+
+/// Mirror of the vault's `BondMetadata` view.
+#[derive(Clone)]
+#[contracttype]
+pub struct BondMetadata {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u32,
+    pub maturity: u64,
+    pub underlying: Address,
+}
+
+/// Subset of the vault interface the venue relies on.
+#[contractclient(name = "VaultClient")]
+pub trait VaultInterface {
+    fn bond_id(e: Env) -> Address;
+    fn bond_metadata(e: Env) -> BondMetadata;
+}
+
+/// Offer to sell `amount` bond shares, escrowed by the venue, for `price` of the underlying
+/// token until `expiry`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct SellOrder {
+    pub seller: Address,
+    pub amount: i128,
+    pub price: i128,
+    pub expiry: u64,
+}
+
+fn get_vault(e: &Env) -> Result<Address, OtcError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::Vault)
+        .ok_or(OtcError::NotInitialized)
+}
+
+fn put_vault(e: &Env, vault: &Address) {
+    e.storage().instance().set(&DataKey::Vault, vault);
+}
+
+fn is_initialized(e: &Env) -> bool {
+    e.storage()
+        .instance()
+        .get(&DataKey::Initialized)
+        .unwrap_or(0)
+        == 1
+}
+
+fn set_initialized(e: &Env) {
+    e.storage().instance().set(&DataKey::Initialized, &1);
+}
+
+fn get_order(e: &Env, order_id: u32) -> Result<SellOrder, OtcError> {
+    e.storage()
+        .persistent()
+        .get(&DataKey::Order(order_id))
+        .ok_or(OtcError::OrderNotFound)
+}
+
+fn remove_order(e: &Env, order_id: u32) {
+    e.storage().persistent().remove(&DataKey::Order(order_id));
+}
+
+fn extend_instance_ttl(e: &Env) {
+    e.storage()
+        .instance()
+        .extend_ttl(MAX_TTL - DAY_IN_LEDGERS, MAX_TTL)
+}
+
+/// Client of the bond share token of the vault.
+fn share_token(e: &Env) -> Result<token::Client<'_>, OtcError> {
+    let vault = VaultClient::new(e, &get_vault(e)?);
+    Ok(token::Client::new(e, &vault.bond_id()))
+}
+
+#[contract]
+pub struct BondOtc;
+
+#[contractimpl]
+impl BondOtc {
+    pub fn initialize(e: &Env, vault: Address) -> Result<String, OtcError> {
+        if is_initialized(e) {
+            return Err(OtcError::AlreadyInitialized);
+        }
+
+        put_vault(e, &vault);
+        set_initialized(e);
+
+        e.events()
+            .publish((symbol_short!("Init"), vault.clone()), vault);
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Escrows `amount` of `seller`'s bond shares and offers them for `price` of the underlying
+    /// token until `expiry`. Returns the id of the order.
+    pub fn create_sell_order(
+        e: &Env,
+        seller: Address,
+        amount: i128,
+        price: i128,
+        expiry: u64,
+    ) -> Result<u32, OtcError> {
+        seller.require_auth();
+        extend_instance_ttl(e);
+
+        if amount <= 0 || price <= 0 {
+            return Err(OtcError::InvalidAmount);
+        }
+        if expiry <= e.ledger().timestamp() {
+            return Err(OtcError::OrderExpired);
+        }
+
+        share_token(e)?.transfer(&seller, &e.current_contract_address(), &amount);
+
+        let order_id: u32 = e.storage().instance().get(&DataKey::OrderCounter).unwrap_or(0);
+        e.storage().instance().set(&DataKey::OrderCounter, &(order_id + 1));
+        let order = SellOrder {
+            seller: seller.clone(),
+            amount,
+            price,
+            expiry,
+        };
+        e.storage().persistent().set(&DataKey::Order(order_id), &order);
+
+        e.events().publish(
+            (symbol_short!("Order"), seller),
+            (order_id, amount, price, expiry),
+        );
+
+        Ok(order_id)
+    }
+
+    /// Settles an order delivery-versus-payment: `buyer` pays the price to the seller and
+    /// receives the escrowed shares in the same transaction. Returns the shares delivered.
+    pub fn fill_order(e: &Env, buyer: Address, order_id: u32) -> Result<i128, OtcError> {
+        buyer.require_auth();
+        extend_instance_ttl(e);
+
+        let order = get_order(e, order_id)?;
+        if e.ledger().timestamp() >= order.expiry {
+            return Err(OtcError::OrderExpired);
+        }
+        remove_order(e, order_id);
+
+        let vault = VaultClient::new(e, &get_vault(e)?);
+        token::Client::new(e, &vault.bond_metadata().underlying).transfer(
+            &buyer,
+            &order.seller,
+            &order.price,
+        );
+        token::Client::new(e, &vault.bond_id()).transfer(
+            &e.current_contract_address(),
+            &buyer,
+            &order.amount,
+        );
+
+        e.events().publish(
+            (symbol_short!("Fill"), buyer),
+            (order_id, order.seller, order.amount, order.price),
+        );
+
+        Ok(order.amount)
+    }
+
+    /// Returns the escrowed shares of an order to the seller. Until the order expires only the
+    /// seller can cancel it, anyone can afterwards.
+    pub fn cancel_order(e: &Env, order_id: u32) -> Result<i128, OtcError> {
+        extend_instance_ttl(e);
+
+        let order = get_order(e, order_id)?;
+        if e.ledger().timestamp() < order.expiry {
+            order.seller.require_auth();
+        }
+        remove_order(e, order_id);
+
+        share_token(e)?.transfer(&e.current_contract_address(), &order.seller, &order.amount);
+
+        e.events()
+            .publish((symbol_short!("Cancel"), order.seller), order_id);
+
+        Ok(order.amount)
+    }
+
+    /// Public function to query an open order.
+    pub fn get_order(e: &Env, order_id: u32) -> Result<SellOrder, OtcError> {
+        extend_instance_ttl(e);
+        get_order(e, order_id)
+    }
+
+    /// Public function to query the vault whose bond shares are traded.
+    pub fn vault(e: &Env) -> Result<Address, OtcError> {
+        extend_instance_ttl(e);
+        get_vault(e)
+    }
+}
+
+mod test;
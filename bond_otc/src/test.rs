@@ -0,0 +1,121 @@
+#![cfg(test)]
+extern crate std;
+
+use super::*;
+use soroban_sdk::{
+    testutils::{Address as _, Ledger},
+    token, Address, Env, String,
+};
+
+#[contract]
+struct MockVault;
+
+#[contractimpl]
+impl MockVault {
+    pub fn setup(e: Env, bond: Address, underlying: Address) {
+        e.storage().instance().set(&symbol_short!("bond"), &bond);
+        e.storage()
+            .instance()
+            .set(&symbol_short!("under"), &underlying);
+    }
+
+    pub fn bond_id(e: Env) -> Address {
+        e.storage().instance().get(&symbol_short!("bond")).unwrap()
+    }
+
+    pub fn bond_metadata(e: Env) -> BondMetadata {
+        BondMetadata {
+            name: String::from_str(&e, "Bond Hive"),
+            symbol: String::from_str(&e, "BOND"),
+            decimals: 7,
+            maturity: 1735290000,
+            underlying: e.storage().instance().get(&symbol_short!("under")).unwrap(),
+        }
+    }
+}
+
+fn create_token_contract<'a>(
+    e: &Env,
+    admin: &Address,
+) -> (token::Client<'a>, token::StellarAssetClient<'a>) {
+    let sac = e.register_stellar_asset_contract(admin.clone());
+    (
+        token::Client::new(e, &sac),
+        token::StellarAssetClient::new(e, &sac),
+    )
+}
+
+#[test]
+fn test_sell_order_settles_dvp() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().set_timestamp(1727424000);
+
+    let admin = Address::generate(&e);
+    let seller = Address::generate(&e);
+    let buyer = Address::generate(&e);
+
+    let (bond_client, bond_admin) = create_token_contract(&e, &admin);
+    let (underlying_client, underlying_admin) = create_token_contract(&e, &admin);
+    bond_admin.mint(&seller, &1000);
+    underlying_admin.mint(&buyer, &1000);
+
+    let vault = MockVaultClient::new(&e, &e.register_contract(None, MockVault {}));
+    vault.setup(&bond_client.address, &underlying_client.address);
+    let otc = BondOtcClient::new(&e, &e.register_contract(None, BondOtc {}));
+    otc.initialize(&vault.address);
+
+    assert_eq!(
+        otc.try_create_sell_order(&seller, &600, &0, &1727424100),
+        Err(Ok(OtcError::InvalidAmount))
+    );
+    assert_eq!(
+        otc.try_create_sell_order(&seller, &600, &630, &1727424000),
+        Err(Ok(OtcError::OrderExpired))
+    );
+
+    // The shares are escrowed until the order is filled
+    let order_id = otc.create_sell_order(&seller, &600, &630, &1727424100);
+    assert_eq!(bond_client.balance(&seller), 400);
+    assert_eq!(bond_client.balance(&otc.address), 600);
+    assert_eq!(
+        otc.get_order(&order_id),
+        SellOrder {
+            seller: seller.clone(),
+            amount: 600,
+            price: 630,
+            expiry: 1727424100,
+        }
+    );
+
+    assert_eq!(otc.fill_order(&buyer, &order_id), 600);
+    assert_eq!(bond_client.balance(&buyer), 600);
+    assert_eq!(underlying_client.balance(&seller), 630);
+    assert_eq!(underlying_client.balance(&buyer), 370);
+    assert_eq!(otc.try_get_order(&order_id), Err(Ok(OtcError::OrderNotFound)));
+    assert_eq!(
+        otc.try_fill_order(&buyer, &order_id),
+        Err(Ok(OtcError::OrderNotFound))
+    );
+
+    // An expired order can no longer be filled and is returned to the seller
+    let order_id = otc.create_sell_order(&seller, &400, &420, &1727424100);
+    e.ledger().set_timestamp(1727424100);
+    assert_eq!(
+        otc.try_fill_order(&buyer, &order_id),
+        Err(Ok(OtcError::OrderExpired))
+    );
+    assert_eq!(otc.cancel_order(&order_id), 400);
+    assert_eq!(bond_client.balance(&seller), 400);
+    assert_eq!(bond_client.balance(&otc.address), 0);
+}
+
+#[test]
+#[should_panic(expected = "HostError: Error(Contract, #2)")]
+fn test_not_double_initialization() {
+    let e = Env::default();
+
+    let otc = BondOtcClient::new(&e, &e.register_contract(None, BondOtc {}));
+    otc.initialize(&Address::generate(&e));
+    otc.initialize(&Address::generate(&e));
+}
@@ -0,0 +1,203 @@
+#![no_std]
+
+use soroban_sdk::{
+    contract, contracterror, contractimpl, contracttype, symbol_short, Address, Env, String,
+};
+
+pub(crate) const DAY_IN_LEDGERS: u32 = 17280;
+pub(crate) const MAX_TTL: u32 = 3110400;
+
+#[derive(Clone)]
+#[contracttype]
+pub enum DataKey {
+    Minter,
+    Initialized,
+    CertificateCounter,
+    Certificate(u32),
+}
+
+#[contracterror]
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum CertificateError {
+    NotInitialized = 1,
+    AlreadyInitialized = 2,
+    InvalidAmount = 3,
+    CertificateNotFound = 4,
+    NotOwner = 5,
+}
+
+/// Non-fungible certificate for `locked` bond shares of the series `series` maturing at
+/// `maturity`, held by `owner`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct BondCertificate {
+    pub owner: Address,
+    pub series: String,
+    pub maturity: u64,
+    pub locked: i128,
+}
+
+fn get_minter(e: &Env) -> Result<Address, CertificateError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::Minter)
+        .ok_or(CertificateError::NotInitialized)
+}
+
+fn is_initialized(e: &Env) -> bool {
+    e.storage()
+        .instance()
+        .get(&DataKey::Initialized)
+        .unwrap_or(0)
+        == 1
+}
+
+fn set_initialized(e: &Env) {
+    e.storage().instance().set(&DataKey::Initialized, &1);
+}
+
+// Certificates stand for shares locked in the vault, so their entries are kept alive on every
+// access rather than left to expire
+fn get_certificate(e: &Env, id: u32) -> Result<BondCertificate, CertificateError> {
+    let key = DataKey::Certificate(id);
+    let certificate = e
+        .storage()
+        .persistent()
+        .get(&key)
+        .ok_or(CertificateError::CertificateNotFound)?;
+    extend_certificate_ttl(e, id);
+    Ok(certificate)
+}
+
+fn put_certificate(e: &Env, id: u32, certificate: &BondCertificate) {
+    e.storage()
+        .persistent()
+        .set(&DataKey::Certificate(id), certificate);
+    extend_certificate_ttl(e, id);
+}
+
+fn extend_certificate_ttl(e: &Env, id: u32) {
+    e.storage()
+        .persistent()
+        .extend_ttl(&DataKey::Certificate(id), MAX_TTL - DAY_IN_LEDGERS, MAX_TTL);
+}
+
+fn extend_instance_ttl(e: &Env) {
+    e.storage()
+        .instance()
+        .extend_ttl(MAX_TTL - DAY_IN_LEDGERS, MAX_TTL)
+}
+
+#[contract]
+pub struct Certificate;
+
+#[contractimpl]
+impl Certificate {
+    /// `minter` is the vault locking the bond shares the certificates stand for.
+    pub fn initialize(e: &Env, minter: Address) -> Result<String, CertificateError> {
+        if is_initialized(e) {
+            return Err(CertificateError::AlreadyInitialized);
+        }
+
+        e.storage().instance().set(&DataKey::Minter, &minter);
+        set_initialized(e);
+
+        e.events()
+            .publish((symbol_short!("Init"), minter.clone()), minter);
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Mints a certificate for `locked` bond shares of `series` to `to`. Only the minter can
+    /// mint. Returns the id of the certificate.
+    pub fn mint(
+        e: &Env,
+        to: Address,
+        series: String,
+        maturity: u64,
+        locked: i128,
+    ) -> Result<u32, CertificateError> {
+        get_minter(e)?.require_auth();
+        extend_instance_ttl(e);
+
+        if locked <= 0 {
+            return Err(CertificateError::InvalidAmount);
+        }
+
+        let id: u32 = e
+            .storage()
+            .instance()
+            .get(&DataKey::CertificateCounter)
+            .unwrap_or(0);
+        e.storage()
+            .instance()
+            .set(&DataKey::CertificateCounter, &(id + 1));
+        put_certificate(
+            e,
+            id,
+            &BondCertificate {
+                owner: to.clone(),
+                series,
+                maturity,
+                locked,
+            },
+        );
+
+        e.events()
+            .publish((symbol_short!("Mint"), to), (id, maturity, locked));
+
+        Ok(id)
+    }
+
+    /// Burns a certificate once the minter has released the shares it stands for.
+    pub fn burn(e: &Env, id: u32) -> Result<BondCertificate, CertificateError> {
+        get_minter(e)?.require_auth();
+        extend_instance_ttl(e);
+
+        let certificate = get_certificate(e, id)?;
+        e.storage().persistent().remove(&DataKey::Certificate(id));
+
+        e.events()
+            .publish((symbol_short!("Burn"), certificate.owner.clone()), id);
+
+        Ok(certificate)
+    }
+
+    /// Transfers a certificate, e.g. into custody.
+    pub fn transfer(e: &Env, from: Address, to: Address, id: u32) -> Result<String, CertificateError> {
+        from.require_auth();
+        extend_instance_ttl(e);
+
+        let mut certificate = get_certificate(e, id)?;
+        if certificate.owner != from {
+            return Err(CertificateError::NotOwner);
+        }
+        certificate.owner = to.clone();
+        put_certificate(e, id, &certificate);
+
+        e.events()
+            .publish((symbol_short!("Transfer"), from), (to, id));
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Public function to query a certificate.
+    pub fn get_certificate(e: &Env, id: u32) -> Result<BondCertificate, CertificateError> {
+        extend_instance_ttl(e);
+        get_certificate(e, id)
+    }
+
+    /// Public function to query the owner of a certificate.
+    pub fn owner_of(e: &Env, id: u32) -> Result<Address, CertificateError> {
+        extend_instance_ttl(e);
+        Ok(get_certificate(e, id)?.owner)
+    }
+
+    /// Public function to query the minter address.
+    pub fn minter(e: &Env) -> Result<Address, CertificateError> {
+        get_minter(e)
+    }
+}
+
+mod test;
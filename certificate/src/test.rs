@@ -0,0 +1,76 @@
+#![cfg(test)]
+extern crate std;
+
+use super::*;
+use soroban_sdk::{
+    testutils::{storage::Persistent as _, Address as _, Ledger},
+    Address, Env, String,
+};
+
+#[test]
+fn test_mint_transfer_burn() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let vault = Address::generate(&e);
+    let holder = Address::generate(&e);
+    let custodian = Address::generate(&e);
+
+    let certificate = CertificateClient::new(&e, &e.register_contract(None, Certificate {}));
+    certificate.initialize(&vault);
+
+    let series = String::from_str(&e, "BTC_Dec_24");
+    assert_eq!(
+        certificate.try_mint(&holder, &series, &1735290000, &0),
+        Err(Ok(CertificateError::InvalidAmount))
+    );
+    let id = certificate.mint(&holder, &series, &1735290000, &5000);
+    assert_eq!(e.auths()[0].0, vault);
+    assert_eq!(
+        certificate.get_certificate(&id),
+        BondCertificate {
+            owner: holder.clone(),
+            series: series.clone(),
+            maturity: 1735290000,
+            locked: 5000,
+        }
+    );
+
+    // Only the owner can move a certificate
+    assert_eq!(
+        certificate.try_transfer(&custodian, &holder, &id),
+        Err(Ok(CertificateError::NotOwner))
+    );
+    certificate.transfer(&holder, &custodian, &id);
+    assert_eq!(certificate.owner_of(&id), custodian);
+
+    // Reads keep the certificate alive as well as writes
+    let ttl = || {
+        e.as_contract(&certificate.address, || {
+            e.storage().persistent().get_ttl(&DataKey::Certificate(id))
+        })
+    };
+    assert_eq!(ttl(), MAX_TTL);
+    e.ledger().set_sequence_number(e.ledger().sequence() + DAY_IN_LEDGERS + 1);
+    assert!(ttl() < MAX_TTL);
+    certificate.owner_of(&id);
+    assert_eq!(ttl(), MAX_TTL);
+
+    assert_eq!(certificate.burn(&id).locked, 5000);
+    assert_eq!(e.auths()[0].0, vault);
+    assert_eq!(
+        certificate.try_owner_of(&id),
+        Err(Ok(CertificateError::CertificateNotFound))
+    );
+    assert_eq!(certificate.mint(&holder, &series, &1735290000, &100), id + 1);
+}
+
+#[test]
+#[should_panic(expected = "HostError: Error(Contract, #2)")]
+fn test_not_double_initialization() {
+    let e = Env::default();
+
+    let certificate = CertificateClient::new(&e, &e.register_contract(None, Certificate {}));
+    certificate.initialize(&Address::generate(&e));
+    certificate.initialize(&Address::generate(&e));
+}
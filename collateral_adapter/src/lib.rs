@@ -0,0 +1,192 @@
+#![no_std]
+
+use soroban_sdk::{
+    contract, contractclient, contracterror, contractimpl, contracttype, symbol_short, Address,
+    Env, String, Symbol,
+};
+
+pub(crate) const DAY_IN_LEDGERS: u32 = 17280;
+pub(crate) const MAX_TTL: u32 = 3110400;
+pub(crate) const DECIMALS: u32 = 7;
+
+#[derive(Clone, Copy)]
+#[contracttype]
+pub enum DataKey {
+    Vault = 0,
+    Initialized = 1,
+}
+
+#[contracterror]
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum AdapterError {
+    NotInitialized = 1,
+    AlreadyInitialized = 2,
+    PriceUnavailable = 3,
+}
+
+/// Asset identifier as used by SEP-40 price feeds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum Asset {
+    Stellar(Address),
+    Other(Symbol),
+}
+
+/// Price record as used by SEP-40 price feeds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PriceData {
+    pub price: i128,
+    pub timestamp: u64,
+}
+
+/// Mirror of the vault's `BondMetadata` view.
+#[derive(Clone)]
+#[contracttype]
+pub struct BondMetadata {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u32,
+    pub maturity: u64,
+    pub underlying: Address,
+}
+
+/// Subset of the vault interface the adapter relies on.
+#[contractclient(name = "VaultClient")]
+pub trait VaultInterface {
+    fn bond_id(e: Env) -> Address;
+    fn bond_metadata(e: Env) -> BondMetadata;
+    fn quote(e: Env) -> i128;
+    fn total_deposit(e: Env) -> i128;
+    fn total_bonds(e: Env) -> i128;
+    fn available_redemption(e: Env) -> i128;
+    fn maturity(e: Env) -> u64;
+}
+
+fn get_vault(e: &Env) -> Result<Address, AdapterError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::Vault)
+        .ok_or(AdapterError::NotInitialized)
+}
+
+fn put_vault(e: &Env, vault: &Address) {
+    e.storage().instance().set(&DataKey::Vault, vault);
+}
+
+fn is_initialized(e: &Env) -> bool {
+    e.storage()
+        .instance()
+        .get(&DataKey::Initialized)
+        .unwrap_or(0)
+        == 1
+}
+
+fn set_initialized(e: &Env) {
+    e.storage().instance().set(&DataKey::Initialized, &1);
+}
+
+fn extend_instance_ttl(e: &Env) {
+    e.storage()
+        .instance()
+        .extend_ttl(MAX_TTL - DAY_IN_LEDGERS, MAX_TTL)
+}
+
+/// Value of one bond share in the underlying token, scaled by 10^DECIMALS.
+///
+/// Once the redemption is funded a share is worth its pro-rata part of it. Before that it is
+/// valued at the price it was issued at, falling back to the current quote while no bond is out.
+fn share_price(e: &Env) -> Result<i128, AdapterError> {
+    let vault = VaultClient::new(e, &get_vault(e)?);
+    let scale = 10i128.pow(DECIMALS);
+
+    let total_bonds = vault.total_bonds();
+    if total_bonds > 0 {
+        let available_redemption = vault.available_redemption();
+        if available_redemption > 0 {
+            return Ok(available_redemption * scale / total_bonds);
+        }
+        return Ok(vault.total_deposit() * scale / total_bonds);
+    }
+
+    let quote = vault.quote();
+    if quote > 0 {
+        Ok(scale * scale / quote)
+    } else {
+        Err(AdapterError::PriceUnavailable)
+    }
+}
+
+#[contract]
+pub struct CollateralAdapter;
+
+#[contractimpl]
+impl CollateralAdapter {
+    pub fn initialize(e: &Env, vault: Address) -> Result<String, AdapterError> {
+        if is_initialized(e) {
+            return Err(AdapterError::AlreadyInitialized);
+        }
+
+        put_vault(e, &vault);
+        set_initialized(e);
+
+        e.events()
+            .publish((symbol_short!("Init"), vault.clone()), vault);
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Public function to query the value of one bond share in the underlying token.
+    pub fn price(e: &Env) -> Result<i128, AdapterError> {
+        extend_instance_ttl(e);
+        share_price(e)
+    }
+
+    /// Public function to query the token the bond is denominated in.
+    pub fn underlying(e: &Env) -> Result<Address, AdapterError> {
+        extend_instance_ttl(e);
+        Ok(VaultClient::new(e, &get_vault(e)?)
+            .bond_metadata()
+            .underlying)
+    }
+
+    /// Public function to query the bond maturity timestamp.
+    pub fn maturity(e: &Env) -> Result<u64, AdapterError> {
+        extend_instance_ttl(e);
+        Ok(VaultClient::new(e, &get_vault(e)?).maturity())
+    }
+
+    /// Public function to query the bond share token address.
+    pub fn bond(e: &Env) -> Result<Address, AdapterError> {
+        extend_instance_ttl(e);
+        Ok(VaultClient::new(e, &get_vault(e)?).bond_id())
+    }
+
+    /// SEP-40: asset prices are quoted in.
+    pub fn base(e: &Env) -> Result<Asset, AdapterError> {
+        Self::underlying(e).map(Asset::Stellar)
+    }
+
+    /// SEP-40: number of decimals of the quoted prices.
+    pub fn decimals(_e: &Env) -> u32 {
+        DECIMALS
+    }
+
+    /// SEP-40: latest price of `asset`, only the bond share token is priced.
+    pub fn lastprice(e: &Env, asset: Asset) -> Result<Option<PriceData>, AdapterError> {
+        extend_instance_ttl(e);
+
+        let bond = VaultClient::new(e, &get_vault(e)?).bond_id();
+        if asset != Asset::Stellar(bond) {
+            return Ok(None);
+        }
+
+        Ok(Some(PriceData {
+            price: share_price(e)?,
+            timestamp: e.ledger().timestamp(),
+        }))
+    }
+}
+
+mod test;
@@ -0,0 +1,134 @@
+#![cfg(test)]
+extern crate std;
+
+use super::*;
+use soroban_sdk::{
+    testutils::{Address as _, Ledger},
+    Address, Env, String,
+};
+
+#[contract]
+struct MockVault;
+
+#[contractimpl]
+impl MockVault {
+    pub fn setup(
+        e: Env,
+        bond: Address,
+        underlying: Address,
+        quote: i128,
+        total_deposit: i128,
+        total_bonds: i128,
+        available_redemption: i128,
+    ) {
+        e.storage().instance().set(&symbol_short!("bond"), &bond);
+        e.storage()
+            .instance()
+            .set(&symbol_short!("under"), &underlying);
+        e.storage().instance().set(&symbol_short!("quote"), &quote);
+        e.storage()
+            .instance()
+            .set(&symbol_short!("deposit"), &total_deposit);
+        e.storage()
+            .instance()
+            .set(&symbol_short!("bonds"), &total_bonds);
+        e.storage()
+            .instance()
+            .set(&symbol_short!("redeem"), &available_redemption);
+    }
+
+    pub fn bond_id(e: Env) -> Address {
+        e.storage().instance().get(&symbol_short!("bond")).unwrap()
+    }
+
+    pub fn bond_metadata(e: Env) -> BondMetadata {
+        BondMetadata {
+            name: String::from_str(&e, "Bond Hive"),
+            symbol: String::from_str(&e, "BOND"),
+            decimals: 7,
+            maturity: 1735290000,
+            underlying: e.storage().instance().get(&symbol_short!("under")).unwrap(),
+        }
+    }
+
+    pub fn quote(e: Env) -> i128 {
+        e.storage().instance().get(&symbol_short!("quote")).unwrap()
+    }
+
+    pub fn total_deposit(e: Env) -> i128 {
+        e.storage()
+            .instance()
+            .get(&symbol_short!("deposit"))
+            .unwrap()
+    }
+
+    pub fn total_bonds(e: Env) -> i128 {
+        e.storage().instance().get(&symbol_short!("bonds")).unwrap()
+    }
+
+    pub fn available_redemption(e: Env) -> i128 {
+        e.storage()
+            .instance()
+            .get(&symbol_short!("redeem"))
+            .unwrap()
+    }
+
+    pub fn maturity(_e: Env) -> u64 {
+        1735290000
+    }
+}
+
+#[test]
+fn test_adapter_prices_bond() {
+    let e = Env::default();
+    e.ledger().set_timestamp(1727424000);
+
+    let bond = Address::generate(&e);
+    let underlying = Address::generate(&e);
+
+    let vault = MockVaultClient::new(&e, &e.register_contract(None, MockVault {}));
+    let adapter =
+        CollateralAdapterClient::new(&e, &e.register_contract(None, CollateralAdapter {}));
+    adapter.initialize(&vault.address);
+
+    // No bond issued yet and no quote: no price
+    vault.setup(&bond, &underlying, &0, &0, &0, &0);
+    assert_eq!(adapter.try_price(), Err(Ok(AdapterError::PriceUnavailable)));
+
+    // Priced from the quote while no bond is outstanding
+    vault.setup(&bond, &underlying, &12500000, &0, &0, &0);
+    assert_eq!(adapter.price(), 8000000);
+
+    // Priced at the issue price once bonds are out
+    vault.setup(&bond, &underlying, &12500000, &800, &1000, &0);
+    assert_eq!(adapter.price(), 8000000);
+
+    // Priced at the redemption value once funded
+    vault.setup(&bond, &underlying, &0, &800, &1000, &1100);
+    assert_eq!(adapter.price(), 11000000);
+
+    assert_eq!(adapter.underlying(), underlying);
+    assert_eq!(adapter.maturity(), 1735290000);
+    assert_eq!(adapter.bond(), bond);
+    assert_eq!(adapter.decimals(), 7);
+    assert_eq!(adapter.base(), Asset::Stellar(underlying.clone()));
+    assert_eq!(
+        adapter.lastprice(&Asset::Stellar(bond)),
+        Some(PriceData {
+            price: 11000000,
+            timestamp: 1727424000
+        })
+    );
+    assert_eq!(adapter.lastprice(&Asset::Stellar(underlying)), None);
+}
+
+#[test]
+#[should_panic(expected = "HostError: Error(Contract, #2)")]
+fn test_not_double_initialization() {
+    let e = Env::default();
+
+    let adapter =
+        CollateralAdapterClient::new(&e, &e.register_contract(None, CollateralAdapter {}));
+    adapter.initialize(&Address::generate(&e));
+    adapter.initialize(&Address::generate(&e));
+}
@@ -0,0 +1,287 @@
+#![no_std]
+
+use soroban_sdk::{
+    contract, contractclient, contracterror, contractimpl, contracttype, symbol_short, token,
+    Address, Env, String, Vec,
+};
+
+pub(crate) const DAY_IN_LEDGERS: u32 = 17280;
+pub(crate) const MAX_TTL: u32 = 3110400;
+pub(crate) const MAX_STREAMS: u32 = 10;
+
+#[derive(Clone)]
+#[contracttype]
+pub enum DataKey {
+    Admin,
+    Token,
+    Streams,
+    Initialized,
+}
+
+#[contracterror]
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum EmitterError {
+    NotInitialized = 1,
+    AlreadyInitialized = 2,
+    InvalidAmount = 3,
+    StreamLimit = 4,
+    StreamNotFound = 5,
+    StreamOutstanding = 6,
+}
+
+/// Schedule streaming `rate` tokens per ledger to `farm` from `start_ledger` until
+/// `end_ledger`. `last_ledger` is the ledger up to which the stream has been paid.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Stream {
+    pub farm: Address,
+    pub rate: i128,
+    pub start_ledger: u32,
+    pub end_ledger: u32,
+    pub last_ledger: u32,
+}
+
+/// Subset of the farm interface the emitter relies on.
+#[contractclient(name = "FarmClient")]
+pub trait FarmInterface {
+    fn notify_reward(e: Env, amount: i128) -> (i128, i128);
+}
+
+fn get_admin(e: &Env) -> Result<Address, EmitterError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::Admin)
+        .ok_or(EmitterError::NotInitialized)
+}
+
+fn get_token(e: &Env) -> Result<Address, EmitterError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::Token)
+        .ok_or(EmitterError::NotInitialized)
+}
+
+fn get_streams(e: &Env) -> Vec<Stream> {
+    e.storage()
+        .instance()
+        .get(&DataKey::Streams)
+        .unwrap_or(Vec::new(e))
+}
+
+fn put_streams(e: &Env, streams: &Vec<Stream>) {
+    e.storage().instance().set(&DataKey::Streams, streams);
+}
+
+/// What `stream` has still to be paid until its end ledger.
+fn remaining(stream: &Stream) -> i128 {
+    stream.rate * stream.end_ledger.saturating_sub(stream.last_ledger) as i128
+}
+
+fn is_initialized(e: &Env) -> bool {
+    e.storage()
+        .instance()
+        .get(&DataKey::Initialized)
+        .unwrap_or(0)
+        == 1
+}
+
+fn set_initialized(e: &Env) {
+    e.storage().instance().set(&DataKey::Initialized, &1);
+}
+
+fn extend_instance_ttl(e: &Env) {
+    e.storage()
+        .instance()
+        .extend_ttl(MAX_TTL - DAY_IN_LEDGERS, MAX_TTL)
+}
+
+#[contract]
+pub struct Emitter;
+
+#[contractimpl]
+impl Emitter {
+    pub fn initialize(e: &Env, admin: Address, token: Address) -> Result<String, EmitterError> {
+        if is_initialized(e) {
+            return Err(EmitterError::AlreadyInitialized);
+        }
+
+        e.storage().instance().set(&DataKey::Admin, &admin);
+        e.storage().instance().set(&DataKey::Token, &token);
+        set_initialized(e);
+
+        e.events()
+            .publish((symbol_short!("Init"), admin), token);
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Streams `rate` tokens per ledger to `farm` from `start_ledger` until `end_ledger`, up to
+    /// MAX_STREAMS farms. The farm must accept the emitter through `set_emitter`.
+    pub fn add_stream(
+        e: &Env,
+        farm: Address,
+        rate: i128,
+        start_ledger: u32,
+        end_ledger: u32,
+    ) -> Result<String, EmitterError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        if rate <= 0 || end_ledger <= start_ledger {
+            return Err(EmitterError::InvalidAmount);
+        }
+        let mut streams = get_streams(e);
+        if streams.len() >= MAX_STREAMS {
+            return Err(EmitterError::StreamLimit);
+        }
+        if streams.iter().any(|stream| stream.farm == farm) {
+            return Err(EmitterError::InvalidAmount);
+        }
+
+        streams.push_back(Stream {
+            farm: farm.clone(),
+            rate,
+            start_ledger,
+            end_ledger,
+            last_ledger: start_ledger,
+        });
+        put_streams(e, &streams);
+
+        e.events().publish(
+            (symbol_short!("Stream"), farm),
+            (rate, start_ledger, end_ledger),
+        );
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Stops streaming to `farm` once it has been paid the whole stream, since the farm may
+    /// have allocated rewards against what is still to come.
+    pub fn remove_stream(e: &Env, farm: Address) -> Result<String, EmitterError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        let mut streams = get_streams(e);
+        let index = streams
+            .iter()
+            .position(|stream| stream.farm == farm)
+            .ok_or(EmitterError::StreamNotFound)?;
+        if remaining(&streams.get_unchecked(index as u32)) > 0 {
+            return Err(EmitterError::StreamOutstanding);
+        }
+        streams.remove(index as u32);
+        put_streams(e, &streams);
+
+        e.events()
+            .publish((symbol_short!("Unstream"), admin), farm);
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Pays every stream what it has accrued since it was last paid and notifies the farms.
+    /// Anyone can call it. Once the budget runs short, streams are paid for the whole ledgers
+    /// it still covers. A farm rejecting the notification is skipped, without holding up the
+    /// other streams. Returns the total amount paid.
+    pub fn drip(e: &Env) -> Result<i128, EmitterError> {
+        extend_instance_ttl(e);
+
+        let token_client = token::Client::new(e, &get_token(e)?);
+        let mut balance = token_client.balance(&e.current_contract_address());
+        let current_ledger = e.ledger().sequence();
+        let mut streams = get_streams(e);
+        let mut total = 0;
+
+        for (index, mut stream) in streams.clone().iter().enumerate() {
+            let to = core::cmp::min(current_ledger, stream.end_ledger);
+            if to <= stream.last_ledger {
+                continue;
+            }
+            let ledgers = core::cmp::min((to - stream.last_ledger) as i128, balance / stream.rate);
+            if ledgers == 0 {
+                continue;
+            }
+            let amount = ledgers * stream.rate;
+
+            // The farm is notified first so that a failing farm is skipped before anything is
+            // paid to it, the transfer follows in the same invocation
+            if !matches!(FarmClient::new(e, &stream.farm).try_notify_reward(&amount), Ok(Ok(_))) {
+                e.events()
+                    .publish((symbol_short!("DripFail"), stream.farm), amount);
+                continue;
+            }
+            stream.last_ledger += ledgers as u32;
+            streams.set(index as u32, stream.clone());
+
+            token_client.transfer(&e.current_contract_address(), &stream.farm, &amount);
+            balance -= amount;
+            total += amount;
+
+            e.events()
+                .publish((symbol_short!("Drip"), stream.farm), amount);
+        }
+        put_streams(e, &streams);
+
+        Ok(total)
+    }
+
+    /// Returns budget the streams do not need anymore to the admin: at most the balance beyond
+    /// what every stream has still to be paid.
+    pub fn withdraw(e: &Env, amount: i128) -> Result<i128, EmitterError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        let token_client = token::Client::new(e, &get_token(e)?);
+        let owed: i128 = get_streams(e).iter().map(|stream| remaining(&stream)).sum();
+        if amount <= 0 || amount > token_client.balance(&e.current_contract_address()) - owed {
+            return Err(EmitterError::InvalidAmount);
+        }
+        token_client.transfer(&e.current_contract_address(), &admin, &amount);
+
+        e.events()
+            .publish((symbol_short!("Withdraw"), admin), amount);
+
+        Ok(amount)
+    }
+
+    /// Public function to query the streams.
+    pub fn get_streams(e: &Env) -> Vec<Stream> {
+        extend_instance_ttl(e);
+        get_streams(e)
+    }
+
+    /// Public function to query the emissions still owed to `farm` that the budget covers: what
+    /// its stream has still to be paid, capped at its share of the budget pro rata to what
+    /// every stream has still to be paid.
+    pub fn committed(e: &Env, farm: Address) -> Result<i128, EmitterError> {
+        extend_instance_ttl(e);
+
+        let streams = get_streams(e);
+        let Some(stream) = streams.iter().find(|stream| stream.farm == farm) else {
+            return Ok(0);
+        };
+        let owed = remaining(&stream);
+        let total: i128 = streams.iter().map(|stream| remaining(&stream)).sum();
+        if owed == 0 {
+            return Ok(0);
+        }
+        let balance = token::Client::new(e, &get_token(e)?).balance(&e.current_contract_address());
+        Ok(core::cmp::min(owed, balance * owed / total))
+    }
+
+    /// Public function to query the token streamed to the farms.
+    pub fn token(e: &Env) -> Result<Address, EmitterError> {
+        extend_instance_ttl(e);
+        get_token(e)
+    }
+
+    /// Public function to query the admin address.
+    pub fn get_admin_address(e: &Env) -> Result<Address, EmitterError> {
+        get_admin(e)
+    }
+}
+
+mod test;
@@ -0,0 +1,161 @@
+#![cfg(test)]
+extern crate std;
+
+use super::*;
+use soroban_sdk::{
+    testutils::{Address as _, Ledger},
+    token, Address, Env,
+};
+
+#[contract]
+struct MockFarm;
+
+#[contractimpl]
+impl MockFarm {
+    pub fn notify_reward(e: Env, amount: i128) -> (i128, i128) {
+        let notified: i128 = e
+            .storage()
+            .instance()
+            .get(&symbol_short!("notified"))
+            .unwrap_or(0);
+        e.storage()
+            .instance()
+            .set(&symbol_short!("notified"), &(notified + amount));
+        (notified + amount, 0)
+    }
+
+    pub fn notified(e: Env) -> i128 {
+        e.storage()
+            .instance()
+            .get(&symbol_short!("notified"))
+            .unwrap_or(0)
+    }
+}
+
+mod broken_farm {
+    use soroban_sdk::{contract, contracterror, contractimpl, Env};
+
+    #[contracterror]
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    #[repr(u32)]
+    pub enum BrokenFarmError {
+        Rejected = 1,
+    }
+
+    #[contract]
+    pub struct BrokenFarm;
+
+    #[contractimpl]
+    impl BrokenFarm {
+        pub fn notify_reward(_e: Env, _amount: i128) -> Result<(i128, i128), BrokenFarmError> {
+            Err(BrokenFarmError::Rejected)
+        }
+    }
+}
+
+fn create_token_contract<'a>(
+    e: &Env,
+    admin: &Address,
+) -> (token::Client<'a>, token::StellarAssetClient<'a>) {
+    let sac = e.register_stellar_asset_contract(admin.clone());
+    (
+        token::Client::new(e, &sac),
+        token::StellarAssetClient::new(e, &sac),
+    )
+}
+
+#[test]
+fn test_drip_streams_rewards() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().set_sequence_number(100);
+
+    let admin = Address::generate(&e);
+    let (token_client, token_admin) = create_token_contract(&e, &admin);
+
+    let emitter = EmitterClient::new(&e, &e.register_contract(None, Emitter {}));
+    emitter.initialize(&admin, &token_client.address);
+    token_admin.mint(&emitter.address, &2500);
+
+    let farm1 = MockFarmClient::new(&e, &e.register_contract(None, MockFarm {}));
+    let farm2 = MockFarmClient::new(&e, &e.register_contract(None, MockFarm {}));
+    assert_eq!(
+        emitter.try_add_stream(&farm1.address, &10, &200, &200),
+        Err(Ok(EmitterError::InvalidAmount))
+    );
+    emitter.add_stream(&farm1.address, &10, &100, &200);
+    emitter.add_stream(&farm2.address, &5, &150, &1000);
+
+    // Nothing accrues within the ledger a stream was paid up to
+    assert_eq!(emitter.drip(), 0);
+
+    // Streams owe 1000 and 4250, the budget covers each pro rata
+    assert_eq!(emitter.committed(&farm1.address), 1000 * 2500 / 5250);
+    assert_eq!(emitter.committed(&farm2.address), 4250 * 2500 / 5250);
+    assert_eq!(emitter.committed(&admin), 0);
+
+    e.ledger().set_sequence_number(160);
+    assert_eq!(emitter.drip(), 650);
+    assert_eq!(token_client.balance(&farm1.address), 600);
+    assert_eq!(farm1.notified(), 600);
+    assert_eq!(farm2.notified(), 50);
+
+    // The first stream ends at its end ledger, the second one is paid what the budget covers
+    e.ledger().set_sequence_number(2000);
+    assert_eq!(emitter.drip(), 1850);
+    assert_eq!(farm1.notified(), 1000);
+    assert_eq!(farm2.notified(), 1500);
+    assert_eq!(emitter.get_streams().get(1).unwrap().last_ledger, 450);
+    assert_eq!(token_client.balance(&emitter.address), 0);
+
+    token_admin.mint(&emitter.address, &10000);
+    emitter.remove_stream(&farm1.address);
+    assert_eq!(
+        emitter.try_remove_stream(&farm2.address),
+        Err(Ok(EmitterError::StreamOutstanding))
+    );
+    assert_eq!(emitter.try_withdraw(&7251), Err(Ok(EmitterError::InvalidAmount)));
+    assert_eq!(
+        emitter.try_remove_stream(&farm1.address),
+        Err(Ok(EmitterError::StreamNotFound))
+    );
+    assert_eq!(emitter.drip(), 2750);
+    assert_eq!(emitter.withdraw(&7250), 7250);
+    assert_eq!(token_client.balance(&admin), 7250);
+}
+
+#[test]
+#[should_panic(expected = "HostError: Error(Contract, #2)")]
+fn test_not_double_initialization() {
+    let e = Env::default();
+
+    let emitter = EmitterClient::new(&e, &e.register_contract(None, Emitter {}));
+    emitter.initialize(&Address::generate(&e), &Address::generate(&e));
+    emitter.initialize(&Address::generate(&e), &Address::generate(&e));
+}
+
+#[test]
+fn test_drip_skips_failing_farm() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().set_sequence_number(100);
+
+    let admin = Address::generate(&e);
+    let (token_client, token_admin) = create_token_contract(&e, &admin);
+
+    let emitter = EmitterClient::new(&e, &e.register_contract(None, Emitter {}));
+    emitter.initialize(&admin, &token_client.address);
+    token_admin.mint(&emitter.address, &2500);
+
+    let broken = e.register_contract(None, broken_farm::BrokenFarm);
+    let farm = MockFarmClient::new(&e, &e.register_contract(None, MockFarm {}));
+    emitter.add_stream(&broken, &10, &100, &200);
+    emitter.add_stream(&farm.address, &5, &100, &200);
+
+    // The failing farm is neither paid nor advanced, the other stream is paid
+    e.ledger().set_sequence_number(150);
+    assert_eq!(emitter.drip(), 250);
+    assert_eq!(farm.notified(), 250);
+    assert_eq!(token_client.balance(&broken), 0);
+    assert_eq!(emitter.get_streams().get(0).unwrap().last_ledger, 100);
+}
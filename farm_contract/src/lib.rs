@@ -1,15 +1,34 @@
 #![no_std]
 
 use soroban_sdk::{
-    contract, contracterror, contractimpl, contracttype, symbol_short, Address, Env, String, token,
+    auth::{ContractContext, InvokerContractAuthEntry, SubContractInvocation},
+    contract, contracterror, contractimpl, contracttype, symbol_short, Address, BytesN,
+    ConversionError, Env, IntoVal, String, Symbol, token, TryFromVal, Val, Vec,
 };
 
 pub(crate) const DAY_IN_LEDGERS: u32 = 17280;
 pub(crate) const MAX_TTL: u32 = 3110400;
+// Decimals assumed for farms initialized before the token decimals were cached
 pub(crate) const DECIMALS: u32 = 7;
-
+// Reward ratios are scaled by 10^RATE_DECIMALS, well beyond the token decimals, so that rates
+// below one stroop per staked token per second can be expressed
+pub(crate) const RATE_DECIMALS: u32 = 18;
+pub(crate) const LEADERBOARD_SIZE: u32 = 10;
+pub(crate) const MAX_BONUS_ROUNDS: u32 = 5;
+pub(crate) const BONUS_INDEX_SCALE: i128 = 1_000_000_000_000;
+pub(crate) const MAX_INSURANCE_BPS: u32 = 1000;
+pub(crate) const MAX_PAGE_SIZE: u32 = 50;
+pub(crate) const MAX_STAKE_CHECKPOINTS: u32 = 100;
+// Ids of pools created under a salt have the top bit set, so they never meet the pool counter
+pub(crate) const SALTED_POOL_ID_BASE: u32 = 1 << 31;
+pub(crate) const SECONDS_PER_DAY: u64 = 86400;
+pub(crate) const SECONDS_PER_YEAR: u64 = 365 * SECONDS_PER_DAY;
+// Time before maturity from which positions are reminded that their rewards stop accruing
+pub(crate) const REMINDER_WINDOW: u64 = 7 * SECONDS_PER_DAY;
+
+// Stored as its u32 discriminant, as contract types are limited to 50 enum variants
 #[derive(Clone, Copy)]
-#[contracttype]
+#[repr(u32)]
 pub enum DataKey {
     Admin = 0,
     RewardedToken1 = 1,
@@ -22,9 +41,74 @@ pub enum DataKey {
     PoolData = 8,          // Prefix for pool data
     UserData = 9,          // Prefix for user data
     PoolToken = 10,        // Global pool token
-    Stopped = 11,          // For stop switch
+    State = 11,            // Lifecycle state, see FarmState
     MaxRewardRatio1 = 12,
     MaxRewardRatio2 = 13,
+    FundedRewards1 = 14,   // Total rewards funded through fund_rewards for token 1
+    FundedRewards2 = 15,   // Total rewards funded through fund_rewards for token 2
+    FundingRestricted = 16, // Only the admin may fund rewards when set
+    WithdrawPausedAt = 17, // Timestamp at which withdrawals were paused
+    GracePeriod = 18,      // Outage length tolerated before compensating users
+    CompensationSecs = 19, // Cumulative outage seconds users are compensated for
+    Leaderboard = 20,      // Prefix for per-pool leaderboards
+    LifetimeRewards = 21,  // Prefix for per-user lifetime rewards paid
+    TotalRewardsPaid1 = 22,
+    TotalRewardsPaid2 = 23,
+    BonusRounds = 24,      // Prefix for per-pool bonus rounds
+    BonusReserved = 25,    // Token 1 set aside for bonus rounds and not yet allocated
+    RiskOracle = 26,       // Address allowed to publish pool risk scores
+    RiskMaxAge = 27,       // Age after which a risk score is reported as stale
+    RiskScore = 28,        // Prefix for per-pool risk scores
+    PositionCount = 29,    // Number of registered user positions
+    Position = 30,         // Prefix for the (user, pool) position at an index
+    PositionIndex = 31,    // Prefix for the index of a (user, pool) position
+    RewardRoute = 32,      // Prefix for per-user reward donation routes
+    TotalStaked = 33,      // Pool token principal held for all positions
+    MinPosition = 34,      // Minimum principal of a position
+    Settlement = 35,       // Running totals of the current settlement day
+    ReconcileTolerance = 36, // Gap tolerated by reconcile before stopping the contract
+    ReconcileAutoStop = 37, // Whether reconcile stops the contract on a gap beyond tolerance
+    BondVault = 38,        // Vault whose bond share token is staked, source of the maturity
+    ClaimInterval = 39,    // Prefix for the per-pool minimum interval between claims
+    Unwinding = 40,        // Set once the admin starts unwinding the farm
+    PayoutAddress = 41,    // Prefix for the address a position's rewards are paid to
+    PoolTotals = 42,       // Prefix for the per-pool principal staked and deposited over its life
+    ArchivedPool = 43,     // Prefix for the summaries of archived pools
+    VestingPeriod = 44,    // Period over which claimed rewards vest, 0 pays them out at once
+    Vesting = 45,          // Prefix for per-recipient vesting schedules
+    VestingEscrow1 = 46,   // Token 1 rewards escrowed and not yet released
+    VestingEscrow2 = 47,   // Token 2 rewards escrowed and not yet released
+    SplitCounter = 48,     // Number of positions split off so far, the next split position id
+    SplitPosition = 49,    // Prefix for positions split off a (user, pool) position, by id
+    CheckpointCount = 50,  // Prefix for the number of stake checkpoints of a (user, pool) position
+    Checkpoint = 51,       // Prefix for the stake checkpoints of a (user, pool) position, by index
+    Emitter = 52,          // Contract streaming reward tokens to the farm through notify_reward, and its token
+    Callback = 53,         // Prefix for the per-user contract notified of maturity reminders
+    Reminded = 54,         // Prefix for the last reminder sent for a (user, pool) position
+    TokenDecimals = 55,    // Decimals of the pool token and of reward tokens 1 and 2
+    InsuranceBps = 56,     // Share of every reward payout, in basis points, set aside as insurance
+    InsuranceBalance1 = 57, // Token 1 rewards held back as insurance and not yet drawn
+    InsuranceBalance2 = 58, // Token 2 rewards held back as insurance and not yet drawn
+    WithdrawCooldown = 59, // Prefix for the per-pool cooldown of queued withdrawals
+    WithdrawRequest = 60,  // Prefix for the queued withdrawal of a (user, pool) position
+    Entered = 61,          // Temporary flag set while a deposit or withdrawal executes
+    Blocked = 62,          // Prefix for the addresses barred from depositing, withdrawing and claiming
+    MinStake = 63,         // Prefix for the per-pool minimum stake duration
+    PoolKey = 64,          // Prefix for the ids of pools created under a salt, by salt
+    PoolPause = 65,        // Prefix for the per-pool stop flag and seconds rewards were frozen
+    TransferTolerance = 66, // Shortfall, in basis points, a deposit transfer may arrive with
+    Config = 67,           // Configuration fixed at initialization, replacing keys 1, 2, 6, 10, 12, 13 and 55
+    InsuranceChange = 68,  // Insurance rate replaced by the last change, and when it changed
+    SaltedPools = 69,      // Ids of the pools created under a salt, in creation order
+    CheckpointStart = 70,  // Prefix for the index of the oldest stake checkpoint kept of a (user, pool) position
+}
+
+impl TryFromVal<Env, DataKey> for Val {
+    type Error = ConversionError;
+
+    fn try_from_val(_env: &Env, v: &DataKey) -> Result<Self, Self::Error> {
+        Ok((*v as u32).into())
+    }
 }
 
 #[contracterror]
@@ -43,14 +127,55 @@ pub enum FarmError {
     TokenConflict = 10,
     AlreadyInitialized = 11,
     ContractStopped = 12,
+    WithdrawalsPaused = 13,
+    MathOverflow = 14,
+    BonusRoundLimit = 15,
+    PoolClosed = 16,
+    RiskOracleNotSet = 17,
+    RiskScoreNotFound = 18,
+    RewardRouteNotSet = 19,
+    ProtectedFunds = 20,
+    BondVaultMismatch = 21,
+    ClaimTooSoon = 22,
+    Unwinding = 23,
+    NotUnwinding = 24,
+    SameLedger = 25,
+    PoolNotEmpty = 26,
+    MaturityNotReached = 27,
+    PositionNotFound = 28,
+    EmitterNotSet = 29,
+    CooldownActive = 30,
+    WithdrawRequestNotFound = 31,
+    ReentrantCall = 32,
+    AddressBlocked = 33,
+    StakeTooShort = 34,
+    PoolKeyTaken = 35,
+    ReceivedLessThanExpected = 36,
+    StakeOutstanding = 37,
+}
+
+/// Reward tokens a pool emits, in the proportion of its two reward ratios.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum PoolTokens {
+    /// Both reward tokens.
+    Both = 0,
+    /// Reward token 1 only, also on a farm configured with two reward tokens.
+    Token1 = 1,
+    /// Reward token 2 only.
+    Token2 = 2,
 }
 
 #[derive(Clone)]
 #[contracttype]
 pub struct Pool {
     pub start_time: u64,
-    pub reward_ratio1: i128,
+    pub end_time: u64, // Deposits are rejected from this time on
+    pub reward_ratio1: i128, // Reward tokens per staked token per second, scaled by 10^RATE_DECIMALS
     pub reward_ratio2: i128,
+    pub tokens: PoolTokens,
+    pub apr_bps1: u32, // Reward ratios annualized, in basis points of the staked tokens
+    pub apr_bps2: u32,
 }
 
 #[derive(Clone)]
@@ -60,6 +185,274 @@ pub struct UserData {
     pub deposit_time: u64,
     pub accrued_rewards1: i128,
     pub accrued_rewards2: i128,
+    pub compensation_index: u64,
+    pub last_claim: u64, // Time rewards were last paid out
+    pub deposit_ledger: u32, // Ledger of the last deposit
+    pub staked_at: u64, // Time of the last deposit, from which the minimum stake duration runs
+    pub pause_index: u64, // Seconds the pool had been frozen as of the last update
+    pub bonus_index: Vec<i128>, // Index of each bonus round of the pool as of the last update
+}
+
+/// How rewards accrue for a position.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum RewardModel {
+    /// `deposited * reward_ratio * seconds_elapsed / ratio_scale`, converted from the pool
+    /// token decimals to the reward token decimals, accruing from the later of the pool start
+    /// and the last deposit until maturity.
+    LinearPerSecond = 0,
+}
+
+/// Part of a position split off by `split_position`. It keeps accruing like the position it
+/// came from, can change hands through `transfer_split_position` and is folded into its
+/// owner's position by `merge_positions`.
+#[derive(Clone)]
+#[contracttype]
+pub struct SplitPosition {
+    pub owner: Address,
+    pub pool_id: u32,
+    pub position: UserData,
+}
+
+/// Machine-readable description of how a pool computes rewards.
+#[derive(Clone)]
+#[contracttype]
+pub struct RewardFormula {
+    pub model: RewardModel,
+    pub ratio_scale: i128,
+    pub decimals: u32, // Decimals of the pool token
+    pub reward_decimals1: u32,
+    pub reward_decimals2: u32,
+    pub reward_ratio1: i128,
+    pub reward_ratio2: i128,
+    pub accrual_start: u64,
+    pub accrual_end: u64,
+    pub lock_multiplier: i128, // Scaled by ratio_scale, positions are not time-locked so this is 1x
+}
+
+/// Temporary boosted rate paid in reward token 1 on top of a pool's base rates, funded
+/// from its own budget. `index` is the bonus paid per unit staked, scaled by
+/// BONUS_INDEX_SCALE, up to `updated_at`. `remaining` is the budget not paid into the index
+/// yet, released once the round has ended.
+#[derive(Clone)]
+#[contracttype]
+pub struct BonusRound {
+    pub start_time: u64,
+    pub end_time: u64,
+    pub extra_ratio: i128,
+    pub budget: i128,
+    pub remaining: i128,
+    pub index: i128,
+    pub updated_at: u64,
+}
+
+/// Risk score published for a pool by the risk oracle, in basis points.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct RiskScore {
+    pub score: u32,
+    pub updated_at: u64,
+    pub stale: bool, // Set on read when the score is older than the configured max age
+}
+
+/// Outcome of a deposit simulated with `preview_deposit`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct DepositPreview {
+    pub projected_reward1: i128, // Rewards the deposit would earn by maturity
+    pub projected_reward2: i128,
+    pub required_budget1: i128,  // Total allocated rewards after the deposit
+    pub required_budget2: i128,
+    pub sufficient: bool,        // Whether the reward balance covers the required budget
+}
+
+/// Farm wide configuration and totals, as exported by `export_state`.
+#[derive(Clone)]
+#[contracttype]
+pub struct FarmConfig {
+    pub admin: Address,
+    pub rewarded_tokens: Vec<Address>,
+    pub pool_token: Address,
+    pub maturity: u64,
+    pub max_reward_ratio1: i128,
+    pub max_reward_ratio2: i128, // 0 without a second reward token
+    pub allocated_rewards1: i128,
+    pub allocated_rewards2: i128,
+    pub pool_count: u32,
+    pub position_count: u32,
+    pub state: FarmState,
+}
+
+/// Lifecycle state of the farm.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum FarmState {
+    /// Deposits, withdrawals and claims are open.
+    Active = 0,
+    /// Deposits are rejected. Positions can still be withdrawn and rewards claimed.
+    DepositsPaused = 1,
+    /// Deposits, withdrawals, claims and vested releases are rejected. Only admin functions,
+    /// including rescue and unallocated reward recovery, remain available.
+    FullyStopped = 2,
+}
+
+/// Share of a user's claimed rewards, in basis points, routed to `recipient`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct RewardRoute {
+    pub recipient: Address,
+    pub share_bps: u32,
+}
+
+/// User action whose authorization tree is described by `get_required_auth`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum FarmAction {
+    Deposit = 0,
+    Withdraw = 1,
+}
+
+/// Section of the farm state to export.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum ExportSection {
+    Config = 0,
+    Pools = 1,
+    Positions = 2,
+}
+
+/// Single record of the exported farm state.
+#[derive(Clone)]
+#[contracttype]
+#[allow(clippy::large_enum_variant)]
+pub enum StateEntry {
+    Config(FarmConfig),
+    Pool(u32, Pool),
+    Position(Address, u32, UserData),
+}
+
+/// Balance of a token held by the farm compared with what it owes in that token.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Reconciliation {
+    pub token: Address,
+    pub funded: i128,
+    pub allocated: i128,
+    pub balance: i128,
+    pub gap: i128,
+}
+
+/// How long the unallocated budget of a reward token lasts at the current emission rate.
+/// `emission_rate` is the rewards per second accrued by the open positions, scaled by
+/// 10^RATE_DECIMALS, and `depletes_at` is 0 while nothing is being emitted.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct RewardRunway {
+    pub token: Address,
+    pub unallocated: i128,
+    pub emission_rate: i128,
+    pub depletes_at: u64,
+}
+
+/// Configuration and reward balances of the farm, for dashboards. The available rewards are
+/// the balances not allocated to positions, bonus rounds, vesting escrows or the insurance.
+/// `reward_tokens` lists reward token 1, followed by reward token 2 if the farm has one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct FarmInfo {
+    pub reward_tokens: Vec<Address>,
+    pub pool_token: Address,
+    pub maturity: u64,
+    pub max_reward_ratio1: i128,
+    pub max_reward_ratio2: Option<i128>,
+    pub pool_count: u32,
+    pub state: FarmState,
+    pub allocated_rewards1: i128,
+    pub allocated_rewards2: i128,
+    pub available_rewards1: i128,
+    pub available_rewards2: i128,
+}
+
+/// Claimed rewards held in escrow for a recipient. The locked amounts vest linearly from
+/// `start` until `end` and move to the vested amounts, which can be released.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct VestingSchedule {
+    pub vested1: i128,
+    pub vested2: i128,
+    pub locked1: i128,
+    pub locked2: i128,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Principal taken out of a position through `request_withdraw`. It no longer accrues rewards
+/// and is paid out by `execute_withdraw` from `unlocks_at`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct WithdrawRequest {
+    pub amount: i128,
+    pub unlocks_at: u64,
+}
+
+/// Minimum time a position of a pool stays staked after its last deposit. Withdrawing earlier
+/// reverts, or with `forfeit` returns the rewards accrued by the position to the unallocated
+/// budget, in which case its rewards cannot be claimed until then either.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct MinStake {
+    pub duration: u64,
+    pub forfeit: bool,
+}
+
+/// Accrual freeze of a pool. The pool is frozen while `stopped` or while the whole farm is fully
+/// stopped, since `frozen_at`, and rewards do not accrue then. `paused_secs` accumulates the
+/// frozen seconds before maturity of past freezes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PoolPause {
+    pub stopped: bool,
+    pub frozen_at: u64,
+    pub paused_secs: u64,
+}
+
+/// Aggregate flows of one settlement day, reported once the day has closed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct DailySettlement {
+    pub day: u64,
+    pub deposits: i128,
+    pub withdrawals: i128,
+    pub rewards_paid1: i128,
+    pub rewards_paid2: i128,
+}
+
+/// What remains of a pool once `archive_pool` has reclaimed its storage.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct ArchivedPool {
+    pub start_time: u64,
+    pub end_time: u64,
+    pub reward_ratio1: i128,
+    pub reward_ratio2: i128,
+    pub total_deposited: i128, // Principal deposited over the life of the pool
+    pub archived_at: u64,
+}
+
+/// Stake of a position from `timestamp` on, with the stake-seconds accumulated before it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct StakeCheckpoint {
+    pub timestamp: u64,
+    pub stake: i128,
+    pub cumulative: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct LeaderboardEntry {
+    pub user: Address,
+    pub deposited: i128,
 }
 
 #[contract]
@@ -75,11 +468,47 @@ fn user_data_key(user: Address, pool_id: u32) -> (Address, u32) {
     (user, pool_id)
 }
 
-fn has_sufficient_rewards(e: &Env, required1: i128, required2: i128) -> Result<bool, FarmError> {
-    let rewarded_token1 = get_rewarded_token1(e)?;
-    let available1 = token::Client::new(e, &rewarded_token1).balance(&e.current_contract_address());
-    if let Some(rewarded_token2) = get_rewarded_token2(e)? {
-        let available2 = token::Client::new(e, &rewarded_token2).balance(&e.current_contract_address());
+/// Emissions of reward tokens 1 and 2 the emitter, if any, has committed to the farm and not
+/// streamed yet. An emitter that cannot tell commits nothing.
+fn committed_emissions(e: &Env, config: &Config) -> (i128, i128) {
+    let Some((emitter, token)) = e.storage().instance().get::<_, (Address, Address)>(&DataKey::Emitter) else {
+        return (0, 0);
+    };
+    let result = e.try_invoke_contract::<i128, soroban_sdk::Error>(
+        &emitter,
+        &Symbol::new(e, "committed"),
+        (e.current_contract_address(),).into_val(e),
+    );
+    let committed = match result {
+        Ok(Ok(committed)) => core::cmp::max(committed, 0),
+        _ => 0,
+    };
+    if token == config.rewarded_token1 {
+        (committed, 0)
+    } else {
+        (0, committed)
+    }
+}
+
+/// Whether the rewards in the farm, along with the emissions committed to it, cover
+/// `required1` and `required2`.
+fn has_sufficient_rewards(
+    e: &Env,
+    config: &Config,
+    required1: i128,
+    required2: i128,
+) -> Result<bool, FarmError> {
+    let (insurance1, insurance2) = get_insurance_balance(e);
+    let (committed1, committed2) = committed_emissions(e, config);
+    // Bonus budgets and the insurance are not available to the base campaign
+    let available1 = token::Client::new(e, &config.rewarded_token1).balance(&e.current_contract_address())
+        + committed1
+        - get_bonus_reserved(e)
+        - insurance1;
+    if let Some(rewarded_token2) = config.rewarded_token2() {
+        let available2 = token::Client::new(e, &rewarded_token2).balance(&e.current_contract_address())
+            + committed2
+            - insurance2;
         Ok(available1 >= required1 && available2 >= required2)
     } else {
         Ok(available1 >= required1 && required2 == 0)
@@ -97,54 +526,89 @@ fn get_admin(e: &Env) -> Result<Address, FarmError> {
         .ok_or(FarmError::NotInitialized)
 }
 
-fn put_rewarded_tokens(e: &Env, token1: Address, token2: Option<Address>) -> Result<(), FarmError> {
-    if let Some(ref token2_addr) = token2 {
-        if token1 == *token2_addr {
-            return Err(FarmError::SameRewardTokens);
-        }
+/// Configuration fixed at initialization, stored as a single instance entry that is read and
+/// written as a unit, so that deposits, withdrawals and claims load it with one storage access.
+#[derive(Clone)]
+#[contracttype]
+struct Config {
+    pool_token: Address,
+    rewarded_token1: Address,
+    rewarded_token2: Vec<Address>, // Empty without a second reward token
+    maturity: u64,
+    max_reward_ratio1: i128,
+    max_reward_ratio2: Option<i128>,
+    decimals: (u32, u32, u32), // Pool token, reward token 1 and reward token 2
+}
+
+impl Config {
+    fn rewarded_token2(&self) -> Option<Address> {
+        self.rewarded_token2.first()
     }
-    e.storage()
-        .instance()
-        .set(&DataKey::RewardedToken1, &token1);
-    if let Some(token2_addr) = token2 {
-        e.storage().instance().set(&DataKey::RewardedToken2, &token2_addr);
-    } else {
-        e.storage().instance().remove(&DataKey::RewardedToken2);
+}
+
+fn put_config(e: &Env, config: &Config) {
+    e.storage().instance().set(&DataKey::Config, config);
+}
+
+/// Reads the configuration. Farms initialized before it was kept in one entry assemble it from
+/// the individual keys once, store it and drop the individual keys.
+fn get_config(e: &Env) -> Result<Config, FarmError> {
+    let storage = e.storage().instance();
+    if let Some(config) = storage.get(&DataKey::Config) {
+        return Ok(config);
     }
-    Ok(())
+
+    let config = Config {
+        pool_token: storage.get(&DataKey::PoolToken).ok_or(FarmError::NotInitialized)?,
+        rewarded_token1: storage
+            .get(&DataKey::RewardedToken1)
+            .ok_or(FarmError::NotInitialized)?,
+        rewarded_token2: Vec::from_slice(e, storage.get::<_, Address>(&DataKey::RewardedToken2).as_slice()),
+        maturity: storage.get(&DataKey::Maturity).ok_or(FarmError::NotInitialized)?,
+        max_reward_ratio1: storage
+            .get(&DataKey::MaxRewardRatio1)
+            .ok_or(FarmError::NotInitialized)?,
+        max_reward_ratio2: storage.get(&DataKey::MaxRewardRatio2),
+        decimals: storage
+            .get(&DataKey::TokenDecimals)
+            .unwrap_or((DECIMALS, DECIMALS, DECIMALS)),
+    };
+    put_config(e, &config);
+    for key in [
+        DataKey::PoolToken,
+        DataKey::RewardedToken1,
+        DataKey::RewardedToken2,
+        DataKey::Maturity,
+        DataKey::MaxRewardRatio1,
+        DataKey::MaxRewardRatio2,
+        DataKey::TokenDecimals,
+    ] {
+        storage.remove(&key);
+    }
+    Ok(config)
 }
 
-fn put_maturity(e: &Env, maturity: u64) {
-    e.storage().instance().set(&DataKey::Maturity, &maturity);
+fn put_maturity(e: &Env, maturity: u64) -> Result<(), FarmError> {
+    let mut config = get_config(e)?;
+    config.maturity = maturity;
+    put_config(e, &config);
+    Ok(())
 }
 
 fn get_maturity(e: &Env) -> Result<u64, FarmError> {
-    e.storage()
-        .instance()
-        .get(&DataKey::Maturity)
-        .ok_or(FarmError::NotInitialized)
+    Ok(get_config(e)?.maturity)
 }
 
 fn get_rewarded_token1(e: &Env) -> Result<Address, FarmError> {
-    e.storage()
-        .instance()
-        .get(&DataKey::RewardedToken1)
-        .ok_or(FarmError::NotInitialized)
+    Ok(get_config(e)?.rewarded_token1)
 }
 
 fn get_rewarded_token2(e: &Env) -> Result<Option<Address>, FarmError> {
-    Ok(e.storage().instance().get(&DataKey::RewardedToken2))
-}
-
-fn put_pool_token(e: &Env, pool_token: Address) {
-    e.storage().instance().set(&DataKey::PoolToken, &pool_token);
+    Ok(get_config(e)?.rewarded_token2())
 }
 
 fn get_pool_token(e: &Env) -> Result<Address, FarmError> {
-    e.storage()
-        .instance()
-        .get(&DataKey::PoolToken)
-        .ok_or(FarmError::NotInitialized)
+    Ok(get_config(e)?.pool_token)
 }
 
 fn put_allocated_rewards(e: &Env, allocated1: i128, allocated2: i128) {
@@ -183,11 +647,61 @@ fn get_pool_data(e: &Env, pool_id: u32) -> Result<Pool, FarmError> {
         .ok_or(FarmError::PoolNotFound)
 }
 
+fn pool_totals_key(pool_id: u32) -> (u32, u32) {
+    (DataKey::PoolTotals as u32, pool_id)
+}
+
+/// Returns the principal currently staked in a pool and deposited over its life.
+fn get_pool_totals(e: &Env, pool_id: u32) -> (i128, i128) {
+    e.storage()
+        .persistent()
+        .get(&pool_totals_key(pool_id))
+        .unwrap_or((0, 0))
+}
+
+fn put_pool_totals(e: &Env, pool_id: u32, staked: i128, deposited: i128) {
+    e.storage()
+        .persistent()
+        .set(&pool_totals_key(pool_id), &(staked, deposited));
+}
+
 fn put_user_data(e: &Env, user: Address, pool_id: u32, user_data: UserData) {
+    register_position(e, &user, pool_id);
     let storage_key = user_data_key(user, pool_id);
     e.storage().persistent().set(&storage_key, &user_data);
 }
 
+fn get_position_count(e: &Env) -> u32 {
+    e.storage()
+        .instance()
+        .get(&DataKey::PositionCount)
+        .unwrap_or(0)
+}
+
+fn get_position(e: &Env, index: u32) -> Option<(Address, u32)> {
+    e.storage()
+        .persistent()
+        .get(&(DataKey::Position as u32, index))
+}
+
+/// Adds the (user, pool) position to the position registry if it is not already part of it,
+/// so that positions can be enumerated.
+fn register_position(e: &Env, user: &Address, pool_id: u32) {
+    let index_key = (DataKey::PositionIndex as u32, user.clone(), pool_id);
+    if e.storage().persistent().has(&index_key) {
+        return;
+    }
+
+    let count = get_position_count(e);
+    e.storage()
+        .persistent()
+        .set(&(DataKey::Position as u32, count), &(user.clone(), pool_id));
+    e.storage().persistent().set(&index_key, &count);
+    e.storage()
+        .instance()
+        .set(&DataKey::PositionCount, &(count + 1));
+}
+
 fn get_user_data(e: &Env, user: Address, pool_id: u32) -> Result<UserData, FarmError> {
     let storage_key = user_data_key(user, pool_id);
     e.storage()
@@ -200,582 +714,4008 @@ fn get_user_data(e: &Env, user: Address, pool_id: u32) -> Result<UserData, FarmE
 fn remove_user_data(e: &Env, user: &Address, pool_id: u32) -> Result<(), FarmError> {
     let storage_key = user_data_key(user.clone(), pool_id);
     e.storage().persistent().remove(&storage_key);
+    e.storage()
+        .persistent()
+        .remove(&payout_address_key(user.clone(), pool_id));
     Ok(())
 }
 
-fn get_token_client2(e: &Env) -> Option<token::Client> {
-    if let Ok(Some(rewarded_token2)) = get_rewarded_token2(e) {
-        Some(token::Client::new(e, &rewarded_token2))
-    } else {
-        None
-    }
+/// Helper function to generate the leaderboard key of a pool.
+fn leaderboard_key(pool_id: u32) -> (u32, u32) {
+    (DataKey::Leaderboard as u32, pool_id)
 }
 
-fn check_nonnegative_amount(amount: i128) -> Result<(), FarmError> {
-    if amount < 0 {
-        Err(FarmError::InvalidAmount)
-    } else {
-        Ok(())
-    }
+fn get_leaderboard(e: &Env, pool_id: u32) -> Vec<LeaderboardEntry> {
+    e.storage()
+        .persistent()
+        .get(&leaderboard_key(pool_id))
+        .unwrap_or(Vec::new(e))
 }
 
-fn check_nonzero_amount(amount: i128) -> Result<(), FarmError> {
-    if amount == 0 {
-        Err(FarmError::InvalidAmount)
-    } else {
-        Ok(())
+/// Re-ranks `user` on the pool leaderboard with their new deposited amount, keeping only the
+/// top `LEADERBOARD_SIZE` stakers. Stakers that drop out are only ranked again on their next
+/// deposit or withdraw, or through `rank_stakers`, so after withdrawals the board can miss
+/// stakers who now belong on it.
+fn update_leaderboard(e: &Env, pool_id: u32, user: &Address, deposited: i128) {
+    let mut leaderboard = get_leaderboard(e, pool_id);
+
+    if let Some(index) = leaderboard.iter().position(|entry| entry.user == *user) {
+        leaderboard.remove(index as u32);
+    }
+
+    if deposited > 0 {
+        let rank = leaderboard
+            .iter()
+            .position(|entry| entry.deposited < deposited)
+            .unwrap_or(leaderboard.len() as usize) as u32;
+        if rank < LEADERBOARD_SIZE {
+            leaderboard.insert(
+                rank,
+                LeaderboardEntry {
+                    user: user.clone(),
+                    deposited,
+                },
+            );
+        }
+    }
+
+    while leaderboard.len() > LEADERBOARD_SIZE {
+        leaderboard.pop_back();
     }
+
+    e.storage()
+        .persistent()
+        .set(&leaderboard_key(pool_id), &leaderboard);
 }
 
-fn time(e: &Env) -> u64 {
-    e.ledger().timestamp()
+fn checkpoint_count_key(user: Address, pool_id: u32) -> (u32, Address, u32) {
+    (DataKey::CheckpointCount as u32, user, pool_id)
 }
 
-fn extend_instance_ttl(e: &Env) {
+fn checkpoint_key(user: Address, pool_id: u32, index: u32) -> (u32, Address, u32, u32) {
+    (DataKey::Checkpoint as u32, user, pool_id, index)
+}
+
+fn get_checkpoint_count(e: &Env, user: Address, pool_id: u32) -> u32 {
     e.storage()
-        .instance()
-        .extend_ttl(MAX_TTL - DAY_IN_LEDGERS, MAX_TTL)
+        .persistent()
+        .get(&checkpoint_count_key(user, pool_id))
+        .unwrap_or(0)
 }
 
-fn put_pool_counter(e: &Env, counter: u32) {
-    e.storage().instance().set(&DataKey::PoolCounter, &counter);
+fn checkpoint_start_key(user: Address, pool_id: u32) -> (u32, Address, u32) {
+    (DataKey::CheckpointStart as u32, user, pool_id)
 }
 
-fn put_max_reward_ratios(
-    e: &Env,
-    ratio1: i128,
-    ratio2: Option<i128>,
-) -> Result<(), FarmError> {
-    e.storage().instance().set(&DataKey::MaxRewardRatio1, &ratio1);
-    if let Some(ratio2_value) = ratio2 {
-        e.storage()
-            .instance()
-            .set(&DataKey::MaxRewardRatio2, &ratio2_value);
+fn get_checkpoint_start(e: &Env, user: Address, pool_id: u32) -> u32 {
+    e.storage()
+        .persistent()
+        .get(&checkpoint_start_key(user, pool_id))
+        .unwrap_or(0)
+}
+
+fn extend_persistent_ttl<K: IntoVal<Env, Val>>(e: &Env, key: &K) {
+    e.storage()
+        .persistent()
+        .extend_ttl(key, MAX_TTL - DAY_IN_LEDGERS, MAX_TTL);
+}
+
+fn get_checkpoint(e: &Env, user: Address, pool_id: u32, index: u32) -> Result<StakeCheckpoint, FarmError> {
+    e.storage()
+        .persistent()
+        .get(&checkpoint_key(user, pool_id, index))
+        .ok_or(FarmError::UserNotFound)
+}
+
+/// Records the new stake of a position, accumulating the stake-seconds of the previous one.
+/// Several changes within the same second share a checkpoint, and an unchanged stake, e.g. on
+/// a claim, needs none. Only the last MAX_STAKE_CHECKPOINTS checkpoints are kept.
+fn record_stake(e: &Env, user: &Address, pool_id: u32, stake: i128) -> Result<(), FarmError> {
+    let current_time = time(e);
+    let count = get_checkpoint_count(e, user.clone(), pool_id);
+
+    let (index, cumulative) = if count == 0 {
+        (0, 0)
     } else {
-        e.storage().instance().remove(&DataKey::MaxRewardRatio2);
+        let last = get_checkpoint(e, user.clone(), pool_id, count - 1)?;
+        if last.stake == stake {
+            return Ok(());
+        }
+        if last.timestamp == current_time {
+            (count - 1, last.cumulative)
+        } else {
+            let elapsed = (current_time - last.timestamp) as i128;
+            (count, checked_add(last.cumulative, checked_mul(last.stake, elapsed)?)?)
+        }
+    };
+
+    let key = checkpoint_key(user.clone(), pool_id, index);
+    e.storage().persistent().set(
+        &key,
+        &StakeCheckpoint {
+            timestamp: current_time,
+            stake,
+            cumulative,
+        },
+    );
+    extend_persistent_ttl(e, &key);
+    if index == count {
+        let count_key = checkpoint_count_key(user.clone(), pool_id);
+        e.storage().persistent().set(&count_key, &(count + 1));
+        extend_persistent_ttl(e, &count_key);
+
+        // The oldest checkpoint makes way for the new one, its cumulative carries over
+        let start = if count < MAX_STAKE_CHECKPOINTS {
+            0
+        } else {
+            get_checkpoint_start(e, user.clone(), pool_id)
+        };
+        if count + 1 - start > MAX_STAKE_CHECKPOINTS {
+            e.storage()
+                .persistent()
+                .remove(&checkpoint_key(user.clone(), pool_id, start));
+            let start_key = checkpoint_start_key(user.clone(), pool_id);
+            e.storage().persistent().set(&start_key, &(start + 1));
+            extend_persistent_ttl(e, &start_key);
+        }
     }
+
     Ok(())
 }
 
-fn get_max_reward_ratios(e: &Env) -> Result<(i128, Option<i128>), FarmError> {
-    let ratio1: i128 = e
-        .storage()
-        .instance()
-        .get(&DataKey::MaxRewardRatio1)
-        .ok_or(FarmError::NotInitialized)?;
-    let ratio2: Option<i128> = e.storage().instance().get(&DataKey::MaxRewardRatio2);
-    Ok((ratio1, ratio2))
+/// Stake-seconds accumulated by a position up to `timestamp`, found by binary search over its
+/// checkpoints. Fails for times before the oldest checkpoint kept once older ones were dropped.
+fn cumulative_stake(e: &Env, user: &Address, pool_id: u32, timestamp: u64) -> Result<i128, FarmError> {
+    let start = get_checkpoint_start(e, user.clone(), pool_id);
+    let mut low = start;
+    let mut high = get_checkpoint_count(e, user.clone(), pool_id);
+    while low < high {
+        let mid = (low + high) / 2;
+        if get_checkpoint(e, user.clone(), pool_id, mid)?.timestamp <= timestamp {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    if low == start {
+        return if start == 0 { Ok(0) } else { Err(FarmError::InvalidAmount) };
+    }
+
+    let checkpoint = get_checkpoint(e, user.clone(), pool_id, low - 1)?;
+    let elapsed = (timestamp - checkpoint.timestamp) as i128;
+    checked_add(checkpoint.cumulative, checked_mul(checkpoint.stake, elapsed)?)
 }
 
-fn get_pool_counter(e: &Env) -> Result<u32, FarmError> {
+/// Helper function to generate the lifetime rewards key of a user's position.
+fn lifetime_rewards_key(user: Address, pool_id: u32) -> (u32, Address, u32) {
+    (DataKey::LifetimeRewards as u32, user, pool_id)
+}
+
+fn get_lifetime_rewards(e: &Env, user: Address, pool_id: u32) -> (i128, i128) {
     e.storage()
-        .instance()
-        .get(&DataKey::PoolCounter)
-        .unwrap_or(Ok(0))
+        .persistent()
+        .get(&lifetime_rewards_key(user, pool_id))
+        .unwrap_or((0, 0))
 }
 
-fn is_initialized(e: &Env) -> Result<bool, FarmError> {
-    Ok(e.storage()
+fn get_vesting_period(e: &Env) -> u64 {
+    e.storage()
         .instance()
-        .get(&DataKey::Initialized)
-        .unwrap_or(0) == 1)
+        .get(&DataKey::VestingPeriod)
+        .unwrap_or(0)
 }
 
-fn set_initialized(e: &Env) {
-    e.storage().instance().set(&DataKey::Initialized, &1);
+fn vesting_key(user: Address) -> (u32, Address) {
+    (DataKey::Vesting as u32, user)
 }
 
-fn put_stopped(e: &Env, stopped: bool) {
-    if stopped {
-        e.storage().instance().set(&DataKey::Stopped, &1);
-    } else {
-        e.storage().instance().remove(&DataKey::Stopped);
-    }
+fn get_vesting_escrow(e: &Env) -> (i128, i128) {
+    let escrow1: i128 = e
+        .storage()
+        .instance()
+        .get(&DataKey::VestingEscrow1)
+        .unwrap_or(0);
+    let escrow2: i128 = e
+        .storage()
+        .instance()
+        .get(&DataKey::VestingEscrow2)
+        .unwrap_or(0);
+    (escrow1, escrow2)
 }
 
-fn get_stopped(e: &Env) -> Result<bool, FarmError> {
-    Ok(e.storage()
-        .instance()
-        .get(&DataKey::Stopped)
-        .unwrap_or(0) == 1)
+fn put_vesting_escrow(e: &Env, escrow1: i128, escrow2: i128) {
+    e.storage().instance().set(&DataKey::VestingEscrow1, &escrow1);
+    e.storage().instance().set(&DataKey::VestingEscrow2, &escrow2);
 }
 
-#[contractimpl]
-impl Farm {
-    pub fn initialize(
-        e: &Env,
-        admin: Address,
-        rewarded_token1: Address,
-        rewarded_token2: Option<Address>,
+/// Returns the vesting schedule of `user` with the locked amounts vested up to `current_time`.
+fn get_vesting_schedule(
+    e: &Env,
+    user: Address,
+    current_time: u64,
+) -> Result<VestingSchedule, FarmError> {
+    let mut schedule: VestingSchedule = e
+        .storage()
+        .persistent()
+        .get(&vesting_key(user))
+        .unwrap_or(VestingSchedule {
+            vested1: 0,
+            vested2: 0,
+            locked1: 0,
+            locked2: 0,
+            start: current_time,
+            end: current_time,
+        });
+
+    if current_time > schedule.start {
+        let (unlocked1, unlocked2) = if current_time >= schedule.end {
+            (schedule.locked1, schedule.locked2)
+        } else {
+            let elapsed = (current_time - schedule.start) as i128;
+            let duration = (schedule.end - schedule.start) as i128;
+            (
+                mul_div(schedule.locked1, elapsed, 1, duration)?,
+                mul_div(schedule.locked2, elapsed, 1, duration)?,
+            )
+        };
+        schedule.vested1 = checked_add(schedule.vested1, unlocked1)?;
+        schedule.vested2 = checked_add(schedule.vested2, unlocked2)?;
+        schedule.locked1 -= unlocked1;
+        schedule.locked2 -= unlocked2;
+        schedule.start = current_time;
+    }
+
+    Ok(schedule)
+}
+
+/// Escrows rewards for `user` to vest over the vesting period. The schedule ends at the end
+/// times of the rewards still locked and of the new ones, averaged by amount, so that a claim
+/// delays the rewards already locked only in proportion to what it adds.
+fn escrow_rewards(e: &Env, user: &Address, amount1: i128, amount2: i128) -> Result<(), FarmError> {
+    let current_time = time(e);
+    let mut schedule = get_vesting_schedule(e, user.clone(), current_time)?;
+    // Weighted by reward token 1, or by reward token 2 when no token 1 is involved
+    let (locked, added) = if schedule.locked1 > 0 || amount1 > 0 {
+        (schedule.locked1, amount1)
+    } else {
+        (schedule.locked2, amount2)
+    };
+    let new_end = current_time + get_vesting_period(e);
+    if locked > 0 && schedule.end > current_time {
+        let blended = checked_add(
+            checked_mul(locked, schedule.end as i128)?,
+            checked_mul(added, new_end as i128)?,
+        )? / checked_add(locked, added)?;
+        schedule.end = blended as u64;
+    } else {
+        schedule.end = new_end;
+    }
+    schedule.locked1 = checked_add(schedule.locked1, amount1)?;
+    schedule.locked2 = checked_add(schedule.locked2, amount2)?;
+    e.storage()
+        .persistent()
+        .set(&vesting_key(user.clone()), &schedule);
+
+    let (escrow1, escrow2) = get_vesting_escrow(e);
+    put_vesting_escrow(e, checked_add(escrow1, amount1)?, checked_add(escrow2, amount2)?);
+    Ok(())
+}
+
+fn get_insurance_bps(e: &Env) -> u32 {
+    e.storage().instance().get(&DataKey::InsuranceBps).unwrap_or(0)
+}
+
+/// Insurance rate, in basis points, for rewards accrued since `accrued_since`. A change of the
+/// rate only applies to rewards accruing after it, so positions last paid before the change are
+/// charged the lower of the previous and the current rate.
+fn insurance_bps_since(e: &Env, accrued_since: u64) -> u32 {
+    let insurance_bps = get_insurance_bps(e);
+    match e.storage().instance().get::<_, (u32, u64)>(&DataKey::InsuranceChange) {
+        Some((previous, changed_at)) if accrued_since < changed_at => core::cmp::min(previous, insurance_bps),
+        _ => insurance_bps,
+    }
+}
+
+fn get_insurance_balance(e: &Env) -> (i128, i128) {
+    let insurance1: i128 = e
+        .storage()
+        .instance()
+        .get(&DataKey::InsuranceBalance1)
+        .unwrap_or(0);
+    let insurance2: i128 = e
+        .storage()
+        .instance()
+        .get(&DataKey::InsuranceBalance2)
+        .unwrap_or(0);
+    (insurance1, insurance2)
+}
+
+fn put_insurance_balance(e: &Env, insurance1: i128, insurance2: i128) {
+    e.storage().instance().set(&DataKey::InsuranceBalance1, &insurance1);
+    e.storage().instance().set(&DataKey::InsuranceBalance2, &insurance2);
+}
+
+fn get_total_rewards_paid(e: &Env) -> (i128, i128) {
+    let paid1: i128 = e
+        .storage()
+        .instance()
+        .get(&DataKey::TotalRewardsPaid1)
+        .unwrap_or(0);
+    let paid2: i128 = e
+        .storage()
+        .instance()
+        .get(&DataKey::TotalRewardsPaid2)
+        .unwrap_or(0);
+    (paid1, paid2)
+}
+
+fn reward_route_key(user: Address) -> (u32, Address) {
+    (DataKey::RewardRoute as u32, user)
+}
+
+fn get_reward_route(e: &Env, user: Address) -> Option<RewardRoute> {
+    e.storage().persistent().get(&reward_route_key(user))
+}
+
+/// Helper function to generate the payout address key of a user's position.
+fn payout_address_key(user: Address, pool_id: u32) -> (u32, Address, u32) {
+    (DataKey::PayoutAddress as u32, user, pool_id)
+}
+
+/// Returns the address rewards on a position are paid to, the depositor unless redirected.
+fn get_payout_address(e: &Env, user: Address, pool_id: u32) -> Address {
+    e.storage()
+        .persistent()
+        .get(&payout_address_key(user.clone(), pool_id))
+        .unwrap_or(user)
+}
+
+/// Share of `amount` of a reward token the user donates through their reward route.
+fn donation_share(route: &Option<RewardRoute>, amount: i128) -> Result<i128, FarmError> {
+    match route {
+        Some(route) if amount > 0 => mul_div(amount, route.share_bps as i128, 1, 10000),
+        _ => Ok(0),
+    }
+}
+
+/// Transfers `amount` of a reward token, the donation to the route's recipient and the rest
+/// to the payee unless it is escrowed for vesting.
+fn transfer_rewards(
+    e: &Env,
+    token: &Address,
+    route: &Option<RewardRoute>,
+    payee: &Address,
+    amount: i128,
+    donation: i128,
+    vesting: bool,
+) {
+    let client = token::Client::new(e, token);
+    if let Some(route) = route.as_ref().filter(|_| donation > 0) {
+        client.transfer(&e.current_contract_address(), &route.recipient, &donation);
+    }
+    if !vesting && amount > donation {
+        client.transfer(&e.current_contract_address(), payee, &(amount - donation));
+    }
+}
+
+/// Transfers rewards owed on a position to its payout address, less the insurance slice held
+/// back by the farm and any share the user routes elsewhere, and records them in the lifetime
+/// totals. In vesting mode the payout address's part is escrowed instead. All bookkeeping is
+/// written before the tokens move. `accrued_since` is when the position was last paid.
+fn pay_rewards(
+    e: &Env,
+    user: &Address,
+    payee: &Address,
+    pool_id: u32,
+    rewards1: i128,
+    rewards2: i128,
+    accrued_since: u64,
+) -> Result<(), FarmError> {
+    let route = get_reward_route(e, user.clone()).filter(|route| !is_blocked(e, &route.recipient));
+    let rewarded_token1 = get_rewarded_token1(e)?;
+    let rewarded_token2 = get_rewarded_token2(e)?;
+
+    let insurance_bps = insurance_bps_since(e, accrued_since) as i128;
+    let insured1 = mul_div(rewards1, insurance_bps, 1, 10000)?;
+    let insured2 = if rewarded_token2.is_some() {
+        mul_div(rewards2, insurance_bps, 1, 10000)?
+    } else {
+        0
+    };
+    if insured1 > 0 || insured2 > 0 {
+        let (insurance1, insurance2) = get_insurance_balance(e);
+        put_insurance_balance(e, checked_add(insurance1, insured1)?, checked_add(insurance2, insured2)?);
+    }
+    let rewards1 = rewards1 - insured1;
+    let rewards2 = match rewarded_token2 {
+        Some(_) if rewards2 > insured2 => rewards2 - insured2,
+        _ => 0,
+    };
+
+    if rewards1 == 0 && rewards2 == 0 {
+        return Ok(());
+    }
+
+    let vesting = get_vesting_period(e) > 0;
+    let donated1 = donation_share(&route, rewards1)?;
+    let donated2 = donation_share(&route, rewards2)?;
+
+    if vesting {
+        escrow_rewards(e, payee, rewards1 - donated1, rewards2 - donated2)?;
+    }
+
+    let (lifetime1, lifetime2) = get_lifetime_rewards(e, user.clone(), pool_id);
+    e.storage().persistent().set(
+        &lifetime_rewards_key(user.clone(), pool_id),
+        &(checked_add(lifetime1, rewards1)?, checked_add(lifetime2, rewards2)?),
+    );
+
+    let (paid1, paid2) = get_total_rewards_paid(e);
+    e.storage()
+        .instance()
+        .set(&DataKey::TotalRewardsPaid1, &checked_add(paid1, rewards1)?);
+    e.storage()
+        .instance()
+        .set(&DataKey::TotalRewardsPaid2, &checked_add(paid2, rewards2)?);
+    record_settlement(e, 0, 0, rewards1, rewards2)?;
+
+    if rewards1 > 0 {
+        transfer_rewards(e, &rewarded_token1, &route, payee, rewards1, donated1, vesting);
+    }
+    if let Some(rewarded_token2) = rewarded_token2.filter(|_| rewards2 > 0) {
+        transfer_rewards(e, &rewarded_token2, &route, payee, rewards2, donated2, vesting);
+    }
+
+    if let Some(route) = route {
+        if donated1 > 0 || donated2 > 0 {
+            e.events().publish(
+                (symbol_short!("Donated"), user.clone()),
+                (route.recipient, pool_id, donated1, donated2),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn get_settlement(e: &Env) -> DailySettlement {
+    let day = time(e) / SECONDS_PER_DAY;
+    match e
+        .storage()
+        .instance()
+        .get::<_, DailySettlement>(&DataKey::Settlement)
+    {
+        Some(settlement) if settlement.day == day => settlement,
+        _ => DailySettlement {
+            day,
+            deposits: 0,
+            withdrawals: 0,
+            rewards_paid1: 0,
+            rewards_paid2: 0,
+        },
+    }
+}
+
+/// Adds flows to the current settlement day. The first flow after a day boundary publishes
+/// the totals of the previous day before they are reset.
+fn record_settlement(
+    e: &Env,
+    deposits: i128,
+    withdrawals: i128,
+    rewards_paid1: i128,
+    rewards_paid2: i128,
+) -> Result<(), FarmError> {
+    let day = time(e) / SECONDS_PER_DAY;
+    if let Some(closed) = e
+        .storage()
+        .instance()
+        .get::<_, DailySettlement>(&DataKey::Settlement)
+    {
+        if closed.day < day {
+            e.events()
+                .publish((symbol_short!("Settle"), closed.day), closed);
+        }
+    }
+
+    let mut settlement = get_settlement(e);
+    settlement.deposits = checked_add(settlement.deposits, deposits)?;
+    settlement.withdrawals = checked_add(settlement.withdrawals, withdrawals)?;
+    settlement.rewards_paid1 = checked_add(settlement.rewards_paid1, rewards_paid1)?;
+    settlement.rewards_paid2 = checked_add(settlement.rewards_paid2, rewards_paid2)?;
+    e.storage()
+        .instance()
+        .set(&DataKey::Settlement, &settlement);
+
+    Ok(())
+}
+
+/// Helper function to generate the bonus rounds key of a pool.
+fn bonus_rounds_key(pool_id: u32) -> (u32, u32) {
+    (DataKey::BonusRounds as u32, pool_id)
+}
+
+fn get_bonus_rounds(e: &Env, pool_id: u32) -> Vec<BonusRound> {
+    e.storage()
+        .persistent()
+        .get(&bonus_rounds_key(pool_id))
+        .unwrap_or(Vec::new(e))
+}
+
+fn put_bonus_rounds(e: &Env, pool_id: u32, rounds: &Vec<BonusRound>) {
+    e.storage().persistent().set(&bonus_rounds_key(pool_id), rounds);
+}
+
+fn get_bonus_reserved(e: &Env) -> i128 {
+    e.storage()
+        .instance()
+        .get(&DataKey::BonusReserved)
+        .unwrap_or(0)
+}
+
+fn put_bonus_reserved(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::BonusReserved, &amount);
+}
+
+/// Advances the bonus rounds of a pool to `current_time`. While its budget lasts, a round pays
+/// the stake of the pool at its extra rate; once it runs short, what is left is split pro rata
+/// to stake. Budget left when a round ends is released. Returns the rounds and the budget
+/// released.
+fn advance_bonus_rounds(
+    e: &Env,
+    config: &Config,
+    pool_id: u32,
+    current_time: u64,
+) -> Result<(Vec<BonusRound>, i128), FarmError> {
+    let mut rounds = get_bonus_rounds(e, pool_id);
+    let (staked, _) = get_pool_totals(e, pool_id);
+    let mut released = 0;
+
+    for index in 0..rounds.len() {
+        let mut round = rounds.get(index).ok_or(FarmError::PoolNotFound)?;
+        let from = core::cmp::max(round.updated_at, round.start_time);
+        let to = core::cmp::min(current_time, round.end_time);
+        if to > from && round.remaining > 0 && staked > 0 {
+            let earned = scaled_yield(config, staked, round.extra_ratio, (to - from) as i128, 1)?;
+            let earned = core::cmp::min(earned, round.remaining);
+            round.index = checked_add(round.index, mul_div(earned, BONUS_INDEX_SCALE, 1, staked)?)?;
+            round.remaining -= earned;
+        }
+        round.updated_at = core::cmp::max(round.updated_at, to);
+        if current_time >= round.end_time && round.remaining > 0 {
+            released = checked_add(released, round.remaining)?;
+            round.remaining = 0;
+        }
+        rounds.set(index, round);
+    }
+
+    Ok((rounds, released))
+}
+
+/// Bonus earned by a position since its last update across the pool's bonus rounds. Returns
+/// the bonus, the advanced rounds and the budget they release.
+fn bonus_yield(
+    e: &Env,
+    pool_id: u32,
+    user_data: &UserData,
+    current_time: u64,
+) -> Result<(i128, Vec<BonusRound>, i128), FarmError> {
+    if get_bonus_rounds(e, pool_id).is_empty() {
+        return Ok((0, Vec::new(e), 0));
+    }
+
+    let (rounds, released) = advance_bonus_rounds(e, &get_config(e)?, pool_id, current_time)?;
+    let mut total = 0;
+    for (index, round) in rounds.iter().enumerate() {
+        let since = user_data.bonus_index.get(index as u32).unwrap_or(0);
+        let earned = mul_div(user_data.deposited, round.index - since, 1, BONUS_INDEX_SCALE)?;
+        total = checked_add(total, earned)?;
+    }
+
+    Ok((total, rounds, released))
+}
+
+/// Moves the bonus earned since the last update into the user's accrued rewards, shifting it
+/// from the bonus reserve to the allocated rewards. Runs before every change of the pool's
+/// stake, so that the rounds are advanced with the stake they were earned on.
+fn crystallize_bonus(
+    e: &Env,
+    pool_id: u32,
+    user_data: &mut UserData,
+    current_time: u64,
+) -> Result<(), FarmError> {
+    let (bonus, rounds, released) = bonus_yield(e, pool_id, user_data, current_time)?;
+    if rounds.is_empty() {
+        return Ok(());
+    }
+
+    put_bonus_rounds(e, pool_id, &rounds);
+    put_bonus_reserved(e, get_bonus_reserved(e) - bonus - released);
+    let mut bonus_index = Vec::new(e);
+    for round in rounds.iter() {
+        bonus_index.push_back(round.index);
+    }
+    user_data.bonus_index = bonus_index;
+
+    if bonus > 0 {
+        let (allocated_rewards1, allocated_rewards2) = get_allocated_rewards(e)?;
+        put_allocated_rewards(e, checked_add(allocated_rewards1, bonus)?, allocated_rewards2);
+        user_data.accrued_rewards1 = checked_add(user_data.accrued_rewards1, bonus)?;
+    }
+    Ok(())
+}
+
+fn get_risk_oracle(e: &Env) -> Result<Address, FarmError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::RiskOracle)
+        .ok_or(FarmError::RiskOracleNotSet)
+}
+
+fn get_risk_max_age(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::RiskMaxAge).unwrap_or(0)
+}
+
+fn get_risk_score(e: &Env, pool_id: u32) -> Result<RiskScore, FarmError> {
+    e.storage()
+        .persistent()
+        .get(&(DataKey::RiskScore as u32, pool_id))
+        .ok_or(FarmError::RiskScoreNotFound)
+}
+
+fn put_risk_score(e: &Env, pool_id: u32, risk_score: &RiskScore) {
+    e.storage()
+        .persistent()
+        .set(&(DataKey::RiskScore as u32, pool_id), risk_score);
+}
+
+fn get_token_client2(e: &Env) -> Option<token::Client<'_>> {
+    if let Ok(Some(rewarded_token2)) = get_rewarded_token2(e) {
+        Some(token::Client::new(e, &rewarded_token2))
+    } else {
+        None
+    }
+}
+
+fn check_nonnegative_amount(amount: i128) -> Result<(), FarmError> {
+    if amount < 0 {
+        Err(FarmError::InvalidAmount)
+    } else {
+        Ok(())
+    }
+}
+
+fn check_nonzero_amount(amount: i128) -> Result<(), FarmError> {
+    if amount == 0 {
+        Err(FarmError::InvalidAmount)
+    } else {
+        Ok(())
+    }
+}
+
+/// Computes `a * b * c / denominator`, failing with `MathOverflow` instead of trapping.
+fn mul_div(a: i128, b: i128, c: i128, denominator: i128) -> Result<i128, FarmError> {
+    a.checked_mul(b)
+        .and_then(|v| v.checked_mul(c))
+        .and_then(|v| v.checked_div(denominator))
+        .ok_or(FarmError::MathOverflow)
+}
+
+fn checked_add(a: i128, b: i128) -> Result<i128, FarmError> {
+    a.checked_add(b).ok_or(FarmError::MathOverflow)
+}
+
+fn checked_sub(a: i128, b: i128) -> Result<i128, FarmError> {
+    a.checked_sub(b).ok_or(FarmError::MathOverflow)
+}
+
+fn checked_mul(a: i128, b: i128) -> Result<i128, FarmError> {
+    a.checked_mul(b).ok_or(FarmError::MathOverflow)
+}
+
+/// Reward ratios `pool` actually emits, zero for a token it does not reward.
+fn effective_ratios(config: &Config, pool: &Pool) -> (i128, i128) {
+    let ratio1 = if pool.tokens != PoolTokens::Token2 {
+        core::cmp::max(pool.reward_ratio1, 0)
+    } else {
+        0
+    };
+    let ratio2 = if pool.tokens != PoolTokens::Token1 && !config.rewarded_token2.is_empty() {
+        core::cmp::max(pool.reward_ratio2, 0)
+    } else {
+        0
+    };
+    (ratio1, ratio2)
+}
+
+/// Converts an annual rate in basis points into a reward ratio per second.
+fn apr_to_ratio(apr_bps: u32) -> Result<i128, FarmError> {
+    mul_div(apr_bps as i128, 10i128.pow(RATE_DECIMALS), 1, 10000 * SECONDS_PER_YEAR as i128)
+}
+
+/// Annualizes a reward ratio per second into basis points, to the nearest one. Rates beyond
+/// what basis points in a u32 can express saturate.
+fn ratio_to_apr(ratio: i128) -> u32 {
+    let scale = 10i128.pow(RATE_DECIMALS);
+    ratio
+        .checked_mul(10000 * SECONDS_PER_YEAR as i128)
+        .and_then(|annual| annual.checked_add(scale / 2))
+        .and_then(|annual| u32::try_from(annual / scale).ok())
+        .unwrap_or(u32::MAX)
+}
+
+/// Decimals of the pool token and of reward tokens 1 and 2.
+fn get_token_decimals(e: &Env) -> (u32, u32, u32) {
+    get_config(e).map_or((DECIMALS, DECIMALS, DECIMALS), |config| config.decimals)
+}
+
+/// Factors converting pool token units to units of reward token `index`, as a multiplier and
+/// a divisor of which at least one is 1.
+fn decimal_factors(config: &Config, index: u32) -> (i128, i128) {
+    let (pool_decimals, decimals1, decimals2) = config.decimals;
+    let reward_decimals = if index == 1 { decimals1 } else { decimals2 };
+    if reward_decimals >= pool_decimals {
+        (10i128.pow(reward_decimals - pool_decimals), 1)
+    } else {
+        (1, 10i128.pow(pool_decimals - reward_decimals))
+    }
+}
+
+/// Rewards in reward token `index` produced by `amount` of the pool token at `ratio` for
+/// `seconds`. Ratios are in reward tokens per staked token, so the amount is first converted
+/// to the decimals of the reward token, dropping dust below one of its units.
+fn scaled_yield(config: &Config, amount: i128, ratio: i128, seconds: i128, index: u32) -> Result<i128, FarmError> {
+    let (multiplier, divisor) = decimal_factors(config, index);
+    let amount = checked_mul(amount, multiplier)? / divisor;
+    mul_div(amount, ratio, seconds, 10i128.pow(RATE_DECIMALS))
+}
+
+/// Rewards produced by `amount` staked in `pool` for `seconds`, for each reward token.
+fn pool_yield(config: &Config, pool: &Pool, amount: i128, seconds: u64) -> Result<(i128, i128), FarmError> {
+    let (ratio1, ratio2) = effective_ratios(config, pool);
+    let yield1 = if ratio1 > 0 {
+        scaled_yield(config, amount, ratio1, seconds as i128, 1)?
+    } else {
+        0
+    };
+    let yield2 = if ratio2 > 0 {
+        scaled_yield(config, amount, ratio2, seconds as i128, 2)?
+    } else {
+        0
+    };
+    Ok((yield1, yield2))
+}
+
+/// Rewards per second the open positions of all pools accrue, scaled by 10^RATE_DECIMALS.
+fn emission_rates(e: &Env) -> Result<(i128, i128), FarmError> {
+    let (mut rate1, mut rate2) = (0, 0);
+    let config = get_config(e)?;
+    if time(e) >= config.maturity {
+        return Ok((rate1, rate2));
+    }
+    for pool_id in get_pool_ids(e)?.iter() {
+        // Archived pools no longer hold positions
+        let pool = match get_pool_data(e, pool_id) {
+            Ok(pool) => pool,
+            Err(_) => continue,
+        };
+        if is_frozen(e, &get_pool_pause(e, pool_id)) {
+            continue;
+        }
+        let (staked, _) = get_pool_totals(e, pool_id);
+        let (ratio1, ratio2) = effective_ratios(&config, &pool);
+        let (multiplier1, divisor1) = decimal_factors(&config, 1);
+        let (multiplier2, divisor2) = decimal_factors(&config, 2);
+        rate1 = checked_add(rate1, mul_div(staked, ratio1, multiplier1, divisor1)?)?;
+        rate2 = checked_add(rate2, mul_div(staked, ratio2, multiplier2, divisor2)?)?;
+    }
+    Ok((rate1, rate2))
+}
+
+/// Balance of `token` the farm does not owe to anyone.
+fn unallocated_balance(e: &Env, token: &Address) -> Result<i128, FarmError> {
+    let balance = token::Client::new(e, token).balance(&e.current_contract_address());
+    Ok(core::cmp::max(balance - protected_balance(e, token)?, 0))
+}
+
+/// Runway of the unallocated budget of `token` if it were emitted at `rate`.
+fn reward_runway(e: &Env, token: Address, rate: i128) -> Result<RewardRunway, FarmError> {
+    let unallocated = unallocated_balance(e, &token)?;
+    let depletes_at = if rate > 0 {
+        let seconds = mul_div(unallocated, 10i128.pow(RATE_DECIMALS), 1, rate)?;
+        time(e).saturating_add(u64::try_from(seconds).unwrap_or(u64::MAX))
+    } else {
+        0
+    };
+    Ok(RewardRunway {
+        token,
+        unallocated,
+        emission_rate: rate,
+        depletes_at,
+    })
+}
+
+/// Seconds a position has accrued since its last update, only considering up to maturity.
+fn accrual_seconds(user_data: &UserData, current_time: u64, maturity: u64) -> u64 {
+    core::cmp::min(
+        current_time.saturating_sub(user_data.deposit_time),
+        maturity.saturating_sub(user_data.deposit_time),
+    )
+}
+
+fn blocked_key(user: Address) -> (u32, Address) {
+    (DataKey::Blocked as u32, user)
+}
+
+fn is_blocked(e: &Env, user: &Address) -> bool {
+    e.storage().persistent().has(&blocked_key(user.clone()))
+}
+
+fn check_not_blocked(e: &Env, user: &Address) -> Result<(), FarmError> {
+    let key = blocked_key(user.clone());
+    if e.storage().persistent().has(&key) {
+        e.storage()
+            .persistent()
+            .extend_ttl(&key, MAX_TTL - DAY_IN_LEDGERS, MAX_TTL);
+        return Err(FarmError::AddressBlocked);
+    }
+    Ok(())
+}
+
+/// Whether a force-close must leave `user`'s position in `pool_id` alone because the user or
+/// its payout address is blocked.
+fn is_payout_blocked(e: &Env, user: &Address, pool_id: u32) -> bool {
+    is_blocked(e, user) || is_blocked(e, &get_payout_address(e, user.clone(), pool_id))
+}
+
+fn callback_key(user: Address) -> (u32, Address) {
+    (DataKey::Callback as u32, user)
+}
+
+fn reminded_key(user: Address, pool_id: u32) -> (u32, Address, u32) {
+    (DataKey::Reminded as u32, user, pool_id)
+}
+
+/// Reminder due for a position at `current_time`: 1 once its rewards are about to stop
+/// accruing, 2 once they have, 0 before that.
+fn reminder_stage(current_time: u64, maturity: u64) -> u32 {
+    if current_time >= maturity {
+        2
+    } else if current_time >= maturity.saturating_sub(REMINDER_WINDOW) {
+        1
+    } else {
+        0
+    }
+}
+
+fn time(e: &Env) -> u64 {
+    e.ledger().timestamp()
+}
+
+fn extend_instance_ttl(e: &Env) {
+    e.storage()
+        .instance()
+        .extend_ttl(MAX_TTL - DAY_IN_LEDGERS, MAX_TTL)
+}
+
+fn put_pool_counter(e: &Env, counter: u32) {
+    e.storage().instance().set(&DataKey::PoolCounter, &counter);
+}
+
+fn get_max_reward_ratios(e: &Env) -> Result<(i128, Option<i128>), FarmError> {
+    let config = get_config(e)?;
+    Ok((config.max_reward_ratio1, config.max_reward_ratio2))
+}
+
+fn get_pool_counter(e: &Env) -> Result<u32, FarmError> {
+    e.storage()
+        .instance()
+        .get(&DataKey::PoolCounter)
+        .unwrap_or(Ok(0))
+}
+
+fn get_salted_pools(e: &Env) -> Vec<u32> {
+    e.storage()
+        .instance()
+        .get(&DataKey::SaltedPools)
+        .unwrap_or(Vec::new(e))
+}
+
+/// Ids of every pool ever created, counter pools first and then pools created under a salt.
+fn get_pool_ids(e: &Env) -> Result<Vec<u32>, FarmError> {
+    let mut pool_ids = Vec::new(e);
+    for pool_id in 0..get_pool_counter(e)? {
+        pool_ids.push_back(pool_id);
+    }
+    pool_ids.append(&get_salted_pools(e));
+    Ok(pool_ids)
+}
+
+/// Id of the pool created under `salt`, taken from the hash of the salt.
+fn salted_pool_id(e: &Env, salt: &BytesN<32>) -> u32 {
+    let hash = e.crypto().sha256(&salt.clone().into()).to_array();
+    let id = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]);
+    SALTED_POOL_ID_BASE | id
+}
+
+fn is_initialized(e: &Env) -> Result<bool, FarmError> {
+    Ok(e.storage()
+        .instance()
+        .get(&DataKey::Initialized)
+        .unwrap_or(0) == 1)
+}
+
+fn set_initialized(e: &Env) {
+    e.storage().instance().set(&DataKey::Initialized, &1);
+}
+
+fn get_state(e: &Env) -> FarmState {
+    e.storage()
+        .instance()
+        .get(&DataKey::State)
+        .unwrap_or(FarmState::Active)
+}
+
+/// Moves the farm to `state`, publishing the transition on behalf of `by`. Fully stopping the
+/// farm, or restarting it, freezes or resumes the accrual of every pool not stopped on its own.
+fn put_state(e: &Env, state: FarmState, by: Address) -> Result<(), FarmError> {
+    let previous = get_state(e);
+    let pauses = if (previous == FarmState::FullyStopped) != (state == FarmState::FullyStopped) {
+        let mut pauses = Vec::new(e);
+        for pool_id in get_pool_ids(e)?.iter() {
+            if get_pool_data(e, pool_id).is_ok() {
+                pauses.push_back((pool_id, get_pool_pause(e, pool_id)));
+            }
+        }
+        pauses
+    } else {
+        Vec::new(e)
+    };
+
+    if state == FarmState::Active {
+        e.storage().instance().remove(&DataKey::State);
+    } else {
+        e.storage().instance().set(&DataKey::State, &state);
+    }
+    for (pool_id, pause) in pauses.iter() {
+        if !pause.stopped {
+            put_pool_pause(e, pool_id, pause, previous == FarmState::FullyStopped)?;
+        }
+    }
+
+    e.events()
+        .publish((symbol_short!("State"), by), (previous, state));
+    Ok(())
+}
+
+fn check_deposits_open(e: &Env) -> Result<(), FarmError> {
+    if get_state(e) != FarmState::Active {
+        return Err(FarmError::ContractStopped);
+    }
+    Ok(())
+}
+
+/// Rejects deposits during a withdrawal outage: the compensation is one global counter, so a
+/// deposit made late in an outage would be compensated for all of it.
+fn check_withdrawals_not_paused(e: &Env) -> Result<(), FarmError> {
+    if get_withdraw_paused_at(e).is_some() {
+        return Err(FarmError::WithdrawalsPaused);
+    }
+    Ok(())
+}
+
+fn check_not_stopped(e: &Env) -> Result<(), FarmError> {
+    if get_state(e) == FarmState::FullyStopped {
+        return Err(FarmError::ContractStopped);
+    }
+    Ok(())
+}
+
+fn put_funded_rewards(e: &Env, funded1: i128, funded2: i128) {
+    e.storage()
+        .instance()
+        .set(&DataKey::FundedRewards1, &funded1);
+    e.storage()
+        .instance()
+        .set(&DataKey::FundedRewards2, &funded2);
+}
+
+fn get_min_position(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::MinPosition).unwrap_or(0)
+}
+
+fn get_transfer_tolerance(e: &Env) -> u32 {
+    e.storage().instance().get(&DataKey::TransferTolerance).unwrap_or(0)
+}
+
+/// Transfers `amount` of the pool token from `from` to the farm and returns how much the farm
+/// actually received, which is less than `amount` for tokens charging a fee on transfer. Fails
+/// if the shortfall exceeds the transfer tolerance.
+fn receive_pool_token(
+    e: &Env,
+    pool_token: &Address,
+    from: &Address,
+    amount: i128,
+) -> Result<i128, FarmError> {
+    let client = token::Client::new(e, pool_token);
+    let contract = e.current_contract_address();
+    let balance_before = client.balance(&contract);
+    client.transfer(from, &contract, &amount);
+    let received = checked_sub(client.balance(&contract), balance_before)?;
+
+    let tolerance = mul_div(amount, get_transfer_tolerance(e) as i128, 1, 10000)?;
+    if received < checked_sub(amount, tolerance)? {
+        return Err(FarmError::ReceivedLessThanExpected);
+    }
+    // Anything beyond the requested amount is not credited and stays with the farm as surplus
+    Ok(core::cmp::min(received, amount))
+}
+
+/// Amount of `token` the farm owes to stakers, reward allocations, bonus rounds, vesting
+/// escrows and the insurance.
+fn protected_balance(e: &Env, token: &Address) -> Result<i128, FarmError> {
+    let (allocated_rewards1, allocated_rewards2) = get_allocated_rewards(e)?;
+    let (escrow1, escrow2) = get_vesting_escrow(e);
+    let (insurance1, insurance2) = get_insurance_balance(e);
+    let mut protected = 0;
+    if *token == get_pool_token(e)? {
+        protected = checked_add(protected, get_total_staked(e))?;
+    }
+    if *token == get_rewarded_token1(e)? {
+        protected = checked_add(protected, checked_add(allocated_rewards1, get_bonus_reserved(e))?)?;
+        protected = checked_add(protected, checked_add(escrow1, insurance1)?)?;
+    }
+    if Some(token.clone()) == get_rewarded_token2(e)? {
+        protected = checked_add(protected, checked_add(allocated_rewards2, escrow2)?)?;
+        protected = checked_add(protected, insurance2)?;
+    }
+    Ok(protected)
+}
+
+fn reconcile_token(e: &Env, token: Address, funded: i128) -> Result<Reconciliation, FarmError> {
+    let allocated = protected_balance(e, &token)?;
+    let balance = token::Client::new(e, &token).balance(&e.current_contract_address());
+    Ok(Reconciliation {
+        token,
+        funded,
+        allocated,
+        balance,
+        gap: core::cmp::max(allocated - balance, 0),
+    })
+}
+
+fn get_unwinding(e: &Env) -> bool {
+    e.storage().instance().has(&DataKey::Unwinding)
+}
+
+fn get_total_staked(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::TotalStaked).unwrap_or(0)
+}
+
+fn put_total_staked(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::TotalStaked, &amount);
+}
+
+fn get_funded_rewards(e: &Env) -> (i128, i128) {
+    let funded1: i128 = e
+        .storage()
+        .instance()
+        .get(&DataKey::FundedRewards1)
+        .unwrap_or(0);
+    let funded2: i128 = e
+        .storage()
+        .instance()
+        .get(&DataKey::FundedRewards2)
+        .unwrap_or(0);
+    (funded1, funded2)
+}
+
+fn put_funding_restricted(e: &Env, restricted: bool) {
+    if restricted {
+        e.storage().instance().set(&DataKey::FundingRestricted, &1);
+    } else {
+        e.storage().instance().remove(&DataKey::FundingRestricted);
+    }
+}
+
+fn get_funding_restricted(e: &Env) -> bool {
+    e.storage()
+        .instance()
+        .get(&DataKey::FundingRestricted)
+        .unwrap_or(0) == 1
+}
+
+fn put_withdraw_paused_at(e: &Env, paused_at: Option<u64>) {
+    if let Some(paused_at) = paused_at {
+        e.storage().instance().set(&DataKey::WithdrawPausedAt, &paused_at);
+    } else {
+        e.storage().instance().remove(&DataKey::WithdrawPausedAt);
+    }
+}
+
+fn get_withdraw_paused_at(e: &Env) -> Option<u64> {
+    e.storage().instance().get(&DataKey::WithdrawPausedAt)
+}
+
+fn put_grace_period(e: &Env, grace_period: u64) {
+    e.storage().instance().set(&DataKey::GracePeriod, &grace_period);
+}
+
+fn get_grace_period(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::GracePeriod).unwrap_or(0)
+}
+
+fn put_compensation_secs(e: &Env, seconds: u64) {
+    e.storage().instance().set(&DataKey::CompensationSecs, &seconds);
+}
+
+fn get_compensation_secs(e: &Env) -> u64 {
+    e.storage()
+        .instance()
+        .get(&DataKey::CompensationSecs)
+        .unwrap_or(0)
+}
+
+/// Ensures the pool accepts deposits at `current_time`.
+fn check_deposit_open(pool: &Pool, current_time: u64, maturity: u64) -> Result<(), FarmError> {
+    // Check if the current time has passed the maturity date
+    if current_time >= maturity {
+        return Err(FarmError::PoolNotActive);
+    }
+
+    if current_time < pool.start_time {
+        return Err(FarmError::PoolNotActive);
+    }
+
+    if current_time >= pool.end_time {
+        return Err(FarmError::PoolClosed);
+    }
+
+    Ok(())
+}
+
+/// Rewards owed to a user for withdrawal outages longer than the grace period that
+/// happened since their last checkpoint, accrued at the pool's regular rates.
+fn compensation_yield(
+    e: &Env,
+    config: &Config,
+    pool: &Pool,
+    user_data: &UserData,
+) -> Result<(i128, i128), FarmError> {
+    let outage = get_compensation_secs(e).saturating_sub(user_data.compensation_index);
+    pool_yield(config, pool, user_data.deposited, outage)
+}
+
+/// Moves any pending outage compensation into the user's accrued rewards and allocates it.
+fn crystallize_compensation(
+    e: &Env,
+    config: &Config,
+    pool: &Pool,
+    user_data: &mut UserData,
+) -> Result<(), FarmError> {
+    let (yield1, yield2) = compensation_yield(e, config, pool, user_data)?;
+    user_data.compensation_index = get_compensation_secs(e);
+
+    if yield1 == 0 && yield2 == 0 {
+        return Ok(());
+    }
+
+    let (allocated_rewards1, allocated_rewards2) = get_allocated_rewards(e)?;
+    let allocated_rewards1 = checked_add(allocated_rewards1, yield1)?;
+    let allocated_rewards2 = checked_add(allocated_rewards2, yield2)?;
+    if !has_sufficient_rewards(e, config, allocated_rewards1, allocated_rewards2)? {
+        return Err(FarmError::InsufficientRewards);
+    }
+    put_allocated_rewards(e, allocated_rewards1, allocated_rewards2);
+
+    user_data.accrued_rewards1 = checked_add(user_data.accrued_rewards1, yield1)?;
+    user_data.accrued_rewards2 = checked_add(user_data.accrued_rewards2, yield2)?;
+    Ok(())
+}
+
+/// Moves everything a position has earned so far into its accrued rewards, so that it starts
+/// accruing afresh from `current_time`.
+fn crystallize_position(
+    e: &Env,
+    pool: &Pool,
+    pool_id: u32,
+    user_data: &mut UserData,
+    current_time: u64,
+) -> Result<(), FarmError> {
+    let config = get_config(e)?;
+    crystallize_compensation(e, &config, pool, user_data)?;
+    crystallize_bonus(e, pool_id, user_data, current_time)?;
+    let paused = crystallize_pause(e, &config, pool, pool_id, user_data, current_time)?;
+
+    let time_elapsed = accrual_seconds(user_data, current_time, config.maturity).saturating_sub(paused);
+    let (accrued_yield1, accrued_yield2) = pool_yield(&config, pool, user_data.deposited, time_elapsed)?;
+    user_data.accrued_rewards1 = checked_add(user_data.accrued_rewards1, accrued_yield1)?;
+    user_data.accrued_rewards2 = checked_add(user_data.accrued_rewards2, accrued_yield2)?;
+    user_data.deposit_time = current_time;
+    Ok(())
+}
+
+fn split_position_key(id: u32) -> (u32, u32) {
+    (DataKey::SplitPosition as u32, id)
+}
+
+fn get_split_position(e: &Env, id: u32) -> Result<SplitPosition, FarmError> {
+    e.storage()
+        .persistent()
+        .get(&split_position_key(id))
+        .ok_or(FarmError::PositionNotFound)
+}
+
+fn put_split_position(e: &Env, id: u32, split: &SplitPosition) {
+    let key = split_position_key(id);
+    e.storage().persistent().set(&key, split);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, MAX_TTL - DAY_IN_LEDGERS, MAX_TTL);
+}
+
+/// Folds the split position `id` of `owner` into `user_data`, their position in `pool_id`,
+/// both crystallized to `current_time`, and removes it.
+fn fold_split_position(
+    e: &Env,
+    pool: &Pool,
+    pool_id: u32,
+    owner: &Address,
+    user_data: &mut UserData,
+    id: u32,
+    current_time: u64,
+) -> Result<(), FarmError> {
+    let split = get_split_position(e, id)?;
+    if split.owner != *owner {
+        return Err(FarmError::NotAuthorized);
+    }
+    if split.pool_id != pool_id {
+        return Err(FarmError::PositionNotFound);
+    }
+
+    let mut position = split.position;
+    crystallize_position(e, pool, pool_id, &mut position, current_time)?;
+    user_data.deposited = checked_add(user_data.deposited, position.deposited)?;
+    user_data.accrued_rewards1 = checked_add(user_data.accrued_rewards1, position.accrued_rewards1)?;
+    user_data.accrued_rewards2 = checked_add(user_data.accrued_rewards2, position.accrued_rewards2)?;
+    user_data.deposit_ledger = core::cmp::max(user_data.deposit_ledger, position.deposit_ledger);
+    user_data.staked_at = core::cmp::max(user_data.staked_at, position.staked_at);
+    e.storage().persistent().remove(&split_position_key(id));
+    Ok(())
+}
+
+/// A position of `pool_id` holding nothing yet, as of `current_time`.
+fn empty_position(e: &Env, pool_id: u32, current_time: u64) -> Result<UserData, FarmError> {
+    Ok(UserData {
+        deposited: 0,
+        deposit_time: current_time,
+        accrued_rewards1: 0,
+        accrued_rewards2: 0,
+        compensation_index: get_compensation_secs(e),
+        last_claim: current_time,
+        deposit_ledger: e.ledger().sequence(),
+        staked_at: current_time,
+        pause_index: paused_secs(e, pool_id, current_time, get_maturity(e)?),
+        bonus_index: Vec::new(e),
+    })
+}
+
+/// Helper function to generate the claim interval key of a pool.
+fn claim_interval_key(pool_id: u32) -> (u32, u32) {
+    (DataKey::ClaimInterval as u32, pool_id)
+}
+
+fn get_claim_interval(e: &Env, pool_id: u32) -> u64 {
+    e.storage()
+        .persistent()
+        .get(&claim_interval_key(pool_id))
+        .unwrap_or(0)
+}
+
+/// Helper function to generate the withdrawal cooldown key of a pool.
+fn withdraw_cooldown_key(pool_id: u32) -> (u32, u32) {
+    (DataKey::WithdrawCooldown as u32, pool_id)
+}
+
+fn get_withdraw_cooldown(e: &Env, pool_id: u32) -> u64 {
+    e.storage()
+        .persistent()
+        .get(&withdraw_cooldown_key(pool_id))
+        .unwrap_or(0)
+}
+
+fn pool_key(salt: BytesN<32>) -> (u32, BytesN<32>) {
+    (DataKey::PoolKey as u32, salt)
+}
+
+fn min_stake_key(pool_id: u32) -> (u32, u32) {
+    (DataKey::MinStake as u32, pool_id)
+}
+
+fn get_min_stake(e: &Env, pool_id: u32) -> MinStake {
+    e.storage()
+        .persistent()
+        .get(&min_stake_key(pool_id))
+        .unwrap_or(MinStake {
+            duration: 0,
+            forfeit: false,
+        })
+}
+
+/// Applies the minimum stake duration of a pool to a withdrawal of `amount` from `user`'s
+/// position, zero for a claim. Returns whether the position forfeits its rewards. The duration
+/// ends at maturity at the latest.
+fn check_min_stake(e: &Env, user: &Address, pool_id: u32, amount: i128) -> Result<bool, FarmError> {
+    let min_stake = get_min_stake(e, pool_id);
+    if min_stake.duration == 0 {
+        return Ok(false);
+    }
+    let Ok(user_data) = get_user_data(e, user.clone(), pool_id) else {
+        return Ok(false);
+    };
+    let current_time = time(e);
+    if current_time >= user_data.staked_at.saturating_add(min_stake.duration)
+        || current_time >= get_maturity(e)?
+    {
+        return Ok(false);
+    }
+
+    match (amount, min_stake.forfeit) {
+        (0, true) => Err(FarmError::ClaimTooSoon),
+        (0, false) => Ok(false),
+        (_, true) => Ok(true),
+        (_, false) => Err(FarmError::StakeTooShort),
+    }
+}
+
+/// Rejects a deposit or withdrawal started while another one is executing, e.g. from a token
+/// calling back into the farm. The host already rejects re-entering a running contract; the
+/// flag keeps the farm safe should a token reach it through a path the host allows.
+fn enter_guard(e: &Env) -> Result<(), FarmError> {
+    if e.storage().temporary().has(&DataKey::Entered) {
+        return Err(FarmError::ReentrantCall);
+    }
+    e.storage().temporary().set(&DataKey::Entered, &true);
+    Ok(())
+}
+
+fn exit_guard(e: &Env) {
+    e.storage().temporary().remove(&DataKey::Entered);
+}
+
+fn pool_pause_key(pool_id: u32) -> (u32, u32) {
+    (DataKey::PoolPause as u32, pool_id)
+}
+
+fn get_pool_pause(e: &Env, pool_id: u32) -> PoolPause {
+    e.storage()
+        .persistent()
+        .get(&pool_pause_key(pool_id))
+        .unwrap_or(PoolPause {
+            stopped: false,
+            frozen_at: 0,
+            paused_secs: 0,
+        })
+}
+
+fn is_frozen(e: &Env, pause: &PoolPause) -> bool {
+    pause.stopped || get_state(e) == FarmState::FullyStopped
+}
+
+/// Stores the pause of `pool_id` after its pool or the farm was stopped or restarted, starting
+/// or ending a freeze if that changed whether the pool is frozen.
+fn put_pool_pause(e: &Env, pool_id: u32, mut pause: PoolPause, was_frozen: bool) -> Result<(), FarmError> {
+    let current_time = time(e);
+    match (was_frozen, is_frozen(e, &pause)) {
+        (false, true) => pause.frozen_at = current_time,
+        (true, false) => {
+            let frozen_until = core::cmp::min(current_time, get_maturity(e)?);
+            pause.paused_secs += frozen_until.saturating_sub(pause.frozen_at);
+            pause.frozen_at = 0;
+        }
+        _ => {}
+    }
+    e.storage().persistent().set(&pool_pause_key(pool_id), &pause);
+    Ok(())
+}
+
+/// Seconds before maturity `pool_id` has been frozen for so far, including an ongoing freeze.
+fn paused_secs(e: &Env, pool_id: u32, current_time: u64, maturity: u64) -> u64 {
+    let pause = get_pool_pause(e, pool_id);
+    if is_frozen(e, &pause) {
+        let frozen_until = core::cmp::min(current_time, maturity);
+        pause.paused_secs + frozen_until.saturating_sub(pause.frozen_at)
+    } else {
+        pause.paused_secs
+    }
+}
+
+fn check_pool_not_stopped(e: &Env, pool_id: u32) -> Result<(), FarmError> {
+    if get_pool_pause(e, pool_id).stopped {
+        return Err(FarmError::ContractStopped);
+    }
+    Ok(())
+}
+
+/// Moves the position past the seconds its pool was frozen since its last update, which earn
+/// nothing, releasing the rewards allocated for them. Returns the seconds skipped.
+fn crystallize_pause(
+    e: &Env,
+    config: &Config,
+    pool: &Pool,
+    pool_id: u32,
+    user_data: &mut UserData,
+    current_time: u64,
+) -> Result<u64, FarmError> {
+    let index = paused_secs(e, pool_id, current_time, config.maturity);
+    let paused = index.saturating_sub(user_data.pause_index);
+    user_data.pause_index = index;
+    if paused == 0 {
+        return Ok(0);
+    }
+
+    let (released1, released2) = pool_yield(config, pool, user_data.deposited, paused)?;
+    let (allocated_rewards1, allocated_rewards2) = get_allocated_rewards(e)?;
+    put_allocated_rewards(
+        e,
+        core::cmp::max(allocated_rewards1 - released1, 0),
+        core::cmp::max(allocated_rewards2 - released2, 0),
+    );
+    Ok(paused)
+}
+
+fn withdraw_request_key(user: Address, pool_id: u32) -> (u32, Address, u32) {
+    (DataKey::WithdrawRequest as u32, user, pool_id)
+}
+
+fn get_withdraw_request(e: &Env, user: Address, pool_id: u32) -> Result<WithdrawRequest, FarmError> {
+    e.storage()
+        .persistent()
+        .get(&withdraw_request_key(user, pool_id))
+        .ok_or(FarmError::WithdrawRequestNotFound)
+}
+
+/// Rejects withdrawals and claims in the ledger of the position's last deposit, so that
+/// rewards cannot be extracted around a ratio update within a single ledger.
+fn check_not_deposit_ledger(e: &Env, user: &Address, pool_id: u32) -> Result<(), FarmError> {
+    if get_user_data(e, user.clone(), pool_id)?.deposit_ledger == e.ledger().sequence() {
+        return Err(FarmError::SameLedger);
+    }
+    Ok(())
+}
+
+/// Withdraws `amount` of a position's principal along with all its rewards, releasing the
+/// rewards allocated for the withdrawn principal. Within the pool's claim interval a partial
+/// withdrawal keeps the rewards in the position rather than paying them. Queued principal
+/// stays in the farm until `execute_withdraw` pays it out. The position and the allocated
+/// rewards are updated before any token moves. Returns the rewards paid.
+fn withdraw_position(
+    e: &Env,
+    withdrawer: &Address,
+    payee: &Address,
+    amount: i128,
+    pool_id: u32,
+    queued: bool,
+    forfeit: bool,
+) -> Result<(i128, i128), FarmError> {
+    check_not_stopped(e)?;
+    check_pool_not_stopped(e, pool_id)?;
+    check_not_blocked(e, withdrawer)?;
+    check_not_blocked(e, payee)?;
+
+    let pool = get_pool_data(e, pool_id)?;
+    let config = get_config(e)?;
+    let current_time = time(e);
+
+    let mut user_data = get_user_data(e, withdrawer.clone(), pool_id)?;
+    crystallize_compensation(e, &config, &pool, &mut user_data)?;
+    crystallize_bonus(e, pool_id, &mut user_data, current_time)?;
+
+    if amount > user_data.deposited {
+        return Err(FarmError::InvalidAmount);
+    }
+
+    // Partial withdrawals cannot leave a position below the minimum size
+    let remaining = user_data.deposited - amount;
+    if remaining > 0 && remaining < get_min_position(e) {
+        return Err(FarmError::InvalidAmount);
+    }
+
+    if current_time < pool.start_time {
+        return Err(FarmError::PoolNotActive);
+    }
+
+    // Withdrawing nothing only claims rewards, which the pool may rate limit. Partial
+    // withdrawals within the interval leave the rewards in the position instead
+    let claim_too_soon = current_time < user_data.last_claim + get_claim_interval(e, pool_id);
+    if amount == 0 && claim_too_soon {
+        return Err(FarmError::ClaimTooSoon);
+    }
+    let keep_rewards = claim_too_soon && remaining > 0 && !forfeit;
+
+    let maturity = config.maturity;
+
+    // Ensure that the time elapsed only considers up to the maturity date, and skips the time
+    // the pool was frozen
+    let paused = crystallize_pause(e, &config, &pool, pool_id, &mut user_data, current_time)?;
+    let time_elapsed = accrual_seconds(&user_data, current_time, maturity).saturating_sub(paused);
+    let (total_yield1, total_yield2) =
+        pool_yield(&config, &pool, user_data.deposited, time_elapsed)?;
+    let rewards1 = checked_add(user_data.accrued_rewards1, total_yield1)?;
+    let rewards2 = checked_add(user_data.accrued_rewards2, total_yield2)?;
+
+    // While unwinding, rewards are paid as far as the reward balances allow and the rest is
+    // forfeited, so that principal can always be returned. Escrowed rewards and the insurance
+    // are not available. Rewards forfeited by an early withdrawal stay unallocated
+    let (paid1, paid2) = if forfeit || keep_rewards {
+        (0, 0)
+    } else if get_unwinding(e) {
+        let (escrow1, escrow2) = get_vesting_escrow(e);
+        let (insurance1, insurance2) = get_insurance_balance(e);
+        let balance1 = token::Client::new(e, &config.rewarded_token1)
+            .balance(&e.current_contract_address())
+            - escrow1
+            - insurance1;
+        let balance2 = get_token_client2(e)
+            .map_or(0, |client| client.balance(&e.current_contract_address()))
+            - escrow2
+            - insurance2;
+        (
+            core::cmp::min(rewards1, core::cmp::max(balance1, 0)),
+            core::cmp::min(rewards2, core::cmp::max(balance2, 0)),
+        )
+    } else {
+        (rewards1, rewards2)
+    };
+
+    enter_guard(e)?;
+
+    let (mut allocated_rewards1, mut allocated_rewards2) = get_allocated_rewards(e)?;
+    if !keep_rewards {
+        allocated_rewards1 = checked_sub(allocated_rewards1, rewards1)?;
+        allocated_rewards2 = checked_sub(allocated_rewards2, rewards2)?;
+    }
+
+    // Adjust allocated rewards if the user withdraws early (i.e., before maturity)
+    if current_time < maturity {
+        let time_to_maturity = maturity - current_time;
+        let (full_yield1, full_yield2) = pool_yield(&config, &pool, amount, time_to_maturity)?;
+
+        // Reduce the global allocated rewards
+        allocated_rewards1 = checked_sub(allocated_rewards1, full_yield1)?;
+        allocated_rewards2 = checked_sub(allocated_rewards2, full_yield2)?;
+        user_data.deposit_time = current_time;
+    } else {
+        user_data.deposit_time = maturity;
+    }
+
+    put_allocated_rewards(e, allocated_rewards1, allocated_rewards2);
+
+    // Update the user's deposited balance and reset accrued rewards
+    user_data.deposited -= amount;
+    if !queued {
+        put_total_staked(e, core::cmp::max(get_total_staked(e) - amount, 0));
+        record_settlement(e, 0, amount, 0, 0)?;
+    }
+    let (pool_staked, pool_deposited) = get_pool_totals(e, pool_id);
+    put_pool_totals(e, pool_id, core::cmp::max(pool_staked - amount, 0), pool_deposited);
+    let accrued_since = user_data.last_claim;
+    if keep_rewards {
+        user_data.accrued_rewards1 = rewards1;
+        user_data.accrued_rewards2 = rewards2;
+    } else {
+        user_data.accrued_rewards1 = 0;
+        user_data.accrued_rewards2 = 0;
+        user_data.last_claim = current_time;
+    }
+
+    update_leaderboard(e, pool_id, withdrawer, user_data.deposited);
+    record_stake(e, withdrawer, pool_id, user_data.deposited)?;
+    if user_data.deposited > 0 {
+        put_user_data(e, withdrawer.clone(), pool_id, user_data);
+    } else {
+        // Remove user data if all funds are withdrawn
+        remove_user_data(e, withdrawer, pool_id)?;
+    }
+
+    // Transfer accrued rewards up to the maturity date
+    pay_rewards(e, withdrawer, payee, pool_id, paid1, paid2, accrued_since)?;
+
+    // Transfer the withdrawn amount back to the user
+    if amount > 0 && !queued {
+        token::Client::new(e, &config.pool_token).transfer(
+            &e.current_contract_address(),
+            withdrawer,
+            &amount,
+        );
+    }
+    exit_guard(e);
+
+    if forfeit {
+        e.events()
+            .publish((symbol_short!("Forfeit"), withdrawer.clone()), (pool_id, rewards1, rewards2));
+    }
+    if !queued {
+        e.events()
+            .publish((symbol_short!("Withdraw"), withdrawer.clone()), amount);
+    }
+
+    Ok((paid1, paid2))
+}
+
+/// Validates a pool against the maturity and max reward ratios and stores it under the next
+/// pool id. Without `reward_ratio2` the pool emits only reward token 1, and with a zero
+/// `reward_ratio1` only reward token 2.
+fn add_pool(
+    e: &Env,
+    start_time: u64,
+    reward_ratio1: i128,
+    reward_ratio2: Option<i128>,
+    end_time: Option<u64>,
+) -> Result<u32, FarmError> {
+    let counter = get_pool_counter(e)?;
+    put_new_pool(e, counter, start_time, reward_ratio1, reward_ratio2, end_time)?;
+    put_pool_counter(e, counter + 1);
+    Ok(counter)
+}
+
+/// Validates the parameters of a new pool and stores it under `pool_id`.
+fn put_new_pool(
+    e: &Env,
+    pool_id: u32,
+    start_time: u64,
+    reward_ratio1: i128,
+    reward_ratio2: Option<i128>,
+    end_time: Option<u64>,
+) -> Result<(), FarmError> {
+    // Deposit windows default to the global maturity and cannot extend past it
+    let maturity = get_maturity(e)?;
+    let end_time = end_time.unwrap_or(maturity);
+    if end_time <= start_time || end_time > maturity {
+        return Err(FarmError::InvalidAmount);
+    }
+
+    // Get the global max reward ratios
+    let (max_reward_ratio1, max_reward_ratio2) = get_max_reward_ratios(e)?;
+
+    // Ensure the reward ratios are within the specified limits
+    if reward_ratio1 < 0 || reward_ratio2.is_some_and(|ratio2| ratio2 < 0) {
+        return Err(FarmError::InvalidAmount);
+    }
+    if reward_ratio1 > max_reward_ratio1 {
+        return Err(FarmError::InvalidAmount);
+    }
+    if let Some(ratio2) = reward_ratio2 {
+        if let Some(max_ratio2) = max_reward_ratio2 {
+            if ratio2 > max_ratio2 {
+                return Err(FarmError::InvalidAmount);
+            }
+        } else {
+            return Err(FarmError::InvalidAmount);
+        }
+    }
+
+    let tokens = match reward_ratio2 {
+        None => PoolTokens::Token1,
+        Some(ratio2) if reward_ratio1 == 0 && ratio2 > 0 => PoolTokens::Token2,
+        Some(_) => PoolTokens::Both,
+    };
+
+    let pool = Pool {
+        start_time,
+        end_time,
+        reward_ratio1,
+        reward_ratio2: reward_ratio2.unwrap_or(0),
+        tokens,
+        apr_bps1: ratio_to_apr(reward_ratio1),
+        apr_bps2: ratio_to_apr(reward_ratio2.unwrap_or(0)),
+    };
+
+    put_pool_data(e, pool_id, pool);
+    if get_state(e) == FarmState::FullyStopped {
+        let pause = get_pool_pause(e, pool_id);
+        put_pool_pause(e, pool_id, pause, false)?;
+    }
+
+    Ok(())
+}
+
+#[contractimpl]
+impl Farm {
+    pub fn initialize(
+        e: &Env,
+        admin: Address,
+        rewarded_token1: Address,
+        rewarded_token2: Option<Address>,
         pool_token: Address,
         maturity: u64,
         max_reward_ratio1: i128,
         max_reward_ratio2: Option<i128>,
     ) -> Result<String, FarmError> {
-        // Check if the contract is already initialized
-        if is_initialized(e)? {
-            return Err(FarmError::AlreadyInitialized);
+        // Check if the contract is already initialized
+        if is_initialized(e)? {
+            return Err(FarmError::AlreadyInitialized);
+        }
+
+        check_nonnegative_amount(max_reward_ratio1)?;
+        check_nonnegative_amount(max_reward_ratio2.unwrap_or(0))?;
+
+        // Ensure that the reward tokens are not the same as the pool token
+        if rewarded_token1 == pool_token {
+            return Err(FarmError::TokenConflict);
+        }
+        if let Some(ref token2) = rewarded_token2 {
+            if *token2 == pool_token {
+                return Err(FarmError::TokenConflict);
+            }
+            if *token2 == rewarded_token1 {
+                return Err(FarmError::SameRewardTokens);
+            }
+        }
+
+        // Store the admin, reward tokens, pool token, and maturity in the contract's storage
+        put_admin(e, &admin);
+        put_allocated_rewards(e, 0, 0); // Initialize global allocated rewards
+        put_pool_counter(e, 0); // Initialize pool counter
+
+        // Decimals are cached so that accrual does not call the token contracts
+        let decimals = (
+            token::Client::new(e, &pool_token).decimals(),
+            token::Client::new(e, &rewarded_token1).decimals(),
+            rewarded_token2
+                .as_ref()
+                .map_or(DECIMALS, |token2| token::Client::new(e, token2).decimals()),
+        );
+        put_config(
+            e,
+            &Config {
+                pool_token: pool_token.clone(),
+                rewarded_token1: rewarded_token1.clone(),
+                rewarded_token2: Vec::from_slice(e, rewarded_token2.as_slice()),
+                maturity,
+                max_reward_ratio1,
+                max_reward_ratio2,
+                decimals,
+            },
+        );
+
+        set_initialized(e);
+
+        e.events().publish(
+            (symbol_short!("Init"), admin.clone()),
+            (
+                admin,
+                rewarded_token1,
+                rewarded_token2.clone(),
+                pool_token,
+                maturity,
+                max_reward_ratio1,
+                max_reward_ratio2,
+            ),
+        );
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Initializes the farm for the bond shares of `vault`, taking the pool token (the vault's
+    /// bond id) and the maturity from the vault rather than from the caller. The vault is
+    /// recorded as the farm's bond vault.
+    pub fn initialize_from_vault(
+        e: &Env,
+        admin: Address,
+        vault: Address,
+        rewarded_token1: Address,
+        rewarded_token2: Option<Address>,
+        max_reward_ratio1: i128,
+        max_reward_ratio2: Option<i128>,
+    ) -> Result<String, FarmError> {
+        let bond_id: Address = e.invoke_contract(&vault, &Symbol::new(e, "bond_id"), Vec::new(e));
+        let maturity: u64 = e.invoke_contract(&vault, &Symbol::new(e, "maturity"), Vec::new(e));
+        Self::initialize(
+            e,
+            admin.clone(),
+            rewarded_token1,
+            rewarded_token2,
+            bond_id,
+            maturity,
+            max_reward_ratio1,
+            max_reward_ratio2,
+        )?;
+        e.storage().instance().set(&DataKey::BondVault, &vault);
+
+        e.events()
+            .publish((symbol_short!("BondVault"), admin), (vault, maturity));
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    pub fn create_pool(
+        e: &Env,
+        start_time: u64,
+        reward_ratio1: i128,
+        reward_ratio2: Option<i128>,
+        end_time: Option<u64>,
+    ) -> Result<u32, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        let pool_id = add_pool(e, start_time, reward_ratio1, reward_ratio2, end_time)?;
+
+        e.events()
+            .publish((symbol_short!("NewPool"), admin.clone()), pool_id);
+
+        Ok(pool_id)
+    }
+
+    /// Creates a pool whose id is derived from `salt` rather than taken from the pool counter,
+    /// so off-chain systems can pick the salt ahead of the transaction and know the id before it
+    /// lands, see `get_salted_pool_id`. A salt can only be used once.
+    pub fn create_pool_deterministic(
+        e: &Env,
+        salt: BytesN<32>,
+        start_time: u64,
+        reward_ratio1: i128,
+        reward_ratio2: Option<i128>,
+        end_time: Option<u64>,
+    ) -> Result<u32, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        // Distinct salts hashing to the same id are rejected like a reused salt
+        let key = pool_key(salt.clone());
+        let pool_id = salted_pool_id(e, &salt);
+        let mut salted_pools = get_salted_pools(e);
+        if e.storage().persistent().has(&key) || salted_pools.contains(pool_id) {
+            return Err(FarmError::PoolKeyTaken);
+        }
+        put_new_pool(e, pool_id, start_time, reward_ratio1, reward_ratio2, end_time)?;
+        e.storage().persistent().set(&key, &pool_id);
+        salted_pools.push_back(pool_id);
+        e.storage().instance().set(&DataKey::SaltedPools, &salted_pools);
+
+        e.events()
+            .publish((symbol_short!("NewPool"), admin.clone()), pool_id);
+        e.events()
+            .publish((symbol_short!("PoolKey"), admin), (salt, pool_id));
+
+        Ok(pool_id)
+    }
+
+    /// Public function to query the id a pool created under `salt` gets, whether or not it
+    /// exists yet.
+    pub fn get_salted_pool_id(e: &Env, salt: BytesN<32>) -> u32 {
+        salted_pool_id(e, &salt)
+    }
+
+    /// Public function to query the id of the pool created under `salt`.
+    pub fn get_pool_id(e: &Env, salt: BytesN<32>) -> Result<u32, FarmError> {
+        e.storage()
+            .persistent()
+            .get(&pool_key(salt))
+            .ok_or(FarmError::PoolNotFound)
+    }
+
+    /// Creates a pool from annual rates in basis points of the staked tokens instead of ratios
+    /// per second, e.g. 500 for 5% a year. The rates are converted to the ratios the pool
+    /// accrues with, within the max reward ratios.
+    pub fn create_pool_apr(
+        e: &Env,
+        start_time: u64,
+        apr_bps1: u32,
+        apr_bps2: Option<u32>,
+        end_time: Option<u64>,
+    ) -> Result<u32, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        let reward_ratio2 = match apr_bps2 {
+            Some(apr_bps2) => Some(apr_to_ratio(apr_bps2)?),
+            None => None,
+        };
+        let pool_id = add_pool(e, start_time, apr_to_ratio(apr_bps1)?, reward_ratio2, end_time)?;
+
+        e.events()
+            .publish((symbol_short!("NewPool"), admin.clone()), pool_id);
+
+        Ok(pool_id)
+    }
+
+    /// Creates a pool for each `(start_time, reward_ratio1, reward_ratio2)` spec, accepting
+    /// deposits until maturity. Any invalid spec reverts the whole batch. Returns the pool ids.
+    pub fn create_pools(e: &Env, specs: Vec<(u64, i128, Option<i128>)>) -> Result<Vec<u32>, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        if specs.is_empty() || specs.len() > MAX_PAGE_SIZE {
+            return Err(FarmError::InvalidAmount);
+        }
+
+        let mut pool_ids = Vec::new(e);
+        for (start_time, reward_ratio1, reward_ratio2) in specs.iter() {
+            pool_ids.push_back(add_pool(e, start_time, reward_ratio1, reward_ratio2, None)?);
+        }
+
+        e.events()
+            .publish((symbol_short!("NewPools"), admin), pool_ids.clone());
+
+        Ok(pool_ids)
+    }
+
+    /// Ends a pool's deposit window early. Existing positions keep accruing until maturity.
+    pub fn close_pool(e: &Env, pool_id: u32) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        let mut pool = get_pool_data(e, pool_id)?;
+        let current_time = time(e);
+        if current_time >= pool.end_time {
+            return Err(FarmError::PoolClosed);
+        }
+
+        pool.end_time = current_time;
+        put_pool_data(e, pool_id, pool);
+
+        e.events()
+            .publish((symbol_short!("ClosePool"), admin), pool_id);
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Reclaims the storage of a pool once the farm has matured and every position in it has
+    /// been closed, keeping a compact summary readable through `get_archived_summary`.
+    pub fn archive_pool(e: &Env, pool_id: u32) -> Result<ArchivedPool, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        let pool = get_pool_data(e, pool_id)?;
+        let current_time = time(e);
+        if current_time < get_maturity(e)? {
+            return Err(FarmError::MaturityNotReached);
+        }
+        let (staked, deposited) = get_pool_totals(e, pool_id);
+        if staked > 0 {
+            return Err(FarmError::PoolNotEmpty);
+        }
+
+        let summary = ArchivedPool {
+            start_time: pool.start_time,
+            end_time: pool.end_time,
+            reward_ratio1: pool.reward_ratio1,
+            reward_ratio2: pool.reward_ratio2,
+            total_deposited: deposited,
+            archived_at: current_time,
+        };
+
+        // Bonus budget no position can earn anymore becomes unallocated
+        let mut unspent_bonus = 0;
+        for round in get_bonus_rounds(e, pool_id).iter() {
+            unspent_bonus = checked_add(unspent_bonus, round.remaining)?;
+        }
+        put_bonus_reserved(e, get_bonus_reserved(e) - unspent_bonus);
+
+        let storage = e.storage().persistent();
+        storage.remove(&pool_data_key(pool_id));
+        storage.remove(&pool_totals_key(pool_id));
+        storage.remove(&leaderboard_key(pool_id));
+        storage.remove(&bonus_rounds_key(pool_id));
+        storage.remove(&claim_interval_key(pool_id));
+        storage.remove(&withdraw_cooldown_key(pool_id));
+        storage.remove(&(DataKey::RiskScore as u32, pool_id));
+        storage.set(&(DataKey::ArchivedPool as u32, pool_id), &summary);
+
+        e.events()
+            .publish((symbol_short!("Archive"), admin), (pool_id, deposited));
+
+        Ok(summary)
+    }
+
+    /// Public function to query the summary of an archived pool.
+    pub fn get_archived_summary(e: &Env, pool_id: u32) -> Result<ArchivedPool, FarmError> {
+        e.storage()
+            .persistent()
+            .get(&(DataKey::ArchivedPool as u32, pool_id))
+            .ok_or(FarmError::PoolNotFound)
+    }
+
+    pub fn deposit(
+        e: &Env,
+        depositor: Address,
+        amount: i128,
+        pool_id: u32,
+    ) -> Result<i128, FarmError> {
+        depositor.require_auth();
+        extend_instance_ttl(e);
+
+        check_deposits_open(e)?;
+        check_not_blocked(e, &depositor)?;
+        check_pool_not_stopped(e, pool_id)?;
+
+        if get_unwinding(e) {
+            return Err(FarmError::Unwinding);
+        }
+        check_withdrawals_not_paused(e)?;
+
+        check_nonnegative_amount(amount)?;
+        check_nonzero_amount(amount)?;
+
+        let pool = get_pool_data(e, pool_id)?;
+        let config = get_config(e)?;
+        let current_time = time(e);
+        let maturity = config.maturity;
+        check_deposit_open(&pool, current_time, maturity)?;
+
+        // The tokens are pulled in first so the position is credited with what actually arrived.
+        // The guard keeps the token from calling back into the farm before the books are updated.
+        enter_guard(e)?;
+        let amount = receive_pool_token(e, &config.pool_token, &depositor, amount)?;
+        check_nonzero_amount(amount)?;
+
+        // Get existing user data or initialize it
+        let mut user_data = get_user_data(e, depositor.clone(), pool_id).unwrap_or(UserData {
+            deposited: 0,
+            deposit_time: current_time,
+            accrued_rewards1: 0,
+            accrued_rewards2: 0,
+            compensation_index: get_compensation_secs(e),
+            last_claim: current_time,
+            deposit_ledger: e.ledger().sequence(),
+            staked_at: current_time,
+            pause_index: paused_secs(e, pool_id, current_time, maturity),
+            bonus_index: Vec::new(e),
+        });
+        crystallize_compensation(e, &config, &pool, &mut user_data)?;
+        crystallize_bonus(e, pool_id, &mut user_data, current_time)?;
+        let paused = crystallize_pause(e, &config, &pool, pool_id, &mut user_data, current_time)?;
+
+        let time_elapsed = accrual_seconds(&user_data, current_time, maturity).saturating_sub(paused);
+        let (accrued_yield1, accrued_yield2) =
+            pool_yield(&config, &pool, user_data.deposited, time_elapsed)?;
+
+        // Allocate the new potential yield based on the new total deposit
+        let time_to_maturity = maturity - current_time;
+        let (potential_yield1, potential_yield2) =
+            pool_yield(&config, &pool, amount, time_to_maturity)?;
+
+        // Get current allocated rewards and update them
+        let (allocated_rewards1, allocated_rewards2) = get_allocated_rewards(e)?;
+        let allocated_rewards1 = checked_add(allocated_rewards1, potential_yield1)?;
+        let allocated_rewards2 = checked_add(allocated_rewards2, potential_yield2)?;
+
+        // Check if there is enough balance in the contract to cover these new yields
+        if !has_sufficient_rewards(e, &config, allocated_rewards1, allocated_rewards2)? {
+            return Err(FarmError::InsufficientRewards);
+        }
+
+        // Allocate the new rewards globally
+        put_allocated_rewards(e, allocated_rewards1, allocated_rewards2);
+
+        // Update the user's accrued rewards
+        user_data.accrued_rewards1 = checked_add(user_data.accrued_rewards1, accrued_yield1)?;
+        user_data.accrued_rewards2 = checked_add(user_data.accrued_rewards2, accrued_yield2)?;
+
+        // Add the new deposit to the existing deposit amount
+        user_data.deposited = checked_add(user_data.deposited, amount)?;
+        if user_data.deposited < get_min_position(e) {
+            return Err(FarmError::InvalidAmount);
+        }
+        put_total_staked(e, checked_add(get_total_staked(e), amount)?);
+        let (pool_staked, pool_deposited) = get_pool_totals(e, pool_id);
+        put_pool_totals(
+            e,
+            pool_id,
+            checked_add(pool_staked, amount)?,
+            checked_add(pool_deposited, amount)?,
+        );
+        record_settlement(e, amount, 0, 0, 0)?;
+        user_data.deposit_time = current_time; // Reset deposit time to the time of the new deposit
+        user_data.deposit_ledger = e.ledger().sequence();
+        user_data.staked_at = current_time;
+
+        update_leaderboard(e, pool_id, &depositor, user_data.deposited);
+        record_stake(e, &depositor, pool_id, user_data.deposited)?;
+        put_user_data(e, depositor.clone(), pool_id, user_data);
+        exit_guard(e);
+
+        e.events()
+            .publish((symbol_short!("Deposit"), depositor.clone()), amount);
+
+        Ok(amount)
+    }
+
+    /// Splits `amount` of principal off `user`'s position in `pool_id` into a separate position,
+    /// e.g. to be sold over the counter. The rewards earned so far stay with the original
+    /// position. Returns the id of the new position.
+    pub fn split_position(e: &Env, user: Address, pool_id: u32, amount: i128) -> Result<u32, FarmError> {
+        user.require_auth();
+        extend_instance_ttl(e);
+        check_not_stopped(e)?;
+
+        let pool = get_pool_data(e, pool_id)?;
+        let mut user_data = get_user_data(e, user.clone(), pool_id)?;
+        let min_position = core::cmp::max(get_min_position(e), 1);
+        if amount < min_position || user_data.deposited - amount < min_position {
+            return Err(FarmError::InvalidAmount);
+        }
+
+        let current_time = time(e);
+        crystallize_position(e, &pool, pool_id, &mut user_data, current_time)?;
+
+        let split = UserData {
+            deposited: amount,
+            deposit_time: current_time,
+            accrued_rewards1: 0,
+            accrued_rewards2: 0,
+            compensation_index: user_data.compensation_index,
+            last_claim: user_data.last_claim,
+            deposit_ledger: user_data.deposit_ledger,
+            staked_at: user_data.staked_at,
+            pause_index: user_data.pause_index,
+            bonus_index: user_data.bonus_index.clone(),
+        };
+        user_data.deposited -= amount;
+
+        let id: u32 = e.storage().instance().get(&DataKey::SplitCounter).unwrap_or(0);
+        e.storage().instance().set(&DataKey::SplitCounter, &(id + 1));
+        put_split_position(
+            e,
+            id,
+            &SplitPosition {
+                owner: user.clone(),
+                pool_id,
+                position: split,
+            },
+        );
+
+        update_leaderboard(e, pool_id, &user, user_data.deposited);
+        record_stake(e, &user, pool_id, user_data.deposited)?;
+        put_user_data(e, user.clone(), pool_id, user_data);
+
+        e.events()
+            .publish((symbol_short!("Split"), user), (pool_id, id, amount));
+
+        Ok(id)
+    }
+
+    /// Folds the positions split off with the given `ids` back into `user`'s position in
+    /// `pool_id`, along with their accrued rewards. Returns the principal of the merged position.
+    pub fn merge_positions(e: &Env, user: Address, pool_id: u32, ids: Vec<u32>) -> Result<i128, FarmError> {
+        user.require_auth();
+        extend_instance_ttl(e);
+        check_not_stopped(e)?;
+
+        let pool = get_pool_data(e, pool_id)?;
+        let current_time = time(e);
+        let mut user_data = match get_user_data(e, user.clone(), pool_id) {
+            Ok(user_data) => user_data,
+            Err(_) => empty_position(e, pool_id, current_time)?,
+        };
+        crystallize_position(e, &pool, pool_id, &mut user_data, current_time)?;
+
+        for id in ids.iter() {
+            fold_split_position(e, &pool, pool_id, &user, &mut user_data, id, current_time)?;
+        }
+
+        let deposited = user_data.deposited;
+        update_leaderboard(e, pool_id, &user, deposited);
+        record_stake(e, &user, pool_id, deposited)?;
+        put_user_data(e, user.clone(), pool_id, user_data);
+
+        e.events()
+            .publish((symbol_short!("Merge"), user), (pool_id, ids));
+
+        Ok(deposited)
+    }
+
+    /// Public function to query a position split off by `split_position`.
+    pub fn get_split_position(e: &Env, id: u32) -> Result<SplitPosition, FarmError> {
+        get_split_position(e, id)
+    }
+
+    /// Hands the split position `id` over to `to`, along with the rewards it has accrued. The
+    /// new owner can merge it into their own position. Returns the position.
+    pub fn transfer_split_position(e: &Env, id: u32, to: Address) -> Result<SplitPosition, FarmError> {
+        extend_instance_ttl(e);
+        check_not_stopped(e)?;
+
+        let mut split = get_split_position(e, id)?;
+        split.owner.require_auth();
+        check_not_blocked(e, &split.owner)?;
+        check_not_blocked(e, &to)?;
+        if to == split.owner {
+            return Err(FarmError::InvalidAmount);
+        }
+
+        let from = split.owner.clone();
+        split.owner = to.clone();
+        put_split_position(e, id, &split);
+
+        e.events()
+            .publish((symbol_short!("SplitMove"), from), (id, to));
+
+        Ok(split)
+    }
+
+    /// Simulates a deposit of `amount` into a pool without changing any state, returning the
+    /// rewards it would be allocated and whether the reward balance can cover them.
+    pub fn preview_deposit(e: &Env, amount: i128, pool_id: u32) -> Result<DepositPreview, FarmError> {
+        check_deposits_open(e)?;
+        check_withdrawals_not_paused(e)?;
+
+        check_nonnegative_amount(amount)?;
+        check_nonzero_amount(amount)?;
+
+        let pool = get_pool_data(e, pool_id)?;
+        let config = get_config(e)?;
+        let current_time = time(e);
+        let maturity = config.maturity;
+        check_deposit_open(&pool, current_time, maturity)?;
+
+        let time_to_maturity = maturity - current_time;
+        let (projected_reward1, projected_reward2) =
+            pool_yield(&config, &pool, amount, time_to_maturity)?;
+
+        let (allocated_rewards1, allocated_rewards2) = get_allocated_rewards(e)?;
+        let required_budget1 = checked_add(allocated_rewards1, projected_reward1)?;
+        let required_budget2 = checked_add(allocated_rewards2, projected_reward2)?;
+
+        Ok(DepositPreview {
+            projected_reward1,
+            projected_reward2,
+            required_budget1,
+            required_budget2,
+            sufficient: has_sufficient_rewards(e, &config, required_budget1, required_budget2)?,
+        })
+    }
+
+    pub fn withdraw(
+        e: &Env,
+        withdrawer: Address,
+        amount: i128,
+        pool_id: u32,
+    ) -> Result<i128, FarmError> {
+        withdrawer.require_auth();
+        extend_instance_ttl(e);
+
+        check_nonnegative_amount(amount)?;
+
+        if get_withdraw_paused_at(e).is_some() {
+            return Err(FarmError::WithdrawalsPaused);
+        }
+
+        // Principal of pools with a cooldown goes through the withdrawal queue until maturity
+        if amount > 0 && get_withdraw_cooldown(e, pool_id) > 0 && time(e) < get_maturity(e)? {
+            return Err(FarmError::CooldownActive);
+        }
+
+        check_not_blocked(e, &withdrawer)?;
+        check_not_deposit_ledger(e, &withdrawer, pool_id)?;
+        let forfeit = check_min_stake(e, &withdrawer, pool_id, amount)?;
+        let payee = get_payout_address(e, withdrawer.clone(), pool_id);
+        withdraw_position(e, &withdrawer, &payee, amount, pool_id, false, forfeit)?;
+        Ok(amount)
+    }
+
+    /// Queues the withdrawal of `amount` of a position's principal. The amount stops accruing
+    /// at once and the rewards accrued so far are paid, while the principal is paid out by
+    /// `execute_withdraw` after the cooldown of the pool. Only one withdrawal per position can
+    /// be pending. Returns the time from which it can be executed.
+    pub fn request_withdraw(
+        e: &Env,
+        withdrawer: Address,
+        amount: i128,
+        pool_id: u32,
+    ) -> Result<u64, FarmError> {
+        withdrawer.require_auth();
+        extend_instance_ttl(e);
+
+        check_nonnegative_amount(amount)?;
+        check_nonzero_amount(amount)?;
+
+        if get_withdraw_paused_at(e).is_some() {
+            return Err(FarmError::WithdrawalsPaused);
+        }
+
+        let key = withdraw_request_key(withdrawer.clone(), pool_id);
+        if e.storage().persistent().has(&key) {
+            return Err(FarmError::CooldownActive);
+        }
+
+        check_not_blocked(e, &withdrawer)?;
+        check_not_deposit_ledger(e, &withdrawer, pool_id)?;
+        let forfeit = check_min_stake(e, &withdrawer, pool_id, amount)?;
+        let payee = get_payout_address(e, withdrawer.clone(), pool_id);
+        withdraw_position(e, &withdrawer, &payee, amount, pool_id, true, forfeit)?;
+
+        let unlocks_at = time(e) + get_withdraw_cooldown(e, pool_id);
+        e.storage()
+            .persistent()
+            .set(&key, &WithdrawRequest { amount, unlocks_at });
+
+        e.events()
+            .publish((symbol_short!("WdRequest"), withdrawer), (pool_id, amount, unlocks_at));
+
+        Ok(unlocks_at)
+    }
+
+    /// Pays out the principal of a queued withdrawal once its cooldown is over, or at once
+    /// while the farm is unwinding. Returns the principal paid.
+    pub fn execute_withdraw(e: &Env, withdrawer: Address, pool_id: u32) -> Result<i128, FarmError> {
+        withdrawer.require_auth();
+        extend_instance_ttl(e);
+
+        check_not_stopped(e)?;
+        if get_withdraw_paused_at(e).is_some() {
+            return Err(FarmError::WithdrawalsPaused);
+        }
+
+        check_not_blocked(e, &withdrawer)?;
+        let request = get_withdraw_request(e, withdrawer.clone(), pool_id)?;
+        if time(e) < request.unlocks_at && !get_unwinding(e) {
+            return Err(FarmError::CooldownActive);
+        }
+        enter_guard(e)?;
+        e.storage()
+            .persistent()
+            .remove(&withdraw_request_key(withdrawer.clone(), pool_id));
+        put_total_staked(e, core::cmp::max(get_total_staked(e) - request.amount, 0));
+        record_settlement(e, 0, request.amount, 0, 0)?;
+
+        token::Client::new(e, &get_pool_token(e)?).transfer(
+            &e.current_contract_address(),
+            &withdrawer,
+            &request.amount,
+        );
+        exit_guard(e);
+
+        e.events()
+            .publish((symbol_short!("Withdraw"), withdrawer), request.amount);
+
+        Ok(request.amount)
+    }
+
+    /// Public function to query the queued withdrawal of a position.
+    pub fn get_withdraw_request(e: &Env, user: Address, pool_id: u32) -> Result<WithdrawRequest, FarmError> {
+        get_withdraw_request(e, user, pool_id)
+    }
+
+    /// Sets the cooldown, in seconds, between requesting and executing a withdrawal in a pool.
+    /// Until maturity, principal of a pool with a cooldown can only be withdrawn through
+    /// `request_withdraw`. Zero lets positions withdraw directly again.
+    pub fn set_withdraw_cooldown(e: &Env, pool_id: u32, cooldown: u64) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        get_pool_data(e, pool_id)?;
+        e.storage()
+            .persistent()
+            .set(&withdraw_cooldown_key(pool_id), &cooldown);
+
+        e.events()
+            .publish((symbol_short!("Cooldown"), admin), (pool_id, cooldown));
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Public function to query the withdrawal cooldown of a pool.
+    pub fn get_withdraw_cooldown(e: &Env, pool_id: u32) -> Result<u64, FarmError> {
+        get_pool_data(e, pool_id)?;
+        Ok(get_withdraw_cooldown(e, pool_id))
+    }
+
+    /// Sets the minimum time, in seconds, positions of a pool stay staked after their last
+    /// deposit, e.g. for campaigns co-funded by partners. Earlier withdrawals revert, or with
+    /// `forfeit` give up the rewards accrued by the position. Zero removes the minimum.
+    pub fn set_min_stake_duration(
+        e: &Env,
+        pool_id: u32,
+        duration: u64,
+        forfeit: bool,
+    ) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        get_pool_data(e, pool_id)?;
+        if duration == 0 {
+            e.storage().persistent().remove(&min_stake_key(pool_id));
+        } else {
+            e.storage()
+                .persistent()
+                .set(&min_stake_key(pool_id), &MinStake { duration, forfeit });
+        }
+
+        e.events()
+            .publish((symbol_short!("MinStake"), admin), (pool_id, duration, forfeit));
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Public function to query the minimum stake duration of a pool.
+    pub fn get_min_stake_duration(e: &Env, pool_id: u32) -> Result<MinStake, FarmError> {
+        get_pool_data(e, pool_id)?;
+        Ok(get_min_stake(e, pool_id))
+    }
+
+    /// Pays out the rewards of a position without withdrawing principal, at most once per
+    /// claim interval of the pool. Returns the rewards paid.
+    pub fn claim(e: &Env, claimer: Address, pool_id: u32) -> Result<(i128, i128), FarmError> {
+        claimer.require_auth();
+        extend_instance_ttl(e);
+
+        if get_withdraw_paused_at(e).is_some() {
+            return Err(FarmError::WithdrawalsPaused);
+        }
+
+        check_not_blocked(e, &claimer)?;
+        check_not_deposit_ledger(e, &claimer, pool_id)?;
+        check_min_stake(e, &claimer, pool_id, 0)?;
+        let payee = get_payout_address(e, claimer.clone(), pool_id);
+        withdraw_position(e, &claimer, &payee, 0, pool_id, false, false)
+    }
+
+    /// Claims the rewards of `user`'s position on their behalf, e.g. by a keeper ahead of an
+    /// expiring TTL or before maturity. Callable by anyone. The rewards are paid to `user`
+    /// regardless of their payout address, under the same claim interval as `claim`. While
+    /// rewards vest, only `user` can call it: every escrow delays the rewards already locked,
+    /// which third parties must not be able to do.
+    pub fn claim_for(e: &Env, caller: Address, user: Address, pool_id: u32) -> Result<(i128, i128), FarmError> {
+        caller.require_auth();
+        extend_instance_ttl(e);
+
+        if caller != user && get_vesting_period(e) > 0 {
+            return Err(FarmError::NotAuthorized);
+        }
+
+        if get_withdraw_paused_at(e).is_some() {
+            return Err(FarmError::WithdrawalsPaused);
+        }
+
+        check_not_blocked(e, &user)?;
+        check_not_deposit_ledger(e, &user, pool_id)?;
+        check_min_stake(e, &user, pool_id, 0)?;
+        let (paid1, paid2) = withdraw_position(e, &user, &user, 0, pool_id, false, false)?;
+
+        e.events()
+            .publish((symbol_short!("ClaimFor"), caller), (user, pool_id, paid1, paid2));
+
+        Ok((paid1, paid2))
+    }
+
+    /// Pays out the rewards of all of `claimer`'s positions in one go, e.g. for an aggregator
+    /// harvesting many farms. Positions that cannot be claimed yet, because the pool has not
+    /// started, the claim interval has not elapsed or they were deposited in this ledger, are
+    /// skipped. Returns the total rewards paid.
+    pub fn claim_all(e: &Env, claimer: Address) -> Result<(i128, i128), FarmError> {
+        claimer.require_auth();
+        extend_instance_ttl(e);
+
+        if get_withdraw_paused_at(e).is_some() {
+            return Err(FarmError::WithdrawalsPaused);
+        }
+
+        check_not_blocked(e, &claimer)?;
+        let current_time = time(e);
+        let (mut total1, mut total2) = (0, 0);
+        for pool_id in get_pool_ids(e)?.iter() {
+            let (Ok(pool), Ok(user_data)) = (get_pool_data(e, pool_id), get_user_data(e, claimer.clone(), pool_id))
+            else {
+                continue;
+            };
+            if current_time < pool.start_time
+                || current_time < user_data.last_claim + get_claim_interval(e, pool_id)
+                || user_data.deposit_ledger == e.ledger().sequence()
+                || check_min_stake(e, &claimer, pool_id, 0).is_err()
+                || get_pool_pause(e, pool_id).stopped
+            {
+                continue;
+            }
+
+            let payee = get_payout_address(e, claimer.clone(), pool_id);
+            let (paid1, paid2) = withdraw_position(e, &claimer, &payee, 0, pool_id, false, false)?;
+            total1 = checked_add(total1, paid1)?;
+            total2 = checked_add(total2, paid2)?;
+        }
+
+        Ok((total1, total2))
+    }
+
+    /// Rolls the rewards `user`'s position has earned so far into its stored accrued rewards,
+    /// so that it accrues afresh from now on, e.g. by a keeper ahead of a ratio update or a
+    /// stop. Callable by anyone. Returns the accrued rewards of the position.
+    pub fn checkpoint_user(e: &Env, user: Address, pool_id: u32) -> Result<(i128, i128), FarmError> {
+        extend_instance_ttl(e);
+
+        let pool = get_pool_data(e, pool_id)?;
+        let mut user_data = get_user_data(e, user.clone(), pool_id)?;
+        crystallize_position(e, &pool, pool_id, &mut user_data, time(e))?;
+        let accrued = (user_data.accrued_rewards1, user_data.accrued_rewards2);
+        put_user_data(e, user.clone(), pool_id, user_data);
+
+        e.events()
+            .publish((symbol_short!("Checkpnt"), user), (pool_id, accrued.0, accrued.1));
+
+        Ok(accrued)
+    }
+
+    /// Sets the minimum time, in seconds, between two claims of a user in a pool.
+    pub fn set_claim_interval(e: &Env, pool_id: u32, interval: u64) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        get_pool_data(e, pool_id)?;
+        e.storage()
+            .persistent()
+            .set(&claim_interval_key(pool_id), &interval);
+
+        e.events()
+            .publish((symbol_short!("ClaimIntv"), admin), (pool_id, interval));
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Public function to query the minimum time between claims in a pool.
+    pub fn get_claim_interval(e: &Env, pool_id: u32) -> Result<u64, FarmError> {
+        get_pool_data(e, pool_id)?;
+        Ok(get_claim_interval(e, pool_id))
+    }
+
+    /// Sets the minimum principal of a position. Positions below it can only be closed, and
+    /// existing ones can be swept with `sweep_dust`.
+    pub fn set_min_position(e: &Env, min_position: i128) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        check_nonnegative_amount(min_position)?;
+        e.storage()
+            .instance()
+            .set(&DataKey::MinPosition, &min_position);
+
+        e.events()
+            .publish((symbol_short!("MinPos"), admin), min_position);
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Public function to query the minimum principal of a position.
+    pub fn get_min_position(e: &Env) -> Result<i128, FarmError> {
+        Ok(get_min_position(e))
+    }
+
+    /// Sets the shortfall, in basis points of the requested amount, a deposit transfer may arrive
+    /// with, for pool tokens charging a fee on transfer. Deposits are always credited with the
+    /// amount actually received; a larger shortfall fails with `ReceivedLessThanExpected`.
+    pub fn set_transfer_tolerance(e: &Env, tolerance_bps: u32) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        if tolerance_bps > 10000 {
+            return Err(FarmError::InvalidAmount);
+        }
+        e.storage()
+            .instance()
+            .set(&DataKey::TransferTolerance, &tolerance_bps);
+
+        e.events()
+            .publish((symbol_short!("XferTol"), admin), tolerance_bps);
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Public function to query the shortfall, in basis points, a deposit transfer may arrive with.
+    pub fn get_transfer_tolerance(e: &Env) -> Result<u32, FarmError> {
+        Ok(get_transfer_tolerance(e))
+    }
+
+    /// Force-closes the positions of `users` in a pool that are below the minimum position size,
+    /// returning their principal and rewards to them. Other users, and users who are blocked or
+    /// pay out to a blocked address, are skipped. Returns the number of positions closed.
+    pub fn sweep_dust(e: &Env, pool_id: u32, users: Vec<Address>) -> Result<u32, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        if get_withdraw_paused_at(e).is_some() {
+            return Err(FarmError::WithdrawalsPaused);
+        }
+
+        let min_position = get_min_position(e);
+        let mut swept = 0;
+        for user in users.iter() {
+            let deposited = match get_user_data(e, user.clone(), pool_id) {
+                Ok(user_data) => user_data.deposited,
+                Err(_) => continue,
+            };
+            if deposited >= min_position || is_payout_blocked(e, &user, pool_id) {
+                continue;
+            }
+
+            let payee = get_payout_address(e, user.clone(), pool_id);
+            withdraw_position(e, &user, &payee, deposited, pool_id, false, false)?;
+            swept += 1;
+        }
+
+        e.events()
+            .publish((symbol_short!("Swept"), admin), (pool_id, swept));
+
+        Ok(swept)
+    }
+
+    /// Starts an orderly shutdown of the farm. Deposits are rejected from then on, and
+    /// positions are closed through `unwind_batch`. Cannot be undone.
+    pub fn start_unwind(e: &Env) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        e.storage().instance().set(&DataKey::Unwinding, &true);
+
+        e.events()
+            .publish((symbol_short!("Unwind"), admin), get_total_staked(e));
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Returns the principal of the listed users in a pool along with their rewards, as far as
+    /// the reward balances allow, closing their positions. Callable by anyone once the farm
+    /// is unwinding, in batches of at most MAX_PAGE_SIZE users. Users who are blocked or pay out to
+    /// a blocked address are skipped. Returns the number of closed positions.
+    pub fn unwind_batch(e: &Env, pool_id: u32, users: Vec<Address>) -> Result<u32, FarmError> {
+        extend_instance_ttl(e);
+
+        if !get_unwinding(e) {
+            return Err(FarmError::NotUnwinding);
+        }
+        if users.len() > MAX_PAGE_SIZE {
+            return Err(FarmError::InvalidAmount);
+        }
+
+        let mut closed = 0;
+        for user in users.iter() {
+            let deposited = match get_user_data(e, user.clone(), pool_id) {
+                Ok(user_data) => user_data.deposited,
+                Err(_) => continue,
+            };
+            if is_payout_blocked(e, &user, pool_id) {
+                continue;
+            }
+
+            let payee = get_payout_address(e, user.clone(), pool_id);
+            withdraw_position(e, &user, &payee, deposited, pool_id, false, false)?;
+            closed += 1;
+        }
+
+        e.events().publish(
+            (symbol_short!("Unwound"), e.current_contract_address()),
+            (pool_id, closed, get_total_staked(e)),
+        );
+
+        Ok(closed)
+    }
+
+    /// Returns the principal of the listed split positions to their owners along with their
+    /// rewards, as far as the reward balances allow, folding each into its owner's position and
+    /// closing it. Callable by anyone once the farm is unwinding, in batches of at most
+    /// MAX_PAGE_SIZE ids. Splits of blocked owners, or owners paying out to a blocked address,
+    /// are skipped. Returns the number of closed positions.
+    pub fn unwind_splits(e: &Env, ids: Vec<u32>) -> Result<u32, FarmError> {
+        extend_instance_ttl(e);
+
+        if !get_unwinding(e) {
+            return Err(FarmError::NotUnwinding);
+        }
+        if ids.len() > MAX_PAGE_SIZE {
+            return Err(FarmError::InvalidAmount);
+        }
+
+        let current_time = time(e);
+        let mut closed = 0;
+        for id in ids.iter() {
+            let Ok(split) = get_split_position(e, id) else {
+                continue;
+            };
+            let (owner, pool_id) = (split.owner, split.pool_id);
+            if is_payout_blocked(e, &owner, pool_id) {
+                continue;
+            }
+            let pool = get_pool_data(e, pool_id)?;
+            let mut user_data = match get_user_data(e, owner.clone(), pool_id) {
+                Ok(user_data) => user_data,
+                Err(_) => empty_position(e, pool_id, current_time)?,
+            };
+            crystallize_position(e, &pool, pool_id, &mut user_data, current_time)?;
+            fold_split_position(e, &pool, pool_id, &owner, &mut user_data, id, current_time)?;
+            let deposited = user_data.deposited;
+            put_user_data(e, owner.clone(), pool_id, user_data);
+
+            let payee = get_payout_address(e, owner.clone(), pool_id);
+            withdraw_position(e, &owner, &payee, deposited, pool_id, false, false)?;
+            closed += 1;
+        }
+
+        e.events().publish(
+            (symbol_short!("Unwound"), e.current_contract_address()),
+            (ids, closed, get_total_staked(e)),
+        );
+
+        Ok(closed)
+    }
+
+    /// Public function to query whether the farm is unwinding.
+    pub fn is_unwinding(e: &Env) -> Result<bool, FarmError> {
+        Ok(get_unwinding(e))
+    }
+
+    pub fn set_admin(e: &Env, new_admin: Address) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        put_admin(e, &new_admin);
+
+        e.events()
+            .publish((symbol_short!("AdminChg"), admin.clone()), new_admin);
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    pub fn withdraw_unallocated_rewards(
+        e: &Env,
+    ) -> Result<(i128, i128), FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+
+        let current_time = time(e);
+        let maturity = get_maturity(e)?;
+
+        // Ensure that the current time is after the maturity date
+        if current_time < maturity {
+            return Err(FarmError::NotAuthorized);
+        }
+
+        let rewarded_token1 = get_rewarded_token1(e)?;
+
+        // Get the total allocated rewards that should not be withdrawn
+        let (allocated_rewards1, allocated_rewards2) = get_allocated_rewards(e)?;
+        let (escrow1, escrow2) = get_vesting_escrow(e);
+        let (insurance1, insurance2) = get_insurance_balance(e);
+
+        let token_client1 = token::Client::new(e, &rewarded_token1);
+        let available_balance1: i128 = token_client1.balance(&e.current_contract_address());
+        let unallocated_rewards1 = core::cmp::max(
+            available_balance1 - allocated_rewards1 - get_bonus_reserved(e) - escrow1 - insurance1,
+            0,
+        );
+
+        let token_client2 = get_token_client2(e); // Get token client 2 if it exists
+
+        // Get the current balance of the contract
+        let available_balance2 = token_client2
+            .as_ref()
+            .map_or(0, |client| client.balance(&e.current_contract_address()));
+
+        // Calculate unallocated rewards
+        let unallocated_rewards2 =
+            core::cmp::max(available_balance2 - allocated_rewards2 - escrow2 - insurance2, 0);
+
+        // Transfer unallocated rewards to the admin
+        if unallocated_rewards1 > 0 {
+            token_client1.transfer(&e.current_contract_address(), &admin, &unallocated_rewards1);
+        }
+
+        if let Some(client) = token_client2 {
+            if unallocated_rewards2 > 0 {
+                client.transfer(&e.current_contract_address(), &admin, &unallocated_rewards2);
+            }
+        }
+
+        e.events().publish(
+            (symbol_short!("Withdraw"), admin.clone()),
+            (unallocated_rewards1, unallocated_rewards2),
+        );
+
+        Ok((unallocated_rewards1, unallocated_rewards2))
+    }
+
+    /// Recovers tokens sent to the farm by mistake. Staked principal, allocated rewards and bonus
+    /// budgets are protected and cannot be rescued.
+    pub fn rescue_token(
+        e: &Env,
+        token: Address,
+        to: Address,
+        amount: i128,
+    ) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        check_nonnegative_amount(amount)?;
+        check_nonzero_amount(amount)?;
+
+        let client = token::Client::new(e, &token);
+        let surplus = client.balance(&e.current_contract_address()) - protected_balance(e, &token)?;
+        if amount > surplus {
+            return Err(FarmError::ProtectedFunds);
+        }
+
+        client.transfer(&e.current_contract_address(), &to, &amount);
+
+        e.events()
+            .publish((symbol_short!("Rescue"), admin), (token, to, amount));
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Moves the farm to another lifecycle state. Any transition is allowed, so the admin can
+    /// recover from a stop.
+    pub fn set_state(e: &Env, state: FarmState) -> Result<String, FarmError> {
+        let current_admin = get_admin(e)?;
+        current_admin.require_auth();
+        extend_instance_ttl(e);
+
+        put_state(e, state, current_admin)?;
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Public function to query the lifecycle state of the farm.
+    pub fn get_state(e: &Env) -> Result<FarmState, FarmError> {
+        Ok(get_state(e))
+    }
+
+    /// Stops (or restarts) a single pool. Deposits, withdrawals and claims in a stopped pool are
+    /// rejected, and its positions accrue no rewards until it is restarted.
+    pub fn set_pool_stopped(e: &Env, pool_id: u32, stopped: bool) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        get_pool_data(e, pool_id)?;
+        let mut pause = get_pool_pause(e, pool_id);
+        let was_frozen = is_frozen(e, &pause);
+        pause.stopped = stopped;
+        put_pool_pause(e, pool_id, pause, was_frozen)?;
+
+        e.events()
+            .publish((symbol_short!("PoolStop"), admin), (pool_id, stopped));
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Public function to query whether a pool is stopped and how long its rewards were frozen.
+    pub fn get_pool_pause(e: &Env, pool_id: u32) -> Result<PoolPause, FarmError> {
+        get_pool_data(e, pool_id)?;
+        Ok(get_pool_pause(e, pool_id))
+    }
+
+    /// Compares what the farm owes in the pool token and each reward token with its balances.
+    /// A `Discrepancy` event is published for every token short of its obligations, and the
+    /// contract is fully stopped if auto-stop is enabled and a gap exceeds the tolerance.
+    /// Callable by anyone.
+    pub fn reconcile(e: &Env) -> Result<Vec<Reconciliation>, FarmError> {
+        extend_instance_ttl(e);
+
+        let (funded1, funded2) = get_funded_rewards(e);
+        let mut report = Vec::from_array(
+            e,
+            [
+                reconcile_token(e, get_pool_token(e)?, 0)?,
+                reconcile_token(e, get_rewarded_token1(e)?, funded1)?,
+            ],
+        );
+        if let Some(rewarded_token2) = get_rewarded_token2(e)? {
+            report.push_back(reconcile_token(e, rewarded_token2, funded2)?);
+        }
+
+        let tolerance: i128 = e
+            .storage()
+            .instance()
+            .get(&DataKey::ReconcileTolerance)
+            .unwrap_or(0);
+        let auto_stop: bool = e
+            .storage()
+            .instance()
+            .get(&DataKey::ReconcileAutoStop)
+            .unwrap_or(false);
+
+        for entry in report.iter() {
+            if entry.gap == 0 {
+                continue;
+            }
+            e.events().publish(
+                (Symbol::new(e, "Discrepancy"), entry.token.clone()),
+                (entry.allocated, entry.balance, entry.gap),
+            );
+            if auto_stop && entry.gap > tolerance && get_state(e) != FarmState::FullyStopped {
+                put_state(e, FarmState::FullyStopped, e.current_contract_address())?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Sets the gap `reconcile` tolerates and whether it stops the contract beyond it.
+    pub fn set_reconcile_policy(
+        e: &Env,
+        tolerance: i128,
+        auto_stop: bool,
+    ) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        check_nonnegative_amount(tolerance)?;
+        e.storage()
+            .instance()
+            .set(&DataKey::ReconcileTolerance, &tolerance);
+        e.storage()
+            .instance()
+            .set(&DataKey::ReconcileAutoStop, &auto_stop);
+
+        e.events()
+            .publish((symbol_short!("Reconcile"), admin), (tolerance, auto_stop));
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Pulls reward tokens from `from` into the farm and records them as funded rewards.
+    pub fn fund_rewards(
+        e: &Env,
+        from: Address,
+        amount1: i128,
+        amount2: i128,
+    ) -> Result<(i128, i128), FarmError> {
+        from.require_auth();
+        extend_instance_ttl(e);
+
+        if get_funding_restricted(e) && from != get_admin(e)? {
+            return Err(FarmError::NotAuthorized);
         }
 
-        // Ensure that the reward tokens are not the same as the pool token
-        if rewarded_token1 == pool_token {
-            return Err(FarmError::TokenConflict);
+        check_nonnegative_amount(amount1)?;
+        check_nonnegative_amount(amount2)?;
+
+        if amount1 > 0 {
+            token::Client::new(e, &get_rewarded_token1(e)?).transfer(
+                &from,
+                &e.current_contract_address(),
+                &amount1,
+            );
         }
-        if let Some(ref token2) = rewarded_token2 {
-            if *token2 == pool_token {
-                return Err(FarmError::TokenConflict);
-            }
-            if *token2 == rewarded_token1 {
-                return Err(FarmError::SameRewardTokens);
-            }
+
+        if amount2 > 0 {
+            let rewarded_token2 = get_rewarded_token2(e)?.ok_or(FarmError::InvalidAmount)?;
+            token::Client::new(e, &rewarded_token2).transfer(
+                &from,
+                &e.current_contract_address(),
+                &amount2,
+            );
         }
 
-        // Store the admin, reward tokens, pool token, and maturity in the contract's storage
-        put_admin(e, &admin);
-        put_rewarded_tokens(e, rewarded_token1.clone(), rewarded_token2.clone())?;
-        put_pool_token(e, pool_token.clone());
-        put_maturity(e, maturity);
-        put_allocated_rewards(e, 0, 0); // Initialize global allocated rewards
-        put_pool_counter(e, 0); // Initialize pool counter
-        put_max_reward_ratios(e, max_reward_ratio1, max_reward_ratio2)?;
+        let (funded1, funded2) = get_funded_rewards(e);
+        put_funded_rewards(e, funded1 + amount1, funded2 + amount2);
 
-        set_initialized(e);
+        e.events()
+            .publish((symbol_short!("Funded"), from), (amount1, amount2));
 
-        e.events().publish(
-            (symbol_short!("Init"), admin.clone()),
-            (
-                admin,
-                rewarded_token1,
-                rewarded_token2.clone(),
-                pool_token,
-                maturity,
-                max_reward_ratio1,
-                max_reward_ratio2,
-            ),
-        );
+        Ok((funded1 + amount1, funded2 + amount2))
+    }
+
+    /// Sets the contract allowed to stream reward tokens to the farm through `notify_reward`,
+    /// `None` removes it. The emitter must stream one of the reward tokens.
+    pub fn set_emitter(e: &Env, emitter: Option<Address>) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        match &emitter {
+            Some(emitter) => {
+                // Looked up once, the emitter cannot be called back from within its own drip
+                let token: Address = e.invoke_contract(emitter, &Symbol::new(e, "token"), Vec::new(e));
+                if token != get_rewarded_token1(e)? && Some(token.clone()) != get_rewarded_token2(e)? {
+                    return Err(FarmError::TokenConflict);
+                }
+                e.storage().instance().set(&DataKey::Emitter, &(emitter.clone(), token));
+            }
+            None => e.storage().instance().remove(&DataKey::Emitter),
+        }
+
+        e.events()
+            .publish((symbol_short!("Emitter"), admin), emitter);
 
         Ok(String::from_str(e, "Ok"))
     }
 
-    pub fn create_pool(
-        e: &Env,
-        start_time: u64,
-        reward_ratio1: i128,
-        reward_ratio2: Option<i128>,
-    ) -> Result<u32, FarmError> {
+    /// Public function to query the contract streaming reward tokens to the farm, if any.
+    pub fn get_emitter(e: &Env) -> Result<Option<Address>, FarmError> {
+        let emitter: Option<(Address, Address)> = e.storage().instance().get(&DataKey::Emitter);
+        Ok(emitter.map(|(emitter, _)| emitter))
+    }
+
+    /// Records `amount` of the emitter's token, which the emitter transfers to the farm in the
+    /// same invocation, as funded rewards. Only callable by the emitter.
+    pub fn notify_reward(e: &Env, amount: i128) -> Result<(i128, i128), FarmError> {
+        let (emitter, token): (Address, Address) = e
+            .storage()
+            .instance()
+            .get(&DataKey::Emitter)
+            .ok_or(FarmError::EmitterNotSet)?;
+        emitter.require_auth();
+        extend_instance_ttl(e);
+
+        check_nonnegative_amount(amount)?;
+
+        let (mut funded1, mut funded2) = get_funded_rewards(e);
+        if token == get_rewarded_token1(e)? {
+            funded1 = checked_add(funded1, amount)?;
+        } else {
+            funded2 = checked_add(funded2, amount)?;
+        }
+        put_funded_rewards(e, funded1, funded2);
+
+        e.events()
+            .publish((symbol_short!("Notified"), emitter), (token, amount));
+
+        Ok((funded1, funded2))
+    }
+
+    /// Restricts reward funding to the admin when `restricted` is true.
+    pub fn set_funding_restricted(e: &Env, restricted: bool) -> Result<String, FarmError> {
         let admin = get_admin(e)?;
         admin.require_auth();
         extend_instance_ttl(e);
 
-        // Get the global max reward ratios
-        let (max_reward_ratio1, max_reward_ratio2) = get_max_reward_ratios(e)?;
+        put_funding_restricted(e, restricted);
 
-        // Ensure the reward ratios are within the specified limits
-        if reward_ratio1 > max_reward_ratio1 {
-            return Err(FarmError::InvalidAmount);
+        e.events()
+            .publish((symbol_short!("FundRestr"), admin), restricted);
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Public function to query the total rewards funded through `fund_rewards`.
+    pub fn get_funded_rewards(e: &Env) -> Result<(i128, i128), FarmError> {
+        Ok(get_funded_rewards(e))
+    }
+
+    /// Adds `user` to (or removes it from) the blocklist. Blocked addresses cannot deposit,
+    /// withdraw or claim, nor receive payouts or routed rewards, until they are unblocked, their
+    /// positions keep accruing meanwhile and are skipped by force-closes.
+    pub fn set_blocked(e: &Env, user: Address, blocked: bool) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        let key = blocked_key(user.clone());
+        if blocked {
+            e.storage().persistent().set(&key, &true);
+            e.storage()
+                .persistent()
+                .extend_ttl(&key, MAX_TTL - DAY_IN_LEDGERS, MAX_TTL);
+            e.events()
+                .publish((symbol_short!("Blocked"), admin), user);
+        } else {
+            e.storage().persistent().remove(&key);
+            e.events()
+                .publish((symbol_short!("Unblocked"), admin), user);
         }
-        if let Some(ratio2) = reward_ratio2 {
-            if let Some(max_ratio2) = max_reward_ratio2 {
-                if ratio2 > max_ratio2 {
-                    return Err(FarmError::InvalidAmount);
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Public function to query whether an address is blocked.
+    pub fn is_blocked(e: &Env, user: Address) -> bool {
+        is_blocked(e, &user)
+    }
+
+    /// Flags (or clears) a temporary inability to honor withdrawals. Outages lasting longer
+    /// than the grace period are compensated to stakers at their pool's reward rates. Deposits
+    /// are rejected while withdrawals are paused.
+    pub fn set_withdrawals_paused(e: &Env, paused: bool) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        let current_time = time(e);
+        match (get_withdraw_paused_at(e), paused) {
+            (None, true) => put_withdraw_paused_at(e, Some(current_time)),
+            (Some(paused_at), false) => {
+                // Rewards stop accruing at maturity, so does the compensation
+                let outage_end = core::cmp::min(current_time, get_maturity(e)?);
+                let outage = outage_end.saturating_sub(paused_at);
+                if outage > get_grace_period(e) {
+                    put_compensation_secs(e, get_compensation_secs(e) + outage);
                 }
-            } else {
-                return Err(FarmError::InvalidAmount);
+                put_withdraw_paused_at(e, None);
             }
-        } else if max_reward_ratio2.is_some() {
-            return Err(FarmError::InvalidAmount);
+            _ => {}
         }
 
-        let mut counter = get_pool_counter(e)?;
-        let pool = Pool {
-            start_time,
-            reward_ratio1,
-            reward_ratio2: reward_ratio2.unwrap_or(0),
-        };
+        e.events()
+            .publish((symbol_short!("WdPaused"), admin), paused);
 
-        put_pool_data(e, counter, pool);
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Sets how long withdrawals may be paused before stakers are compensated.
+    pub fn set_grace_period(e: &Env, grace_period: u64) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
 
-        counter += 1;
-        put_pool_counter(e, counter);
+        put_grace_period(e, grace_period);
 
         e.events()
-            .publish((symbol_short!("NewPool"), admin.clone()), counter - 1);
+            .publish((symbol_short!("Grace"), admin), grace_period);
 
-        Ok(counter - 1)
+        Ok(String::from_str(e, "Ok"))
     }
 
-    pub fn deposit(
+    /// Public function to query the cumulative compensated outage, in seconds.
+    pub fn get_compensated_outage(e: &Env) -> Result<u64, FarmError> {
+        Ok(get_compensation_secs(e))
+    }
+
+    /// Public function to query the formula used to compute a pool's rewards.
+    pub fn get_reward_formula(e: &Env, pool_id: u32) -> Result<RewardFormula, FarmError> {
+        let pool = get_pool_data(e, pool_id)?;
+        let ratio_scale = 10i128.pow(RATE_DECIMALS);
+        let (decimals, reward_decimals1, reward_decimals2) = get_token_decimals(e);
+
+        Ok(RewardFormula {
+            model: RewardModel::LinearPerSecond,
+            ratio_scale,
+            decimals,
+            reward_decimals1,
+            reward_decimals2,
+            reward_ratio1: pool.reward_ratio1,
+            reward_ratio2: if get_rewarded_token2(e)?.is_some() && pool.tokens != PoolTokens::Token1 {
+                pool.reward_ratio2
+            } else {
+                0
+            },
+            accrual_start: pool.start_time,
+            accrual_end: get_maturity(e)?,
+            lock_multiplier: ratio_scale,
+        })
+    }
+
+    /// Layers a temporary boosted token 1 rate on an active pool for `duration` seconds
+    /// (bounded by maturity), paid from `extra_budget` pulled from the admin. The boosted rate
+    /// cannot exceed the max reward ratio. The bonus budget is kept apart from the base campaign
+    /// rewards, split pro rata to stake if it runs short, and what is left when the round ends
+    /// becomes unallocated.
+    pub fn add_bonus_round(
         e: &Env,
-        depositor: Address,
-        amount: i128,
         pool_id: u32,
-    ) -> Result<i128, FarmError> {
-        depositor.require_auth();
+        extra_budget: i128,
+        extra_ratio: i128,
+        duration: u64,
+    ) -> Result<u32, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
         extend_instance_ttl(e);
 
-        if get_stopped(e)? {
-            return Err(FarmError::ContractStopped);
-        }
-
-        check_nonnegative_amount(amount)?;
-        check_nonzero_amount(amount)?;
+        check_nonnegative_amount(extra_budget)?;
+        check_nonzero_amount(extra_budget)?;
+        check_nonnegative_amount(extra_ratio)?;
+        check_nonzero_amount(extra_ratio)?;
 
         let pool = get_pool_data(e, pool_id)?;
-        let pool_token = get_pool_token(e)?;
+        let config = get_config(e)?;
+        if checked_add(pool.reward_ratio1, extra_ratio)? > config.max_reward_ratio1 {
+            return Err(FarmError::InvalidAmount);
+        }
+        let maturity = config.maturity;
         let current_time = time(e);
-
-        // Check if the current time has passed the maturity date
-        let maturity = get_maturity(e)?;
-        if current_time >= maturity {
+        if current_time >= maturity || duration == 0 {
             return Err(FarmError::PoolNotActive);
         }
 
-        if current_time < pool.start_time {
-            return Err(FarmError::PoolNotActive);
+        let mut rounds = get_bonus_rounds(e, pool_id);
+        if rounds.len() >= MAX_BONUS_ROUNDS {
+            return Err(FarmError::BonusRoundLimit);
         }
 
-        // Get existing user data or initialize it
-        let mut user_data = get_user_data(e, depositor.clone(), pool_id).unwrap_or(UserData {
-            deposited: 0,
-            deposit_time: current_time,
-            accrued_rewards1: 0,
-            accrued_rewards2: 0,
+        token::Client::new(e, &config.rewarded_token1).transfer(
+            &admin,
+            &e.current_contract_address(),
+            &extra_budget,
+        );
+
+        let start_time = core::cmp::max(current_time, pool.start_time);
+        let end_time = core::cmp::min(start_time.saturating_add(duration), maturity);
+        rounds.push_back(BonusRound {
+            start_time,
+            end_time,
+            extra_ratio,
+            budget: extra_budget,
+            remaining: extra_budget,
+            index: 0,
+            updated_at: start_time,
         });
+        put_bonus_rounds(e, pool_id, &rounds);
+        put_bonus_reserved(e, checked_add(get_bonus_reserved(e), extra_budget)?);
 
-        let time_elapsed = core::cmp::min(
-            current_time - user_data.deposit_time,
-            maturity - user_data.deposit_time,
+        e.events().publish(
+            (symbol_short!("Bonus"), admin),
+            (pool_id, extra_budget, extra_ratio, start_time, end_time),
         );
 
-        let accrued_yield1 = if pool.reward_ratio1 > 0 {
-            (user_data.deposited * pool.reward_ratio1 * time_elapsed as i128) / 10i128.pow(DECIMALS)
-        } else {
-            0
-        };
+        Ok(rounds.len() - 1)
+    }
 
-        let accrued_yield2 = if pool.reward_ratio2 > 0 && get_rewarded_token2(e)?.is_some() {
-            (user_data.deposited * pool.reward_ratio2 * time_elapsed as i128) / 10i128.pow(DECIMALS)
-        } else {
-            0
-        };
+    /// Public function to query the bonus rounds of a pool.
+    pub fn get_bonus_rounds(e: &Env, pool_id: u32) -> Result<Vec<BonusRound>, FarmError> {
+        get_pool_data(e, pool_id)?;
+        Ok(get_bonus_rounds(e, pool_id))
+    }
 
-        let time_to_maturity = maturity - current_time;
+    /// Public function to query the current pool counter.
+    pub fn get_current_pool_counter(e: &Env) -> Result<u32, FarmError> {
+        get_pool_counter(e)
+    }
 
-        // Allocate the new potential yield based on the new total deposit
-        let potential_yield1 = if pool.reward_ratio1 > 0 {
-            (amount * pool.reward_ratio1 * time_to_maturity as i128) / 10i128.pow(DECIMALS)
-        } else {
-            0
-        };
-        let potential_yield2 = if pool.reward_ratio2 > 0 && get_rewarded_token2(e)?.is_some() {
-            (amount * pool.reward_ratio2 * time_to_maturity as i128) / 10i128.pow(DECIMALS)
-        } else {
-            0
-        };
+    /// Links the farm to the vault issuing the staked bond share token and brings the farm's
+    /// maturity forward to the bond's if it expires earlier, so accrual and deposits stop when
+    /// the bond expires. Only possible while no principal is staked, `sync_bond_maturity` keeps
+    /// the maturity in line with the vault afterwards.
+    pub fn set_bond_vault(e: &Env, vault: Address) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
 
-        // Get current allocated rewards and update them
-        let (mut allocated_rewards1, mut allocated_rewards2) = get_allocated_rewards(e)?;
+        if get_total_staked(e) > 0 {
+            return Err(FarmError::StakeOutstanding);
+        }
 
-        // Check if there is enough balance in the contract to cover these new yields
-        if !has_sufficient_rewards(
+        let bond_id: Address = e.invoke_contract(&vault, &Symbol::new(e, "bond_id"), Vec::new(e));
+        if bond_id != get_pool_token(e)? {
+            return Err(FarmError::BondVaultMismatch);
+        }
+
+        let vault_maturity: u64 = e.invoke_contract(&vault, &Symbol::new(e, "maturity"), Vec::new(e));
+        let maturity = core::cmp::min(get_maturity(e)?, vault_maturity);
+        put_maturity(e, maturity)?;
+        e.storage().instance().set(&DataKey::BondVault, &vault);
+
+        e.events()
+            .publish((symbol_short!("BondVault"), admin), (vault, maturity));
+
+        Ok(String::from_str(e, "Ok"))
+    }
+
+    /// Brings the farm's maturity forward to the bond vault's if the bond now expires earlier,
+    /// though never before the current time, releasing the rewards allocated to staked
+    /// principal for the time cut off. A later bond maturity leaves the farm's as it is.
+    /// Callable by anyone. Returns the farm's maturity.
+    pub fn sync_bond_maturity(e: &Env) -> Result<u64, FarmError> {
+        extend_instance_ttl(e);
+
+        let vault: Address = e
+            .storage()
+            .instance()
+            .get(&DataKey::BondVault)
+            .ok_or(FarmError::BondVaultMismatch)?;
+        let vault_maturity: u64 = e.invoke_contract(&vault, &Symbol::new(e, "maturity"), Vec::new(e));
+
+        let config = get_config(e)?;
+        let maturity = core::cmp::max(vault_maturity, time(e));
+        if maturity >= config.maturity {
+            return Ok(config.maturity);
+        }
+
+        // Positions were allocated rewards up to the old maturity
+        let cut_off = config.maturity - maturity;
+        let (mut released1, mut released2) = (0, 0);
+        for pool_id in get_pool_ids(e)?.iter() {
+            let Ok(pool) = get_pool_data(e, pool_id) else {
+                continue;
+            };
+            let (staked, _) = get_pool_totals(e, pool_id);
+            let (yield1, yield2) = pool_yield(&config, &pool, staked, cut_off)?;
+            released1 = checked_add(released1, yield1)?;
+            released2 = checked_add(released2, yield2)?;
+        }
+        let (allocated_rewards1, allocated_rewards2) = get_allocated_rewards(e)?;
+        put_allocated_rewards(
             e,
-            allocated_rewards1 + potential_yield1,
-            allocated_rewards2 + potential_yield2,
-        )? {
-            return Err(FarmError::InsufficientRewards);
+            core::cmp::max(allocated_rewards1 - released1, 0),
+            core::cmp::max(allocated_rewards2 - released2, 0),
+        );
+        put_maturity(e, maturity)?;
+
+        e.events()
+            .publish((symbol_short!("Maturity"), vault), (config.maturity, maturity));
+
+        Ok(maturity)
+    }
+
+    /// Returns the vault the farm takes its maturity from, if any.
+    pub fn get_bond_vault(e: &Env) -> Result<Option<Address>, FarmError> {
+        Ok(e.storage().instance().get(&DataKey::BondVault))
+    }
+
+    /// Extends the TTL of the contract instance and of every pool. Read-only entrypoints leave
+    /// TTLs untouched so that they simulate as pure reads; anyone can call this to keep the
+    /// farm alive instead.
+    pub fn bump(e: &Env) -> Result<u32, FarmError> {
+        extend_instance_ttl(e);
+
+        let pool_ids = get_pool_ids(e)?;
+        for pool_id in pool_ids.iter() {
+            let storage_key = pool_data_key(pool_id);
+            if e.storage().persistent().has(&storage_key) {
+                e.storage()
+                    .persistent()
+                    .extend_ttl(&storage_key, MAX_TTL - DAY_IN_LEDGERS, MAX_TTL);
+            }
         }
 
-        // Allocate the new rewards globally
-        allocated_rewards1 += potential_yield1;
-        allocated_rewards2 += potential_yield2;
-        put_allocated_rewards(e, allocated_rewards1, allocated_rewards2);
+        Ok(pool_ids.len())
+    }
 
-        // Update the user's accrued rewards
-        user_data.accrued_rewards1 += accrued_yield1;
-        user_data.accrued_rewards2 += accrued_yield2;
+    /// Public function to query the maturity date.
+    pub fn get_maturity_date(e: &Env) -> Result<u64, FarmError> {
+        get_maturity(e)
+    }
 
-        // Add the new deposit to the existing deposit amount
-        user_data.deposited += amount;
-        user_data.deposit_time = current_time; // Reset deposit time to the time of the new deposit
+    /// Public function to query the configuration and reward balances of the farm in one call.
+    pub fn get_farm_info(e: &Env) -> Result<FarmInfo, FarmError> {
+        let rewarded_token1 = get_rewarded_token1(e)?;
+        let rewarded_token2 = get_rewarded_token2(e)?;
+        let (max_reward_ratio1, max_reward_ratio2) = get_max_reward_ratios(e)?;
+        let (allocated_rewards1, allocated_rewards2) = get_allocated_rewards(e)?;
+        let available_rewards1 = unallocated_balance(e, &rewarded_token1)?;
+        let mut reward_tokens = Vec::from_array(e, [rewarded_token1]);
+        let available_rewards2 = match rewarded_token2 {
+            Some(token) => {
+                reward_tokens.push_back(token.clone());
+                unallocated_balance(e, &token)?
+            }
+            None => 0,
+        };
+
+        Ok(FarmInfo {
+            reward_tokens,
+            pool_token: get_pool_token(e)?,
+            maturity: get_maturity(e)?,
+            max_reward_ratio1,
+            max_reward_ratio2,
+            pool_count: get_pool_ids(e)?.len(),
+            state: get_state(e),
+            allocated_rewards1,
+            allocated_rewards2,
+            available_rewards1,
+            available_rewards2,
+        })
+    }
+
+    /// Returns, for each reward token, the budget not allocated to positions or bonus rounds,
+    /// the rate the open positions currently accrue rewards at and when the unallocated budget
+    /// would run out if it kept being emitted at that rate.
+    pub fn get_reward_runway(e: &Env) -> Result<Vec<RewardRunway>, FarmError> {
+        let (rate1, rate2) = emission_rates(e)?;
+        let mut runway = Vec::from_array(e, [reward_runway(e, get_rewarded_token1(e)?, rate1)?]);
+        if let Some(rewarded_token2) = get_rewarded_token2(e)? {
+            runway.push_back(reward_runway(e, rewarded_token2, rate2)?);
+        }
+        Ok(runway)
+    }
+
+    /// Public function to query the allocated rewards.
+    pub fn get_global_allocated_rewards(e: &Env) -> Result<(i128, i128), FarmError> {
+        get_allocated_rewards(e)
+    }
+
+    /// Public function to query the admin address.
+    pub fn get_admin_address(e: &Env) -> Result<Address, FarmError> {
+        get_admin(e)
+    }
+
+    /// Public function to query a specific pool's data.
+    pub fn get_pool_info(e: &Env, pool_id: u32) -> Result<Pool, FarmError> {
+        get_pool_data(e, pool_id)
+    }
+
+    /// Public function to query a user's data for a specific pool.
+    pub fn get_user_info(e: &Env, user: Address, pool_id: u32) -> Result<UserData, FarmError> {
+        let mut user_data = get_user_data(e, user.clone(), pool_id)?;
+
+        let pool = get_pool_data(e, pool_id)?;
+        let current_time = time(e);
+
+        // Calculate time elapsed since the last deposit or rewards update
+        let config = get_config(e)?;
+        let paused = paused_secs(e, pool_id, current_time, config.maturity).saturating_sub(user_data.pause_index);
+        let time_elapsed = accrual_seconds(&user_data, current_time, config.maturity).saturating_sub(paused);
+
+        // Calculate current accrued rewards
+        let (accrued_yield1, accrued_yield2) =
+            pool_yield(&config, &pool, user_data.deposited, time_elapsed)?;
+
+        // Include any pending outage compensation and bonus
+        let (compensation1, compensation2) = compensation_yield(e, &config, &pool, &user_data)?;
+        let (bonus, _, _) = bonus_yield(e, pool_id, &user_data, current_time)?;
+        let compensation1 = checked_add(compensation1, bonus)?;
+
+        // Update the user data with current accrued rewards
+        user_data.accrued_rewards1 = checked_add(
+            user_data.accrued_rewards1,
+            checked_add(accrued_yield1, compensation1)?,
+        )?;
+        user_data.accrued_rewards2 = checked_add(
+            user_data.accrued_rewards2,
+            checked_add(accrued_yield2, compensation2)?,
+        )?;
+
+        Ok(user_data)
+    }
+
+    /// Public function to query the largest stakers of a pool, largest first. The board is
+    /// approximate: stakers pushed off it are not ranked again until their position changes or
+    /// they are passed to `rank_stakers`, so after withdrawals it may not be the true top.
+    pub fn get_leaderboard(e: &Env, pool_id: u32) -> Result<Vec<LeaderboardEntry>, FarmError> {
+        get_pool_data(e, pool_id)?;
+        Ok(get_leaderboard(e, pool_id))
+    }
+
+    /// Ranks the listed stakers of a pool on its leaderboard with their current positions, e.g.
+    /// stakers that dropped off it before others withdrew. Callable by anyone, in batches of at
+    /// most MAX_PAGE_SIZE users. Users without a position in the pool are skipped.
+    pub fn rank_stakers(e: &Env, pool_id: u32, users: Vec<Address>) -> Result<Vec<LeaderboardEntry>, FarmError> {
+        extend_instance_ttl(e);
 
-        token::Client::new(e, &pool_token).transfer(
-            &depositor,
-            &e.current_contract_address(),
-            &amount,
-        );
-        put_user_data(e, depositor.clone(), pool_id, user_data);
+        get_pool_data(e, pool_id)?;
+        if users.len() > MAX_PAGE_SIZE {
+            return Err(FarmError::InvalidAmount);
+        }
 
-        e.events()
-            .publish((symbol_short!("Deposit"), depositor.clone()), amount);
+        for user in users.iter() {
+            if let Ok(user_data) = get_user_data(e, user.clone(), pool_id) {
+                update_leaderboard(e, pool_id, &user, user_data.deposited);
+            }
+        }
 
-        Ok(amount)
+        Ok(get_leaderboard(e, pool_id))
     }
 
-    pub fn withdraw(
+    /// Public function to query the rewards paid on a user's position over its lifetime.
+    pub fn get_user_lifetime_rewards(
         e: &Env,
-        withdrawer: Address,
-        amount: i128,
+        user: Address,
         pool_id: u32,
-    ) -> Result<i128, FarmError> {
-        withdrawer.require_auth();
-        extend_instance_ttl(e);
+    ) -> Result<(i128, i128), FarmError> {
+        Ok(get_lifetime_rewards(e, user, pool_id))
+    }
 
-        check_nonnegative_amount(amount)?;
+    /// Public function to query the time-weighted average stake of a user's position between
+    /// `from` and `to`, which must not be in the future nor before the oldest stake checkpoint
+    /// kept. Split positions are not included.
+    pub fn get_twab(e: &Env, user: Address, pool_id: u32, from: u64, to: u64) -> Result<i128, FarmError> {
+        if from >= to || to > time(e) {
+            return Err(FarmError::InvalidAmount);
+        }
 
-        let pool = get_pool_data(e, pool_id)?;
-        let pool_token = get_pool_token(e)?;
-        let current_time = time(e);
+        let stake_seconds = checked_sub(
+            cumulative_stake(e, &user, pool_id, to)?,
+            cumulative_stake(e, &user, pool_id, from)?,
+        )?;
+        Ok(stake_seconds / (to - from) as i128)
+    }
 
-        let mut user_data = get_user_data(e, withdrawer.clone(), pool_id)?;
+    /// Returns up to `limit` stake checkpoints of a user's position starting at index `start`,
+    /// oldest first. Only the last MAX_STAKE_CHECKPOINTS are kept, earlier indices are skipped.
+    pub fn get_stake_checkpoints(
+        e: &Env,
+        user: Address,
+        pool_id: u32,
+        start: u32,
+        limit: u32,
+    ) -> Result<Vec<StakeCheckpoint>, FarmError> {
+        let start = core::cmp::max(start, get_checkpoint_start(e, user.clone(), pool_id));
+        let end = core::cmp::min(
+            get_checkpoint_count(e, user.clone(), pool_id),
+            start.saturating_add(core::cmp::min(limit, MAX_PAGE_SIZE)),
+        );
 
-        if amount > user_data.deposited {
-            return Err(FarmError::InvalidAmount);
+        let mut checkpoints = Vec::new(e);
+        for index in start..end {
+            checkpoints.push_back(get_checkpoint(e, user.clone(), pool_id, index)?);
         }
+        Ok(checkpoints)
+    }
 
-        if current_time < pool.start_time {
-            return Err(FarmError::PoolNotActive);
-        }
+    /// Returns the running totals of the current settlement day.
+    pub fn get_daily_settlement(e: &Env) -> Result<DailySettlement, FarmError> {
+        Ok(get_settlement(e))
+    }
 
-        let maturity = get_maturity(e)?;
+    /// Public function to query the rewards paid by the farm across all users.
+    pub fn get_total_rewards_paid(e: &Env) -> Result<(i128, i128), FarmError> {
+        Ok(get_total_rewards_paid(e))
+    }
 
-        // Ensure that the time elapsed only considers up to the maturity date
-        let time_elapsed = core::cmp::min(
-            current_time - user_data.deposit_time,
-            maturity - user_data.deposit_time,
-        );
+    /// Sets the risk oracle allowed to publish pool risk scores, and the age in seconds after
+    /// which a score is reported as stale (0 never goes stale).
+    pub fn set_risk_oracle(e: &Env, oracle: Address, max_age: u64) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
 
-        let total_yield1 = if pool.reward_ratio1 > 0 {
-            (user_data.deposited * pool.reward_ratio1 * time_elapsed as i128) / 10i128.pow(DECIMALS)
-        } else {
-            0
-        };
+        e.storage().instance().set(&DataKey::RiskOracle, &oracle);
+        e.storage().instance().set(&DataKey::RiskMaxAge, &max_age);
 
-        let total_yield2 = if pool.reward_ratio2 > 0 && get_rewarded_token2(e)?.is_some() {
-            (user_data.deposited * pool.reward_ratio2 * time_elapsed as i128) / 10i128.pow(DECIMALS)
-        } else {
-            0
-        };
+        e.events()
+            .publish((symbol_short!("RiskOracl"), admin), (oracle, max_age));
 
-        // Transfer the withdrawn amount back to the user
-        if amount > 0 {
-            token::Client::new(e, &pool_token).transfer(
-                &e.current_contract_address(),
-                &withdrawer,
-                &amount,
-            );
-        }
+        Ok(String::from_str(e, "Ok"))
+    }
 
-        // Transfer accrued rewards up to the maturity date
-        if user_data.accrued_rewards1 + total_yield1 > 0 {
-            token::Client::new(e, &get_rewarded_token1(e)?).transfer(
-                &e.current_contract_address(),
-                &withdrawer,
-                &(user_data.accrued_rewards1 + total_yield1),
-            );
-        }
+    /// Public function to query the risk oracle and the max age of its scores.
+    pub fn get_risk_oracle(e: &Env) -> Result<(Address, u64), FarmError> {
+        Ok((get_risk_oracle(e)?, get_risk_max_age(e)))
+    }
 
-        if user_data.accrued_rewards2 + total_yield2 > 0 {
-            if let Some(rewarded_token2) = get_rewarded_token2(e)? {
-                token::Client::new(e, &rewarded_token2).transfer(
-                    &e.current_contract_address(),
-                    &withdrawer,
-                    &(user_data.accrued_rewards2 + total_yield2),
-                );
-            }
+    /// Publishes the risk score of a pool, in basis points. Only callable by the risk oracle.
+    pub fn publish_risk_score(e: &Env, pool_id: u32, score: u32) -> Result<String, FarmError> {
+        let oracle = get_risk_oracle(e)?;
+        oracle.require_auth();
+        extend_instance_ttl(e);
+
+        get_pool_data(e, pool_id)?;
+        if score > 10000 {
+            return Err(FarmError::InvalidAmount);
         }
 
-        let (mut allocated_rewards1, mut allocated_rewards2) = get_allocated_rewards(e)?;
-        allocated_rewards1 -= user_data.accrued_rewards1 + total_yield1;
-        allocated_rewards2 -= user_data.accrued_rewards2 + total_yield2;
+        let updated_at = time(e);
+        put_risk_score(
+            e,
+            pool_id,
+            &RiskScore {
+                score,
+                updated_at,
+                stale: false,
+            },
+        );
 
-        // Adjust allocated rewards if the user withdraws early (i.e., before maturity)
-        if current_time < maturity {
-            let time_to_maturity = maturity - current_time;
-            let full_yield1 = if pool.reward_ratio1 > 0 {
-                (amount * pool.reward_ratio1 * time_to_maturity as i128) / 10i128.pow(DECIMALS)
-            } else {
-                0
-            };
-            let full_yield2 = if pool.reward_ratio2 > 0 && get_rewarded_token2(e)?.is_some() {
-                (amount * pool.reward_ratio2 * time_to_maturity as i128) / 10i128.pow(DECIMALS)
-            } else {
-                0
-            };
+        e.events()
+            .publish((symbol_short!("RiskScore"), oracle), (pool_id, score, updated_at));
 
-            // Reduce the global allocated rewards
-            allocated_rewards1 -= full_yield1;
-            allocated_rewards2 -= full_yield2;
-            user_data.deposit_time = current_time;
-        } else {
-            user_data.deposit_time = maturity;
-        }
+        Ok(String::from_str(e, "Ok"))
+    }
 
-        put_allocated_rewards(e, allocated_rewards1, allocated_rewards2);
+    /// Public function to query the latest risk score of a pool and whether it is stale.
+    pub fn get_risk_score(e: &Env, pool_id: u32) -> Result<RiskScore, FarmError> {
+        let mut risk_score = get_risk_score(e, pool_id)?;
+        let max_age = get_risk_max_age(e);
+        risk_score.stale =
+            max_age > 0 && time(e).saturating_sub(risk_score.updated_at) > max_age;
 
-        // Update the user's deposited balance and reset accrued rewards
-        user_data.deposited -= amount;
-        user_data.accrued_rewards1 = 0;
-        user_data.accrued_rewards2 = 0;
+        Ok(risk_score)
+    }
 
-        if user_data.deposited > 0 {
-            put_user_data(e, withdrawer.clone(), pool_id, user_data);
-        } else {
-            // Remove user data if all funds are withdrawn
-            remove_user_data(e, &withdrawer, pool_id)?;
-        }
+    /// Sets the period over which rewards claimed from now on vest, in seconds. Claimed rewards
+    /// are escrowed and released linearly through `release_vested`. Zero pays them out at once.
+    pub fn set_vesting_period(e: &Env, period: u64) -> Result<String, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+        extend_instance_ttl(e);
+
+        e.storage().instance().set(&DataKey::VestingPeriod, &period);
 
         e.events()
-            .publish((symbol_short!("Withdraw"), withdrawer.clone()), amount);
+            .publish((symbol_short!("VestPer"), admin), period);
 
-        Ok(amount)
+        Ok(String::from_str(e, "Ok"))
     }
 
-    pub fn set_admin(e: &Env, new_admin: Address) -> Result<String, FarmError> {
+    pub fn get_vesting_period(e: &Env) -> Result<u64, FarmError> {
+        Ok(get_vesting_period(e))
+    }
+
+    /// Sets the share of every reward payout, in basis points, the farm holds back as insurance
+    /// against accounting errors, at most 10%. Zero stops setting rewards aside. The new rate
+    /// applies to rewards accruing after the change: positions last paid before it are charged
+    /// the lower of the previous and the new rate on their next payout.
+    pub fn set_insurance_bps(e: &Env, insurance_bps: u32) -> Result<String, FarmError> {
         let admin = get_admin(e)?;
         admin.require_auth();
         extend_instance_ttl(e);
 
-        put_admin(e, &new_admin);
+        if insurance_bps > MAX_INSURANCE_BPS {
+            return Err(FarmError::InvalidAmount);
+        }
+        e.storage()
+            .instance()
+            .set(&DataKey::InsuranceChange, &(get_insurance_bps(e), time(e)));
+        e.storage().instance().set(&DataKey::InsuranceBps, &insurance_bps);
 
         e.events()
-            .publish((symbol_short!("AdminChg"), admin.clone()), new_admin);
+            .publish((symbol_short!("InsureBps"), admin), insurance_bps);
 
         Ok(String::from_str(e, "Ok"))
     }
 
-    pub fn withdraw_unallocated_rewards(
+    /// Public function to query the share of every reward payout, in basis points, held back
+    /// as insurance.
+    pub fn get_insurance_bps(e: &Env) -> Result<u32, FarmError> {
+        Ok(get_insurance_bps(e))
+    }
+
+    /// Transfers insurance held back from reward payouts to `to`. Only possible after maturity.
+    pub fn draw_insurance(
         e: &Env,
+        to: Address,
+        amount1: i128,
+        amount2: i128,
     ) -> Result<(i128, i128), FarmError> {
         let admin = get_admin(e)?;
         admin.require_auth();
+        extend_instance_ttl(e);
 
-        let current_time = time(e);
-        let maturity = get_maturity(e)?;
+        check_nonnegative_amount(amount1)?;
+        check_nonnegative_amount(amount2)?;
+        if time(e) < get_maturity(e)? {
+            return Err(FarmError::MaturityNotReached);
+        }
 
-        // Ensure that the current time is after the maturity date
-        if current_time < maturity {
-            return Err(FarmError::NotAuthorized);
+        let (insurance1, insurance2) = get_insurance_balance(e);
+        if amount1 > insurance1 || amount2 > insurance2 {
+            return Err(FarmError::InsufficientRewards);
         }
+        put_insurance_balance(e, insurance1 - amount1, insurance2 - amount2);
 
-        let rewarded_token1 = get_rewarded_token1(e)?;
+        if amount1 > 0 {
+            token::Client::new(e, &get_rewarded_token1(e)?).transfer(
+                &e.current_contract_address(),
+                &to,
+                &amount1,
+            );
+        }
+        if let Some(client) = get_token_client2(e) {
+            if amount2 > 0 {
+                client.transfer(&e.current_contract_address(), &to, &amount2);
+            }
+        }
 
-        // Get the total allocated rewards that should not be withdrawn
-        let (allocated_rewards1, allocated_rewards2) = get_allocated_rewards(e)?;
+        e.events()
+            .publish((symbol_short!("DrawInsur"), admin), (to, amount1, amount2));
 
-        let token_client1 = token::Client::new(e, &rewarded_token1);
-        let available_balance1: i128 = token_client1.balance(&e.current_contract_address());
-        let unallocated_rewards1 = core::cmp::max(available_balance1 - allocated_rewards1, 0);
+        Ok((amount1, amount2))
+    }
 
-        let token_client2 = get_token_client2(e); // Get token client 2 if it exists
+    /// Public function to query the rewards held back as insurance and not yet drawn.
+    pub fn insurance_balance(e: &Env) -> (i128, i128) {
+        get_insurance_balance(e)
+    }
 
-        // Get the current balance of the contract
-        let available_balance2 = token_client2
-            .as_ref()
-            .map_or(0, |client| client.balance(&e.current_contract_address()));
+    /// Public function to query the escrowed rewards of `user` vested so far and not yet
+    /// released.
+    pub fn claimable_vested(e: &Env, user: Address) -> Result<(i128, i128), FarmError> {
+        let schedule = get_vesting_schedule(e, user, time(e))?;
+        Ok((schedule.vested1, schedule.vested2))
+    }
 
-        // Calculate unallocated rewards
-        let unallocated_rewards2 = core::cmp::max(available_balance2 - allocated_rewards2, 0);
+    /// Public function to query the vesting schedule of `user` as last updated.
+    pub fn get_vesting_schedule(e: &Env, user: Address) -> Result<VestingSchedule, FarmError> {
+        e.storage()
+            .persistent()
+            .get(&vesting_key(user))
+            .ok_or(FarmError::UserNotFound)
+    }
 
-        // Transfer unallocated rewards to the admin
-        if unallocated_rewards1 > 0 {
-            token_client1.transfer(&e.current_contract_address(), &admin, &unallocated_rewards1);
+    /// Transfers the escrowed rewards of `user` vested so far. Returns the amounts released.
+    pub fn release_vested(e: &Env, user: Address) -> Result<(i128, i128), FarmError> {
+        user.require_auth();
+        extend_instance_ttl(e);
+        check_not_stopped(e)?;
+
+        let mut schedule = get_vesting_schedule(e, user.clone(), time(e))?;
+        let (released1, released2) = (schedule.vested1, schedule.vested2);
+        if released1 == 0 && released2 == 0 {
+            return Ok((0, 0));
         }
 
-        if let Some(client) = token_client2 {
-            if unallocated_rewards2 > 0 {
-                client.transfer(&e.current_contract_address(), &admin, &unallocated_rewards2);
+        if released1 > 0 {
+            token::Client::new(e, &get_rewarded_token1(e)?).transfer(
+                &e.current_contract_address(),
+                &user,
+                &released1,
+            );
+        }
+        if released2 > 0 {
+            if let Some(client) = get_token_client2(e) {
+                client.transfer(&e.current_contract_address(), &user, &released2);
             }
         }
 
-        e.events().publish(
-            (symbol_short!("Withdraw"), admin.clone()),
-            (unallocated_rewards1, unallocated_rewards2),
-        );
-
-        Ok((unallocated_rewards1, unallocated_rewards2))
-    }
+        let key = vesting_key(user.clone());
+        if schedule.locked1 == 0 && schedule.locked2 == 0 {
+            e.storage().persistent().remove(&key);
+        } else {
+            schedule.vested1 = 0;
+            schedule.vested2 = 0;
+            e.storage().persistent().set(&key, &schedule);
+        }
 
-    pub fn set_contract_stopped(e: &Env, stopped: bool) -> Result<String, FarmError> {
-        let current_admin = get_admin(e)?;
-        current_admin.require_auth();
+        let (escrow1, escrow2) = get_vesting_escrow(e);
+        put_vesting_escrow(e, escrow1 - released1, escrow2 - released2);
 
-        put_stopped(e, stopped);
+        e.events()
+            .publish((symbol_short!("Vested"), user), (released1, released2));
 
-        e.events().publish((symbol_short!("Stopped"), current_admin.clone()), stopped);
-        Ok(String::from_str(e, "Contract stopped"))
+        Ok((released1, released2))
     }
 
-    /// Public function to query the current pool counter.
-    pub fn get_current_pool_counter(e: &Env) -> Result<u32, FarmError> {
+    /// Routes `share_bps` basis points of the rewards `user` claims to `recipient`. A zero share
+    /// removes the route.
+    pub fn set_reward_route(
+        e: &Env,
+        user: Address,
+        recipient: Address,
+        share_bps: u32,
+    ) -> Result<String, FarmError> {
+        user.require_auth();
         extend_instance_ttl(e);
-        get_pool_counter(e)
+
+        if share_bps > 10000 {
+            return Err(FarmError::InvalidAmount);
+        }
+        if share_bps > 0 {
+            check_not_blocked(e, &recipient)?;
+        }
+
+        if share_bps == 0 {
+            e.storage().persistent().remove(&reward_route_key(user.clone()));
+        } else {
+            e.storage().persistent().set(
+                &reward_route_key(user.clone()),
+                &RewardRoute {
+                    recipient: recipient.clone(),
+                    share_bps,
+                },
+            );
+        }
+
+        e.events()
+            .publish((symbol_short!("Route"), user), (recipient, share_bps));
+
+        Ok(String::from_str(e, "Ok"))
     }
 
-    /// Public function to query the maturity date.
-    pub fn get_maturity_date(e: &Env) -> Result<u64, FarmError> {
-        extend_instance_ttl(e);
-        get_maturity(e)
+    /// Public function to query where a user routes part of its claimed rewards.
+    pub fn get_reward_route(e: &Env, user: Address) -> Result<RewardRoute, FarmError> {
+        get_reward_route(e, user).ok_or(FarmError::RewardRouteNotSet)
     }
 
-    /// Public function to query the allocated rewards.
-    pub fn get_global_allocated_rewards(e: &Env) -> Result<(i128, i128), FarmError> {
+    /// Pays the rewards of `user`'s position in `pool_id` to `to`, e.g. a cold wallet, while the
+    /// principal stays with `user`. Setting `to` back to `user` removes the redirection.
+    pub fn set_payout_address(
+        e: &Env,
+        user: Address,
+        pool_id: u32,
+        to: Address,
+    ) -> Result<String, FarmError> {
+        user.require_auth();
         extend_instance_ttl(e);
-        get_allocated_rewards(e)
+
+        get_user_data(e, user.clone(), pool_id)?;
+
+        check_not_blocked(e, &to)?;
+
+        let key = payout_address_key(user.clone(), pool_id);
+        if to == user {
+            e.storage().persistent().remove(&key);
+        } else {
+            e.storage().persistent().set(&key, &to);
+        }
+
+        e.events()
+            .publish((symbol_short!("Payout"), user), (pool_id, to));
+
+        Ok(String::from_str(e, "Ok"))
     }
 
-    /// Public function to query the admin address.
-    pub fn get_admin_address(e: &Env) -> Result<Address, FarmError> {
+    /// Registers a contract the farm calls, best effort, when `user`'s positions are about to
+    /// stop accruing rewards and once they have, e.g. an auto-claim keeper. `None` removes it.
+    pub fn set_callback(e: &Env, user: Address, callback: Option<Address>) -> Result<String, FarmError> {
+        user.require_auth();
         extend_instance_ttl(e);
-        get_admin(e)
+
+        let key = callback_key(user.clone());
+        match &callback {
+            Some(callback) => e.storage().persistent().set(&key, callback),
+            None => e.storage().persistent().remove(&key),
+        }
+
+        e.events()
+            .publish((symbol_short!("Callback"), user), callback);
+
+        Ok(String::from_str(e, "Ok"))
     }
 
-    /// Public function to query a specific pool's data.
-    pub fn get_pool_info(e: &Env, pool_id: u32) -> Result<Pool, FarmError> {
-        extend_instance_ttl(e);
-        get_pool_data(e, pool_id)
+    /// Public function to query the contract notified of `user`'s maturity reminders.
+    pub fn get_callback(e: &Env, user: Address) -> Result<Option<Address>, FarmError> {
+        Ok(e.storage().persistent().get(&callback_key(user)))
     }
 
-    /// Public function to query a user's data for a specific pool.
-    pub fn get_user_info(e: &Env, user: Address, pool_id: u32) -> Result<UserData, FarmError> {
+    /// Calls `on_farm_event(farm, user, pool_id, event)` on the callbacks of `users` with a
+    /// position in `pool_id`, with `event` "expiring" within REMINDER_WINDOW of maturity and
+    /// "matured" from maturity on. Each reminder is sent once per position, and a failing
+    /// callback does not prevent the others. Callable by anyone, e.g. keepers. Returns the
+    /// number of callbacks that accepted the reminder.
+    pub fn remind(e: &Env, pool_id: u32, users: Vec<Address>) -> Result<u32, FarmError> {
         extend_instance_ttl(e);
-        let mut user_data = get_user_data(e, user.clone(), pool_id)?;
-
-        let pool = get_pool_data(e, pool_id)?;
-        let current_time = time(e);
-
-        // Calculate time elapsed since the last deposit or rewards update
-        let maturity = get_maturity(e)?;
-        let time_elapsed = core::cmp::min(
-            current_time - user_data.deposit_time,
-            maturity - user_data.deposit_time,
-        );
 
-        // Calculate current accrued rewards
-        let accrued_yield1 = if pool.reward_ratio1 > 0 {
-            (user_data.deposited * pool.reward_ratio1 * time_elapsed as i128) / 10i128.pow(DECIMALS)
+        let stage = reminder_stage(time(e), get_maturity(e)?);
+        if stage == 0 {
+            return Ok(0);
+        }
+        let event = if stage == 1 {
+            Symbol::new(e, "expiring")
         } else {
-            0
+            Symbol::new(e, "matured")
         };
 
-        let accrued_yield2 = if pool.reward_ratio2 > 0 && get_rewarded_token2(e)?.is_some() {
-            (user_data.deposited * pool.reward_ratio2 * time_elapsed as i128) / 10i128.pow(DECIMALS)
-        } else {
-            0
+        let mut notified = 0;
+        for user in users.iter() {
+            let callback: Address = match e.storage().persistent().get(&callback_key(user.clone())) {
+                Some(callback) => callback,
+                None => continue,
+            };
+            if get_user_data(e, user.clone(), pool_id).is_err() {
+                continue;
+            }
+            let key = reminded_key(user.clone(), pool_id);
+            if e.storage().persistent().get(&key).unwrap_or(0u32) >= stage {
+                continue;
+            }
+            e.storage().persistent().set(&key, &stage);
+
+            let args = (e.current_contract_address(), user.clone(), pool_id, event.clone()).into_val(e);
+            let result = e.try_invoke_contract::<Val, soroban_sdk::Error>(
+                &callback,
+                &Symbol::new(e, "on_farm_event"),
+                args,
+            );
+            if matches!(result, Ok(Ok(_))) {
+                notified += 1;
+            }
+        }
+
+        e.events()
+            .publish((symbol_short!("Remind"), pool_id), (event, notified));
+
+        Ok(notified)
+    }
+
+    /// Public function to query the address rewards on a position are paid to.
+    pub fn get_payout_address(e: &Env, user: Address, pool_id: u32) -> Result<Address, FarmError> {
+        get_user_data(e, user.clone(), pool_id)?;
+        Ok(get_payout_address(e, user, pool_id))
+    }
+
+    /// Describes the invocation tree `user` must authorize for a deposit or withdrawal: the farm
+    /// call itself and, for deposits, the pool token transfer the farm makes on the user's behalf.
+    /// Contract callers authorize the root call as direct invoker and pass the sub-invocations to
+    /// `authorize_as_current_contract`.
+    pub fn get_required_auth(
+        e: &Env,
+        user: Address,
+        action: FarmAction,
+        amount: i128,
+        pool_id: u32,
+    ) -> Result<SubContractInvocation, FarmError> {
+        check_nonnegative_amount(amount)?;
+        get_pool_data(e, pool_id)?;
+
+        let farm = e.current_contract_address();
+        let (fn_name, sub_invocations) = match action {
+            FarmAction::Deposit => {
+                let transfer = SubContractInvocation {
+                    context: ContractContext {
+                        contract: get_pool_token(e)?,
+                        fn_name: Symbol::new(e, "transfer"),
+                        args: (user.clone(), farm.clone(), amount).into_val(e),
+                    },
+                    sub_invocations: Vec::new(e),
+                };
+                (
+                    Symbol::new(e, "deposit"),
+                    Vec::from_array(e, [InvokerContractAuthEntry::Contract(transfer)]),
+                )
+            }
+            // Withdrawals only move tokens out of the farm, which the farm authorizes itself
+            FarmAction::Withdraw => (Symbol::new(e, "withdraw"), Vec::new(e)),
         };
 
-        // Update the user data with current accrued rewards
-        user_data.accrued_rewards1 += accrued_yield1;
-        user_data.accrued_rewards2 += accrued_yield2;
+        Ok(SubContractInvocation {
+            context: ContractContext {
+                contract: farm,
+                fn_name,
+                args: (user, amount, pool_id).into_val(e),
+            },
+            sub_invocations,
+        })
+    }
 
-        Ok(user_data)
+    /// Returns up to `limit` records of a section of the farm state starting at index `start`,
+    /// for migrations and audits. Positions that were fully withdrawn are skipped.
+    pub fn export_state(
+        e: &Env,
+        section: ExportSection,
+        start: u32,
+        limit: u32,
+    ) -> Result<Vec<StateEntry>, FarmError> {
+        let admin = get_admin(e)?;
+        admin.require_auth();
+
+        let mut entries = Vec::new(e);
+        let limit = core::cmp::min(limit, MAX_PAGE_SIZE);
+
+        match section {
+            ExportSection::Config => {
+                if start == 0 && limit > 0 {
+                    let mut rewarded_tokens = Vec::from_array(e, [get_rewarded_token1(e)?]);
+                    if let Some(rewarded_token2) = get_rewarded_token2(e)? {
+                        rewarded_tokens.push_back(rewarded_token2);
+                    }
+                    let (max_reward_ratio1, max_reward_ratio2) = get_max_reward_ratios(e)?;
+                    let (allocated_rewards1, allocated_rewards2) = get_allocated_rewards(e)?;
+
+                    entries.push_back(StateEntry::Config(FarmConfig {
+                        admin,
+                        rewarded_tokens,
+                        pool_token: get_pool_token(e)?,
+                        maturity: get_maturity(e)?,
+                        max_reward_ratio1,
+                        max_reward_ratio2: max_reward_ratio2.unwrap_or(0),
+                        allocated_rewards1,
+                        allocated_rewards2,
+                        pool_count: get_pool_ids(e)?.len(),
+                        position_count: get_position_count(e),
+                        state: get_state(e),
+                    }));
+                }
+            }
+            ExportSection::Pools => {
+                let pool_ids = get_pool_ids(e)?;
+                let end = core::cmp::min(pool_ids.len(), start.saturating_add(limit));
+                for pool_id in pool_ids.slice(start.min(end)..end).iter() {
+                    // Archived pools no longer have pool data
+                    if let Ok(pool) = get_pool_data(e, pool_id) {
+                        entries.push_back(StateEntry::Pool(pool_id, pool));
+                    }
+                }
+            }
+            ExportSection::Positions => {
+                let end = core::cmp::min(get_position_count(e), start.saturating_add(limit));
+                for index in start..end {
+                    if let Some((user, pool_id)) = get_position(e, index) {
+                        if let Ok(user_data) = get_user_data(e, user.clone(), pool_id) {
+                            entries.push_back(StateEntry::Position(user, pool_id, user_data));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(entries)
     }
 
     /// Public function to query the reward token addresses.
     pub fn get_reward_token_addresses(e: &Env) -> Result<(Address, Option<Address>), FarmError> {
-        extend_instance_ttl(e);
-
-        let rewarded_token1 = get_rewarded_token1(&e)?;
-        let rewarded_token2 = get_rewarded_token2(&e)?;
+        let rewarded_token1 = get_rewarded_token1(e)?;
+        let rewarded_token2 = get_rewarded_token2(e)?;
 
         Ok((rewarded_token1, rewarded_token2))
     }
@@ -1,10 +1,14 @@
 #![cfg(test)]
+#![allow(clippy::unnecessary_cast, clippy::unnecessary_literal_unwrap)]
 extern crate std;
 
 use super::*;
 use soroban_sdk::{
-    testutils::{Address as _, Ledger},
-    Address, Env, String
+    testutils::{
+        storage::{Instance as _, Persistent as _},
+        Address as _, Ledger, MockAuth, MockAuthInvoke,
+    },
+    Address, BytesN, Env, IntoVal, String, Symbol
 };
 
 fn create_token_contract<'a>(
@@ -18,6 +22,17 @@ fn create_token_contract<'a>(
     )
 }
 
+// Reward ratio of one unit per staked unit per second
+const RATE: i128 = 10i128.pow(RATE_DECIMALS);
+
+// Moves to a later ledger closing at `timestamp`
+fn set_ledger_time(e: &Env, timestamp: u64) {
+    e.ledger().with_mut(|li| {
+        li.timestamp = timestamp;
+        li.sequence_number += 1;
+    });
+}
+
 #[test]
 #[should_panic(expected = "HostError: Error(Contract, #11)")]
 fn test_not_double_initialization() {
@@ -88,8 +103,8 @@ fn deposit_without_sufficient_rewards() {
         &Some(rewarded_token2.0.address.clone()),
         &pool_token.0.address,
         &(e.ledger().timestamp() + 10000),
-        &100000000,
-        &Some(100000000),
+        &(10 * RATE),
+        &Some(10 * RATE),
     );
     let expected = String::from_str(&e, "Ok");
 
@@ -99,8 +114,9 @@ fn deposit_without_sufficient_rewards() {
     // Create a new pool
     let pool_id = farm.create_pool(
         &(e.ledger().timestamp()),
-        &10000000,
-        &Some(10000000),
+        &RATE,
+        &Some(RATE),
+        &None,
     );
     assert_eq!(pool_id, 0, "Pool creation failed");
 
@@ -128,8 +144,8 @@ fn test_withdraw_before_and_after_maturity() {
     // Initialize the farm contract
     let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
     let maturity = e.ledger().timestamp() + 10_000; // Maturity in 10,000 seconds
-    let max_reward_ratio1 = 100000000; // Set max reward ratio to 1e6
-    let max_reward_ratio2 = Some(100000000);
+    let max_reward_ratio1 = 10 * RATE; // Set max reward ratio to 1e6
+    let max_reward_ratio2 = Some(10 * RATE);
 
     let result = farm.initialize(
         &admin,
@@ -147,12 +163,13 @@ fn test_withdraw_before_and_after_maturity() {
     rewarded_token1_admin.mint(&farm.address, &total_reward_amount);    
     rewarded_token2_admin.mint(&farm.address, &total_reward_amount);    
 
-    let reward_ratio1 = 10000000;
-    let reward_ratio2 = Some(10000000);
+    let reward_ratio1 = RATE;
+    let reward_ratio2 = Some(RATE);
     let pool_id = farm.create_pool(
         &e.ledger().timestamp(), // Start now
         &reward_ratio1,
         &reward_ratio2,
+        &None,
     );
     assert_eq!(pool_id, 0, "Pool creation failed");
 
@@ -171,7 +188,7 @@ fn test_withdraw_before_and_after_maturity() {
 
     // Move time forward to before maturity
     let time_elapsed_before_withdraw = 5000; // 5,000 seconds
-    e.ledger().set_timestamp(e.ledger().timestamp() + time_elapsed_before_withdraw);
+    set_ledger_time(&e, e.ledger().timestamp() + time_elapsed_before_withdraw);
 
     // User withdraws part of the deposit before maturity
     let withdraw_amount_before_maturity = 50; // Withdraw 50 tokens
@@ -185,8 +202,8 @@ fn test_withdraw_before_and_after_maturity() {
 
     // Calculate expected accrued rewards
     let time_elapsed = time_elapsed_before_withdraw;
-    let expected_accrued_rewards1 = (deposit_amount as i128 * reward_ratio1 as i128 * time_elapsed as i128) / 10i128.pow(DECIMALS);
-    let expected_accrued_rewards2 = (deposit_amount as i128 * reward_ratio2.unwrap() as i128 * time_elapsed as i128) / 10i128.pow(DECIMALS);
+    let expected_accrued_rewards1 = (deposit_amount as i128 * reward_ratio1 as i128 * time_elapsed as i128) / RATE;
+    let expected_accrued_rewards2 = (deposit_amount as i128 * reward_ratio2.unwrap() as i128 * time_elapsed as i128) / RATE;
 
     // Check the user's reward balances
     let user_reward_token1_balance = rewarded_token1_client.balance(&user);
@@ -198,7 +215,7 @@ fn test_withdraw_before_and_after_maturity() {
 
     // Move time forward to after maturity
     let time_to_maturity = maturity - e.ledger().timestamp();
-    e.ledger().set_timestamp(e.ledger().timestamp() + time_to_maturity + 1);
+    set_ledger_time(&e, e.ledger().timestamp() + time_to_maturity + 1);
 
     // User withdraws the remaining amount after maturity
     let withdraw_amount_after_maturity = deposit_amount - withdraw_amount_before_maturity;
@@ -221,7 +238,7 @@ fn test_full_withdraw_before_maturity() {
 
     let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
     let maturity = e.ledger().timestamp() + 10000;
-    let max_reward_ratio1 = 100000000;
+    let max_reward_ratio1 = 10 * RATE;
 
     let result = farm.initialize(
         &admin,
@@ -236,11 +253,12 @@ fn test_full_withdraw_before_maturity() {
 
     rewarded_token1_admin.mint(&farm.address, &50000000);
 
-    let reward_ratio1 = 10000000;
+    let reward_ratio1 = RATE;
     let pool_id = farm.create_pool(
         &e.ledger().timestamp(),
         &reward_ratio1,
         &None,
+        &None,
     );
     assert_eq!(pool_id, 0);
 
@@ -249,7 +267,7 @@ fn test_full_withdraw_before_maturity() {
     assert_eq!(deposit_result, deposit_amount);
 
     let time_elapsed = 5000;
-    e.ledger().set_timestamp(e.ledger().timestamp() + time_elapsed);
+    set_ledger_time(&e, e.ledger().timestamp() + time_elapsed);
 
     // Withdraw full amount before maturity
     let withdraw_result = farm.withdraw(&user, &deposit_amount, &pool_id);
@@ -260,7 +278,7 @@ fn test_full_withdraw_before_maturity() {
     assert_eq!(user_pool_token_balance, 1000);
 
     // Check user's reward token balance
-    let expected_rewards = (deposit_amount as i128 * reward_ratio1 as i128 * time_elapsed as i128) / 10i128.pow(DECIMALS);
+    let expected_rewards = (deposit_amount as i128 * reward_ratio1 as i128 * time_elapsed as i128) / RATE;
     let user_reward_balance = rewarded_token1_client.balance(&user);
     assert_eq!(user_reward_balance, expected_rewards);
 }
@@ -280,7 +298,7 @@ fn test_full_withdraw_after_maturity() {
 
     let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
     let maturity = e.ledger().timestamp() + 10000;
-    let max_reward_ratio1 = 100000000;
+    let max_reward_ratio1 = 10 * RATE;
 
     let result = farm.initialize(
         &admin,
@@ -295,11 +313,12 @@ fn test_full_withdraw_after_maturity() {
 
     rewarded_token1_admin.mint(&farm.address, &50000000);
 
-    let reward_ratio1 = 10000000;
+    let reward_ratio1 = RATE;
     let pool_id = farm.create_pool(
         &e.ledger().timestamp(),
         &reward_ratio1,
         &None,
+        &None,
     );
     assert_eq!(pool_id, 0);
 
@@ -308,7 +327,7 @@ fn test_full_withdraw_after_maturity() {
     assert_eq!(deposit_result, deposit_amount);
 
     let time_elapsed = 10000;
-    e.ledger().set_timestamp(e.ledger().timestamp() + time_elapsed + 1);
+    set_ledger_time(&e, e.ledger().timestamp() + time_elapsed + 1);
 
     // Withdraw full amount after maturity
     let withdraw_result = farm.withdraw(&user, &deposit_amount, &pool_id);
@@ -319,7 +338,7 @@ fn test_full_withdraw_after_maturity() {
     assert_eq!(user_pool_token_balance, 1000);
 
     // Check user's reward token balance
-    let expected_rewards = (deposit_amount as i128 * reward_ratio1 as i128 * time_elapsed as i128) / 10i128.pow(DECIMALS);
+    let expected_rewards = (deposit_amount as i128 * reward_ratio1 as i128 * time_elapsed as i128) / RATE;
     let user_reward_balance = rewarded_token1_client.balance(&user);
     assert_eq!(user_reward_balance, expected_rewards);
 
@@ -329,3 +348,3154 @@ fn test_full_withdraw_after_maturity() {
     let unallocated_rewards = farm.withdraw_unallocated_rewards();
     assert_eq!(unallocated_rewards.0, contract_reward_balance);
 }
+
+#[test]
+fn test_fund_rewards() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let funder = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, _) = create_token_contract(&e, &admin);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+
+    rewarded_token1_admin.mint(&funder, &1000);
+    rewarded_token2_admin.mint(&funder, &1000);
+
+    assert_eq!(farm.fund_rewards(&funder, &300, &200), (300, 200));
+    assert_eq!(farm.fund_rewards(&funder, &100, &0), (400, 200));
+    assert_eq!(farm.get_funded_rewards(), (400, 200));
+    assert_eq!(rewarded_token1_client.balance(&farm.address), 400);
+    assert_eq!(rewarded_token2_client.balance(&farm.address), 200);
+
+    // Once restricted, only the admin can fund
+    farm.set_funding_restricted(&true);
+    assert_eq!(
+        farm.try_fund_rewards(&funder, &100, &0),
+        Err(Ok(FarmError::NotAuthorized))
+    );
+}
+
+#[test]
+fn test_withdraw_outage_compensation() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &50000000);
+
+    let reward_ratio1 = RATE;
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &reward_ratio1, &None, &None);
+    farm.deposit(&user, &1, &pool_id);
+    farm.set_grace_period(&100);
+
+    // A short outage within the grace period is not compensated
+    farm.set_withdrawals_paused(&true);
+    set_ledger_time(&e, e.ledger().timestamp() + 50);
+    assert_eq!(
+        farm.try_withdraw(&user, &1, &pool_id),
+        Err(Ok(FarmError::WithdrawalsPaused))
+    );
+    farm.set_withdrawals_paused(&false);
+    assert_eq!(farm.get_compensated_outage(), 0);
+
+    // A longer outage is compensated for its full duration
+    farm.set_withdrawals_paused(&true);
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+    // Nobody can join late and be compensated for the whole outage
+    assert_eq!(
+        farm.try_deposit(&user, &1, &pool_id),
+        Err(Ok(FarmError::WithdrawalsPaused))
+    );
+    farm.set_withdrawals_paused(&false);
+    assert_eq!(farm.get_compensated_outage(), 1000);
+
+    farm.withdraw(&user, &1, &pool_id);
+
+    let expected_rewards = (reward_ratio1 * (1050 + 1000)) / RATE;
+    assert_eq!(rewarded_token1_client.balance(&user), expected_rewards);
+}
+
+#[test]
+fn test_reward_formula() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+
+    let (rewarded_token1_client, _) = create_token_contract(&e, &admin);
+    let (pool_token_client, _) = create_token_contract(&e, &admin);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let maturity = e.ledger().timestamp() + 10000;
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &maturity,
+        &(10 * RATE),
+        &None,
+    );
+
+    let pool_id = farm.create_pool(&(e.ledger().timestamp() + 10), &RATE, &None, &None);
+    let formula = farm.get_reward_formula(&pool_id);
+
+    assert_eq!(formula.model, RewardModel::LinearPerSecond);
+    assert_eq!(formula.ratio_scale, RATE);
+    assert_eq!(formula.decimals, DECIMALS);
+    assert_eq!(formula.reward_ratio1, RATE);
+    assert_eq!(formula.reward_ratio2, 0);
+    assert_eq!(formula.accrual_start, e.ledger().timestamp() + 10);
+    assert_eq!(formula.accrual_end, maturity);
+    assert_eq!(formula.lock_multiplier, formula.ratio_scale);
+}
+
+#[test]
+fn test_sub_stroop_ratio() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    let year = 365 * 86400;
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + year),
+        &RATE,
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &1000000000);
+
+    // 5% a year is far below one stroop per staked token per second
+    let ratio = RATE / 20 / year as i128;
+    assert!(ratio < 10i128.pow(RATE_DECIMALS - DECIMALS));
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &ratio, &None, &None);
+
+    pool_token_admin.mint(&user, &1000000000);
+    farm.deposit(&user, &1000000000, &pool_id);
+
+    set_ledger_time(&e, e.ledger().timestamp() + year);
+    farm.withdraw(&user, &1000000000, &pool_id);
+    assert_eq!(rewarded_token1_client.balance(&user), 49999999);
+}
+
+#[test]
+fn test_mul_div_extreme_values() {
+    // xorshift generator so the sampled inputs are reproducible
+    let mut state: u64 = 0x9E3779B97F4A7C15;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let scale = 10i128.pow(DECIMALS);
+    for _ in 0..2000 {
+        let amount = (next() as i128) << (next() % 64);
+        let ratio = (next() as i128) >> (next() % 64);
+        let seconds = (next() % (100 * 365 * 86400)) as i128;
+
+        let magnitude = amount as f64 * ratio as f64 * seconds as f64;
+        match mul_div(amount, ratio, seconds, scale) {
+            Ok(value) => {
+                assert!(magnitude < 2e38);
+                assert!(value >= 0);
+            }
+            Err(error) => {
+                assert_eq!(error, FarmError::MathOverflow);
+                assert!(magnitude > 1e38);
+            }
+        }
+    }
+
+    assert_eq!(mul_div(i128::MAX, 1, 1, scale), Ok(i128::MAX / scale));
+    assert_eq!(mul_div(i128::MAX, 2, 1, scale), Err(FarmError::MathOverflow));
+    assert_eq!(mul_div(i128::MIN, -1, 1, scale), Err(FarmError::MathOverflow));
+}
+
+#[test]
+fn test_deposit_overflow_and_negative_ratios() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 100 * 365 * 86400),
+        &i128::MAX,
+        &None,
+    );
+
+    assert_eq!(
+        farm.try_create_pool(&e.ledger().timestamp(), &-1, &None, &None),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &(i128::MAX / 1000), &None, &None);
+
+    pool_token_admin.mint(&user, &i128::MAX);
+    rewarded_token1_admin.mint(&farm.address, &i128::MAX);
+
+    // Huge deposit over a century-long maturity overflows instead of trapping
+    assert_eq!(
+        farm.try_deposit(&user, &(i128::MAX / 2), &pool_id),
+        Err(Ok(FarmError::MathOverflow))
+    );
+}
+
+#[test]
+fn test_leaderboard() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &50000000000);
+
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+
+    let mut users = std::vec::Vec::new();
+    for i in 0..12 {
+        let user = Address::generate(&e);
+        pool_token_admin.mint(&user, &1000);
+        farm.deposit(&user, &(10 * (i + 1)), &pool_id);
+        users.push(user);
+    }
+
+    // Only the top 10 are kept, largest first
+    let leaderboard = farm.get_leaderboard(&pool_id);
+    assert_eq!(leaderboard.len(), LEADERBOARD_SIZE);
+    assert_eq!(leaderboard.get(0).unwrap().user, users[11]);
+    assert_eq!(leaderboard.get(0).unwrap().deposited, 120);
+    assert_eq!(leaderboard.get(9).unwrap().user, users[2]);
+
+    // Growing a position moves it up
+    farm.deposit(&users[0], &500, &pool_id);
+    let leaderboard = farm.get_leaderboard(&pool_id);
+    assert_eq!(leaderboard.get(0).unwrap().user, users[0]);
+    assert_eq!(leaderboard.get(0).unwrap().deposited, 510);
+
+    // Closing a position removes it
+    set_ledger_time(&e, e.ledger().timestamp());
+    farm.withdraw(&users[0], &510, &pool_id);
+    let leaderboard = farm.get_leaderboard(&pool_id);
+    assert_eq!(leaderboard.len(), LEADERBOARD_SIZE - 1);
+    assert_eq!(leaderboard.get(0).unwrap().user, users[11]);
+
+    // Stakers pushed off earlier only come back once ranked again
+    let unranked = Vec::from_array(&e, [users[1].clone(), Address::generate(&e)]);
+    let leaderboard = farm.rank_stakers(&pool_id, &unranked);
+    assert_eq!(leaderboard.len(), LEADERBOARD_SIZE);
+    assert_eq!(leaderboard.get(9).unwrap().user, users[1]);
+    assert_eq!(leaderboard.get(9).unwrap().deposited, 20);
+}
+
+#[test]
+fn test_twab() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &50000000000);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+
+    let start = e.ledger().timestamp();
+    farm.deposit(&user, &100, &pool_id);
+    set_ledger_time(&e, start + 100);
+    farm.deposit(&user, &300, &pool_id);
+    set_ledger_time(&e, start + 300);
+    farm.withdraw(&user, &400, &pool_id);
+    set_ledger_time(&e, start + 400);
+
+    // 100 for 100 seconds, then 400 for 200 seconds, then nothing
+    assert_eq!(farm.get_twab(&user, &pool_id, &start, &(start + 100)), 100);
+    assert_eq!(farm.get_twab(&user, &pool_id, &start, &(start + 300)), 300);
+    assert_eq!(farm.get_twab(&user, &pool_id, &(start + 50), &(start + 150)), 250);
+    assert_eq!(farm.get_twab(&user, &pool_id, &start, &(start + 400)), 225);
+    assert_eq!(
+        farm.try_get_twab(&user, &pool_id, &start, &(start + 500)),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+
+    let checkpoints = farm.get_stake_checkpoints(&user, &pool_id, &0, &10);
+    assert_eq!(checkpoints.len(), 3);
+    assert_eq!(
+        checkpoints.get(2).unwrap(),
+        StakeCheckpoint {
+            timestamp: start + 300,
+            stake: 0,
+            cumulative: 100 * 100 + 400 * 200,
+        }
+    );
+
+    // Claims leave the stake as it is and record no checkpoint
+    let other = Address::generate(&e);
+    pool_token_admin.mint(&other, &1000);
+    farm.deposit(&other, &10, &pool_id);
+    set_ledger_time(&e, start + 500);
+    farm.claim(&other, &pool_id);
+    assert_eq!(farm.get_stake_checkpoints(&other, &pool_id, &0, &10).len(), 1);
+
+    // Only the last MAX_STAKE_CHECKPOINTS checkpoints are kept
+    e.budget().reset_unlimited();
+    for i in 0..MAX_STAKE_CHECKPOINTS {
+        set_ledger_time(&e, start + 501 + i as u64);
+        farm.deposit(&other, &1, &pool_id);
+    }
+    let now = e.ledger().timestamp();
+    assert_eq!(farm.get_stake_checkpoints(&other, &pool_id, &0, &1).get(0).unwrap().timestamp, start + 501);
+    assert_eq!(farm.get_twab(&other, &pool_id, &(now - 1), &now), 10 + MAX_STAKE_CHECKPOINTS as i128 - 1);
+    assert_eq!(
+        farm.try_get_twab(&other, &pool_id, &(start + 500), &now),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+    e.as_contract(&farm.address, || {
+        let key = checkpoint_key(other.clone(), pool_id, MAX_STAKE_CHECKPOINTS);
+        assert_eq!(e.storage().persistent().get_ttl(&key), MAX_TTL);
+    });
+}
+
+#[test]
+fn test_lifetime_rewards() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+    rewarded_token1_admin.mint(&farm.address, &50000000000);
+    rewarded_token2_admin.mint(&farm.address, &50000000000);
+
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &Some(2 * RATE), &None);
+
+    farm.deposit(&user, &100, &pool_id);
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+    farm.withdraw(&user, &100, &pool_id);
+
+    // A new position in the same pool keeps accumulating on top of the closed one
+    farm.deposit(&user, &100, &pool_id);
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+    farm.withdraw(&user, &100, &pool_id);
+
+    assert_eq!(farm.get_user_lifetime_rewards(&user, &pool_id), (200000, 400000));
+    assert_eq!(farm.get_total_rewards_paid(), (200000, 400000));
+    assert_eq!(rewarded_token1_client.balance(&user), 200000);
+    assert_eq!(rewarded_token2_client.balance(&user), 400000);
+}
+
+#[test]
+fn test_bonus_round() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+    pool_token_admin.mint(&user2, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(start + 10000),
+        &(10 * RATE),
+        &None,
+    );
+
+    // Exactly enough for the base campaign of two 100 token deposits
+    rewarded_token1_admin.mint(&farm.address, &2000000);
+    let pool_id = farm.create_pool(&start, &RATE, &None, &None);
+
+    // The boosted rate cannot exceed the max reward ratio
+    assert_eq!(
+        farm.try_add_bonus_round(&pool_id, &15000, &(10 * RATE), &1000),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+
+    // Bonus budget of 15000 at 2x the base rate for 1000 seconds
+    rewarded_token1_admin.mint(&admin, &15000);
+    assert_eq!(farm.add_bonus_round(&pool_id, &15000, &(2 * RATE), &1000), 0);
+
+    // The bonus budget cannot be used to cover base rewards
+    farm.deposit(&user, &100, &pool_id);
+    farm.deposit(&user2, &100, &pool_id);
+    assert_eq!(
+        farm.try_deposit(&user2, &1, &pool_id),
+        Err(Ok(FarmError::InsufficientRewards))
+    );
+
+    // The budget runs out before its window ends and is split pro rata to stake, whoever
+    // claims first
+    set_ledger_time(&e, start + 50);
+    let user_info = farm.get_user_info(&user, &pool_id);
+    assert_eq!(user_info.accrued_rewards1, 50 * 100 + 7500);
+
+    set_ledger_time(&e, start + 2000);
+    farm.withdraw(&user, &100, &pool_id);
+    assert_eq!(rewarded_token1_client.balance(&user), 2000 * 100 + 7500);
+
+    set_ledger_time(&e, start + 3000);
+    farm.withdraw(&user2, &100, &pool_id);
+    assert_eq!(rewarded_token1_client.balance(&user2), 3000 * 100 + 7500);
+
+    let round = farm.get_bonus_rounds(&pool_id).get(0).unwrap();
+    assert_eq!(round.remaining, 0);
+
+    // Budget nobody earned is released once the round ends
+    rewarded_token1_admin.mint(&admin, &5000);
+    assert_eq!(farm.add_bonus_round(&pool_id, &5000, &RATE, &100), 1);
+    set_ledger_time(&e, start + 3200);
+    let unallocated = farm.get_reward_runway().get(0).unwrap().unallocated;
+    farm.deposit(&user, &100, &pool_id);
+    assert_eq!(farm.get_bonus_rounds(&pool_id).get(1).unwrap().remaining, 0);
+    assert_eq!(
+        farm.get_reward_runway().get(0).unwrap().unallocated,
+        unallocated + 5000 - 100 * 6800
+    );
+}
+
+#[test]
+fn test_archive_pool() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let maturity = e.ledger().timestamp() + 10000;
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &maturity,
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &1000000);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+
+    // A bonus round of which only 2000 gets earned
+    rewarded_token1_admin.mint(&admin, &15000);
+    farm.add_bonus_round(&pool_id, &15000, &(2 * RATE), &10);
+    farm.deposit(&user, &100, &pool_id);
+
+    assert_eq!(
+        farm.try_archive_pool(&pool_id),
+        Err(Ok(FarmError::MaturityNotReached))
+    );
+
+    set_ledger_time(&e, maturity);
+    assert_eq!(farm.try_archive_pool(&pool_id), Err(Ok(FarmError::PoolNotEmpty)));
+    assert_eq!(farm.try_get_archived_summary(&pool_id), Err(Ok(FarmError::PoolNotFound)));
+
+    farm.withdraw(&user, &100, &pool_id);
+    assert_eq!(rewarded_token1_client.balance(&user), 1000000 + 2000);
+
+    let pool = farm.get_pool_info(&pool_id);
+    let summary = farm.archive_pool(&pool_id);
+    assert_eq!(
+        summary,
+        ArchivedPool {
+            start_time: pool.start_time,
+            end_time: pool.end_time,
+            reward_ratio1: RATE,
+            reward_ratio2: 0,
+            total_deposited: 100,
+            archived_at: maturity,
+        }
+    );
+    assert_eq!(farm.get_archived_summary(&pool_id), summary);
+
+    // The pool's entries are gone and the unspent bonus is no longer reserved
+    assert_eq!(
+        farm.try_get_pool_info(&pool_id).err(),
+        Some(Ok(FarmError::PoolNotFound))
+    );
+    assert_eq!(
+        farm.try_get_bonus_rounds(&pool_id).err(),
+        Some(Ok(FarmError::PoolNotFound))
+    );
+    assert_eq!(farm.try_archive_pool(&pool_id), Err(Ok(FarmError::PoolNotFound)));
+    assert_eq!(farm.withdraw_unallocated_rewards(), (13000, 0));
+}
+
+#[test]
+fn test_create_pools() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+
+    let (rewarded_token1_client, _) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, _) = create_token_contract(&e, &admin);
+    let (pool_token_client, _) = create_token_contract(&e, &admin);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let maturity = e.ledger().timestamp() + 10000;
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &maturity,
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+
+    let start = e.ledger().timestamp();
+    let pool_ids = farm.create_pools(&Vec::from_array(
+        &e,
+        [
+            (start, RATE, Some(2 * RATE)),
+            (start + 100, 3 * RATE, Some(0)),
+            (start + 200, 10 * RATE, Some(10 * RATE)),
+        ],
+    ));
+    assert_eq!(pool_ids, Vec::from_array(&e, [0, 1, 2]));
+
+    let pool = farm.get_pool_info(&1);
+    assert_eq!(pool.start_time, start + 100);
+    assert_eq!(pool.end_time, maturity);
+    assert_eq!(pool.reward_ratio1, 3 * RATE);
+
+    // One invalid spec reverts the whole batch
+    assert_eq!(
+        farm.try_create_pools(&Vec::from_array(
+            &e,
+            [(start, RATE, Some(2 * RATE)), (start, 10 * RATE + 1, Some(0))],
+        )),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+    assert_eq!(farm.try_create_pools(&Vec::new(&e)), Err(Ok(FarmError::InvalidAmount)));
+    assert_eq!(farm.create_pool(&start, &RATE, &Some(0), &None), 3);
+}
+
+#[test]
+fn test_pool_tokens() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &300);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+    rewarded_token1_admin.mint(&farm.address, &50000000000);
+    rewarded_token2_admin.mint(&farm.address, &50000000000);
+
+    // Single-token and dual-token campaigns run side by side
+    let start = e.ledger().timestamp();
+    let single1 = farm.create_pool(&start, &RATE, &None, &None);
+    let dual = farm.create_pool(&start, &RATE, &Some(3 * RATE), &None);
+    let single2 = farm.create_pool(&start, &0, &Some(2 * RATE), &None);
+    assert_eq!(farm.get_pool_info(&single1).tokens, PoolTokens::Token1);
+    assert_eq!(farm.get_pool_info(&dual).tokens, PoolTokens::Both);
+    assert_eq!(farm.get_pool_info(&single2).tokens, PoolTokens::Token2);
+    assert_eq!(farm.get_reward_formula(&single1).reward_ratio2, 0);
+
+    farm.deposit(&user, &100, &single1);
+    farm.deposit(&user, &100, &dual);
+    farm.deposit(&user, &100, &single2);
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+
+    assert_eq!(farm.claim(&user, &single1), (100000, 0));
+    assert_eq!(farm.claim(&user, &dual), (100000, 300000));
+    assert_eq!(farm.claim(&user, &single2), (0, 200000));
+    assert_eq!(rewarded_token1_client.balance(&user), 200000);
+    assert_eq!(rewarded_token2_client.balance(&user), 500000);
+}
+
+#[test]
+fn test_pool_deposit_window() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let maturity = e.ledger().timestamp() + 10000;
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &maturity,
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &100000000);
+
+    let now = e.ledger().timestamp();
+    assert_eq!(
+        farm.try_create_pool(&now, &10000000, &None, &Some(maturity + 1)),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+    assert_eq!(
+        farm.try_create_pool(&now, &10000000, &None, &Some(now)),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+
+    let wave1 = farm.create_pool(&now, &RATE, &None, &Some(now + 100));
+    let wave2 = farm.create_pool(&now, &RATE, &None, &None);
+
+    farm.deposit(&user, &100, &wave1);
+    set_ledger_time(&e, now + 100);
+    assert_eq!(
+        farm.try_deposit(&user, &100, &wave1),
+        Err(Ok(FarmError::PoolClosed))
+    );
+
+    // Positions in an ended pool keep accruing until maturity
+    farm.deposit(&user, &100, &wave2);
+    farm.close_pool(&wave2);
+    assert_eq!(
+        farm.try_deposit(&user, &100, &wave2),
+        Err(Ok(FarmError::PoolClosed))
+    );
+    assert_eq!(farm.try_close_pool(&wave2), Err(Ok(FarmError::PoolClosed)));
+
+    set_ledger_time(&e, maturity);
+    farm.withdraw(&user, &100, &wave1);
+    assert_eq!(rewarded_token1_client.balance(&user), 100 * 10000);
+}
+
+#[test]
+fn test_risk_scores() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let oracle = Address::generate(&e);
+
+    let (rewarded_token1_client, _) = create_token_contract(&e, &admin);
+    let (pool_token_client, _) = create_token_contract(&e, &admin);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &None,
+    );
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+
+    assert_eq!(
+        farm.try_publish_risk_score(&pool_id, &2500),
+        Err(Ok(FarmError::RiskOracleNotSet))
+    );
+
+    farm.set_risk_oracle(&oracle, &3600);
+    assert_eq!(farm.get_risk_oracle(), (oracle.clone(), 3600));
+    assert_eq!(
+        farm.try_get_risk_score(&pool_id),
+        Err(Ok(FarmError::RiskScoreNotFound))
+    );
+    assert_eq!(
+        farm.try_publish_risk_score(&pool_id, &10001),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+    assert_eq!(
+        farm.try_publish_risk_score(&(pool_id + 1), &2500),
+        Err(Ok(FarmError::PoolNotFound))
+    );
+
+    farm.publish_risk_score(&pool_id, &2500);
+    assert_eq!(e.auths()[0].0, oracle);
+
+    let risk_score = farm.get_risk_score(&pool_id);
+    assert_eq!(risk_score.score, 2500);
+    assert_eq!(risk_score.updated_at, e.ledger().timestamp());
+    assert!(!risk_score.stale);
+
+    set_ledger_time(&e, e.ledger().timestamp() + 3601);
+    assert!(farm.get_risk_score(&pool_id).stale);
+}
+
+#[test]
+fn test_preview_deposit() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let maturity = e.ledger().timestamp() + 1000;
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &maturity,
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+    rewarded_token1_admin.mint(&farm.address, &150000);
+    rewarded_token2_admin.mint(&farm.address, &150000);
+
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &Some(RATE / 2), &None);
+
+    let preview = farm.preview_deposit(&100, &pool_id);
+    assert_eq!(preview.projected_reward1, 100000);
+    assert_eq!(preview.projected_reward2, 50000);
+    assert_eq!(preview.required_budget1, 100000);
+    assert_eq!(preview.required_budget2, 50000);
+    assert!(preview.sufficient);
+
+    // The preview does not allocate anything
+    assert_eq!(farm.preview_deposit(&100, &pool_id), preview);
+    farm.deposit(&user, &100, &pool_id);
+
+    set_ledger_time(&e, e.ledger().timestamp() + 400);
+    let preview = farm.preview_deposit(&100, &pool_id);
+    assert_eq!(preview.projected_reward1, 60000);
+    assert_eq!(preview.required_budget1, 160000);
+    assert!(!preview.sufficient);
+    assert_eq!(
+        farm.try_deposit(&user, &100, &pool_id),
+        Err(Ok(FarmError::InsufficientRewards))
+    );
+
+    // Deposits are rejected at maturity, and so is the preview
+    set_ledger_time(&e, maturity);
+    assert_eq!(
+        farm.try_preview_deposit(&100, &pool_id),
+        Err(Ok(FarmError::PoolNotActive))
+    );
+}
+
+#[test]
+fn test_export_state() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user1, &1000);
+    pool_token_admin.mint(&user2, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let maturity = e.ledger().timestamp() + 1000;
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &maturity,
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &10000000);
+
+    let pool0 = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+    let pool1 = farm.create_pool(&e.ledger().timestamp(), &(2 * RATE), &None, &None);
+    farm.deposit(&user1, &100, &pool0);
+    farm.deposit(&user1, &50, &pool1);
+    farm.deposit(&user1, &50, &pool1);
+    farm.deposit(&user2, &200, &pool1);
+
+    let config = farm.export_state(&ExportSection::Config, &0, &10);
+    assert_eq!(config.len(), 1);
+    match config.get(0).unwrap() {
+        StateEntry::Config(config) => {
+            assert_eq!(config.admin, admin);
+            assert_eq!(config.maturity, maturity);
+            assert_eq!(config.pool_count, 2);
+            assert_eq!(config.position_count, 3);
+            assert_eq!(config.allocated_rewards1, 100 * 1000 + 300 * 2000);
+            assert_eq!(config.state, FarmState::Active);
+        }
+        _ => panic!("expected the config"),
+    }
+
+    let pools = farm.export_state(&ExportSection::Pools, &1, &10);
+    assert_eq!(pools.len(), 1);
+    match pools.get(0).unwrap() {
+        StateEntry::Pool(pool_id, pool) => {
+            assert_eq!(pool_id, pool1);
+            assert_eq!(pool.reward_ratio1, 2 * RATE);
+        }
+        _ => panic!("expected a pool"),
+    }
+
+    // Pages of positions, skipping the ones fully withdrawn
+    set_ledger_time(&e, maturity);
+    farm.withdraw(&user1, &100, &pool0);
+    let positions = farm.export_state(&ExportSection::Positions, &0, &2);
+    assert_eq!(positions.len(), 1);
+    match positions.get(0).unwrap() {
+        StateEntry::Position(user, pool_id, user_data) => {
+            assert_eq!((user, pool_id), (user1.clone(), pool1));
+            assert_eq!(user_data.deposited, 100);
+        }
+        _ => panic!("expected a position"),
+    }
+    assert_eq!(farm.export_state(&ExportSection::Positions, &2, &2).len(), 1);
+}
+
+mod aggregator {
+    use crate::{FarmAction, FarmClient};
+    use soroban_sdk::{contract, contractimpl, Address, Env};
+
+    #[contract]
+    pub struct Aggregator;
+
+    #[contractimpl]
+    impl Aggregator {
+        pub fn deposit(e: Env, farm: Address, amount: i128, pool_id: u32) -> i128 {
+            let farm = FarmClient::new(&e, &farm);
+            let from = e.current_contract_address();
+            let tree = farm.get_required_auth(&from, &FarmAction::Deposit, &amount, &pool_id);
+            e.authorize_as_current_contract(tree.sub_invocations);
+            farm.deposit(&from, &amount, &pool_id)
+        }
+    }
+}
+
+#[test]
+fn test_required_auth() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &10000000);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+
+    let aggregator = aggregator::AggregatorClient::new(&e, &e.register_contract(None, aggregator::Aggregator));
+    pool_token_admin.mint(&aggregator.address, &1000);
+    pool_token_admin.mint(&user, &1000);
+
+    let tree = farm.get_required_auth(&user, &FarmAction::Deposit, &100, &pool_id);
+    assert_eq!(tree.context.contract, farm.address);
+    assert_eq!(tree.context.fn_name, Symbol::new(&e, "deposit"));
+    assert_eq!(tree.sub_invocations.len(), 1);
+
+    let tree = farm.get_required_auth(&user, &FarmAction::Withdraw, &100, &pool_id);
+    assert_eq!(tree.context.fn_name, Symbol::new(&e, "withdraw"));
+    assert_eq!(tree.sub_invocations.len(), 0);
+
+    // From here on only the authorizations actually given count
+    e.set_auths(&[]);
+
+    assert_eq!(aggregator.deposit(&farm.address, &100, &pool_id), 100);
+    assert_eq!(pool_token_client.balance(&aggregator.address), 900);
+
+    // A wallet signing exactly the described tree can deposit
+    farm.mock_auths(&[MockAuth {
+        address: &user,
+        invoke: &MockAuthInvoke {
+            contract: &farm.address,
+            fn_name: "deposit",
+            args: (&user, 100i128, pool_id).into_val(&e),
+            sub_invokes: &[MockAuthInvoke {
+                contract: &pool_token_client.address,
+                fn_name: "transfer",
+                args: (&user, &farm.address, 100i128).into_val(&e),
+                sub_invokes: &[],
+            }],
+        },
+    }])
+    .deposit(&user, &100, &pool_id);
+    assert_eq!(pool_token_client.balance(&user), 900);
+}
+
+#[test]
+fn test_reward_route() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let dao = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+    rewarded_token1_admin.mint(&farm.address, &50000000000);
+    rewarded_token2_admin.mint(&farm.address, &50000000000);
+
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &Some(2 * RATE), &None);
+
+    assert_eq!(
+        farm.try_get_reward_route(&user),
+        Err(Ok(FarmError::RewardRouteNotSet))
+    );
+    assert_eq!(
+        farm.try_set_reward_route(&user, &dao, &10001),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+
+    farm.set_reward_route(&user, &dao, &1000);
+    assert_eq!(
+        farm.get_reward_route(&user),
+        RewardRoute {
+            recipient: dao.clone(),
+            share_bps: 1000
+        }
+    );
+
+    farm.deposit(&user, &100, &pool_id);
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+    farm.withdraw(&user, &100, &pool_id);
+
+    assert_eq!(rewarded_token1_client.balance(&dao), 10000);
+    assert_eq!(rewarded_token2_client.balance(&dao), 20000);
+    assert_eq!(rewarded_token1_client.balance(&user), 90000);
+    assert_eq!(rewarded_token2_client.balance(&user), 180000);
+
+    // Lifetime rewards include the routed share
+    assert_eq!(farm.get_user_lifetime_rewards(&user, &pool_id), (100000, 200000));
+
+    farm.set_reward_route(&user, &dao, &0);
+    assert_eq!(
+        farm.try_get_reward_route(&user),
+        Err(Ok(FarmError::RewardRouteNotSet))
+    );
+}
+
+#[test]
+fn test_payout_address() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let cold = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+    rewarded_token1_admin.mint(&farm.address, &50000000000);
+    rewarded_token2_admin.mint(&farm.address, &50000000000);
+
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &Some(2 * RATE), &None);
+
+    // Only an existing position can be redirected
+    assert_eq!(
+        farm.try_set_payout_address(&user, &pool_id, &cold),
+        Err(Ok(FarmError::UserNotFound))
+    );
+
+    farm.deposit(&user, &100, &pool_id);
+    assert_eq!(farm.get_payout_address(&user, &pool_id), user);
+    farm.set_payout_address(&user, &pool_id, &cold);
+    assert_eq!(farm.get_payout_address(&user, &pool_id), cold);
+
+    // Rewards go to the payout address, principal back to the depositor
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+    farm.withdraw(&user, &50, &pool_id);
+    assert_eq!(rewarded_token1_client.balance(&cold), 100000);
+    assert_eq!(rewarded_token2_client.balance(&cold), 200000);
+    assert_eq!(rewarded_token1_client.balance(&user), 0);
+    assert_eq!(pool_token_client.balance(&user), 950);
+    assert_eq!(farm.get_user_lifetime_rewards(&user, &pool_id), (100000, 200000));
+
+    // Pointing the payout back at the depositor removes the redirection
+    farm.set_payout_address(&user, &pool_id, &user);
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+    farm.withdraw(&user, &50, &pool_id);
+    assert_eq!(rewarded_token1_client.balance(&user), 50000);
+    assert_eq!(rewarded_token1_client.balance(&cold), 100000);
+    assert_eq!(pool_token_client.balance(&user), 1000);
+}
+
+#[test]
+fn test_claim_for() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let cold = Address::generate(&e);
+    let keeper = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &50000000000);
+
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+    farm.deposit(&user, &100, &pool_id);
+    farm.set_payout_address(&user, &pool_id, &cold);
+    farm.set_claim_interval(&pool_id, &500);
+
+    // Only the keeper authorizes, and the rewards go to the user despite the payout address
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+    assert_eq!(farm.claim_for(&keeper, &user, &pool_id), (100000, 0));
+    assert_eq!(e.auths().len(), 1);
+    assert_eq!(e.auths()[0].0, keeper);
+    assert_eq!(rewarded_token1_client.balance(&user), 100000);
+    assert_eq!(rewarded_token1_client.balance(&cold), 0);
+    assert_eq!(rewarded_token1_client.balance(&keeper), 0);
+    assert_eq!(farm.get_user_info(&user, &pool_id).deposited, 100);
+
+    // Keepers are held to the claim interval
+    set_ledger_time(&e, e.ledger().timestamp() + 100);
+    assert_eq!(
+        farm.try_claim_for(&keeper, &user, &pool_id),
+        Err(Ok(FarmError::ClaimTooSoon))
+    );
+    assert_eq!(
+        farm.try_claim_for(&keeper, &cold, &pool_id),
+        Err(Ok(FarmError::UserNotFound))
+    );
+}
+
+#[test]
+fn test_reward_vesting() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+    rewarded_token1_admin.mint(&farm.address, &50000000000);
+    rewarded_token2_admin.mint(&farm.address, &50000000000);
+
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &Some(2 * RATE), &None);
+    farm.set_vesting_period(&1000);
+    assert_eq!(farm.get_vesting_period(), 1000);
+
+    farm.deposit(&user, &100, &pool_id);
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+
+    // Nobody else can claim into the escrow and delay what is already locked
+    let keeper = Address::generate(&e);
+    assert_eq!(
+        farm.try_claim_for(&keeper, &user, &pool_id),
+        Err(Ok(FarmError::NotAuthorized))
+    );
+
+    // Claimed rewards are escrowed rather than paid out
+    assert_eq!(farm.claim(&user, &pool_id), (100000, 200000));
+    assert_eq!(rewarded_token1_client.balance(&user), 0);
+    assert_eq!(farm.claimable_vested(&user), (0, 0));
+    assert_eq!(farm.get_user_lifetime_rewards(&user, &pool_id), (100000, 200000));
+
+    set_ledger_time(&e, e.ledger().timestamp() + 250);
+    assert_eq!(farm.claimable_vested(&user), (25000, 50000));
+    assert_eq!(farm.release_vested(&user), (25000, 50000));
+    assert_eq!(rewarded_token1_client.balance(&user), 25000);
+    assert_eq!(rewarded_token2_client.balance(&user), 50000);
+    assert_eq!(farm.release_vested(&user), (0, 0));
+
+    // Escrowed rewards stay protected next to the rewards allocated to the open position
+    let reconciliation = farm.reconcile().get(1).unwrap();
+    assert_eq!(reconciliation.allocated, 900000 + 75000);
+    assert_eq!(reconciliation.gap, 0);
+
+    // A new claim does not re-lock the escrow for a full period: 75000 locked until 750
+    // seconds from now and 25000 until 1000 seconds from now end 812 seconds from now
+    let claimed_at = e.ledger().timestamp();
+    assert_eq!(farm.claim(&user, &pool_id), (25000, 50000));
+    assert_eq!(farm.get_vesting_schedule(&user).end, claimed_at + 812);
+
+    set_ledger_time(&e, claimed_at + 812);
+    assert_eq!(farm.release_vested(&user), (100000, 200000));
+    assert_eq!(rewarded_token1_client.balance(&user), 125000);
+    assert_eq!(rewarded_token2_client.balance(&user), 250000);
+    assert_eq!(farm.try_get_vesting_schedule(&user), Err(Ok(FarmError::UserNotFound)));
+}
+
+#[test]
+fn test_split_and_merge_positions() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let other = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+    pool_token_admin.mint(&other, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &50000000000);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+
+    farm.deposit(&user, &100, &pool_id);
+    farm.deposit(&other, &100, &pool_id);
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+
+    assert_eq!(
+        farm.try_split_position(&user, &pool_id, &100),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+    let id = farm.split_position(&user, &pool_id, &40);
+    assert_eq!(id, 0);
+
+    // The split off part starts without rewards and both parts keep accruing
+    let split = farm.get_split_position(&id);
+    assert_eq!(split.owner, user);
+    assert_eq!(split.position.deposited, 40);
+    assert_eq!(split.position.accrued_rewards1, 0);
+    assert_eq!(farm.get_user_info(&user, &pool_id).deposited, 60);
+
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+    assert_eq!(farm.get_user_info(&user, &pool_id).accrued_rewards1, 100000 + 60000);
+
+    assert_eq!(
+        farm.try_merge_positions(&other, &pool_id, &Vec::from_array(&e, [id])),
+        Err(Ok(FarmError::NotAuthorized))
+    );
+    assert_eq!(farm.merge_positions(&user, &pool_id, &Vec::from_array(&e, [id])), 100);
+    assert_eq!(
+        farm.try_get_split_position(&id).err(),
+        Some(Ok(FarmError::PositionNotFound))
+    );
+    assert_eq!(
+        farm.try_merge_positions(&user, &pool_id, &Vec::from_array(&e, [id])),
+        Err(Ok(FarmError::PositionNotFound))
+    );
+
+    // Splitting and merging back leaves the rewards of the position unchanged
+    farm.withdraw(&user, &100, &pool_id);
+    farm.withdraw(&other, &100, &pool_id);
+    assert_eq!(
+        rewarded_token1_client.balance(&user),
+        rewarded_token1_client.balance(&other)
+    );
+    assert_eq!(rewarded_token1_client.balance(&user), 200000);
+
+    // A split position can be sold and merged by its buyer
+    let buyer = Address::generate(&e);
+    pool_token_admin.mint(&user, &100);
+    farm.deposit(&user, &100, &pool_id);
+    let id = farm.split_position(&user, &pool_id, &40);
+    assert_eq!(
+        farm.try_transfer_split_position(&id, &user).err(),
+        Some(Ok(FarmError::InvalidAmount))
+    );
+    assert_eq!(farm.transfer_split_position(&id, &buyer).owner, buyer);
+    assert_eq!(
+        farm.try_merge_positions(&user, &pool_id, &Vec::from_array(&e, [id])),
+        Err(Ok(FarmError::NotAuthorized))
+    );
+    set_ledger_time(&e, e.ledger().timestamp() + 100);
+    assert_eq!(farm.merge_positions(&buyer, &pool_id, &Vec::from_array(&e, [id])), 40);
+    assert_eq!(farm.get_user_info(&buyer, &pool_id).accrued_rewards1, 4000);
+}
+
+#[test]
+fn test_rescue_token() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let recipient = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+    let (usdc_client, usdc_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &150000);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+    farm.deposit(&user, &100, &pool_id);
+
+    // Unrelated tokens can be recovered in full
+    usdc_admin.mint(&farm.address, &500);
+    farm.rescue_token(&usdc_client.address, &recipient, &500);
+    assert_eq!(usdc_client.balance(&recipient), 500);
+
+    // Only the surplus of the pool token and reward token can be touched
+    pool_token_admin.mint(&farm.address, &30);
+    assert_eq!(
+        farm.try_rescue_token(&pool_token_client.address, &recipient, &31),
+        Err(Ok(FarmError::ProtectedFunds))
+    );
+    farm.rescue_token(&pool_token_client.address, &recipient, &30);
+
+    assert_eq!(
+        farm.try_rescue_token(&rewarded_token1_client.address, &recipient, &50001),
+        Err(Ok(FarmError::ProtectedFunds))
+    );
+    farm.rescue_token(&rewarded_token1_client.address, &recipient, &50000);
+
+    set_ledger_time(&e, e.ledger().timestamp() + 1000);
+    farm.withdraw(&user, &100, &pool_id);
+    assert_eq!(pool_token_client.balance(&user), 1000);
+    assert_eq!(rewarded_token1_client.balance(&user), 100000);
+}
+
+#[test]
+fn test_min_position_and_sweep_dust() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+    let user3 = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user1, &1000);
+    pool_token_admin.mint(&user2, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &10000000);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+
+    // Dust positions opened before a minimum was enforced
+    farm.deposit(&user1, &1, &pool_id);
+    farm.deposit(&user2, &100, &pool_id);
+
+    set_ledger_time(&e, e.ledger().timestamp() + 100);
+
+    farm.set_min_position(&50);
+    assert_eq!(farm.get_min_position(), 50);
+    assert_eq!(
+        farm.try_deposit(&user1, &48, &pool_id),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+    assert_eq!(
+        farm.try_withdraw(&user2, &51, &pool_id),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+
+    let users = Vec::from_array(&e, [user1.clone(), user2.clone(), user3]);
+    assert_eq!(farm.sweep_dust(&pool_id, &users), 1);
+
+    // The dust position is closed with its principal and rewards returned
+    assert_eq!(
+        farm.try_get_user_info(&user1, &pool_id).err(),
+        Some(Ok(FarmError::UserNotFound))
+    );
+    assert_eq!(pool_token_client.balance(&user1), 1000);
+    assert_eq!(rewarded_token1_client.balance(&user1), 100);
+    assert_eq!(farm.get_user_info(&user2, &pool_id).deposited, 100);
+
+    // Only the dust position's future rewards were released
+    assert_eq!(farm.preview_deposit(&1, &pool_id).required_budget1, 100 * 1000 + 900);
+}
+
+#[test]
+fn test_lifecycle_states() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &10000000);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+    assert_eq!(farm.get_state(), FarmState::Active);
+
+    farm.deposit(&user, &500, &pool_id);
+    set_ledger_time(&e, e.ledger().timestamp() + 100);
+
+    // Paused deposits still let users claim and withdraw
+    farm.set_state(&FarmState::DepositsPaused);
+    assert_eq!(farm.get_state(), FarmState::DepositsPaused);
+    assert_eq!(
+        farm.try_deposit(&user, &100, &pool_id),
+        Err(Ok(FarmError::ContractStopped))
+    );
+    assert_eq!(
+        farm.try_preview_deposit(&100, &pool_id).err(),
+        Some(Ok(FarmError::ContractStopped))
+    );
+    farm.claim(&user, &pool_id);
+    farm.withdraw(&user, &100, &pool_id);
+
+    // A full stop freezes positions, but admin recovery stays available
+    farm.set_state(&FarmState::FullyStopped);
+    assert_eq!(
+        farm.try_withdraw(&user, &100, &pool_id).err(),
+        Some(Ok(FarmError::ContractStopped))
+    );
+    assert_eq!(
+        farm.try_claim(&user, &pool_id).err(),
+        Some(Ok(FarmError::ContractStopped))
+    );
+    assert_eq!(
+        farm.try_split_position(&user, &pool_id, &100).err(),
+        Some(Ok(FarmError::ContractStopped))
+    );
+    farm.set_min_position(&10);
+
+    farm.set_state(&FarmState::Active);
+    farm.deposit(&user, &100, &pool_id);
+    assert_eq!(farm.get_user_info(&user, &pool_id).deposited, 500);
+}
+
+#[test]
+fn test_daily_settlement() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 3 * SECONDS_PER_DAY),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &10000000000);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+
+    farm.deposit(&user, &1000, &pool_id);
+    set_ledger_time(&e, e.ledger().timestamp() + 100);
+    farm.withdraw(&user, &400, &pool_id);
+
+    let settlement = farm.get_daily_settlement();
+    assert_eq!(settlement.day, 0);
+    assert_eq!(settlement.deposits, 1000);
+    assert_eq!(settlement.withdrawals, 400);
+    assert_eq!(settlement.rewards_paid1, 100000);
+    assert_eq!(settlement.rewards_paid2, 0);
+
+    // The first flow of the next day starts fresh totals
+    set_ledger_time(&e, SECONDS_PER_DAY + 10);
+    assert_eq!(farm.get_daily_settlement().deposits, 0);
+    farm.deposit(&user, &400, &pool_id);
+
+    let settlement = farm.get_daily_settlement();
+    assert_eq!(settlement.day, 1);
+    assert_eq!(settlement.deposits, 400);
+    assert_eq!(settlement.withdrawals, 0);
+    assert_eq!(settlement.rewards_paid1, 0);
+}
+
+#[test]
+fn test_reconcile() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+    rewarded_token1_admin.mint(&admin, &150000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    farm.fund_rewards(&admin, &150000, &0);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+    farm.deposit(&user, &100, &pool_id);
+
+    let report = farm.reconcile();
+    assert_eq!(report.len(), 2);
+    assert_eq!(
+        report.get(0).unwrap(),
+        Reconciliation {
+            token: pool_token_client.address.clone(),
+            funded: 0,
+            allocated: 100,
+            balance: 100,
+            gap: 0,
+        }
+    );
+    assert_eq!(
+        report.get(1).unwrap(),
+        Reconciliation {
+            token: rewarded_token1_client.address.clone(),
+            funded: 150000,
+            allocated: 100000,
+            balance: 150000,
+            gap: 0,
+        }
+    );
+
+    // A gap within the tolerance is reported without stopping the contract
+    farm.set_reconcile_policy(&10000, &true);
+    rewarded_token1_client.burn(&farm.address, &60000);
+    assert_eq!(farm.reconcile().get(1).unwrap().gap, 10000);
+    assert_eq!(
+        farm.try_deposit(&user, &1, &pool_id),
+        Err(Ok(FarmError::InsufficientRewards))
+    );
+
+    // Beyond it the contract is fully stopped
+    rewarded_token1_client.burn(&farm.address, &1);
+    assert_eq!(farm.reconcile().get(1).unwrap().gap, 10001);
+    assert_eq!(farm.get_state(), FarmState::FullyStopped);
+    assert_eq!(
+        farm.try_deposit(&user, &1, &pool_id),
+        Err(Ok(FarmError::ContractStopped))
+    );
+}
+
+mod bond_vault {
+    use soroban_sdk::{contract, contractimpl, Address, Env, Symbol};
+
+    #[contract]
+    pub struct BondVault;
+
+    #[contractimpl]
+    impl BondVault {
+        pub fn init(e: Env, bond_id: Address, maturity: u64) {
+            e.storage().instance().set(&Symbol::new(&e, "bond_id"), &bond_id);
+            e.storage().instance().set(&Symbol::new(&e, "maturity"), &maturity);
+        }
+
+        pub fn bond_id(e: Env) -> Address {
+            e.storage().instance().get(&Symbol::new(&e, "bond_id")).unwrap()
+        }
+
+        pub fn maturity(e: Env) -> u64 {
+            e.storage().instance().get(&Symbol::new(&e, "maturity")).unwrap()
+        }
+    }
+}
+
+#[test]
+fn test_initialize_from_vault() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let (rewarded_token1_client, _) = create_token_contract(&e, &admin);
+    let (pool_token_client, _) = create_token_contract(&e, &admin);
+    let maturity = e.ledger().timestamp() + 500;
+
+    let vault = bond_vault::BondVaultClient::new(&e, &e.register_contract(None, bond_vault::BondVault));
+    vault.init(&pool_token_client.address, &maturity);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize_from_vault(
+        &admin,
+        &vault.address,
+        &rewarded_token1_client.address,
+        &None,
+        &(10 * RATE),
+        &None,
+    );
+    assert_eq!(farm.get_farm_info().pool_token, pool_token_client.address);
+    assert_eq!(farm.get_maturity_date(), maturity);
+    assert_eq!(farm.get_bond_vault(), Some(vault.address.clone()));
+    assert_eq!(
+        farm.try_initialize_from_vault(
+            &admin,
+            &vault.address,
+            &rewarded_token1_client.address,
+            &None,
+            &(10 * RATE),
+            &None,
+        ),
+        Err(Ok(FarmError::AlreadyInitialized))
+    );
+}
+
+#[test]
+fn test_bond_vault_maturity() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &10000000);
+    assert_eq!(farm.get_bond_vault(), None);
+
+    let other_vault = bond_vault::BondVaultClient::new(&e, &e.register_contract(None, bond_vault::BondVault));
+    other_vault.init(&rewarded_token1_client.address, &(e.ledger().timestamp() + 500));
+    assert_eq!(
+        farm.try_set_bond_vault(&other_vault.address),
+        Err(Ok(FarmError::BondVaultMismatch))
+    );
+
+    // A bond expiring after the farm leaves the farm's maturity alone
+    let start = e.ledger().timestamp();
+    let vault = bond_vault::BondVaultClient::new(&e, &e.register_contract(None, bond_vault::BondVault));
+    vault.init(&pool_token_client.address, &(start + 2000));
+    farm.set_bond_vault(&vault.address);
+    assert_eq!(farm.get_bond_vault(), Some(vault.address.clone()));
+    assert_eq!(farm.get_maturity_date(), start + 1000);
+
+    // The bond now expires before the maturity the farm was initialized with
+    vault.init(&pool_token_client.address, &(start + 500));
+    assert_eq!(farm.sync_bond_maturity(), start + 500);
+    assert_eq!(farm.get_maturity_date(), start + 500);
+
+    let pool_id = farm.create_pool(&start, &RATE, &None, &None);
+    farm.deposit(&user, &100, &pool_id);
+    assert_eq!(farm.get_global_allocated_rewards(), (50000, 0));
+    assert_eq!(
+        farm.try_set_bond_vault(&vault.address),
+        Err(Ok(FarmError::StakeOutstanding))
+    );
+
+    // Bringing the maturity forward with stake releases the rewards no longer owed
+    vault.init(&pool_token_client.address, &(start + 300));
+    assert_eq!(farm.sync_bond_maturity(), start + 300);
+    assert_eq!(farm.get_global_allocated_rewards(), (30000, 0));
+
+    // Rewards stop accruing at the bond's maturity
+    set_ledger_time(&e, start + 800);
+    assert_eq!(
+        farm.try_deposit(&user, &100, &pool_id),
+        Err(Ok(FarmError::PoolNotActive))
+    );
+    farm.withdraw(&user, &100, &pool_id);
+    assert_eq!(rewarded_token1_client.balance(&user), 30000);
+    assert_eq!(farm.get_global_allocated_rewards(), (0, 0));
+}
+
+#[test]
+fn test_bump() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let (rewarded_token1_client, _) = create_token_contract(&e, &admin);
+    let (pool_token_client, _) = create_token_contract(&e, &admin);
+
+    e.ledger().with_mut(|li| li.min_persistent_entry_ttl = 2 * DAY_IN_LEDGERS);
+
+    let farm_id = e.register_contract(None, crate::Farm {});
+    let farm = FarmClient::new(&e, &farm_id);
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+    farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+
+    // Views leave the TTLs as they are
+    e.ledger().set_sequence_number(e.ledger().sequence() + DAY_IN_LEDGERS + 1);
+    let ttl = e.as_contract(&farm_id, || e.storage().instance().get_ttl());
+    farm.get_pool_info(&0);
+    farm.get_maturity_date();
+    assert_eq!(e.as_contract(&farm_id, || e.storage().instance().get_ttl()), ttl);
+
+    assert_eq!(farm.bump(), 2);
+    e.as_contract(&farm_id, || {
+        assert_eq!(e.storage().instance().get_ttl(), MAX_TTL);
+        assert_eq!(e.storage().persistent().get_ttl(&pool_data_key(1)), MAX_TTL);
+    });
+}
+
+#[test]
+fn test_claim_interval() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &10000000);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+    assert_eq!(
+        farm.try_set_claim_interval(&1, &100),
+        Err(Ok(FarmError::PoolNotFound))
+    );
+    farm.set_claim_interval(&pool_id, &100);
+    assert_eq!(farm.get_claim_interval(&pool_id), 100);
+
+    farm.deposit(&user, &100, &pool_id);
+
+    set_ledger_time(&e, e.ledger().timestamp() + 50);
+    assert_eq!(
+        farm.try_claim(&user, &pool_id),
+        Err(Ok(FarmError::ClaimTooSoon))
+    );
+
+    set_ledger_time(&e, e.ledger().timestamp() + 50);
+    assert_eq!(farm.claim(&user, &pool_id), (10000, 0));
+    assert_eq!(rewarded_token1_client.balance(&user), 10000);
+    assert_eq!(farm.get_user_info(&user, &pool_id).deposited, 100);
+
+    set_ledger_time(&e, e.ledger().timestamp() + 10);
+    assert_eq!(
+        farm.try_withdraw(&user, &0, &pool_id),
+        Err(Ok(FarmError::ClaimTooSoon))
+    );
+
+    // Partial withdrawals within the interval keep the rewards in the position
+    farm.withdraw(&user, &50, &pool_id);
+    assert_eq!(rewarded_token1_client.balance(&user), 10000);
+    assert_eq!(pool_token_client.balance(&user), 950);
+    assert_eq!(farm.get_user_info(&user, &pool_id).accrued_rewards1, 1000);
+
+    // Principal can always be withdrawn, with its rewards
+    set_ledger_time(&e, e.ledger().timestamp() + 10);
+    farm.withdraw(&user, &50, &pool_id);
+    assert_eq!(rewarded_token1_client.balance(&user), 11500);
+    assert_eq!(pool_token_client.balance(&user), 1000);
+    assert_eq!(farm.get_global_allocated_rewards(), (0, 0));
+}
+
+#[test]
+fn test_unwind() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+    let user3 = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user1, &1000);
+    pool_token_admin.mint(&user2, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &200000);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+    farm.deposit(&user1, &100, &pool_id);
+    farm.deposit(&user2, &100, &pool_id);
+    let id = farm.split_position(&user2, &pool_id, &40);
+
+    assert_eq!(
+        farm.try_unwind_batch(&pool_id, &Vec::from_array(&e, [user1.clone()])),
+        Err(Ok(FarmError::NotUnwinding))
+    );
+    assert_eq!(
+        farm.try_unwind_splits(&Vec::from_array(&e, [id])),
+        Err(Ok(FarmError::NotUnwinding))
+    );
+
+    // An incident drains most of the reward budget
+    set_ledger_time(&e, e.ledger().timestamp() + 100);
+    rewarded_token1_client.burn(&farm.address, &185000);
+    farm.set_withdrawals_paused(&true);
+
+    farm.start_unwind();
+    assert!(farm.is_unwinding());
+    assert_eq!(
+        farm.try_deposit(&user1, &100, &pool_id),
+        Err(Ok(FarmError::Unwinding))
+    );
+
+    let users = Vec::from_array(&e, [user1.clone(), user2.clone(), user3]);
+    assert_eq!(farm.unwind_batch(&pool_id, &users), 2);
+
+    // Principal is returned in full, rewards as far as the balance goes
+    assert_eq!(pool_token_client.balance(&user1), 1000);
+    assert_eq!(pool_token_client.balance(&user2), 960);
+    assert_eq!(rewarded_token1_client.balance(&user1), 10000);
+    assert_eq!(rewarded_token1_client.balance(&user2), 5000);
+    assert_eq!(farm.unwind_batch(&pool_id, &users), 0);
+
+    // Split positions are closed as well
+    assert_eq!(farm.unwind_splits(&Vec::from_array(&e, [id, id + 1])), 1);
+    assert_eq!(pool_token_client.balance(&user2), 1000);
+    assert_eq!(rewarded_token1_client.balance(&user2), 5000);
+    assert_eq!(farm.get_global_allocated_rewards(), (0, 0));
+    assert_eq!(farm.unwind_splits(&Vec::from_array(&e, [id])), 0);
+}
+
+#[test]
+fn test_same_ledger_guard() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &10000000);
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+
+    set_ledger_time(&e, e.ledger().timestamp() + 100);
+    farm.deposit(&user, &100, &pool_id);
+    assert_eq!(
+        farm.try_withdraw(&user, &100, &pool_id),
+        Err(Ok(FarmError::SameLedger))
+    );
+    assert_eq!(
+        farm.try_claim(&user, &pool_id),
+        Err(Ok(FarmError::SameLedger))
+    );
+
+    // A deposit topping up an older position resets the guard
+    set_ledger_time(&e, e.ledger().timestamp() + 5);
+    farm.deposit(&user, &100, &pool_id);
+    assert_eq!(
+        farm.try_withdraw(&user, &200, &pool_id),
+        Err(Ok(FarmError::SameLedger))
+    );
+
+    set_ledger_time(&e, e.ledger().timestamp() + 5);
+    farm.withdraw(&user, &200, &pool_id);
+    assert_eq!(pool_token_client.balance(&user), 1000);
+}
+
+#[test]
+fn test_reward_runway() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &200);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+    rewarded_token1_admin.mint(&farm.address, &3000000);
+    rewarded_token2_admin.mint(&farm.address, &5000000);
+
+    let start = e.ledger().timestamp();
+    let single = farm.create_pool(&start, &RATE, &None, &None);
+    let dual = farm.create_pool(&start, &RATE, &Some(3 * RATE), &None);
+
+    // Nothing is emitted before the first deposit
+    let runway = farm.get_reward_runway();
+    assert_eq!(runway.len(), 2);
+    assert_eq!(
+        runway.get(0).unwrap(),
+        RewardRunway {
+            token: rewarded_token1_client.address.clone(),
+            unallocated: 3000000,
+            emission_rate: 0,
+            depletes_at: 0,
+        }
+    );
+
+    farm.deposit(&user, &100, &single);
+    farm.deposit(&user, &100, &dual);
+    let now = e.ledger().timestamp();
+    let runway = farm.get_reward_runway();
+    assert_eq!(
+        runway.get(0).unwrap(),
+        RewardRunway {
+            token: rewarded_token1_client.address.clone(),
+            unallocated: 1000000,
+            emission_rate: 200 * RATE,
+            depletes_at: now + 5000,
+        }
+    );
+    assert_eq!(
+        runway.get(1).unwrap(),
+        RewardRunway {
+            token: rewarded_token2_client.address.clone(),
+            unallocated: 2000000,
+            emission_rate: 300 * RATE,
+            depletes_at: now + 6666,
+        }
+    );
+
+    // Positions stop accruing at maturity
+    set_ledger_time(&e, now + 10000);
+    assert_eq!(farm.get_reward_runway().get(0).unwrap().emission_rate, 0);
+}
+
+mod reward_emitter {
+    use soroban_sdk::{contract, contractimpl, token, vec, Address, Env, IntoVal, Symbol};
+
+    #[contract]
+    pub struct RewardEmitter;
+
+    #[contractimpl]
+    impl RewardEmitter {
+        pub fn init(e: Env, token: Address) {
+            e.storage().instance().set(&Symbol::new(&e, "token"), &token);
+        }
+
+        pub fn token(e: Env) -> Address {
+            e.storage().instance().get(&Symbol::new(&e, "token")).unwrap()
+        }
+
+        pub fn set_committed(e: Env, committed: i128) {
+            e.storage().instance().set(&Symbol::new(&e, "committed"), &committed);
+        }
+
+        pub fn committed(e: Env, _farm: Address) -> i128 {
+            e.storage().instance().get(&Symbol::new(&e, "committed")).unwrap_or(0)
+        }
+
+        pub fn drip(e: Env, farm: Address, amount: i128) -> (i128, i128) {
+            token::Client::new(&e, &Self::token(e.clone())).transfer(
+                &e.current_contract_address(),
+                &farm,
+                &amount,
+            );
+            e.invoke_contract(&farm, &Symbol::new(&e, "notify_reward"), vec![&e, amount.into_val(&e)])
+        }
+    }
+}
+
+#[test]
+fn test_notify_reward() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &200);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &Some(RATE), &None);
+    assert_eq!(farm.try_notify_reward(&100), Err(Ok(FarmError::EmitterNotSet)));
+
+    // Streamed rewards are recorded as funded and let deposits allocate them
+    let emitter = reward_emitter::RewardEmitterClient::new(
+        &e,
+        &e.register_contract(None, reward_emitter::RewardEmitter),
+    );
+    emitter.init(&rewarded_token2_client.address);
+    rewarded_token2_admin.mint(&emitter.address, &1000000);
+    farm.set_emitter(&Some(emitter.address.clone()));
+    assert_eq!(farm.get_emitter(), Some(emitter.address.clone()));
+    assert_eq!(emitter.drip(&farm.address, &1000000), (0, 1000000));
+    assert_eq!(farm.get_funded_rewards(), (0, 1000000));
+    assert_eq!(rewarded_token2_client.balance(&farm.address), 1000000);
+
+    rewarded_token1_admin.mint(&farm.address, &1000000);
+    farm.deposit(&user, &100, &pool_id);
+
+    // Emissions the emitter has committed but not streamed yet count toward deposits
+    rewarded_token1_admin.mint(&farm.address, &1000000);
+    assert_eq!(
+        farm.try_deposit(&user, &100, &pool_id),
+        Err(Ok(FarmError::InsufficientRewards))
+    );
+    emitter.set_committed(&1000000);
+    farm.deposit(&user, &100, &pool_id);
+
+    // An emitter of another token is rejected
+    let (other_token_client, _) = create_token_contract(&e, &admin);
+    emitter.init(&other_token_client.address);
+    assert_eq!(
+        farm.try_set_emitter(&Some(emitter.address.clone())),
+        Err(Ok(FarmError::TokenConflict))
+    );
+
+    farm.set_emitter(&None);
+    assert_eq!(farm.get_emitter(), None);
+}
+
+mod keeper {
+    use soroban_sdk::{contract, contracterror, contractimpl, Address, Env, Symbol, Vec};
+
+    #[contracterror]
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    #[repr(u32)]
+    pub enum KeeperError {
+        Rejected = 1,
+    }
+
+    #[contract]
+    pub struct Keeper;
+
+    #[contractimpl]
+    impl Keeper {
+        pub fn set_failing(e: Env, failing: bool) {
+            e.storage().instance().set(&Symbol::new(&e, "failing"), &failing);
+        }
+
+        pub fn on_farm_event(
+            e: Env,
+            _farm: Address,
+            user: Address,
+            pool_id: u32,
+            event: Symbol,
+        ) -> Result<(), KeeperError> {
+            if e.storage().instance().get(&Symbol::new(&e, "failing")).unwrap_or(false) {
+                return Err(KeeperError::Rejected);
+            }
+            let mut events: Vec<(Address, u32, Symbol)> = Self::events(e.clone());
+            events.push_back((user, pool_id, event));
+            e.storage().instance().set(&Symbol::new(&e, "events"), &events);
+            Ok(())
+        }
+
+        pub fn events(e: Env) -> Vec<(Address, u32, Symbol)> {
+            e.storage()
+                .instance()
+                .get(&Symbol::new(&e, "events"))
+                .unwrap_or(Vec::new(&e))
+        }
+    }
+}
+
+#[test]
+fn test_maturity_reminders() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user1 = Address::generate(&e);
+    let user2 = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user1, &100);
+    pool_token_admin.mint(&user2, &100);
+
+    let start = e.ledger().timestamp();
+    let maturity = start + 30 * SECONDS_PER_DAY;
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &None,
+        &pool_token_client.address,
+        &maturity,
+        &RATE,
+        &None,
+    );
+    rewarded_token1_admin.mint(&farm.address, &50000000000);
+    let pool_id = farm.create_pool(&start, &RATE, &None, &None);
+    farm.deposit(&user1, &100, &pool_id);
+    farm.deposit(&user2, &100, &pool_id);
+
+    let keeper = keeper::KeeperClient::new(&e, &e.register_contract(None, keeper::Keeper));
+    let failing = keeper::KeeperClient::new(&e, &e.register_contract(None, keeper::Keeper));
+    failing.set_failing(&true);
+    farm.set_callback(&user1, &Some(keeper.address.clone()));
+    farm.set_callback(&user2, &Some(failing.address.clone()));
+    assert_eq!(farm.get_callback(&user1), Some(keeper.address.clone()));
+    let users = Vec::from_array(&e, [user1.clone(), user2.clone()]);
+
+    // Nothing to remind of far from maturity
+    assert_eq!(farm.remind(&pool_id, &users), 0);
+
+    // A failing callback does not hold back the others, and each reminder is sent once
+    set_ledger_time(&e, maturity - SECONDS_PER_DAY);
+    assert_eq!(farm.remind(&pool_id, &users), 1);
+    assert_eq!(farm.remind(&pool_id, &users), 0);
+
+    set_ledger_time(&e, maturity);
+    assert_eq!(farm.remind(&pool_id, &users), 1);
+    let expiring = Symbol::new(&e, "expiring");
+    let matured = Symbol::new(&e, "matured");
+    assert_eq!(
+        keeper.events(),
+        Vec::from_array(&e, [(user1.clone(), pool_id, expiring), (user1.clone(), pool_id, matured)])
+    );
+
+    farm.set_callback(&user1, &None);
+    assert_eq!(farm.get_callback(&user1), None);
+}
+
+mod custom_token {
+    soroban_sdk::contractimport!(file = "../soroban_token_contract.wasm");
+}
+
+fn create_custom_token<'a>(e: &Env, admin: &Address, decimals: u32) -> custom_token::Client<'a> {
+    let token = custom_token::Client::new(e, &e.register_contract_wasm(None, custom_token::WASM));
+    token.initialize(
+        admin,
+        &decimals,
+        &String::from_str(e, "Custom"),
+        &String::from_str(e, "CUS"),
+    );
+    token
+}
+
+#[test]
+fn test_token_decimals() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let pool_token = create_custom_token(&e, &admin, 18);
+    let rewarded_token1 = create_custom_token(&e, &admin, 6);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+
+    pool_token.mint(&user, &10i128.pow(18));
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    farm.initialize(
+        &admin,
+        &rewarded_token1.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token.address,
+        &(e.ledger().timestamp() + 10000),
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+    rewarded_token1.mint(&farm.address, &10i128.pow(12));
+    rewarded_token2_admin.mint(&farm.address, &10i128.pow(12));
+
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &Some(RATE), &None);
+    let formula = farm.get_reward_formula(&pool_id);
+    assert_eq!(
+        (formula.decimals, formula.reward_decimals1, formula.reward_decimals2),
+        (18, 6, 7)
+    );
+
+    // One reward token per staked token per second, in each token's own units
+    farm.deposit(&user, &10i128.pow(18), &pool_id);
+    set_ledger_time(&e, e.ledger().timestamp() + 100);
+    assert_eq!(farm.claim(&user, &pool_id), (100 * 10i128.pow(6), 100 * 10i128.pow(7)));
+    assert_eq!(rewarded_token1.balance(&user), 100 * 10i128.pow(6));
+    assert_eq!(
+        farm.get_reward_runway().get(0).unwrap().emission_rate,
+        10i128.pow(6) * RATE
+    );
+}
+
+#[test]
+fn test_reward_insurance() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let fund = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &100);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let maturity = e.ledger().timestamp() + 1000;
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &maturity,
+        &(10 * RATE),
+        &Some(10 * RATE),
+    );
+    rewarded_token1_admin.mint(&farm.address, &150000);
+    rewarded_token2_admin.mint(&farm.address, &250000);
+
+    assert_eq!(farm.try_set_insurance_bps(&1001), Err(Ok(FarmError::InvalidAmount)));
+    farm.set_insurance_bps(&500);
+    assert_eq!(farm.get_insurance_bps(), 500);
+
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &Some(2 * RATE), &None);
+    farm.deposit(&user, &100, &pool_id);
+
+    assert_eq!(
+        farm.try_draw_insurance(&fund, &0, &0),
+        Err(Ok(FarmError::MaturityNotReached))
+    );
+
+    // Raising the rate does not reach the rewards accrued at the former one
+    set_ledger_time(&e, maturity - 500);
+    farm.set_insurance_bps(&1000);
+    assert_eq!(farm.get_insurance_bps(), 1000);
+
+    // A twentieth of the payout is held back in the farm
+    set_ledger_time(&e, maturity);
+    farm.withdraw(&user, &100, &pool_id);
+    assert_eq!(rewarded_token1_client.balance(&user), 95000);
+    assert_eq!(rewarded_token2_client.balance(&user), 190000);
+    assert_eq!(farm.insurance_balance(), (5000, 10000));
+
+    // The insurance is not part of the unallocated rewards
+    assert_eq!(farm.withdraw_unallocated_rewards(), (50000, 50000));
+    assert_eq!(
+        farm.try_draw_insurance(&fund, &5001, &0),
+        Err(Ok(FarmError::InsufficientRewards))
+    );
+    assert_eq!(farm.draw_insurance(&fund, &4000, &10000), (4000, 10000));
+    assert_eq!(rewarded_token1_client.balance(&fund), 4000);
+    assert_eq!(rewarded_token2_client.balance(&fund), 10000);
+    assert_eq!(farm.insurance_balance(), (1000, 0));
+}
+
+#[test]
+fn test_withdraw_queue() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token_client, rewarded_token_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &100);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token_client.address,
+        &None,
+        &pool_token_client.address,
+        &(start + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token_admin.mint(&farm.address, &100000);
+
+    let pool_id = farm.create_pool(&start, &RATE, &None, &None);
+    farm.set_withdraw_cooldown(&pool_id, &100);
+    farm.deposit(&user, &100, &pool_id);
+
+    set_ledger_time(&e, start + 50);
+    assert_eq!(
+        farm.try_withdraw(&user, &40, &pool_id),
+        Err(Ok(FarmError::CooldownActive))
+    );
+
+    // The requested principal stops accruing, the rewards so far are paid
+    assert_eq!(farm.request_withdraw(&user, &40, &pool_id), start + 150);
+    assert_eq!(rewarded_token_client.balance(&user), 5000);
+    assert_eq!(
+        farm.get_withdraw_request(&user, &pool_id),
+        WithdrawRequest {
+            amount: 40,
+            unlocks_at: start + 150,
+        }
+    );
+    assert_eq!(
+        farm.try_request_withdraw(&user, &10, &pool_id),
+        Err(Ok(FarmError::CooldownActive))
+    );
+    assert_eq!(
+        farm.try_execute_withdraw(&user, &pool_id),
+        Err(Ok(FarmError::CooldownActive))
+    );
+
+    set_ledger_time(&e, start + 150);
+    assert_eq!(farm.execute_withdraw(&user, &pool_id), 40);
+    assert_eq!(pool_token_client.balance(&user), 40);
+    assert_eq!(
+        farm.try_get_withdraw_request(&user, &pool_id),
+        Err(Ok(FarmError::WithdrawRequestNotFound))
+    );
+    assert_eq!(farm.claim(&user, &pool_id), (6000, 0));
+}
+
+#[test]
+fn test_get_farm_info() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token1_client, rewarded_token1_admin) = create_token_contract(&e, &admin);
+    let (rewarded_token2_client, rewarded_token2_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &100);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let maturity = e.ledger().timestamp() + 10000;
+    farm.initialize(
+        &admin,
+        &rewarded_token1_client.address,
+        &Some(rewarded_token2_client.address.clone()),
+        &pool_token_client.address,
+        &maturity,
+        &(10 * RATE),
+        &Some(5 * RATE),
+    );
+    rewarded_token1_admin.mint(&farm.address, &3000000);
+    rewarded_token2_admin.mint(&farm.address, &500000);
+
+    let pool_id = farm.create_pool(&e.ledger().timestamp(), &RATE, &None, &None);
+    farm.deposit(&user, &100, &pool_id);
+
+    assert_eq!(
+        farm.get_farm_info(),
+        FarmInfo {
+            reward_tokens: Vec::from_array(
+                &e,
+                [
+                    rewarded_token1_client.address.clone(),
+                    rewarded_token2_client.address.clone(),
+                ],
+            ),
+            pool_token: pool_token_client.address.clone(),
+            maturity,
+            max_reward_ratio1: 10 * RATE,
+            max_reward_ratio2: Some(5 * RATE),
+            pool_count: 1,
+            state: FarmState::Active,
+            allocated_rewards1: 1000000,
+            allocated_rewards2: 0,
+            available_rewards1: 2000000,
+            available_rewards2: 500000,
+        }
+    );
+}
+
+#[test]
+fn test_checkpoint_user() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token_client, rewarded_token_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    pool_token_admin.mint(&user, &100);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token_client.address,
+        &None,
+        &pool_token_client.address,
+        &(start + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token_admin.mint(&farm.address, &100000);
+
+    let pool_id = farm.create_pool(&start, &RATE, &None, &None);
+    farm.deposit(&user, &100, &pool_id);
+
+    // Anyone can crystallize the accruals of a position
+    set_ledger_time(&e, start + 100);
+    assert_eq!(farm.checkpoint_user(&user, &pool_id), (10000, 0));
+    assert!(e.auths().is_empty());
+    let user_data = farm.get_user_info(&user, &pool_id);
+    assert_eq!(user_data.deposit_time, start + 100);
+    assert_eq!(user_data.accrued_rewards1, 10000);
+
+    set_ledger_time(&e, start + 150);
+    assert_eq!(farm.claim(&user, &pool_id), (15000, 0));
+    assert_eq!(rewarded_token_client.balance(&user), 15000);
+}
+
+#[test]
+fn test_claim_all() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token_client, rewarded_token_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+    pool_token_admin.mint(&user, &300);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token_client.address,
+        &None,
+        &pool_token_client.address,
+        &(start + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token_admin.mint(&farm.address, &1000000);
+
+    let pool_id1 = farm.create_pool(&start, &RATE, &None, &None);
+    let pool_id2 = farm.create_pool(&start, &(2 * RATE), &None, &None);
+    farm.create_pool(&start, &RATE, &None, &None);
+    farm.deposit(&user, &100, &pool_id1);
+    farm.deposit(&user, &100, &pool_id2);
+    farm.set_claim_interval(&pool_id2, &200);
+
+    // Every position is claimed, the one whose claim interval has not elapsed is skipped
+    set_ledger_time(&e, start + 100);
+    assert_eq!(farm.claim_all(&user), (10000, 0));
+    set_ledger_time(&e, start + 200);
+    assert_eq!(farm.claim_all(&user), (10000 + 40000, 0));
+    assert_eq!(e.auths()[0].0, user);
+    assert_eq!(rewarded_token_client.balance(&user), 60000);
+}
+
+#[test]
+fn test_pool_apr() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token_client, rewarded_token_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+    pool_token_admin.mint(&user, &10000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token_client.address,
+        &None,
+        &pool_token_client.address,
+        &(start + SECONDS_PER_YEAR),
+        &6341958396,
+        &None,
+    );
+    rewarded_token_admin.mint(&farm.address, &100000);
+
+    // 10% a year accrues a tenth of the principal by maturity, up to rounding
+    let pool_id = farm.create_pool_apr(&start, &1000, &None, &None);
+    let pool = farm.get_pool_info(&pool_id);
+    assert_eq!(pool.reward_ratio1, 3170979198);
+    assert_eq!((pool.apr_bps1, pool.apr_bps2), (1000, 0));
+    assert_eq!(farm.get_pool_info(&farm.create_pool(&start, &3170979198, &None, &None)).apr_bps1, 1000);
+    // The max reward ratio of the farm caps pools at 20% a year
+    assert_eq!(
+        farm.try_create_pool_apr(&start, &2500, &None, &None),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+
+    farm.deposit(&user, &10000, &pool_id);
+    set_ledger_time(&e, start + SECONDS_PER_YEAR);
+    assert_eq!(farm.claim(&user, &pool_id), (999, 0));
+}
+
+#[test]
+fn test_create_pool_deterministic() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let (rewarded_token_client, rewarded_token_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token_client.address,
+        &None,
+        &pool_token_client.address,
+        &(start + 1000),
+        &(10 * RATE),
+        &None,
+    );
+
+    let salt = BytesN::from_array(&e, &[7; 32]);
+    assert_eq!(farm.try_get_pool_id(&salt), Err(Ok(FarmError::PoolNotFound)));
+    farm.create_pool(&start, &RATE, &None, &None);
+
+    // The id is known before the pool exists and does not depend on the pool counter
+    let expected = farm.get_salted_pool_id(&salt);
+    assert!(expected >= crate::SALTED_POOL_ID_BASE);
+    let pool_id = farm.create_pool_deterministic(&salt, &start, &(2 * RATE), &None, &None);
+    assert_eq!(pool_id, expected);
+    assert_eq!(farm.get_pool_id(&salt), pool_id);
+    assert_eq!(farm.get_pool_info(&pool_id).reward_ratio1, 2 * RATE);
+    assert_eq!(
+        farm.try_create_pool_deterministic(&salt, &start, &RATE, &None, &None),
+        Err(Ok(FarmError::PoolKeyTaken))
+    );
+    assert_eq!(farm.get_current_pool_counter(), 1);
+    assert_eq!(farm.create_pool(&start, &RATE, &None, &None), 1);
+
+    // Salted pools take part in farm-wide operations like any other
+    assert_eq!(farm.get_farm_info().pool_count, 3);
+    assert_eq!(farm.bump(), 3);
+    let user = Address::generate(&e);
+    pool_token_admin.mint(&user, &100);
+    rewarded_token_admin.mint(&farm.address, &1000000);
+    farm.deposit(&user, &100, &pool_id);
+    set_ledger_time(&e, start + 100);
+    assert_eq!(farm.claim_all(&user), (2 * 100 * 100, 0));
+}
+
+#[test]
+fn test_min_stake_duration() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token_client, rewarded_token_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+    pool_token_admin.mint(&user, &200);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token_client.address,
+        &None,
+        &pool_token_client.address,
+        &(start + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token_admin.mint(&farm.address, &1000000);
+
+    let strict_pool = farm.create_pool(&start, &RATE, &None, &None);
+    let forfeit_pool = farm.create_pool(&start, &RATE, &None, &None);
+    farm.set_min_stake_duration(&strict_pool, &500, &false);
+    farm.set_min_stake_duration(&forfeit_pool, &500, &true);
+    assert_eq!(
+        farm.get_min_stake_duration(&forfeit_pool),
+        MinStake { duration: 500, forfeit: true }
+    );
+    farm.deposit(&user, &100, &strict_pool);
+    farm.deposit(&user, &100, &forfeit_pool);
+    assert_eq!(farm.get_global_allocated_rewards(), (200000, 0));
+
+    // Early withdrawals revert, while rewards can still be claimed
+    set_ledger_time(&e, start + 100);
+    assert_eq!(
+        farm.try_withdraw(&user, &100, &strict_pool),
+        Err(Ok(FarmError::StakeTooShort))
+    );
+    assert_eq!(farm.claim(&user, &strict_pool), (10000, 0));
+
+    // Or give up the rewards accrued so far, which cannot be claimed ahead of it
+    assert_eq!(farm.try_claim(&user, &forfeit_pool), Err(Ok(FarmError::ClaimTooSoon)));
+    assert_eq!(farm.withdraw(&user, &100, &forfeit_pool), 100);
+    assert_eq!(rewarded_token_client.balance(&user), 10000);
+    assert_eq!(farm.get_global_allocated_rewards(), (90000, 0));
+
+    set_ledger_time(&e, start + 500);
+    assert_eq!(farm.withdraw(&user, &100, &strict_pool), 100);
+    assert_eq!(rewarded_token_client.balance(&user), 50000);
+    assert_eq!(pool_token_client.balance(&user), 200);
+}
+
+#[test]
+fn test_blocklist() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let other = Address::generate(&e);
+    let cold = Address::generate(&e);
+
+    let (rewarded_token_client, rewarded_token_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+    pool_token_admin.mint(&user, &300);
+    pool_token_admin.mint(&other, &100);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token_client.address,
+        &None,
+        &pool_token_client.address,
+        &(start + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token_admin.mint(&farm.address, &1000000);
+
+    let pool_id = farm.create_pool(&start, &RATE, &None, &None);
+    farm.deposit(&user, &100, &pool_id);
+
+    farm.set_blocked(&user, &true);
+    assert_eq!(e.auths()[0].0, admin);
+    assert!(farm.is_blocked(&user));
+    set_ledger_time(&e, start + 100);
+    assert_eq!(farm.try_deposit(&user, &100, &pool_id), Err(Ok(FarmError::AddressBlocked)));
+    assert_eq!(farm.try_withdraw(&user, &100, &pool_id), Err(Ok(FarmError::AddressBlocked)));
+    assert_eq!(farm.try_claim(&user, &pool_id), Err(Ok(FarmError::AddressBlocked)));
+    assert_eq!(farm.try_claim_all(&user), Err(Ok(FarmError::AddressBlocked)));
+    assert_eq!(
+        farm.try_claim_for(&admin, &user, &pool_id),
+        Err(Ok(FarmError::AddressBlocked))
+    );
+
+    // Unblocked positions pick up where they left off
+    farm.set_blocked(&user, &false);
+    assert!(!farm.is_blocked(&user));
+    assert_eq!(farm.withdraw(&user, &100, &pool_id), 100);
+    assert_eq!(rewarded_token_client.balance(&user), 10000);
+    assert_eq!(pool_token_client.balance(&user), 300);
+
+    // Blocked addresses cannot be paid, neither as payout address nor as route recipient
+    farm.deposit(&other, &100, &pool_id);
+    farm.deposit(&user, &100, &pool_id);
+    farm.set_payout_address(&other, &pool_id, &cold);
+    farm.set_blocked(&cold, &true);
+    set_ledger_time(&e, start + 200);
+    assert_eq!(farm.try_claim(&other, &pool_id), Err(Ok(FarmError::AddressBlocked)));
+    assert_eq!(
+        farm.try_set_payout_address(&user, &pool_id, &cold),
+        Err(Ok(FarmError::AddressBlocked))
+    );
+    assert_eq!(
+        farm.try_set_reward_route(&user, &cold, &5000),
+        Err(Ok(FarmError::AddressBlocked))
+    );
+
+    // Force-closes leave positions paying out to a blocked address alone
+    farm.start_unwind();
+    let users = Vec::from_array(&e, [user.clone(), other.clone()]);
+    assert_eq!(farm.unwind_batch(&pool_id, &users), 1);
+    assert_eq!(pool_token_client.balance(&user), 300);
+    assert_eq!(farm.get_user_info(&other, &pool_id).deposited, 100);
+}
+
+#[test]
+fn test_accrual_frozen_while_stopped() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token_client, rewarded_token_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+    pool_token_admin.mint(&user, &100);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token_client.address,
+        &None,
+        &pool_token_client.address,
+        &(start + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token_admin.mint(&farm.address, &1000000);
+
+    let pool_id = farm.create_pool(&start, &RATE, &None, &None);
+    farm.deposit(&user, &100, &pool_id);
+    assert_eq!(farm.get_farm_info().allocated_rewards1, 100 * 1000);
+
+    // Nothing accrues while the farm is fully stopped
+    set_ledger_time(&e, start + 100);
+    farm.set_state(&FarmState::FullyStopped);
+    set_ledger_time(&e, start + 300);
+    assert_eq!(farm.get_user_info(&user, &pool_id).accrued_rewards1, 100 * 100);
+    farm.set_state(&FarmState::Active);
+
+    set_ledger_time(&e, start + 400);
+    assert_eq!(farm.claim(&user, &pool_id), (100 * 200, 0));
+    // The rewards of the frozen period return to the unallocated budget
+    assert_eq!(farm.get_farm_info().allocated_rewards1, 100 * 600);
+
+    // Nor in a pool stopped on its own
+    set_ledger_time(&e, start + 500);
+    farm.set_pool_stopped(&pool_id, &true);
+    set_ledger_time(&e, start + 600);
+    assert_eq!(farm.try_claim(&user, &pool_id), Err(Ok(FarmError::ContractStopped)));
+    assert_eq!(farm.get_user_info(&user, &pool_id).accrued_rewards1, 100 * 100);
+    farm.set_pool_stopped(&pool_id, &false);
+
+    set_ledger_time(&e, start + 700);
+    assert_eq!(farm.claim(&user, &pool_id), (100 * 200, 0));
+    assert_eq!(
+        farm.get_pool_pause(&pool_id),
+        PoolPause {
+            stopped: false,
+            frozen_at: 0,
+            paused_secs: 300,
+        }
+    );
+
+    set_ledger_time(&e, start + 1000);
+    assert_eq!(farm.withdraw(&user, &100, &pool_id), 100);
+    assert_eq!(rewarded_token_client.balance(&user), 100 * 700);
+    assert_eq!(farm.get_farm_info().allocated_rewards1, 0);
+}
+
+mod reentrant_token {
+    use soroban_sdk::{contract, contractimpl, symbol_short, Address, Env, IntoVal, Symbol, Val};
+
+    // Pool token calling back into the farm from every transfer, recording whether it got in
+    #[contract]
+    pub struct ReentrantToken;
+
+    #[contractimpl]
+    impl ReentrantToken {
+        pub fn set_target(e: Env, farm: Address, victim: Address) {
+            e.storage().instance().set(&symbol_short!("farm"), &farm);
+            e.storage().instance().set(&symbol_short!("victim"), &victim);
+        }
+
+        pub fn mint(e: Env, to: Address, amount: i128) {
+            let balance = Self::balance(e.clone(), to.clone());
+            e.storage().persistent().set(&to, &(balance + amount));
+        }
+
+        pub fn balance(e: Env, id: Address) -> i128 {
+            e.storage().persistent().get(&id).unwrap_or(0)
+        }
+
+        pub fn decimals(_e: Env) -> u32 {
+            7
+        }
+
+        pub fn transfer(e: Env, from: Address, to: Address, amount: i128) {
+            if let Some(farm) = e.storage().instance().get::<_, Address>(&symbol_short!("farm")) {
+                let victim: Address = e.storage().instance().get(&symbol_short!("victim")).unwrap();
+                let reentered = e
+                    .try_invoke_contract::<Val, soroban_sdk::Error>(
+                        &farm,
+                        &Symbol::new(&e, "withdraw"),
+                        (victim, amount, 0u32).into_val(&e),
+                    )
+                    .is_ok();
+                e.storage().instance().set(&symbol_short!("reenter"), &reentered);
+            }
+            Self::mint(e.clone(), from, -amount);
+            Self::mint(e, to, amount);
+        }
+
+        pub fn reentered(e: Env) -> bool {
+            e.storage().instance().get(&symbol_short!("reenter")).unwrap_or(false)
+        }
+    }
+}
+
+#[test]
+fn test_reentrant_pool_token() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+    let victim = Address::generate(&e);
+
+    let (rewarded_token_client, rewarded_token_admin) = create_token_contract(&e, &admin);
+    let pool_token = reentrant_token::ReentrantTokenClient::new(
+        &e,
+        &e.register_contract(None, reentrant_token::ReentrantToken),
+    );
+    pool_token.mint(&user, &100);
+    pool_token.mint(&victim, &100);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token_client.address,
+        &None,
+        &pool_token.address,
+        &(start + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token_admin.mint(&farm.address, &300000);
+
+    let pool_id = farm.create_pool(&start, &RATE, &None, &None);
+    farm.deposit(&victim, &100, &pool_id);
+    pool_token.set_target(&farm.address, &victim);
+
+    // The token's attempts to withdraw the victim's position from within a transfer fail. The
+    // host rejects these calls already, so this covers the order of bookkeeping and transfers
+    farm.deposit(&user, &100, &pool_id);
+    assert!(!pool_token.reentered());
+    assert_eq!(pool_token.balance(&farm.address), 200);
+    assert_eq!(farm.get_global_allocated_rewards(), (200000, 0));
+
+    set_ledger_time(&e, start + 100);
+    assert_eq!(farm.withdraw(&user, &100, &pool_id), 100);
+    assert!(!pool_token.reentered());
+    assert_eq!(pool_token.balance(&user), 100);
+    assert_eq!(rewarded_token_client.balance(&user), 10000);
+    assert_eq!(farm.get_user_info(&victim, &pool_id).deposited, 100);
+    assert_eq!(pool_token.balance(&victim), 0);
+    assert_eq!(farm.get_global_allocated_rewards(), (100000, 0));
+
+    // The guard itself rejects deposits and withdrawals while another one is executing
+    e.as_contract(&farm.address, || {
+        e.storage().temporary().set(&crate::DataKey::Entered, &true);
+    });
+    pool_token.mint(&user, &100);
+    assert_eq!(
+        farm.try_deposit(&user, &100, &pool_id),
+        Err(Ok(FarmError::ReentrantCall))
+    );
+    assert_eq!(
+        farm.try_withdraw(&victim, &100, &pool_id),
+        Err(Ok(FarmError::ReentrantCall))
+    );
+}
+
+mod fee_token {
+    use soroban_sdk::{contract, contractimpl, Address, Env};
+
+    // Pool token burning 1% of every transfer
+    #[contract]
+    pub struct FeeToken;
+
+    #[contractimpl]
+    impl FeeToken {
+        pub fn mint(e: Env, to: Address, amount: i128) {
+            let balance = Self::balance(e.clone(), to.clone());
+            e.storage().persistent().set(&to, &(balance + amount));
+        }
+
+        pub fn balance(e: Env, id: Address) -> i128 {
+            e.storage().persistent().get(&id).unwrap_or(0)
+        }
+
+        pub fn decimals(_e: Env) -> u32 {
+            7
+        }
+
+        pub fn transfer(e: Env, from: Address, to: Address, amount: i128) {
+            Self::mint(e.clone(), from, -amount);
+            Self::mint(e, to, amount - amount / 100);
+        }
+    }
+}
+
+#[test]
+fn test_fee_on_transfer_pool_token() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token_client, rewarded_token_admin) = create_token_contract(&e, &admin);
+    let pool_token =
+        fee_token::FeeTokenClient::new(&e, &e.register_contract(None, fee_token::FeeToken));
+    pool_token.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token_client.address,
+        &None,
+        &pool_token.address,
+        &(start + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token_admin.mint(&farm.address, &1000000);
+    let pool_id = farm.create_pool(&start, &RATE, &None, &None);
+
+    // Without a tolerance the fee is a shortfall the deposit refuses
+    assert_eq!(farm.get_transfer_tolerance(), 0);
+    assert_eq!(
+        farm.try_deposit(&user, &100, &pool_id),
+        Err(Ok(FarmError::ReceivedLessThanExpected))
+    );
+    assert_eq!(
+        farm.try_set_transfer_tolerance(&10001),
+        Err(Ok(FarmError::InvalidAmount))
+    );
+
+    // Within the tolerance the position is credited with what the farm received
+    farm.set_transfer_tolerance(&100);
+    assert_eq!(farm.deposit(&user, &500, &pool_id), 495);
+    assert_eq!(pool_token.balance(&farm.address), 495);
+    assert_eq!(farm.get_user_info(&user, &pool_id).deposited, 495);
+    assert_eq!(farm.get_global_allocated_rewards(), (495000, 0));
+
+    farm.set_transfer_tolerance(&50);
+    assert_eq!(
+        farm.try_deposit(&user, &100, &pool_id),
+        Err(Ok(FarmError::ReceivedLessThanExpected))
+    );
+
+    // The whole credited principal can be withdrawn
+    set_ledger_time(&e, start + 100);
+    assert_eq!(farm.withdraw(&user, &495, &pool_id), 495);
+    assert_eq!(pool_token.balance(&farm.address), 0);
+    assert_eq!(pool_token.balance(&user), 500 + 491);
+    assert_eq!(rewarded_token_client.balance(&user), 49500);
+}
+
+#[test]
+fn test_operation_budgets() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    let (rewarded_token_client, rewarded_token_admin) = create_token_contract(&e, &admin);
+    let (pool_token_client, pool_token_admin) = create_token_contract(&e, &admin);
+    pool_token_admin.mint(&user, &1000);
+
+    let farm = FarmClient::new(&e, &e.register_contract(None, crate::Farm {}));
+    let start = e.ledger().timestamp();
+    farm.initialize(
+        &admin,
+        &rewarded_token_client.address,
+        &None,
+        &pool_token_client.address,
+        &(start + 1000),
+        &(10 * RATE),
+        &None,
+    );
+    rewarded_token_admin.mint(&farm.address, &1000000);
+    let pool_id = farm.create_pool(&start, &RATE, &None, &None);
+    farm.deposit(&user, &100, &pool_id);
+
+    // Bounds sit about 10% above the measured costs, to catch regressions on the hot paths
+    set_ledger_time(&e, start + 10);
+    e.budget().reset_default();
+    farm.deposit(&user, &100, &pool_id);
+    assert!(e.budget().cpu_instruction_cost() < 725_000);
+    assert!(e.budget().memory_bytes_cost() < 128_000);
+
+    set_ledger_time(&e, start + 20);
+    e.budget().reset_default();
+    farm.claim(&user, &pool_id);
+    assert!(e.budget().cpu_instruction_cost() < 725_000);
+    assert!(e.budget().memory_bytes_cost() < 135_000);
+
+    set_ledger_time(&e, start + 30);
+    e.budget().reset_default();
+    farm.withdraw(&user, &100, &pool_id);
+    assert!(e.budget().cpu_instruction_cost() < 910_000);
+    assert!(e.budget().memory_bytes_cost() < 150_000);
+}